@@ -0,0 +1,93 @@
+use core::f64::consts::PI;
+
+// Output samples each step is spread over; the output lags the mixer by half of them
+const WIDTH: usize = 16;
+// Sub-sample positions a step's edge can be put at
+const PHASES: usize = 32;
+// Pending deltas, from the next sample to be read on; a power of two so indices wrap with a mask
+const SIZE: usize = 32;
+// Cutoff of the band limit as a fraction of the sample rate, a little under Nyquist (0.5)
+const CUTOFF: f64 = 0.45;
+
+// Band-limited step synthesis in the style of blargg's blip_buf. Rather than sampling the mixer
+// every so many cycles, which aliases anything above half the sample rate back down into the
+// audible range, every change of its level goes in as a step at its exact sub-sample time, with
+// the edge smeared over WIDTH samples by a windowed sinc. Summing the deltas gives the output.
+#[derive(Clone)]
+pub struct Blip {
+    // Per phase, how much of a unit step lands on each of the WIDTH samples; each row sums to 1
+    kernel: [[f32; WIDTH]; PHASES + 1],
+    deltas: [f32; SIZE],
+    head: usize,
+    level: f32,
+}
+
+impl Blip {
+    pub fn new() -> Self {
+        let mut kernel: [[f32; WIDTH]; PHASES + 1] = [[0.0; WIDTH]; PHASES + 1];
+        let half: f64 = (WIDTH / 2) as f64;
+        for (phase, row) in kernel.iter_mut().enumerate() {
+            let weight = |k: usize| {
+                let x: f64 = k as f64 - half - phase as f64 / PHASES as f64;
+                let window: f64 = 0.42 + 0.5 * cosine(PI * x / half) + 0.08 * cosine(2.0 * PI * x / half);
+                let y: f64 = 2.0 * CUTOFF * x;
+                let sinc: f64 = if y.abs() < 1e-9 { 1.0 } else { sine(PI * y) / (PI * y) };
+                if x.abs() >= half { 0.0 } else { sinc * window }
+            };
+            let total: f64 = (0..WIDTH).map(weight).sum();
+            for (k, cell) in row.iter_mut().enumerate() { *cell = (weight(k) / total) as f32; }
+        }
+        Blip { kernel, deltas: [0.0; SIZE], head: 0, level: 0.0 }
+    }
+    // A change of `delta` in the level, `offset` samples after the one before the next read
+    // (1.0 is exactly where the next read samples); at most a few samples ahead
+    pub fn add_delta(&mut self, offset: f64, delta: f32) {
+        let offset: f64 = offset.clamp(0.0, (SIZE - WIDTH) as f64);
+        let whole: usize = offset as usize;
+        let phase: usize = ((offset - whole as f64) * PHASES as f64 + 0.5) as usize;
+        for (k, weight) in self.kernel[phase].iter().enumerate() {
+            self.deltas[(self.head + whole + k) & (SIZE - 1)] += delta * weight;
+        }
+    }
+    // The next output sample
+    pub fn read(&mut self) -> f32 {
+        self.level += self.deltas[self.head];
+        self.deltas[self.head] = 0.0;
+        self.head = (self.head + 1) & (SIZE - 1);
+        self.level
+    }
+}
+
+// Taylor series sine after reducing `x` to -PI..PI; the core library has no trigonometry without std
+fn sine(x: f64) -> f64 {
+    let turns: f64 = x / (2.0 * PI);
+    let nearest: f64 = if turns < 0.0 { (turns - 0.5) as i64 as f64 } else { (turns + 0.5) as i64 as f64 };
+    let x: f64 = x - nearest * 2.0 * PI;
+    let (mut term, mut sum): (f64, f64) = (x, x);
+    for n in 1..12 {
+        term *= -x * x / ((2 * n) as f64 * (2 * n + 1) as f64);
+        sum += term;
+    }
+    sum
+}
+
+fn cosine(x: f64) -> f64 { sine(x + PI / 2.0) }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_steps_settle_at_their_height() {
+        assert!((sine(PI / 6.0) - 0.5).abs() < 1e-9 && (cosine(-3.0 * PI) + 1.0).abs() < 1e-9);
+        for offset in [0.0, 0.3, 0.999, 1.5] {
+            let mut blip: Blip = Blip::new();
+            blip.add_delta(offset, 1.0);
+            let output: Vec<f32> = (0..WIDTH + 2).map(|_| blip.read()).collect();
+            // Nothing before the edge starts, the full step once it's over, ringing in between
+            assert_eq!(output[0], 0.0);
+            assert!(output[WIDTH / 2 - 2] < 0.1 && output[WIDTH / 2 + 2] > 0.9);
+            assert!(output[WIDTH..].iter().all(|sample| (sample - 1.0).abs() < 1e-5));
+        }
+    }
+}
@@ -1,12 +1,22 @@
-use crate::cartridge::Rom;
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::cartridge::Region;
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
-pub const PERIODS: [u8; 16] = [ 214, 190, 170, 160, 143, 127, 113, 107, 95, 80, 71, 64, 53, 42, 36, 27 ];
+#[rustfmt::skip]
+pub const NTSC_PERIODS: [u8; 16] = [ 214, 190, 170, 160, 143, 127, 113, 107, 95, 80, 71, 64, 53, 42, 36, 27 ];
+#[rustfmt::skip]
+pub const PAL_PERIODS: [u8; 16] = [ 199, 177, 158, 149, 138, 118, 105, 99, 88, 74, 66, 59, 49, 39, 33, 25 ];
+
+// The DMC's view of the CPU address space, to fetch its sample bytes from $8000-$FFFF. The Bus
+// lends it the cartridge every tick; any `FnMut(u16) -> u8` will do.
+pub trait SampleMemory {
+    fn read_sample(&mut self, addr: u16) -> u8;
+}
+
+impl<F: FnMut(u16) -> u8> SampleMemory for F {
+    fn read_sample(&mut self, addr: u16) -> u8 { self(addr) }
+}
 
 pub struct DmcChannel {
-    cartridge: Option<Rc<RefCell<Rom>>>,
+    periods: &'static [u8; 16],
     pub irq_enabled: bool,
     pub irq_flag: bool,
     enabled: bool,
@@ -24,20 +34,23 @@ pub struct DmcChannel {
 }
 
 impl DmcChannel {
-    pub fn new() -> Self {
+    pub fn new(region: Region) -> Self {
+        let periods: &'static [u8; 16] = match region { Region::NTSC | Region::Dendy => &NTSC_PERIODS, Region::PAL => &PAL_PERIODS };
         DmcChannel {
-            cartridge: None,
+            periods,
             irq_enabled: false,
             irq_flag: false,
             enabled: false,
             output: 0,
-            sample_address: 0,
-            sample_length: 0,
+            // What $4012 and $4013 at 0 stand for
+            sample_address: 0xC000,
+            sample_length: 1,
             current_address: 0,
             current_length: 0,
             shift_register: 0,
             bit_count: 0,
-            period: 0,
+            // Rate 0 until $4010 is written
+            period: periods[0],
             counter: 0,
             looping: false,
             cpu_stall_cycles: 0,
@@ -50,6 +63,9 @@ impl DmcChannel {
         c
     }
 
+    // Whether the next sequencer tick fetches a sample byte (and so stalls the CPU)
+    pub fn fetch_due(&self) -> bool { self.enabled && self.current_length > 0 && self.bit_count == 0 }
+
     pub fn sample(&self) -> u8 {
         self.output
     }
@@ -60,7 +76,7 @@ impl DmcChannel {
                 self.irq_enabled = value & 0b1000_0000 != 0;
                 self.irq_flag &= self.irq_enabled;
                 self.looping = value & 0b0100_0000 != 0;
-                self.period = PERIODS[value as usize & 0x0F];
+                self.period = self.periods[value as usize & 0x0F];
             }
             0x4011 => {
                 self.output = value & 0b0111_1111;
@@ -88,30 +104,22 @@ impl DmcChannel {
         }
     }
 
-    pub fn set_cartridge(&mut self, cartridge: Rc<RefCell<Rom>>) {
-        self.cartridge = Some(cartridge);
-    }
-
     pub fn restart(&mut self) {
         self.current_address = self.sample_address;
         self.current_length = self.sample_length;
     }
 
-    pub fn tick_sequencer(&mut self) {
+    pub fn tick_sequencer(&mut self, memory: &mut dyn SampleMemory) {
         if self.enabled {
-            self.tick_read();
+            self.tick_read(memory);
             self.tick_shift();
         }
     }
 
-    fn tick_read(&mut self) {
+    fn tick_read(&mut self, memory: &mut dyn SampleMemory) {
         if self.current_length > 0 && self.bit_count == 0 {
             self.cpu_stall_cycles += 4;
-            let a = self.current_address;
-            self.shift_register = match self.cartridge {
-                Some(ref c) => c.borrow_mut().read_prg_byte(a),
-                None => 0,
-            };
+            self.shift_register = memory.read_sample(self.current_address);
             self.bit_count = 8;
             self.current_address = self.current_address.wrapping_add(1);
             if self.current_address == 0 {
@@ -149,3 +157,38 @@ impl DmcChannel {
 
     pub fn playing(&self) -> bool { self.current_length > 0 }
 }
+
+savestate_fields!(DmcChannel {
+    irq_enabled, irq_flag, enabled, output, sample_address, sample_length, current_address, current_length,
+    shift_register, bit_count, period, counter, looping, cpu_stall_cycles,
+});
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prelude::*;
+
+    #[test]
+    fn test_sample_fetched_from_memory() {
+        let mut dmc: DmcChannel = DmcChannel::new(Region::NTSC);
+        dmc.write_register(0x4010, 0x0F);
+        dmc.write_register(0x4011, 0x40);
+        // Two bytes at $C040: all ones, then all zeros
+        dmc.write_register(0x4012, 0x01);
+        dmc.write_register(0x4013, 0x00);
+        // Lengths come in steps of 16 bytes, more than this needs
+        dmc.sample_length = 2;
+        dmc.set_enabled(true);
+        let mut fetched: Vec<u16> = Vec::new();
+        let mut memory = |addr: u16| {
+            fetched.push(addr);
+            if addr == 0xC040 { 0xFF } else { 0x00 }
+        };
+        for _ in 0..8 * 27 { dmc.tick_sequencer(&mut memory); }
+        assert_eq!(dmc.sample(), 0x40 + 16);
+        for _ in 0..8 * 27 { dmc.tick_sequencer(&mut memory); }
+        assert_eq!(dmc.sample(), 0x40);
+        assert_eq!(fetched, vec![0xC040, 0xC041]);
+        assert!(!dmc.playing());
+    }
+}
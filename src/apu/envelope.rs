@@ -59,3 +59,6 @@ impl Envelope {
     pub fn start(&mut self) { self.start = true; }
     pub fn volume(&self) -> u8 { if self.control.constant { self.control.constant_level } else { self.level } }
 }
+
+savestate_fields!(EnvelopeControl { constant_level, decay_period, constant, looping });
+savestate_fields!(Envelope { control, counter, level, start });
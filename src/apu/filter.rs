@@ -1,5 +1,6 @@
-use std::f64::consts::PI;
+use core::f64::consts::PI;
 
+#[derive(Clone)]
 pub struct FirstOrderFilter {
     b0: f64,
     b1: f64,
@@ -40,3 +41,6 @@ impl FirstOrderFilter {
         y
     }
 }
+
+// Coefficients are fixed at construction; only the filter history is state
+savestate_fields!(FirstOrderFilter { prev_x, prev_y });
@@ -1,13 +1,26 @@
+use crate::prelude::*;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use crate::cartridge::Region;
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum Mode { Zero, One }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum FrameResult { None, Quarter, Half }
 
+// CPU cycles after the sequencer's reset of its steps: the first three, the last one of the 4-step
+// sequence (which sets the IRQ flag over three cycles, the second of them clocking a half frame
+// and the third starting over) and the half frame the 5-step one ends with (starting over a cycle later)
+const NTSC_STEPS: [i64; 5] = [7_457, 14_913, 22_371, 29_828, 37_281];
+const PAL_STEPS: [i64; 5] = [8_313, 16_627, 24_939, 33_252, 41_565];
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct FrameCounter {
+    steps: [i64; 5],
     pub counter: i64,
-    pub cycles: u64,
+    // A $4017 write waiting to reset the sequencer, and the cycles left until it does
+    pending_write: Option<u8>,
+    write_delay: u8,
     pub irq_enabled: bool,
     pub public_irq_flag: bool,
     pub private_irq_flag: bool,
@@ -15,10 +28,12 @@ pub struct FrameCounter {
 }
 
 impl FrameCounter {
-    pub fn new() -> Self {
+    pub fn new(region: Region) -> Self {
         FrameCounter {
+            steps: match region { Region::NTSC | Region::Dendy => NTSC_STEPS, Region::PAL => PAL_STEPS },
             counter: 0,
-            cycles: 0,
+            pending_write: None,
+            write_delay: 0,
             irq_enabled: true,
             public_irq_flag: false,
             private_irq_flag: false,
@@ -26,51 +41,63 @@ impl FrameCounter {
         }
     }
 
+    // `cycles` is how many CPU cycles ran before the write's. The IRQ inhibit flag takes effect
+    // right away, the mode and the sequencer reset 3 cycles after an even write cycle and 4 after
+    // an odd one, so always on a cycle the APU clocks on (odd, see APU::step).
     pub fn write_register(&mut self, value: u8, cycles: u64) -> FrameResult {
         self.irq_enabled = value & 0x40 == 0;
         if !self.irq_enabled {
             self.public_irq_flag = false;
             self.private_irq_flag = false;
         }
-        self.mode = if value & 0x80 == 0 { Mode::Zero } else { Mode::One };
-        self.counter = if cycles & 1 == 0 { 0 } else { -1 };
-        match self.mode {
-            Mode::Zero => FrameResult::None,
-            Mode::One => FrameResult::Half,
-        }
+        self.pending_write = Some(value);
+        self.write_delay = if cycles.is_multiple_of(2) { 4 } else { 3 };
+        FrameResult::None
     }
 
+    // One CPU cycle
     pub fn tick(&mut self) -> FrameResult {
-        let result: FrameResult = match self.mode {
+        if self.pending_write.is_some() {
+            if self.write_delay == 0 { return self.reset(); }
+            self.write_delay -= 1;
+        }
+        self.counter += 1;
+        match self.mode {
             Mode::Zero => self.tick_mode_zero(),
             Mode::One => self.tick_mode_one(),
-        };
-        self.counter += 1;
-        result
+        }
+    }
+
+    // A delayed $4017 write landing: the 5-step mode clocks a half frame (and quarter) right away
+    fn reset(&mut self) -> FrameResult {
+        let value: u8 = self.pending_write.take().unwrap_or(0);
+        self.mode = if value & 0x80 == 0 { Mode::Zero } else { Mode::One };
+        self.counter = 0;
+        match self.mode {
+            Mode::Zero => FrameResult::None,
+            Mode::One => FrameResult::Half,
+        }
     }
 
     fn tick_mode_zero(&mut self) -> FrameResult {
+        let [quarter, half, three_quarters, last, _] = self.steps;
         match self.counter {
-            7_459 => FrameResult::Quarter,
-            14_915 => FrameResult::Half,
-            22_373 => FrameResult::Quarter,
-            29_830 => {
+            c if c == quarter || c == three_quarters => FrameResult::Quarter,
+            c if c == half => FrameResult::Half,
+            c if c == last => {
                 self.trigger_irq();
                 FrameResult::None
             }
-            29_831 => {
+            c if c == last + 1 => {
                 self.trigger_irq();
                 self.publish_irq();
                 FrameResult::Half
             }
-            29_832 => {
+            c if c == last + 2 => {
                 self.trigger_irq();
                 self.publish_irq();
-                // The counter *actually* rolls over to zero on cycle 29_830.
-                // The actions at 29_831 and 29_832 happen after the rollover.
-                // We emulate that by resetting our counter at 29_832 and skipping
-                // it ahead as if it had been reset at 29_830.
-                self.counter = 2;
+                // This cycle is also the next sequence's cycle 0
+                self.counter = 0;
                 FrameResult::None
             }
             _ => FrameResult::None,
@@ -78,22 +105,127 @@ impl FrameCounter {
     }
 
     fn tick_mode_one(&mut self) -> FrameResult {
+        let [quarter, half, three_quarters, _, end] = self.steps;
         match self.counter {
-            7_459 => FrameResult::Quarter,
-            14_915 => FrameResult::Half,
-            22_373 => FrameResult::Quarter,
-            37_283 => {
-                // The counter *actually* rolls over to zero on cycle 37_282.
-                // The Half-frame signal is sent 1 tick after. We emulate this
-                // behavior by adding an extra tick to the clock, then skipping
-                // the clock ahead as if it had been reset at 37_282.
-                self.counter = 1;
-                FrameResult::Half
+            c if c == quarter || c == three_quarters => FrameResult::Quarter,
+            c if c == half || c == end => FrameResult::Half,
+            c if c == end + 1 => {
+                self.counter = 0;
+                FrameResult::None
             }
             _ => FrameResult::None,
         }
     }
 
+    // Whether the coming cycle sets the IRQ flag. A $4015 read on that cycle sees the flag set
+    // already and, since it's set again right away, doesn't clear it.
+    pub fn irq_due(&self) -> bool {
+        let last: i64 = self.steps[3];
+        let resetting: bool = self.pending_write.is_some() && self.write_delay == 0;
+        self.mode == Mode::Zero && self.irq_enabled && !resetting && (last..=last + 2).contains(&(self.counter + 1))
+    }
+
     pub fn trigger_irq(&mut self) { if self.irq_enabled { self.private_irq_flag = true; } }
     pub fn publish_irq(&mut self) { self.public_irq_flag = self.private_irq_flag; }
 }
+
+impl Savestate for FrameCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.counter.save_state(w);
+        self.pending_write.save_state(w);
+        self.write_delay.save_state(w);
+        self.irq_enabled.save_state(w);
+        self.public_irq_flag.save_state(w);
+        self.private_irq_flag.save_state(w);
+        self.mode.save_state(w);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.counter.load_state(r)?;
+        if r.version() >= 4 {
+            self.pending_write.load_state(r)?;
+            self.write_delay.load_state(r)?;
+            // Version 4 also kept a cooldown after each clock, since dropped
+            if r.version() == 4 {
+                let mut clock_block: u8 = 0;
+                clock_block.load_state(r)?;
+            }
+        } else {
+            // Before version 4, $4017 writes took effect at once and the counter was compared
+            // before counting the cycle, against steps 2 cycles later than these; its CPU cycle
+            // count is no longer needed
+            let mut cycles: u64 = 0;
+            cycles.load_state(r)?;
+            self.counter -= 3;
+            (self.pending_write, self.write_delay) = (None, 0);
+        }
+        self.irq_enabled.load_state(r)?;
+        self.public_irq_flag.load_state(r)?;
+        self.private_irq_flag.load_state(r)?;
+        self.mode.load_state(r)
+    }
+}
+
+impl Savestate for Mode {
+    fn save_state(&self, w: &mut StateWriter) { (*self == Mode::One).save_state(w); }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let mut one: bool = false;
+        one.load_state(r)?;
+        *self = if one { Mode::One } else { Mode::Zero };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The quarter and half frame clocks of `ticks` cycles, by the cycle (from 1) they happened on
+    fn run(counter: &mut FrameCounter, ticks: usize) -> Vec<(usize, FrameResult)> {
+        (1..=ticks).filter_map(|cycle| match counter.tick() {
+            FrameResult::None => None,
+            result => Some((cycle, result)),
+        }).collect()
+    }
+
+    #[test]
+    fn test_four_step_sequence() {
+        let mut counter: FrameCounter = FrameCounter::new(Region::NTSC);
+        // An even write cycle: the reset lands 3 cycles after it, on cycle 4
+        counter.write_register(0x00, 1);
+        let clocks: Vec<(usize, FrameResult)> = run(&mut counter, 4 + 29_830 + 7_457);
+        assert_eq!(clocks, vec![
+            (4 + 7_457, FrameResult::Quarter), (4 + 14_913, FrameResult::Half), (4 + 22_371, FrameResult::Quarter),
+            (4 + 29_829, FrameResult::Half), (4 + 29_830 + 7_457, FrameResult::Quarter),
+        ]);
+        assert!(counter.private_irq_flag && counter.public_irq_flag);
+        // An odd write cycle waits a cycle longer
+        counter.write_register(0x40, 0);
+        assert!(!counter.private_irq_flag);
+        assert_eq!(run(&mut counter, 5 + 7_457).last(), Some(&(5 + 7_457, FrameResult::Quarter)));
+    }
+
+    #[test]
+    fn test_five_step_sequence() {
+        let mut counter: FrameCounter = FrameCounter::new(Region::NTSC);
+        counter.write_register(0x80, 1);
+        let clocks: Vec<(usize, FrameResult)> = run(&mut counter, 4 + 37_282 + 7_457);
+        assert_eq!(clocks, vec![
+            (4, FrameResult::Half), (4 + 7_457, FrameResult::Quarter), (4 + 14_913, FrameResult::Half),
+            (4 + 22_371, FrameResult::Quarter), (4 + 37_281, FrameResult::Half), (4 + 37_282 + 7_457, FrameResult::Quarter),
+        ]);
+        assert!(!counter.private_irq_flag);
+    }
+
+    #[test]
+    fn test_version_3_state_loads() {
+        let mut w: StateWriter = StateWriter::new();
+        (7_459i64, 1_000u64).save_state(&mut w);
+        ((true, false), (true, false)).save_state(&mut w);
+        let mut counter: FrameCounter = FrameCounter::new(Region::NTSC);
+        counter.write_register(0x80, 1);
+        counter.load_state(&mut StateReader::with_version(&w.data, 3)).unwrap();
+        assert_eq!((counter.pending_write, counter.irq_enabled, counter.private_irq_flag, counter.mode), (None, true, true, Mode::Zero));
+        // Version 3 clocked its first quarter frame on this tick
+        assert_eq!(run(&mut counter, 1), vec![(1, FrameResult::Quarter)]);
+    }
+}
@@ -1,3 +1,6 @@
+use crate::prelude::*;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
 #[cfg_attr(rustfmt, rustfmt_skip)]
 pub const LENGTHS: [u8; 32] = [
     0x0a, 0xfe, 0x14, 0x02, 0x28, 0x04, 0x50, 0x06, 0xa0, 0x08, 0x3c, 0x0a, 0x0e, 0x0c, 0x1a,
@@ -8,8 +11,11 @@ pub struct LengthCounter {
     counter: u8,
     pub enabled: bool,
     halted: bool,
+    // Writes take effect after the cycle's length clock: a clock sees the old halt flag, and a
+    // reload is lost when the clock decremented the counter on the same cycle
     pending_halted: Option<bool>,
     pending_register: Option<u8>,
+    clocked: bool,
 }
 
 impl LengthCounter {
@@ -20,6 +26,7 @@ impl LengthCounter {
             halted: false,
             pending_halted: None,
             pending_register: None,
+            clocked: false,
         }
     }
 
@@ -36,20 +43,89 @@ impl LengthCounter {
             self.pending_halted = None;
         }
 
-        if let Some(value) = self.pending_register {
-            if self.enabled { self.counter = LENGTHS[(value >> 3) as usize]; }
-            self.pending_register = None;
+        if let Some(value) = self.pending_register.take() {
+            if self.enabled && !self.clocked { self.counter = LENGTHS[(value >> 3) as usize]; }
         }
+        self.clocked = false;
     }
 
     pub fn tick(&mut self) {
-        if let Some(_) = self.pending_register {
-            if self.counter == 0 { return; }
-            else { self.pending_register = None; }
+        if self.enabled && !self.halted && self.playing() {
+            self.counter -= 1;
+            self.clocked = true;
         }
-        if self.enabled && !self.halted && self.playing() { self.counter -= 1; }
     }
 
     pub fn active(&self) -> bool { self.enabled && self.counter > 0 }
     pub fn playing(&self) -> bool { self.counter > 0 }
 }
+
+impl Savestate for LengthCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.counter.save_state(w);
+        self.enabled.save_state(w);
+        self.halted.save_state(w);
+        self.pending_halted.save_state(w);
+        self.pending_register.save_state(w);
+        self.clocked.save_state(w);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.counter.load_state(r)?;
+        self.enabled.load_state(r)?;
+        self.halted.load_state(r)?;
+        self.pending_halted.load_state(r)?;
+        self.pending_register.load_state(r)?;
+        // Added in version 5; states are taken between cycles, when it's always clear
+        self.clocked = false;
+        if r.version() >= 5 { self.clocked.load_state(r)?; }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A counter at `counter`, written `value` on a cycle that clocks it
+    fn reload_while_clocked(counter: u8, halted: bool) -> u8 {
+        let mut length: LengthCounter = LengthCounter::new();
+        length.set_enabled(true);
+        length.counter = counter;
+        length.halted = halted;
+        length.write_register(0x08);
+        length.tick();
+        length.update_pending();
+        length.counter
+    }
+
+    #[test]
+    fn test_reload_during_a_clock() {
+        // Ignored if the clock decremented the counter, taken if there was nothing to decrement
+        assert_eq!(reload_while_clocked(10, false), 9);
+        assert_eq!(reload_while_clocked(0, false), LENGTHS[1]);
+        assert_eq!(reload_while_clocked(10, true), LENGTHS[1]);
+    }
+
+    #[test]
+    fn test_version_4_state_has_no_clocked_flag() {
+        let mut w: StateWriter = StateWriter::new();
+        ((10u8, true), (false, (None::<bool>, Some(0x08u8)))).save_state(&mut w);
+        let mut length: LengthCounter = LengthCounter::new();
+        length.clocked = true;
+        length.load_state(&mut StateReader::with_version(&w.data, 4)).unwrap();
+        assert_eq!((length.counter, length.enabled, length.halted, length.pending_register, length.clocked), (10, true, false, Some(0x08), false));
+    }
+
+    #[test]
+    fn test_halt_written_during_a_clock() {
+        let mut length: LengthCounter = LengthCounter::new();
+        length.set_enabled(true);
+        length.counter = 10;
+        length.set_halted(true);
+        length.tick();
+        length.update_pending();
+        assert_eq!(length.counter, 9);
+        length.tick();
+        assert_eq!(length.counter, 9);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::prelude::*;
+
+// The APU's sound channels, in the order the mixer takes their levels, then the cartridge's own
+// sound chip (expansion audio) on boards that have one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    Expansion,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 6] = [Channel::Pulse1, Channel::Pulse2, Channel::Triangle, Channel::Noise, Channel::Dmc, Channel::Expansion];
+    pub fn parse(name: &str) -> Result<Channel, String> {
+        Channel::ALL.into_iter().find(|channel| channel.name() == name.trim().to_lowercase())
+            .ok_or_else(|| format!("Unknown channel {:?}: expected pulse1, pulse2, triangle, noise, dmc or expansion", name))
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+            Channel::Expansion => "expansion",
+        }
+    }
+    pub fn next(self) -> Channel { Channel::ALL[(self as usize + 1) % Channel::ALL.len()] }
+}
+
+// Highest per channel gain and master volume: twice as loud as the console
+pub const MAX_GAIN: f32 = 2.0;
+
+// How the channels' levels combine into the left and right outputs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mixer {
+    // Per Channel, from -1.0 (left speaker only) through 0.0 (both equally) to 1.0 (right only)
+    pub pan: [f32; 6],
+    // Per Channel, left out of the mix as if it were silent
+    pub muted: [bool; 6],
+    // Per Channel, what its level is scaled by before mixing; 1.0 is the console's balance
+    pub gain: [f32; 6],
+    // What the mix is scaled by
+    pub master: f32,
+    // Silences everything, leaving the volumes as they are
+    pub mute_all: bool,
+}
+
+impl Default for Mixer {
+    fn default() -> Self { Mixer { pan: [0.0; 6], muted: [false; 6], gain: [1.0; 6], master: 1.0, mute_all: false } }
+}
+
+impl Mixer {
+    pub fn set_pan(&mut self, channel: Channel, pan: f32) { self.pan[channel as usize] = pan.clamp(-1.0, 1.0); }
+    pub fn set_gain(&mut self, channel: Channel, gain: f32) { self.gain[channel as usize] = gain.clamp(0.0, MAX_GAIN); }
+    pub fn set_master(&mut self, master: f32) { self.master = master.clamp(0.0, MAX_GAIN); }
+    // True if the channel is muted now
+    pub fn toggle_mute(&mut self, channel: Channel) -> bool {
+        self.muted[channel as usize] = !self.muted[channel as usize];
+        self.muted[channel as usize]
+    }
+    // Left and right outputs, from 0.0 to 1.0 at unit gains, given each Channel's level (0-15, the
+    // DMC's 0-127, the expansion's already in output units). A channel panned away from a side plays
+    // quieter on it, down to silent at the far end.
+    pub fn mix(&self, levels: [f64; 6]) -> (f32, f32) {
+        if self.mute_all { return (0.0, 0.0); }
+        let side = |pan_gain: &dyn Fn(f64) -> f64| {
+            let mut scaled: [f64; 6] = levels;
+            for (i, level) in scaled.iter_mut().enumerate() {
+                *level *= if self.muted[i] { 0.0 } else { self.gain[i] as f64 * pan_gain(self.pan[i] as f64) };
+            }
+            (nonlinear(scaled) * self.master as f64) as f32
+        };
+        (side(&|pan| (1.0 - pan).min(1.0)), side(&|pan| (1.0 + pan).min(1.0)))
+    }
+}
+
+// Combine channels into a single value from 0.0 to 1.0
+// Formula is from http://wiki.nesdev.com/w/index.php/APU_Mixer; expansion audio adds on linearly
+fn nonlinear([p0, p1, t, n, d, expansion]: [f64; 6]) -> f64 {
+    let pulse_out: f64 = if p0 + p1 < 0.1 { 0.0 } else { 95.88 / ((8128.0 / (p0 + p1)) + 100.0) };
+    let tnd_out: f64 = if t + n + d < 0.1 { 0.0 } else { 159.79 / ((1.0 / (t / 8227.0 + n / 12241.0 + d / 22638.0)) + 100.0) };
+    // Linear approximation of the above formula
+    //let pulse_out: f64 = 0.00752 * (p0 + p1);
+    //let tnd_out: f64 = 0.00851 * t + 0.00494 * n + 0.00335 * d;
+    pulse_out + tnd_out + expansion
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_panning() {
+        let mut mixer: Mixer = Mixer::default();
+        let levels: [f64; 6] = [15.0, 15.0, 15.0, 15.0, 127.0, 0.0];
+        let (centre, right) = mixer.mix(levels);
+        assert_eq!(centre, right);
+        assert!((centre - 1.0).abs() < 0.01);
+        mixer.set_pan(Channel::parse("Pulse1").unwrap(), -2.0);
+        assert_eq!(mixer.pan[0], -1.0);
+        assert_eq!(mixer.mix([15.0, 0.0, 0.0, 0.0, 0.0, 0.0]).1, 0.0);
+        let (left, right) = mixer.mix(levels);
+        assert!(left == centre && right < left);
+        assert!(Channel::parse("square").is_err());
+        assert!(mixer.toggle_mute(Channel::Pulse1));
+        assert_eq!(mixer.mix([15.0, 0.0, 0.0, 0.0, 0.0, 0.0]), (0.0, 0.0));
+        assert!(!mixer.toggle_mute(Channel::Pulse1));
+        assert!(mixer.mix([15.0, 0.0, 0.0, 0.0, 0.0, 0.0]).0 > 0.0);
+    }
+
+    #[test]
+    fn test_gains() {
+        let mut mixer: Mixer = Mixer::default();
+        let (full, _) = mixer.mix([0.0, 0.0, 15.0, 0.0, 0.0, 0.0]);
+        mixer.set_gain(Channel::Triangle, 0.5);
+        let (half, _) = mixer.mix([0.0, 0.0, 15.0, 0.0, 0.0, 0.0]);
+        assert_eq!(half, Mixer::default().mix([0.0, 0.0, 7.5, 0.0, 0.0, 0.0]).0);
+        assert!(half < full);
+        mixer.set_master(5.0);
+        assert_eq!(mixer.master, MAX_GAIN);
+        assert_eq!(mixer.mix([0.0, 0.0, 15.0, 0.0, 0.0, 0.0]).0, half * 2.0);
+        assert_eq!(Channel::Expansion.next(), Channel::Pulse1);
+        assert_eq!(mixer.mix([0.0, 0.0, 0.0, 0.0, 0.0, 0.25]).0, 0.25 * MAX_GAIN);
+        mixer.mute_all = true;
+        assert_eq!(mixer.mix([15.0, 15.0, 15.0, 15.0, 127.0, 1.0]), (0.0, 0.0));
+    }
+}
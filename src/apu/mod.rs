@@ -1,3 +1,5 @@
+use crate::prelude::*;
+use crate::cartridge::Region;
 mod frame_counter;
 mod lenght_counter;
 mod pulse_channel;
@@ -5,6 +7,8 @@ mod noise_channel;
 mod triangle_channel;
 mod dmc_channel;
 mod filter;
+mod blip;
+pub mod mixer;
 mod sequencer;
 mod sweep;
 mod envelope;
@@ -15,13 +19,48 @@ use frame_counter::{FrameResult, FrameCounter};
 use pulse_channel::PulseChannel;
 use noise_channel::NoiseChannel;
 use dmc_channel::DmcChannel;
+pub use dmc_channel::SampleMemory;
 use filter::FirstOrderFilter;
+use blip::Blip;
+use mixer::Mixer;
+
+// Interleaved in `buffer`: left, then right
+pub const CHANNELS: usize = 2;
 
 use self::{sweep::SweepNegationMode, triangle_channel::TriangleChannel};
 
+// The resampler and output filters between two samples, which savestates leave out. Run-ahead puts
+// the real frame's back after rolling the machine back, so the sound carries on from that frame's
+// levels instead of stepping from the ones of the frames emulated ahead.
+#[derive(Clone)]
+pub struct Output {
+    sample_carry: f64,
+    levels: [f32; CHANNELS],
+    mixed_channels: [f64; 6],
+    blips: [Blip; CHANNELS],
+    filters: [FirstOrderFilter; 3],
+    right_filters: [FirstOrderFilter; 3],
+}
+
 pub struct APU {
+    // Interleaved stereo samples at sample_rate
     pub buffer: Vec<f32>,
     pub executed_cycles: u32,
+    // CPU cycles between two output samples; fractional, with the part of a cycle each sample came late
+    // by carried over to the next, so the output rate comes out exact
+    cycles_per_sample: f64,
+    sample_carry: f64,
+    cpu_clock: f64,
+    pub mixer: Mixer,
+    // The cartridge's sound chip output, as Mapper::audio gives it; kept up to date by the Bus
+    pub expansion: f32,
+    // The mixer's left and right outputs as of the last tick, and their changes turned into alias-free samples
+    levels: [f32; CHANNELS],
+    // The channel_levels `levels` were mixed from
+    mixed_channels: [f64; 6],
+    blips: [Blip; CHANNELS],
+    // `filters` for the right side; not in savestates, which recover from a reset filter within a frame
+    right_filters: [FirstOrderFilter; 3],
     pub frame_counter: FrameCounter,
     pub pulse_0: PulseChannel,
     pub pulse_1: PulseChannel,
@@ -32,41 +71,66 @@ pub struct APU {
 }
 
 impl APU {
-    pub fn new() -> Self {
+    pub fn new(region: Region) -> Self {
         APU {
             buffer: Vec::new(),
             executed_cycles: 0,
-            frame_counter: FrameCounter::new(),
+            cycles_per_sample: region.cpu_clock() / SAMPLE_RATE,
+            sample_carry: 0.0,
+            cpu_clock: region.cpu_clock(),
+            mixer: Mixer::default(),
+            expansion: 0.0,
+            levels: [0.0; CHANNELS],
+            mixed_channels: [0.0; 6],
+            blips: [Blip::new(), Blip::new()],
+            right_filters: output_filters(),
+            frame_counter: FrameCounter::new(region),
             pulse_0: PulseChannel::new(SweepNegationMode::OnesCompliment),
             pulse_1: PulseChannel::new(SweepNegationMode::TwosCompliment),
             triangle: TriangleChannel::new(),
-            noise: NoiseChannel::new(),
-            dmc: DmcChannel::new(),
-            filters: [
-                FirstOrderFilter::high_pass(SAMPLE_RATE, 90.0),
-                FirstOrderFilter::high_pass(SAMPLE_RATE, 440.0),
-                FirstOrderFilter::low_pass(SAMPLE_RATE, 14_000.0),
-            ],
+            noise: NoiseChannel::new(region),
+            dmc: DmcChannel::new(region),
+            filters: output_filters(),
         }
     }
 
     pub fn reset(&mut self) {
         self.write_register(0x4017, 0, 0);
-        for i in 0..11 { self.tick(i, 0); }
+        for i in 0..11 { self.step(i, &mut |_: u16| 0); }
+    }
+
+    pub fn output(&self) -> Output {
+        Output {
+            sample_carry: self.sample_carry, levels: self.levels, mixed_channels: self.mixed_channels,
+            blips: self.blips.clone(), filters: self.filters.clone(), right_filters: self.right_filters.clone(),
+        }
+    }
+    pub fn set_output(&mut self, output: Output) {
+        (self.sample_carry, self.levels, self.mixed_channels) = (output.sample_carry, output.levels, output.mixed_channels);
+        (self.blips, self.filters, self.right_filters) = (output.blips, output.filters, output.right_filters);
     }
 
+    pub fn sample_rate(&self) -> f64 { self.cpu_clock / self.cycles_per_sample }
+    // Stereo sample pairs per second to put in `buffer`. The frontend nudges this around SAMPLE_RATE to keep its
+    // audio queue from running dry or filling up; the filters stay tuned for SAMPLE_RATE.
+    pub fn set_sample_rate(&mut self, rate: f64) { self.cycles_per_sample = self.cpu_clock / rate; }
+
+    // Called with the APU caught up to the cycle before the read's (see Bus::catch_up)
     pub fn read_register(&mut self) -> u8 {
         let mut result = 0;
+        let irq_due: bool = self.frame_counter.irq_due();
         if self.dmc.irq_flag { result |= 0b1000_0000; }
-        if self.frame_counter.private_irq_flag { result |= 0b0100_0000; }
+        if self.frame_counter.private_irq_flag || irq_due { result |= 0b0100_0000; }
         if self.dmc.playing() { result |= 0b0001_0000; }
         if self.noise.playing() { result |= 0b0000_1000; }
         if self.triangle.playing() { result |= 0b0000_0100; }
         if self.pulse_1.playing() { result |= 0b0000_0010; }
         if self.pulse_0.playing() { result |= 0b0000_0001; }
 
-        self.frame_counter.private_irq_flag = false;
-        self.frame_counter.public_irq_flag = false;
+        if !irq_due {
+            self.frame_counter.private_irq_flag = false;
+            self.frame_counter.public_irq_flag = false;
+        }
         result
     }
 
@@ -93,16 +157,25 @@ impl APU {
         }
     }
 
-    pub fn tick(&mut self, cpu_cycles: u64, opcode_cycles: u8) {
+    // The `opcode_cycles` CPU cycles up to and including cycle `cpu_cycles`, one at a time since
+    // the frame counter and the DMC's fetches (which stall the CPU) happen on exact cycles.
+    // `memory` is where the DMC fetches its samples from.
+    pub fn tick(&mut self, cpu_cycles: u64, opcode_cycles: u8, memory: &mut dyn SampleMemory) {
+        for cycle in (cpu_cycles + 1).saturating_sub(opcode_cycles as u64)..=cpu_cycles {
+            self.step(cycle, memory);
+        }
+    }
+
+    fn step(&mut self, cpu_cycle: u64, memory: &mut dyn SampleMemory) {
         // Triangle ticks on each cpu cycle.
         self.triangle.tick_sequencer();
 
         // Everything else ticks on every other cycle
-        if cpu_cycles % 2 == 1 {
+        if cpu_cycle % 2 == 1 {
             self.pulse_0.tick_sequencer();
             self.pulse_1.tick_sequencer();
             self.noise.tick_sequencer();
-            self.dmc.tick_sequencer();
+            self.dmc.tick_sequencer(memory);
         }
 
         let r: FrameResult = self.frame_counter.tick();
@@ -115,13 +188,29 @@ impl APU {
 
         // We need 730 stereo audio samples per frame for 60 fps.
         // Each frame lasts a minimum of 29,779 CPU cycles. This
-        // works out to around 40 CPU cycles per sample.
-        self.executed_cycles += opcode_cycles as u32;
+        // works out to around 40 CPU cycles per sample (37 on PAL).
+        self.executed_cycles += 1;
         //println!("cycles: {}", self.executed_cycles)
-        if self.executed_cycles >= 40 {
-            let s: f32 = self.sample();
-            self.buffer.push(s);
-            //self.buffer.push(s);
+        let due: f64 = self.cycles_per_sample - self.sample_carry;
+        let sampling: bool = self.executed_cycles as f64 >= due;
+        // Mixing every cycle would cost more than the rest of the APU, so only when a channel
+        // changed, and at each sample to keep up with the mixer's settings
+        let channels: [f64; 6] = self.channel_levels();
+        if channels == self.mixed_channels && !sampling { return; }
+        self.mixed_channels = channels;
+        let (left, right): (f32, f32) = self.mixer.mix(channels);
+        // Where this tick falls relative to the next output sample, at 1.0
+        let offset: f64 = (self.executed_cycles as f64 + self.sample_carry) / self.cycles_per_sample;
+        for (side, level) in [left, right].into_iter().enumerate() {
+            if level == self.levels[side] { continue; }
+            self.blips[side].add_delta(offset, level - self.levels[side]);
+            self.levels[side] = level;
+        }
+        if sampling {
+            let left: f32 = sample(&mut self.blips[0], &mut self.filters);
+            let right: f32 = sample(&mut self.blips[1], &mut self.right_filters);
+            self.buffer.extend_from_slice(&[left, right]);
+            self.sample_carry = (self.executed_cycles as f64 - due).min(self.cycles_per_sample);
             self.executed_cycles = 0;
         }
     }
@@ -159,32 +248,66 @@ impl APU {
     }
     */
     
-    fn sample(&mut self) -> f32 {
+    // Each channel's output right now, in mixer::Channel order: 0-15, the DMC's 0-127, the expansion's 0.0-1.0
+    pub fn channel_levels(&self) -> [f64; 6] {
         let p0: f64 = self.pulse_0.sample() as f64;
         let p1: f64 = self.pulse_1.sample() as f64;
         let t: f64 = self.triangle.sample() as f64;
         let n: f64 = self.noise.sample() as f64;
         let d: f64 = self.dmc.sample() as f64;
-
-        // Combine channels into a single value from 0.0 to 1.0
-        // Formula is from http://wiki.nesdev.com/w/index.php/APU_Mixer
         //println!("p0: {}, p1: {}, t: {}, n: {}, d: {}", p0, p1, t, n, d);
-        let pulse_out: f64;
-        let tnd_out: f64;
-        if p0 + p1 < 0.1 { pulse_out = 0.0; } else { pulse_out = 95.88 / ((8128.0 / (p0 + p1)) + 100.0); }
-        if t + n + d < 0.1 { tnd_out = 0.0; } else { tnd_out = 159.79 / ((1.0 / (t / 8227.0 + n / 12241.0 + d / 22638.0)) + 100.0); }
-        // Linear approximation of the above formula
-        //let pulse_out: f64 = 0.00752 * (p0 + p1);
-        //let tnd_out: f64 = 0.00851 * t + 0.00494 * n + 0.00335 * d;
-
-        // Scale to 0..65536
-        //let mut output = (pulse_out + tnd_out) * 65535.0;
-        let mut output: f64 = pulse_out + tnd_out;
-
-        // Apply high pass and low pass filters
-        for i in 0..3 { output = self.filters[i].tick(output); }
-
-        // The final range is -32767 to +32767
-        output as f32
+        [p0, p1, t, n, d, self.expansion as f64]
+    }
+}
+
+fn output_filters() -> [FirstOrderFilter; 3] {
+    [
+        FirstOrderFilter::high_pass(SAMPLE_RATE, 90.0),
+        FirstOrderFilter::high_pass(SAMPLE_RATE, 440.0),
+        FirstOrderFilter::low_pass(SAMPLE_RATE, 14_000.0),
+    ]
+}
+
+// The next output sample of one side
+fn sample(blip: &mut Blip, filters: &mut [FirstOrderFilter; 3]) -> f32 {
+    let mut output: f64 = blip.read() as f64;
+
+    // Apply high pass and low pass filters
+    for filter in filters.iter_mut() { output = filter.tick(output); }
+
+    output as f32
+}
+
+savestate_fields!(APU { executed_cycles, frame_counter, pulse_0, pulse_1, triangle, noise, dmc, filters });
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // An APU whose 4-step sequence, started by a $4017 write on cycle 2, sets the IRQ flag on
+    // cycles 29_833 to 29_835, run up to cycle `last`
+    fn apu_until(last: u64) -> APU {
+        let mut apu: APU = APU::new(Region::NTSC);
+        apu.write_register(0x4017, 0x00, 1);
+        for cycle in 2..=last { apu.tick(cycle, 1, &mut |_: u16| 0); }
+        apu
+    }
+
+    #[test]
+    fn test_4015_read_as_the_frame_irq_flag_is_set() {
+        assert_eq!(apu_until(29_831).read_register() & 0x40, 0);
+        // Reads on the cycles setting the flag see it, but can't clear it
+        let mut apu: APU = apu_until(29_832);
+        assert_eq!(apu.read_register() & 0x40, 0x40);
+        apu.tick(29_833, 1, &mut |_: u16| 0);
+        assert_eq!(apu.read_register() & 0x40, 0x40);
+        apu.tick(29_834, 1, &mut |_: u16| 0);
+        assert_eq!(apu.read_register() & 0x40, 0x40);
+        assert!(apu.irq_flag());
+        // After the last of them a read clears it
+        apu.tick(29_835, 1, &mut |_: u16| 0);
+        assert_eq!(apu.read_register() & 0x40, 0x40);
+        assert_eq!(apu.read_register() & 0x40, 0);
+        assert!(!apu.irq_flag());
     }
 }
@@ -1,25 +1,33 @@
 use crate::apu::envelope::Envelope;
+use crate::cartridge::Region;
 use crate::apu::lenght_counter::LengthCounter;
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
-const PERIODS: [u16; 16] = [ 4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068 ];
+#[rustfmt::skip]
+const NTSC_PERIODS: [u16; 16] = [ 4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068 ];
+#[rustfmt::skip]
+const PAL_PERIODS: [u16; 16] = [ 4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778 ];
 
 pub struct NoiseChannel {
+    periods: &'static [u16; 16],
     envelope: Envelope,
     length_counter: LengthCounter,
+    // Periodic (tonal) mode: feedback from bit 6 instead of bit 1, for a 93 step loop instead of 32767
     mode: bool,
+    // In CPU cycles, as in the tables
     period: u16,
     counter: u16,
     shift: u16,
 }
 
 impl NoiseChannel {
-    pub fn new() -> Self {
+    pub fn new(region: Region) -> Self {
+        let periods: &'static [u16; 16] = match region { Region::NTSC | Region::Dendy => &NTSC_PERIODS, Region::PAL => &PAL_PERIODS };
         NoiseChannel {
+            periods,
             envelope: Envelope::new(),
             length_counter: LengthCounter::new(),
             mode: false,
-            period: 0,
+            period: periods[0],
             counter: 0,
             shift: 1,
         }
@@ -34,7 +42,7 @@ impl NoiseChannel {
             0x400D => (),
             0x400E => {
                 self.mode = value & 0b1000_0000 != 0;
-                self.period = PERIODS[value as usize & 0b1111];
+                self.period = self.periods[value as usize & 0b1111];
             }
             0x400F => {
                 self.length_counter.write_register(value);
@@ -47,10 +55,14 @@ impl NoiseChannel {
 
     pub fn sample(&self) -> u8 { if self.length_counter.active() && self.shift & 1 == 0 { self.envelope.volume() } else { 0 } }
 
+    pub fn tonal(&self) -> bool { self.mode }
+    pub fn period(&self) -> u16 { self.period }
+
+    // Every other CPU cycle, so the timer counts half periods
     pub fn tick_sequencer(&mut self) {
         if self.counter > 0 { self.counter -= 1; }
         else {
-            self.counter = self.period;
+            self.counter = self.period / 2 - 1;
             let bit1: u16 = (self.shift >> (if self.mode { 6 } else { 1 })) & 1;
             let bit2: u16 = self.shift & 1;
             self.shift = (self.shift >> 1) | (bit1 ^ bit2) << 14
@@ -63,3 +75,43 @@ impl NoiseChannel {
     pub fn set_enabled(&mut self, value: bool) { self.length_counter.set_enabled(value); }
     pub fn update_pending_length_counter(&mut self) { self.length_counter.update_pending(); }
 }
+
+savestate_fields!(NoiseChannel { envelope, length_counter, mode, period, counter, shift });
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Timer periods until the shift register is back where it started
+    fn loop_length(noise: &mut NoiseChannel) -> usize {
+        let start: u16 = noise.shift;
+        (1..).find(|_| {
+            for _ in 0..noise.period / 2 { noise.tick_sequencer(); }
+            noise.shift == start
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_lfsr_modes() {
+        let mut noise: NoiseChannel = NoiseChannel::new(Region::NTSC);
+        noise.write_register(0x400E, 0x00);
+        assert_eq!(loop_length(&mut noise), 32767);
+        noise.write_register(0x400E, 0x80);
+        assert!(noise.tonal());
+        assert_eq!(loop_length(&mut noise), 93);
+    }
+
+    #[test]
+    fn test_periods_in_cpu_cycles() {
+        let mut noise: NoiseChannel = NoiseChannel::new(Region::PAL);
+        noise.write_register(0x400E, 0x02);
+        assert_eq!(noise.period(), 14);
+        noise.tick_sequencer();
+        let shift: u16 = noise.shift;
+        // 14 CPU cycles are 7 ticks
+        for _ in 0..6 { noise.tick_sequencer(); }
+        assert_eq!(noise.shift, shift);
+        noise.tick_sequencer();
+        assert_ne!(noise.shift, shift);
+    }
+}
@@ -50,9 +50,7 @@ impl PulseChannel {
     }
 
     pub fn sample(&self) -> u8 {
-        // TODO: removing the target period check makes arkanoid sound effects work
-        if self.length_counter.active() && self.sequencer.period >= 8
-            && self.sweep.target_period(&self.sequencer) < 0x800 {
+        if self.length_counter.active() && !self.sweep.mutes(&self.sequencer) {
             PULSE_WAVEFORMS[self.duty_cycle][self.sequencer.current_step] * self.envelope.volume()
         } else { 0 }
     }
@@ -69,3 +67,5 @@ impl PulseChannel {
     pub fn set_enabled(&mut self, value: bool) { self.length_counter.set_enabled(value); }
     pub fn update_pending_length_counter(&mut self) { self.length_counter.update_pending(); }
 }
+
+savestate_fields!(PulseChannel { sweep, envelope, sequencer, length_counter, duty_cycle });
@@ -29,3 +29,5 @@ impl Sequencer {
     pub fn set_period_low(&mut self, value: u8) { self.period = (self.period & 0xFF00) | value as u16; }
     pub fn set_period_high(&mut self, value: u8) { self.period = (self.period & 0x00FF) | ((value as u16 & 0b111) << 8); }
 }
+
+savestate_fields!(Sequencer { counter, period, current_step });
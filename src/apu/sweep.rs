@@ -38,14 +38,10 @@ impl Sweep {
         self.reload = true;
     }
 
+    // The timer keeps counting down from the old period; the new one is only reloaded after
     pub fn tick(&mut self, sequencer: &mut Sequencer) {
-        if self.counter == 0 && self.enabled && self.shift > 0 && sequencer.period >= 8 {
-            let new_period = self.target_period(sequencer);
-
-            if new_period < 0x800 {
-                sequencer.period = new_period;
-                sequencer.counter = new_period;
-            }
+        if self.counter == 0 && self.enabled && self.shift > 0 && !self.mutes(sequencer) {
+            sequencer.period = self.target_period(sequencer);
         }
 
         if self.counter == 0 || self.reload {
@@ -56,12 +52,65 @@ impl Sweep {
         }
     }
 
+    // Worked out all the time, not just when the sweep is enabled. Negating subtracts the change,
+    // and on pulse 1 (ones' complement) one more, which can take it below 0: that reads as 0.
     pub fn target_period(&self, sequencer: &Sequencer) -> u16 {
-        let period = sequencer.period;
-        if self.negate {
-            period - (period >> self.shift) - self.negation_mode as u16
-        } else {
-            period + (period >> self.shift)
+        let period: i32 = sequencer.period as i32;
+        let change: i32 = period >> self.shift;
+        let target: i32 = if self.negate { period - change - self.negation_mode as i32 } else { period + change };
+        target.max(0) as u16
+    }
+
+    // Whether the pulse is silenced: a period under 8, or a target past 11 bits (which only an
+    // upward sweep can reach). Both apply with the sweep disabled or a shift of 0 too.
+    pub fn mutes(&self, sequencer: &Sequencer) -> bool { sequencer.period < 8 || self.target_period(sequencer) > 0x7FF }
+}
+
+savestate_fields!(Sweep { enabled, reload, shift, negate, period, counter });
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sequencer(period: u16) -> Sequencer {
+        let mut sequencer: Sequencer = Sequencer::new(8);
+        sequencer.period = period;
+        sequencer
+    }
+
+    #[test]
+    fn test_muting() {
+        let mut sweep: Sweep = Sweep::new(SweepNegationMode::TwosCompliment);
+        assert!(sweep.mutes(&sequencer(7)));
+        assert!(!sweep.mutes(&sequencer(0x3FF)));
+        // Disabled with a shift of 0, the target is still twice the period
+        assert!(sweep.mutes(&sequencer(0x400)));
+        sweep.write_register(0x01);
+        assert!(!sweep.mutes(&sequencer(0x554)));
+        assert!(sweep.mutes(&sequencer(0x556)));
+        // Negated targets never overflow; pulse 1's are one lower, and can go below 0
+        for (mode, target) in [(SweepNegationMode::OnesCompliment, 0x3FF), (SweepNegationMode::TwosCompliment, 0x400)] {
+            let mut sweep: Sweep = Sweep::new(mode);
+            sweep.write_register(0x09);
+            assert_eq!(sweep.target_period(&sequencer(0x7FF)), target);
+            assert!(!sweep.mutes(&sequencer(0x7FF)));
+            sweep.write_register(0x08);
+            assert_eq!(sweep.target_period(&sequencer(0x7FF)), 0);
         }
     }
+
+    #[test]
+    fn test_updates_only_unmuted_periods() {
+        let mut sweep: Sweep = Sweep::new(SweepNegationMode::OnesCompliment);
+        sweep.write_register(0x89);
+        let mut pulse: Sequencer = sequencer(0x100);
+        pulse.counter = 5;
+        sweep.tick(&mut pulse);
+        assert_eq!((pulse.period, pulse.counter), (0x100 - 0x80 - 1, 5));
+        // Upward, the next target overflows: the period stays put (and the channel is muted)
+        sweep.write_register(0x81);
+        let mut pulse: Sequencer = sequencer(0x600);
+        sweep.tick(&mut pulse);
+        assert_eq!(pulse.period, 0x600);
+    }
 }
@@ -75,3 +75,5 @@ impl TriangleChannel {
     pub fn set_enabled(&mut self, value: bool) { self.length_counter.set_enabled(value); }
     pub fn update_pending_length_counter(&mut self) { self.length_counter.update_pending(); }
 }
+
+savestate_fields!(TriangleChannel { length_counter, sequencer, linear_counter, linear_counter_start, linear_counter_period, control_flag });
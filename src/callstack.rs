@@ -0,0 +1,123 @@
+use crate::prelude::*;
+use crate::cpu::CPU;
+
+const BRK: u8 = 0x00;
+const JSR: u8 = 0x20;
+const RTI: u8 = 0x40;
+const RTS: u8 = 0x60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallKind {
+    Jsr,
+    Nmi,
+    Brk,
+}
+
+impl CallKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            CallKind::Jsr => "JSR",
+            CallKind::Nmi => "NMI",
+            CallKind::Brk => "BRK",
+        }
+    }
+}
+
+// A subroutine or interrupt handler that hasn't returned yet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Call {
+    pub kind: CallKind,
+    // The JSR or BRK, or the instruction the NMI came before
+    pub from: u16,
+    // The subroutine or handler entered
+    pub target: u16,
+    // Where its RTS or RTI should go
+    pub return_addr: u16,
+    // SP before the call pushed anything, which is where the return should leave it
+    pub stack_pointer: u8,
+}
+
+// The calls the debugger saw being made and not yet returned from, outermost first. Calls whose
+// return address the game threw away (PLA PLA, TXS) are dropped once the stack grows over them; an
+// RTS or RTI that doesn't match the innermost call leaves a warning, once per instruction.
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    pub calls: Vec<Call>,
+    // Not yet shown by the frontend
+    pub warnings: Vec<String>,
+    warned: Vec<u16>,
+}
+
+impl CallStack {
+    pub fn new() -> Self { CallStack::default() }
+    // After `cpu` executed `opcode` at `pc`, which found SP at `stack_pointer`; true if it was a JSR or BRK
+    pub fn executed(&mut self, cpu: &CPU, pc: u16, opcode: u8, stack_pointer: u8) -> bool {
+        match opcode {
+            JSR => self.push(Call { kind: CallKind::Jsr, from: pc, target: cpu.program_counter, return_addr: pc.wrapping_add(3), stack_pointer }),
+            BRK => self.push(Call { kind: CallKind::Brk, from: pc, target: cpu.program_counter, return_addr: pc.wrapping_add(2), stack_pointer }),
+            RTS => self.pop(cpu, pc, "RTS", &[CallKind::Jsr]),
+            RTI => self.pop(cpu, pc, "RTI", &[CallKind::Nmi, CallKind::Brk]),
+            _ => return false,
+        }
+        matches!(opcode, JSR | BRK)
+    }
+    // After `cpu` entered an NMI handler instead of running the instruction at `from`
+    pub fn interrupted(&mut self, cpu: &CPU, from: u16, stack_pointer: u8) {
+        self.push(Call { kind: CallKind::Nmi, from, target: cpu.program_counter, return_addr: from, stack_pointer });
+    }
+    fn push(&mut self, call: Call) {
+        self.calls.retain(|outer| outer.stack_pointer > call.stack_pointer);
+        self.calls.push(call);
+    }
+    fn pop(&mut self, cpu: &CPU, pc: u16, name: &str, kinds: &[CallKind]) {
+        let (to, stack_pointer): (u16, u8) = (cpu.program_counter, cpu.stack_pointer);
+        let problem: Option<String> = match self.calls.last() {
+            None => Some(String::from("without a matching call")),
+            Some(call) if !kinds.contains(&call.kind) => Some(format!("inside {} ${:04X}", call.kind.name(), call.target)),
+            Some(call) if call.stack_pointer != stack_pointer || call.return_addr != to => {
+                Some(format!("from {} ${:04X}, expected back at ${:04X} with SP ${:02X}", call.kind.name(), call.target, call.return_addr, call.stack_pointer))
+            }
+            Some(_) => None,
+        };
+        self.calls.retain(|call| call.stack_pointer > stack_pointer);
+        let Some(problem) = problem else { return; };
+        if self.warned.contains(&pc) { return; }
+        self.warned.push(pc);
+        self.warnings.push(format!("Stack imbalance: {} at ${:04X} {}, returned to ${:04X} with SP ${:02X}", name, pc, problem, to, stack_pointer));
+    }
+    // Innermost first, after the current PC
+    pub fn describe(&self, pc: u16) -> String {
+        let mut lines: Vec<String> = vec![format!("PC ${:04X}", pc)];
+        for call in self.calls.iter().rev() {
+            lines.push(format!("{} ${:04X} from ${:04X}, returns to ${:04X}", call.kind.name(), call.target, call.from, call.return_addr));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::Bus;
+    use crate::cartridge::{test, Rom};
+    use crate::cpu::CPU;
+    use crate::debugger::Debugger;
+
+    #[test]
+    fn test_calls_returns_and_imbalance() {
+        // $8000: JSR $8007; RTS (with nothing to return from)  $8007: JSR $800B; RTS  $800B: RTS
+        let rom: Rom = test::program_rom(&[0x20, 0x07, 0x80, 0x60, 0xEA, 0xEA, 0xEA, 0x20, 0x0B, 0x80, 0x60, 0x60]);
+        let mut cpu: CPU<'static> = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+        let mut debugger: Debugger = Debugger::new();
+        for _ in 0..2 { debugger.command(&mut cpu, "s").unwrap(); }
+        assert_eq!(debugger.command(&mut cpu, "bt").unwrap(), "\
+PC $800B
+JSR $800B from $8007, returns to $800A
+JSR $8007 from $8000, returns to $8003");
+        for _ in 0..2 { debugger.command(&mut cpu, "s").unwrap(); }
+        assert_eq!((cpu.program_counter, debugger.calls.calls.len()), (0x8003, 0));
+        assert!(debugger.calls.warnings.is_empty());
+        debugger.command(&mut cpu, "s").unwrap();
+        assert_eq!(debugger.calls.warnings, vec!["Stack imbalance: RTS at $8003 without a matching call, returned to $0001 with SP $FF"]);
+    }
+}
@@ -1,12 +1,115 @@
+use crate::prelude::*;
+#[cfg(feature = "std")]
+use std::io::Read;
+use crate::hash;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const UNIF_TAG: [u8; 4] = [0x55, 0x4E, 0x49, 0x46];
+const UNIF_HEADER_SIZE: usize = 32;
+const FDS_TAG: [u8; 4] = [0x46, 0x44, 0x53, 0x1A];
+const FDS_HEADER_SIZE: usize = 16;
+// The disk info block every side starts with
+const FDS_DISK_TAG: &[u8; 15] = b"\x01*NINTENDO-HVC*";
+pub const FDS_BIOS_SIZE: usize = 8192;
+pub const FDS_SIDE_SIZE: usize = 65500;
+#[cfg(feature = "std")]
+const ZIP_TAG: [u8; 4] = [0x50, 0x4B, 0x03, 0x04];
+#[cfg(feature = "std")]
+const ROM_EXTENSIONS: [&str; 3] = [".nes", ".unf", ".unif"];
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
+pub const TRAINER_SIZE: usize = 512;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
     FOURSCREEN,
+    ONESCREENLOWER,
+    ONESCREENUPPER,
+}
+
+impl Savestate for Mirroring {
+    fn save_state(&self, w: &mut StateWriter) {
+        let id: u8 = match self {
+            Mirroring::VERTICAL => 0,
+            Mirroring::HORIZONTAL => 1,
+            Mirroring::FOURSCREEN => 2,
+            Mirroring::ONESCREENLOWER => 3,
+            Mirroring::ONESCREENUPPER => 4,
+        };
+        id.save_state(w);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let mut id: u8 = 0;
+        id.load_state(r)?;
+        *self = match id {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::FOURSCREEN,
+            3 => Mirroring::ONESCREENLOWER,
+            4 => Mirroring::ONESCREENUPPER,
+            _ => return Err(format!("Savestate has invalid mirroring {}", id)),
+        };
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Region {
+    NTSC,
+    PAL,
+    // Famiclones built for PAL TVs (the Dendy and its kin): PAL's 312 lines with NTSC's CPU/PPU
+    // divider, and vblank held back 50 lines so NTSC games get their usual time for it
+    Dendy,
+}
+
+// The machine profile each region's consoles run with: frame length, CPU clock and how it divides
+// into PPU dots. The APU's tables per region live with its channels.
+impl Region {
+    pub fn parse(name: &str) -> Result<Region, String> {
+        match name.trim().to_lowercase().as_str() {
+            "ntsc" => Ok(Region::NTSC),
+            "pal" => Ok(Region::PAL),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!("Unknown region {:?}: expected ntsc, pal or dendy", name)),
+        }
+    }
+    // Lines per frame, the pre-render line being the last one
+    pub fn scanlines(self) -> u16 {
+        match self {
+            Region::NTSC => 262,
+            Region::PAL | Region::Dendy => 312,
+        }
+    }
+    // The line whose start raises the vblank flag and NMI
+    pub fn vblank_line(self) -> u16 {
+        match self {
+            Region::NTSC | Region::PAL => 241,
+            Region::Dendy => 291,
+        }
+    }
+    // PPU dots per CPU cycles, as a fraction: 3 on NTSC and Dendy, 3.2 on PAL
+    pub fn dots_per_cpu_cycle(self) -> (u8, u8) {
+        match self {
+            Region::NTSC | Region::Dendy => (3, 1),
+            Region::PAL => (16, 5),
+        }
+    }
+    pub fn cpu_clock(self) -> f64 {
+        match self {
+            Region::NTSC => 1_789_773.0,
+            Region::PAL => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+    // 60.0988 frames per second on NTSC, whose odd frames skip a dot while rendering; 50.007 on PAL and Dendy
+    pub fn frame_rate(self) -> f64 {
+        let (dots, cycles) = self.dots_per_cpu_cycle();
+        let skipped: f64 = if self == Region::NTSC { 0.5 } else { 0.0 };
+        self.cpu_clock() * dots as f64 / cycles as f64 / (self.scanlines() as f64 * 341.0 - skipped)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -15,19 +118,27 @@ pub struct Rom {
     pub chr_rom: Vec<u8>,
     pub mapper: u8,
     pub screen_mirroring: Mirroring,
+    pub trainer: Option<Vec<u8>>,
+    pub battery: bool,
+    pub region: Region,
 }
 
 impl Rom {
     pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG { return Err("File is not in iNES file format".to_string()); }
+        if raw.len() >= 4 && raw[0..4] == UNIF_TAG { return Rom::from_unif(raw); }
+        if raw.starts_with(&FDS_TAG) || raw.starts_with(FDS_DISK_TAG) { return Err("FDS disk images need the disk system BIOS, see Rom::from_fds".to_string()); }
+        if raw.len() < 16 || raw[0..4] != NES_TAG { return Err("File is not in iNES file format".to_string()); }
 
+        // NES 2.0 headers extend iNES with more mapper and size bits and a proper region field
+        let nes2: bool = match (raw[7] >> 2) & 0b11 {
+            0 => false,
+            2 => true,
+            _ => return Err("Unknown iNES header version".to_string()),
+        };
+        let mapper_high: u8 = if nes2 { raw[8] & 0x0F } else { 0 };
+        if mapper_high != 0 { return Err(format!("Mapper {} is not supported", (mapper_high as u16) << 8 | (raw[7] & 0xF0) as u16 | (raw[6] >> 4) as u16)); }
         let mapper: u8 = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
-        println!("Mapper: {}", mapper);
-
-        let ines_ver: u8 = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES2.0 format is not supported".to_string());
-        }
+        log!("Mapper: {}", mapper);
 
         let four_screen = raw[6] & 0b1000 != 0;
         let vertical_mirroring = raw[6] & 0b1 != 0;
@@ -37,28 +148,164 @@ impl Rom {
             (false, false) => Mirroring::HORIZONTAL,
         };
 
-        println!("ROM Size: {}x16k, CHR Size: {}x8k", raw[4], raw[5]);
-        let prg_rom_size: usize = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        log!("ROM Size: {}x16k, CHR Size: {}x8k", raw[4], raw[5]);
+        let invalid_sizes = || "ROM header sizes are invalid".to_string();
+        let (prg_rom_size, chr_rom_size): (usize, usize) = if nes2 {
+            (
+                nes2_rom_size(raw[4], raw[9] & 0x0F, PRG_ROM_PAGE_SIZE).ok_or_else(invalid_sizes)?,
+                nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE).ok_or_else(invalid_sizes)?,
+            )
+        } else {
+            (raw[4] as usize * PRG_ROM_PAGE_SIZE, raw[5] as usize * CHR_ROM_PAGE_SIZE)
+        };
+
+        let battery = raw[6] & 0b10 != 0;
+        let region = match (nes2, raw[12] & 0b11) {
+            (false, _) => if raw[9] & 0b1 != 0 { Region::PAL } else { Region::NTSC },
+            // 2 is "multiple regions", which run fine as NTSC
+            (true, 1) => Region::PAL,
+            (true, 3) => Region::Dendy,
+            (true, _) => Region::NTSC,
+        };
 
-        let skip_trainer = raw[6] & 0b100 != 0;
+        // The 512-byte trainer sits between header and PRG data, and is meant to be loaded at $7000
+        let has_trainer = raw[6] & 0b100 != 0;
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
-        let chr_rom_start = prg_rom_start + prg_rom_size;
+        let prg_rom_start = 16 + if has_trainer { TRAINER_SIZE } else { 0 };
+        let chr_rom_start: usize = prg_rom_start.checked_add(prg_rom_size).ok_or_else(invalid_sizes)?;
+        let chr_rom_end: usize = chr_rom_start.checked_add(chr_rom_size).ok_or_else(invalid_sizes)?;
+        if raw.len() < chr_rom_end { return Err("ROM file is truncated".to_string()); }
 
         Ok(Rom {
             prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+            chr_rom: raw[chr_rom_start..chr_rom_end].to_vec(),
             mapper,
             screen_mirroring,
+            trainer: if has_trainer { Some(raw[16..16 + TRAINER_SIZE].to_vec()) } else { None },
+            battery,
+            region,
         })
     }
+    #[cfg(feature = "std")]
+    // Plain iNES/UNIF files load as-is, zip archives are searched for their first ROM entry
+    pub fn from_file(path: &str) -> Result<Rom, String> {
+        let bytes: Vec<u8> = std::fs::read(path).map_err(|err| format!("Could not read {}: {}", path, err))?;
+        if bytes.len() >= 4 && bytes[0..4] == ZIP_TAG { return Rom::new(&read_rom_from_zip(&bytes)?); }
+        Rom::new(&bytes)
+    }
+    // An .fds disk image (with or without its 16 byte fwNES header) and the disk system's 8KB BIOS,
+    // as a mapper 20 Rom: prg_rom is the BIOS followed by every FDS_SIDE_SIZE side
+    pub fn from_fds(disk: &[u8], bios: &[u8]) -> Result<Rom, String> {
+        if bios.len() != FDS_BIOS_SIZE { return Err(format!("FDS BIOS is {} bytes, expected {}", bios.len(), FDS_BIOS_SIZE)); }
+        let sides: &[u8] = if disk.starts_with(&FDS_TAG) { &disk[FDS_HEADER_SIZE.min(disk.len())..] } else { disk };
+        if sides.is_empty() || !sides.len().is_multiple_of(FDS_SIDE_SIZE) || !sides.starts_with(FDS_DISK_TAG) {
+            return Err("File is not in FDS file format".to_string());
+        }
+        log!("FDS disk: {} sides", sides.len() / FDS_SIDE_SIZE);
+        let mut prg_rom: Vec<u8> = bios.to_vec();
+        prg_rom.extend_from_slice(sides);
+        Ok(Rom { prg_rom, chr_rom: Vec::new(), mapper: 20, screen_mirroring: Mirroring::VERTICAL, trainer: None, battery: false, region: Region::NTSC })
+    }
+    // UNIF is a chunked format: "UNIF" + revision + padding, then 4-byte id / LE u32 length / data
+    pub fn from_unif(raw: &[u8]) -> Result<Rom, String> {
+        if raw.len() < UNIF_HEADER_SIZE || raw[0..4] != UNIF_TAG { return Err("File is not in UNIF file format".to_string()); }
+
+        let mut board: Option<String> = None;
+        let mut prg_chunks: Vec<(u8, &[u8])> = Vec::new();
+        let mut chr_chunks: Vec<(u8, &[u8])> = Vec::new();
+        let mut screen_mirroring = Mirroring::HORIZONTAL;
+        let mut battery: bool = false;
+        let mut region: Region = Region::NTSC;
+
+        let mut pos: usize = UNIF_HEADER_SIZE;
+        while pos + 8 <= raw.len() {
+            let id: &[u8] = &raw[pos..pos + 4];
+            let len: usize = u32::from_le_bytes([raw[pos + 4], raw[pos + 5], raw[pos + 6], raw[pos + 7]]) as usize;
+            let start: usize = pos + 8;
+            if start + len > raw.len() { return Err(format!("UNIF chunk {} is truncated", String::from_utf8_lossy(id))); }
+            let data: &[u8] = &raw[start..start + len];
+            match id {
+                b"MAPR" => board = Some(String::from_utf8_lossy(data).trim_end_matches('\0').to_string()),
+                b"MIRR" => screen_mirroring = match data.first() {
+                    Some(0) => Mirroring::HORIZONTAL,
+                    Some(1) => Mirroring::VERTICAL,
+                    Some(2) => Mirroring::ONESCREENLOWER,
+                    Some(3) => Mirroring::ONESCREENUPPER,
+                    Some(4) => Mirroring::FOURSCREEN,
+                    _ => Mirroring::HORIZONTAL, // mapper controlled
+                },
+                b"BATR" => battery = data.first().is_some_and(|b| *b != 0),
+                b"TVCI" => region = if data.first() == Some(&1) { Region::PAL } else { Region::NTSC },
+                _ if &id[0..3] == b"PRG" => prg_chunks.push((id[3], data)),
+                _ if &id[0..3] == b"CHR" => chr_chunks.push((id[3], data)),
+                _ => {} // NAME, READ, DINF, CTRL, PCKn/CCKn checksums...
+            }
+            pos = start + len;
+        }
+
+        let board: String = board.ok_or("UNIF file has no MAPR chunk".to_string())?;
+        let mapper: u8 = unif_board_mapper(&board).ok_or(format!("UNIF board {} is not supported", board))?;
+        log!("Board: {} (mapper {})", board, mapper);
+        // PRG0..PRGF / CHR0..CHRF are concatenated in hex-digit order
+        prg_chunks.sort_by_key(|(n, _)| *n);
+        chr_chunks.sort_by_key(|(n, _)| *n);
+        let prg_rom: Vec<u8> = prg_chunks.iter().flat_map(|(_, d)| d.iter().copied()).collect();
+        let chr_rom: Vec<u8> = chr_chunks.iter().flat_map(|(_, d)| d.iter().copied()).collect();
+        if prg_rom.is_empty() { return Err("UNIF file has no PRG chunk".to_string()); }
+
+        Ok(Rom { prg_rom, chr_rom, mapper, screen_mirroring, trainer: None, battery, region })
+    }
+    // Checksum of PRG+CHR without the header, the key used by ROM databases
+    pub fn crc32(&self) -> u32 {
+        let mut data: Vec<u8> = self.prg_rom.clone();
+        data.extend(&self.chr_rom);
+        hash::crc32(&data)
+    }
     pub fn read_prg_byte(&self, address: u16) -> u8 {
         let address: usize = address as usize;
         self.prg_rom[address % self.prg_rom.len()]
     }
 }
 
+// NES 2.0 ROM sizes: a 12-bit count of `page` sized units, or with the upper nibble at 0xF, the
+// low byte as 2^E * (2*M + 1) bytes for dumps that aren't a whole number of pages. None when the
+// size doesn't fit a usize, which the exponent form easily doesn't
+fn nes2_rom_size(low: u8, high: u8, page: usize) -> Option<usize> {
+    if high == 0x0F { 1usize.checked_shl((low >> 2) as u32)?.checked_mul((low & 0b11) as usize * 2 + 1) }
+    else { ((high as usize) << 8 | low as usize).checked_mul(page) }
+}
+
+#[cfg(feature = "std")]
+fn read_rom_from_zip(raw: &[u8]) -> Result<Vec<u8>, String> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(raw)).map_err(|err| format!("Invalid zip archive: {}", err))?;
+    for extension in ROM_EXTENSIONS {
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).map_err(|err| format!("Invalid zip archive: {}", err))?;
+            if !entry.is_file() || !entry.name().to_lowercase().ends_with(extension) { continue; }
+            let mut bytes: Vec<u8> = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut bytes).map_err(|err| format!("Could not extract {}: {}", entry.name(), err))?;
+            return Ok(bytes);
+        }
+    }
+    Err("Zip archive contains no .nes file".to_string())
+}
+
+// Maps UNIF board names ("NES-SNROM", "UNL-...") to the equivalent iNES mapper number
+fn unif_board_mapper(board: &str) -> Option<u8> {
+    let name: &str = ["NES-", "HVC-", "UNL-", "BTL-", "BMC-", "IREM-", "KONAMI-"].iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => Some(0),
+        "SAROM" | "SBROM" | "SCROM" | "SEROM" | "SFROM" | "SGROM" | "SHROM" | "SJROM" | "SKROM"
+        | "SLROM" | "SL1ROM" | "SNROM" | "SOROM" | "SUROM" | "SXROM" => Some(1),
+        "UNROM" | "UOROM" => Some(2),
+        "CNROM" => Some(3),
+        "ANROM" | "AN1ROM" | "AMROM" | "AOROM" => Some(7),
+        _ => None,
+    }
+}
+
 pub mod test {
 
     use super::*;
@@ -101,6 +348,41 @@ pub mod test {
         Rom::new(&test_rom).unwrap()
     }
 
+    // test_rom with `program` at $8000, where the reset vector points
+    pub fn program_rom(program: &[u8]) -> Rom {
+        let mut rom: Rom = test_rom();
+        rom.prg_rom[..program.len()].copy_from_slice(program);
+        let len: usize = rom.prg_rom.len();
+        rom.prg_rom[len - 4..len - 2].copy_from_slice(&[0x00, 0x80]);
+        rom
+    }
+
+    // `JMP $8000` forever: the CPU spins while the PPU keeps producing frames
+    pub fn spinning_rom() -> Rom { program_rom(&[0x4C, 0x00, 0x80]) }
+
+    // A two side disk whose first side has just its disk info block, and a BIOS of NOPs
+    pub fn test_fds_rom() -> Rom {
+        let mut disk: Vec<u8> = FDS_TAG.to_vec();
+        disk.resize(FDS_HEADER_SIZE, 0);
+        for _ in 0..2 {
+            let mut side: Vec<u8> = FDS_DISK_TAG.to_vec();
+            side.resize(56, 0);
+            side.resize(FDS_SIDE_SIZE, 0);
+            disk.extend(side);
+        }
+        Rom::from_fds(&disk, &[0xEA; FDS_BIOS_SIZE]).unwrap()
+    }
+
+    #[test]
+    fn test_fds() {
+        let rom: Rom = test_fds_rom();
+        assert_eq!((rom.mapper, rom.prg_rom.len()), (20, FDS_BIOS_SIZE + 2 * FDS_SIDE_SIZE));
+        assert_eq!(&rom.prg_rom[FDS_BIOS_SIZE..FDS_BIOS_SIZE + 3], b"\x01*N");
+        assert!(Rom::from_fds(&rom.prg_rom[FDS_BIOS_SIZE..], &[0; 100]).is_err());
+        assert!(Rom::from_fds(&[0; FDS_SIDE_SIZE], &[0; FDS_BIOS_SIZE]).is_err());
+        assert!(Rom::new(&rom.prg_rom[FDS_BIOS_SIZE..].to_vec()).is_err());
+    }
+
     #[test]
     fn test() {
         let test_rom = create_rom(TestRom {
@@ -152,22 +434,116 @@ pub mod test {
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
         assert_eq!(rom.mapper, 3);
         assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert_eq!(rom.trainer, Some(vec![0; 512]));
     }
 
     #[test]
-    fn test_nes2_is_not_supported() {
+    fn test_truncated_rom() {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31 | 0b100, 00, 00, 00, 00, 00, 00, 00, 00, 00,
             ],
             trainer: None,
-            pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
-            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
         });
-        let rom = Rom::new(&test_rom);
-        match rom {
-            Result::Ok(_) => assert!(false, "should not load rom"),
-            Result::Err(str) => assert_eq!(str, "NES2.0 format is not supported"),
+        match Rom::new(&test_rom) {
+            Result::Ok(_) => panic!("should not load rom"),
+            Result::Err(str) => assert_eq!(str, "ROM file is truncated"),
+        }
+    }
+
+    #[cfg(test)]
+    fn unif_chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk: Vec<u8> = id.to_vec();
+        chunk.extend((data.len() as u32).to_le_bytes());
+        chunk.extend(data);
+        chunk
+    }
+
+    #[test]
+    fn test_unif() {
+        let mut raw: Vec<u8> = b"UNIF".to_vec();
+        raw.extend(7u32.to_le_bytes());
+        raw.extend([0; 24]);
+        raw.extend(unif_chunk(b"MAPR", b"NES-CNROM\0"));
+        raw.extend(unif_chunk(b"MIRR", &[1]));
+        raw.extend(unif_chunk(b"PRG1", &vec![3; PRG_ROM_PAGE_SIZE]));
+        raw.extend(unif_chunk(b"PRG0", &vec![1; PRG_ROM_PAGE_SIZE]));
+        raw.extend(unif_chunk(b"CHR0", &vec![2; CHR_ROM_PAGE_SIZE]));
+
+        let rom: Rom = Rom::new(&raw).unwrap();
+
+        assert_eq!(rom.prg_rom[0], 1);
+        assert_eq!(rom.prg_rom[PRG_ROM_PAGE_SIZE], 3);
+        assert_eq!(rom.chr_rom, vec!(2; CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_unif_unknown_board() {
+        let mut raw: Vec<u8> = b"UNIF".to_vec();
+        raw.extend([0; 28]);
+        raw.extend(unif_chunk(b"MAPR", b"UNL-SOMETHING"));
+        match Rom::new(&raw) {
+            Result::Ok(_) => panic!("should not load rom"),
+            Result::Err(str) => assert_eq!(str, "UNIF board UNL-SOMETHING is not supported"),
         }
     }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_zip() {
+        use std::io::Write;
+        let rom_bytes = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        let mut archive = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        archive.start_file("readme.txt", options).unwrap();
+        archive.write_all(b"not a rom").unwrap();
+        archive.start_file("game.NES", options).unwrap();
+        archive.write_all(&rom_bytes).unwrap();
+        let zipped: Vec<u8> = archive.finish().unwrap().into_inner();
+
+        let rom: Rom = Rom::new(&read_rom_from_zip(&zipped).unwrap()).unwrap();
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.mapper, 3);
+    }
+
+    #[test]
+    fn test_nes2_header() {
+        // Mapper 3 with PAL timing, then a Dendy variant of it
+        let mut header: Vec<u8> = vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 0b1000, 00, 00, 00, 00, 0x01, 00, 00, 00];
+        let rom = |header: &Vec<u8>| create_rom(TestRom {
+            header: header.clone(),
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        let loaded: Rom = Rom::new(&rom(&header)).unwrap();
+        assert_eq!((loaded.mapper, loaded.region, loaded.prg_rom.len()), (3, Region::PAL, 2 * PRG_ROM_PAGE_SIZE));
+        header[12] = 0x03;
+        assert_eq!(Rom::new(&rom(&header)).unwrap().region, Region::Dendy);
+        header[8] = 0x01;
+        assert_eq!(Rom::new(&rom(&header)).err(), Some(String::from("Mapper 259 is not supported")));
+        assert_eq!(Region::parse("Dendy"), Ok(Region::Dendy));
+        // Exponent-multiplier sizes: 2^E * (2*M + 1) bytes, which overflow for big exponents
+        assert_eq!(nes2_rom_size(0b0000_1101, 0x0F, PRG_ROM_PAGE_SIZE), Some(24));
+        assert_eq!(nes2_rom_size(0xFF, 0x0F, PRG_ROM_PAGE_SIZE), None);
+        header[8] = 0x00;
+        header[9] = 0xFF;
+        header[4] = 0xFF;
+        assert_eq!(Rom::new(&rom(&header)).err(), Some(String::from("ROM header sizes are invalid")));
+        // 2^63 bytes of each fit a usize, but not together
+        header[4] = 0xFC;
+        header[5] = 0xFC;
+        assert_eq!(Rom::new(&rom(&header)).err(), Some(String::from("ROM header sizes are invalid")));
+    }
 }
@@ -0,0 +1,172 @@
+use crate::prelude::*;
+
+const GAME_GENIE_LETTERS: &str = "APZLGITYEOXUKSVN";
+
+// When a RAM cheat puts its value in place
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hold {
+    // Written back at the end of every frame, so the game sees it from the next frame on
+    EveryFrame,
+    // Replaces whatever the game writes there, so it never sees anything else once set
+    OnWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheatKind {
+    // Raw and Pro Action Replay codes: a RAM (or cartridge RAM) byte held at a value
+    Ram { addr: u16, value: u8, hold: Hold },
+    // Game Genie codes: CPU reads of a ROM byte return `value` instead, if the ROM holds `compare` there
+    GameGenie { addr: u16, value: u8, compare: Option<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cheat {
+    // As entered, for listing and saving
+    pub code: String,
+    pub kind: CheatKind,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    // `0075:09` (raw, address:value), `007509` (Pro Action Replay style, AAAAVV), or a 6 or 8 letter
+    // Game Genie code like `SXIOPO`. `hold` only matters for the RAM kinds.
+    pub fn parse(code: &str, hold: Hold) -> Result<Cheat, String> {
+        let code: String = code.trim().to_uppercase();
+        let hex = |text: &str| u16::from_str_radix(text, 16).ok();
+        let ram = |addr: Option<u16>, value: Option<u16>| match (addr, value) {
+            (Some(addr), Some(value)) if (addr < 0x2000 || (0x6000..0x8000).contains(&addr)) && value <= 0xFF => Some(CheatKind::Ram { addr, value: value as u8, hold }),
+            _ => None,
+        };
+        let kind: Option<CheatKind> = match code.split_once(':') {
+            Some((addr, value)) => ram(hex(addr), hex(value)),
+            None if code.len() == 6 && code.chars().all(|c| c.is_ascii_hexdigit()) => ram(hex(&code[..4]), hex(&code[4..])),
+            None => decode_game_genie(&code),
+        };
+        let kind: CheatKind = kind.ok_or_else(|| format!("Invalid cheat {:?}: expected ADDR:VALUE or AAAAVV in RAM ($0000-$1FFF, $6000-$7FFF), or a 6/8 letter Game Genie code", code))?;
+        Ok(Cheat { code, kind, enabled: true })
+    }
+}
+
+// The letters stand for 4 bit values that the code scatters across a 15 bit address, the new value
+// and (8 letter codes only) the compare value
+fn decode_game_genie(code: &str) -> Option<CheatKind> {
+    let n: Vec<u16> = code.chars().map(|c| GAME_GENIE_LETTERS.find(c).map(|i| i as u16)).collect::<Option<Vec<u16>>>()?;
+    if n.len() != 6 && n.len() != 8 { return None; }
+    let addr: u16 = 0x8000 | ((n[3] & 7) << 12) | ((n[5] & 7) << 8) | ((n[4] & 8) << 8) | ((n[2] & 7) << 4) | ((n[1] & 8) << 4) | (n[4] & 7) | (n[3] & 8);
+    let value: u16 = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7);
+    if n.len() == 6 { return Some(CheatKind::GameGenie { addr, value: (value | (n[5] & 8)) as u8, compare: None }); }
+    let compare: u16 = ((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8);
+    Some(CheatKind::GameGenie { addr, value: (value | (n[7] & 8)) as u8, compare: Some(compare as u8) })
+}
+
+// RAM addresses below $2000 name all four mirrors of their byte
+fn same_byte(a: u16, b: u16) -> bool { a == b || (a < 0x2000 && b < 0x2000 && a & 0x07FF == b & 0x07FF) }
+
+// Every cheat of the loaded game, in the order added. The Bus consults it on ROM reads and RAM
+// writes and applies the every-frame ones when a frame ends.
+#[derive(Debug, Clone, Default)]
+pub struct Cheats {
+    pub list: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Self { Cheats::default() }
+    // A code that's already in the list is replaced (and enabled again) rather than added twice
+    pub fn add(&mut self, code: &str, hold: Hold) -> Result<&Cheat, String> {
+        let cheat: Cheat = Cheat::parse(code, hold)?;
+        let index: usize = match self.list.iter().position(|existing| existing.code == cheat.code) {
+            Some(index) => { self.list[index] = cheat; index }
+            None => { self.list.push(cheat); self.list.len() - 1 }
+        };
+        Ok(&self.list[index])
+    }
+    // One cheat per line: the code, then `write` for on-write RAM cheats and `off` for disabled ones
+    pub fn to_text(&self) -> String {
+        self.list.iter().map(|cheat| {
+            let on_write: bool = matches!(cheat.kind, CheatKind::Ram { hold: Hold::OnWrite, .. });
+            format!("{}{}{}\n", cheat.code, if on_write { " write" } else { "" }, if cheat.enabled { "" } else { " off" })
+        }).collect()
+    }
+    // Reads to_text's format back; blank lines and `#` comments are skipped
+    pub fn parse_text(text: &str) -> Result<Cheats, String> {
+        let mut cheats: Cheats = Cheats::new();
+        for (number, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split('#').next().unwrap_or("").split_whitespace().collect();
+            let Some((code, flags)) = words.split_first() else { continue; };
+            let hold: Hold = if flags.contains(&"write") { Hold::OnWrite } else { Hold::EveryFrame };
+            if let Some(flag) = flags.iter().find(|flag| !matches!(**flag, "write" | "off")) {
+                return Err(format!("line {}: unknown cheat option {:?}", number + 1, flag));
+            }
+            let enabled: bool = !flags.contains(&"off");
+            cheats.add(code, hold).map_err(|err| format!("line {}: {}", number + 1, err))?;
+            if let Some(cheat) = cheats.list.last_mut() { cheat.enabled = enabled; }
+        }
+        Ok(cheats)
+    }
+    pub fn is_empty(&self) -> bool { self.list.is_empty() }
+    fn enabled(&self) -> impl Iterator<Item = &CheatKind> { self.list.iter().filter(|cheat| cheat.enabled).map(|cheat| &cheat.kind) }
+    // What a CPU read of ROM at `addr` returns, given what the cartridge put on the bus
+    pub fn patch_read(&self, addr: u16, rom_value: u8) -> u8 {
+        self.enabled().find_map(|kind| match *kind {
+            CheatKind::GameGenie { addr: target, value, compare } if target == addr && compare.is_none_or(|compare| compare == rom_value) => Some(value),
+            _ => None,
+        }).unwrap_or(rom_value)
+    }
+    // What actually gets written when the game writes `value` at `addr`
+    pub fn patch_write(&self, addr: u16, value: u8) -> u8 {
+        self.enabled().find_map(|kind| match *kind {
+            CheatKind::Ram { addr: target, value, hold: Hold::OnWrite } if same_byte(target, addr) => Some(value),
+            _ => None,
+        }).unwrap_or(value)
+    }
+    // The bytes to write at the end of a frame
+    pub fn frame_writes(&self) -> Vec<(u16, u8)> {
+        self.enabled().filter_map(|kind| match *kind {
+            CheatKind::Ram { addr, value, hold: Hold::EveryFrame } => Some((addr, value)),
+            _ => None,
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_codes() {
+        assert_eq!(Cheat::parse("0075:09", Hold::EveryFrame).unwrap().kind, CheatKind::Ram { addr: 0x75, value: 9, hold: Hold::EveryFrame });
+        assert_eq!(Cheat::parse("07FA01", Hold::OnWrite).unwrap().kind, CheatKind::Ram { addr: 0x7FA, value: 1, hold: Hold::OnWrite });
+        // Super Mario Bros. infinite lives: DEC $075A turned into LDA
+        assert_eq!(Cheat::parse("sxiopo", Hold::EveryFrame).unwrap().kind, CheatKind::GameGenie { addr: 0x91D9, value: 0xAD, compare: None });
+        assert!(matches!(Cheat::parse("SXIOPOAP", Hold::EveryFrame).unwrap().kind, CheatKind::GameGenie { addr: 0x91D9, compare: Some(_), .. }));
+        assert!(Cheat::parse("8000:01", Hold::EveryFrame).is_err());
+        assert!(Cheat::parse("2000:80", Hold::EveryFrame).is_err());
+        assert!(Cheat::parse("SXIOP", Hold::EveryFrame).is_err());
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let mut cheats: Cheats = Cheats::new();
+        cheats.add("0075:09", Hold::OnWrite).unwrap();
+        cheats.add("sxiopo", Hold::EveryFrame).unwrap();
+        cheats.add("0075:09", Hold::OnWrite).unwrap();
+        cheats.list[1].enabled = false;
+        assert_eq!(cheats.to_text(), "0075:09 write\nSXIOPO off\n");
+        assert_eq!(Cheats::parse_text(&cheats.to_text()).unwrap().list, cheats.list);
+        assert!(Cheats::parse_text("# lives\n\n0075:09 sometimes\n").is_err());
+    }
+
+    #[test]
+    fn test_patches() {
+        let mut cheats: Cheats = Cheats::new();
+        cheats.add("0075:09", Hold::OnWrite).unwrap();
+        cheats.add("0076:05", Hold::EveryFrame).unwrap();
+        cheats.add("SXIOPO", Hold::EveryFrame).unwrap();
+        assert_eq!(cheats.patch_write(0x0875, 1), 9);
+        assert_eq!(cheats.patch_write(0x0076, 1), 1);
+        assert_eq!(cheats.frame_writes(), vec![(0x76, 5)]);
+        assert_eq!(cheats.patch_read(0x91D9, 0xCE), 0xAD);
+        cheats.list[2].enabled = false;
+        assert_eq!(cheats.patch_read(0x91D9, 0xCE), 0xCE);
+    }
+}
@@ -0,0 +1,88 @@
+use crate::prelude::*;
+use crate::cpu::CPU;
+use crate::opcodes::{self, OpCode};
+
+// How often each opcode ran, and each PRG ROM byte started an instruction, by running the CPU itself.
+// For measuring what a test ROM exercises of the CPU core, and which code of a game never ran.
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    pub opcodes: [u64; 256],
+    // By offset into PRG ROM, whatever bank it was mapped through
+    pub prg: Vec<u64>,
+    // Instructions run from RAM
+    pub outside_prg: u64,
+}
+
+impl Coverage {
+    pub fn new(prg_rom_size: usize) -> Self { Coverage { opcodes: [0; 256], prg: vec![0; prg_rom_size], outside_prg: 0 } }
+    pub fn run_frame(&mut self, cpu: &mut CPU) {
+        let frame: u64 = cpu.bus.frames;
+        while cpu.bus.frames == frame { self.step(cpu); }
+    }
+    pub fn step(&mut self, cpu: &mut CPU) {
+        cpu.service_interrupts();
+        let pc: u16 = cpu.program_counter;
+        self.opcodes[cpu.bus.peek(pc) as usize] += 1;
+        match cpu.bus.mapper().borrow().prg_rom_offset(pc).and_then(|offset| self.prg.get_mut(offset)) {
+            Some(count) => *count += 1,
+            None => self.outside_prg += 1,
+        }
+        cpu.execute();
+    }
+    // Unofficial opcodes the CPU knows but never ran
+    pub fn unexecuted_unofficial(&self) -> Vec<&'static OpCode> {
+        (0..=255u8).filter_map(opcodes::lookup).filter(|op| op.is_unofficial() && self.opcodes[op.code as usize] == 0).collect()
+    }
+    pub fn summary(&self) -> String {
+        let known: Vec<&'static OpCode> = (0..=255u8).filter_map(opcodes::lookup).collect();
+        let ran = |unofficial: bool| known.iter().filter(|op| op.is_unofficial() == unofficial && self.opcodes[op.code as usize] > 0).count();
+        let total = |unofficial: bool| known.iter().filter(|op| op.is_unofficial() == unofficial).count();
+        let covered: usize = self.prg.iter().filter(|count| **count > 0).count();
+        let unexecuted: Vec<String> = self.unexecuted_unofficial().iter().map(|op| format!("{:02X} {}", op.code, op.mnemonic)).collect();
+        format!("Official opcodes run: {} of {}\nUnofficial opcodes run: {} of {}\nNever run: {}\nPRG ROM bytes starting an instruction: {} of {}\nInstructions run outside PRG ROM: {}",
+            ran(false), total(false), ran(true), total(true), if unexecuted.is_empty() { String::from("-") } else { unexecuted.join(", ") },
+            covered, self.prg.len(), self.outside_prg)
+    }
+    // One row per opcode byte, unknown ones included with an empty mnemonic
+    pub fn opcodes_csv(&self) -> String {
+        let mut csv: String = String::from("opcode,mnemonic,unofficial,count\n");
+        for code in 0..=255u8 {
+            let op: Option<&'static OpCode> = opcodes::lookup(code);
+            csv.push_str(&format!("{:02X},{},{},{}\n", code, op.map_or("", |op| op.mnemonic), op.is_some_and(|op| op.is_unofficial()), self.opcodes[code as usize]));
+        }
+        csv
+    }
+    // One row per PRG ROM byte that started an instruction, with its 16KB bank
+    pub fn prg_csv(&self) -> String {
+        let mut csv: String = String::from("offset,bank,count\n");
+        for (offset, count) in self.prg.iter().enumerate().filter(|(_, count)| **count > 0) {
+            csv.push_str(&format!("{:06X},{},{}\n", offset, offset / 0x4000, count));
+        }
+        csv
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::{test, Rom};
+
+    #[test]
+    fn test_counts_opcodes_and_prg_addresses() {
+        // RESET: *NOP $10; INX; JMP $8000
+        let rom: Rom = test::program_rom(&[0x04, 0x10, 0xE8, 0x4C, 0x00, 0x80]);
+        let len: usize = rom.prg_rom.len();
+        let mut cpu: CPU<'static> = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+        let mut coverage: Coverage = Coverage::new(len);
+        for _ in 0..30 { coverage.step(&mut cpu); }
+        assert_eq!((coverage.opcodes[0x04], coverage.opcodes[0xE8], coverage.opcodes[0x4C]), (10, 10, 10));
+        assert_eq!(&coverage.prg[0..4], &[10, 0, 10, 10]);
+        assert!(coverage.opcodes_csv().contains("\n04,*NOP,true,10\n"));
+        assert_eq!(coverage.prg_csv(), "offset,bank,count\n000000,0,10\n000002,0,10\n000003,0,10\n");
+        let unexecuted: Vec<u8> = coverage.unexecuted_unofficial().iter().map(|op| op.code).collect();
+        assert!(!unexecuted.contains(&0x04) && unexecuted.contains(&0x44));
+        assert!(coverage.summary().contains("PRG ROM bytes starting an instruction: 3 of 32768"));
+    }
+}
@@ -1,5 +1,6 @@
-use std::collections::HashMap;
+use crate::prelude::*;
 use crate::{opcodes, bus::Bus};
+use crate::hooks::{self, Interrupt};
 
 
 const STACK: u16 = 0x0100;
@@ -437,12 +438,14 @@ impl Instructions for CPU<'_> {
         self.stack_push(self.status);
         self.set_flag(StatusFlag::InterruptDisable, true);
         self.program_counter = self.mem_read_u16(0xFFFE);
+        hooks::interrupt(self, Interrupt::Brk);
     }
 }
 
 mod interrupt {
     #[derive(PartialEq, Eq)]
-    pub enum InterruptType { NMI }
+    #[allow(clippy::upper_case_acronyms)]
+    pub enum InterruptType { NMI, IRQ }
 
     #[derive(PartialEq, Eq)]
     pub(super) struct Interrupt {
@@ -457,6 +460,12 @@ mod interrupt {
         b_flag_mask: 0b00100000,
         cpu_cycles: 2,
     };
+    pub(super) const IRQ: Interrupt = Interrupt {
+        itype: InterruptType::IRQ,
+        vector_addr: 0xFFFE,
+        b_flag_mask: 0b00100000,
+        cpu_cycles: 2,
+    };
 }
 
 
@@ -591,6 +600,7 @@ impl<'a> CPU<'a> {
         }
     }
 
+    #[cfg(feature = "std")]
     pub fn print_rom(&mut self) {
         for i in 0x8000..=0xffff {
             print!("{:02X} ", self.mem_read(i));
@@ -627,6 +637,12 @@ impl<'a> CPU<'a> {
         // self.program_counter = 0xC000; // ! Moved from FFFC to 1FFC to be in RAM and not in ROM space
         self.program_counter = self.mem_read_u16(0xFFFC);
     }
+    // Jumps to the subroutine at `addr` as a JSR would, with its RTS landing on `return_to`. For
+    // players like NSF's that call into the program and wait for it to come back.
+    pub fn call(&mut self, addr: u16, return_to: u16) {
+        self.stack_push_u16(return_to.wrapping_sub(1));
+        self.program_counter = addr;
+    }
     pub fn load_and_run(&mut self, program: Vec<u8>) {
         self.load(program);
         self.reset();
@@ -634,131 +650,161 @@ impl<'a> CPU<'a> {
         self.run()
     }
     pub fn run(&mut self) {
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
-        loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() { self.interrupt(interrupt::NMI); }
-            //callback(self);
-         //   println!("{}", trace::trace(self));
-            let code: u8 = self.mem_read(self.program_counter);
-            //if self.program_counter != 0x8150 && self.program_counter != 0x8153 && self.program_counter != 0x8155{
-            //println!("code: {:X}, pc:{:X}", code, self.program_counter);
-            //}
-            self.program_counter += 1;
-            let program_counter_state: u16 = self.program_counter;
-            let opcode: &&opcodes::OpCode = opcodes.get(&code).expect(&format!("OpCode 0x{:X} is not recognized", code));
-            // Print the current state of the CPU
-            //let v1 = self.mem_read(self.program_counter + 1);
-            //let v2 = self.mem_read(self.program_counter + 2);
-            //println!("A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X} ({:?} | {:X} {:X} {:X})",
-            //         self.register_a, self.register_x, self.register_y, self.status, self.stack_pointer, self.program_counter, opcode.mnemonic, code, v1, v2);
-            match code {
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => self.lda(&opcode.mode), // LDA
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode), // ADC
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode), // AND
-                0x0a => self.asl_accumulator(), // ASL
-                0x06 | 0x16 | 0x0e | 0x1e => { self.asl(&opcode.mode); }, // ASL
-                0x90 => self.branch(!self.get_flag(StatusFlag::Carry)), // BCC
-                0xB0 => self.branch(self.get_flag(StatusFlag::Carry)), // BCS
-                0xF0 => self.branch(self.get_flag(StatusFlag::Zero)), // BEQ
-                0x24 | 0x2C => self.bit_test(&opcode.mode), // BIT
-                0x30 => self.branch(self.get_flag(StatusFlag::Negative)), // BMI
-                0xD0 => self.branch(!self.get_flag(StatusFlag::Zero)), // BNE
-                0x10 => self.branch(!self.get_flag(StatusFlag::Negative)), // BPL
-                0x50 => self.branch(!self.get_flag(StatusFlag::Overflow)), // BVC
-                0x70 => self.branch(self.get_flag(StatusFlag::Overflow)), // BVS
-                0x18 => self.set_flag(StatusFlag::Carry, false), // CLC
-                0xD8 => self.set_flag(StatusFlag::DecimalMode, false), // CLD
-                0x58 => self.set_flag(StatusFlag::InterruptDisable, false), // CLI
-                0xB8 => self.set_flag(StatusFlag::Overflow, false), // CLV
-                0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => self.cmp(&opcode.mode), // CMP
-                0xE0 | 0xE4 | 0xEC => self.cpx(&opcode.mode), // CPX
-                0xC0 | 0xC4 | 0xCC => self.cpy(&opcode.mode), // CPY
-                0xC6 | 0xD6 | 0xCE | 0xDE => { self.dec(&opcode.mode); }, // DEC
-                0xCA => self.dex(), // DEX
-                0x88 => self.dey(), // DEY
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode), // EOR
-                0xE6 | 0xF6 | 0xEE | 0xFE => { self.inc(&opcode.mode); }, // INC
-                0xE8 => self.inx(), // INX
-                0xC8 => self.iny(), // INY
-                0x4C => { // JMP
-                    let addr: u16 = self.mem_read_u16(self.program_counter);
-                    self.program_counter = addr;
-                },
-                0x6C => { // JMP (indirect) - bug emulation
-                    let addr: u16 = self.mem_read_u16(self.program_counter);
-                    let indirect_ref: u16 = if addr & 0x00FF == 0x00FF {
-                        let lo: u8 = self.mem_read(addr);
-                        let hi: u8 = self.mem_read(addr & 0xFF00);
-                        (hi as u16) << 8 | (lo as u16)
-                    } else { self.mem_read_u16(addr) };
-                    self.program_counter = indirect_ref;
-                },
-                0x20 => { // JSR
-                    self.stack_push_u16(self.program_counter + 2 - 1);
-                    let target_address: u16 = self.mem_read_u16(self.program_counter);
-                    self.program_counter = target_address
-                },
-                0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => self.ldx(&opcode.mode), // LDX
-                0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => self.ldy(&opcode.mode), // LDY
-                0x4A => self.lsr_accumulator(), // LSR
-                0x46 | 0x56 | 0x4E | 0x5E => { self.lsr(&opcode.mode); }, // LSR
-                0xEA => { }, // NOP
-                0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode), // ORA
-                0x48 => self.stack_push(self.register_a), // PHA
-                0x08 => self.stack_push(self.status | 0b0011_0000), // PHP
-                0x68 => { self.register_a = self.stack_pop(); self.update_zero_and_negative_flags(self.register_a); }, // PLA
-                0x28 => { // PLP
-                    self.status = self.stack_pop();
-                    self.set_flag(StatusFlag::Break, false);
-                    self.set_flag(StatusFlag::Break2, true);
-                },
-                0x2A => self.rol_accumulator(), // ROL
-                0x26 | 0x36 | 0x2E | 0x3E => { self.rol(&opcode.mode); }, // ROL
-                0x6A => self.ror_accumulator(), // ROR
-                0x66 | 0x76 | 0x6E | 0x7E => { self.ror(&opcode.mode); }, // ROR
-                0x40 => { // RTI
-                    self.status = self.stack_pop();
-                    self.set_flag(StatusFlag::Break, false);
-                    self.set_flag(StatusFlag::Break2, true);
-                    self.program_counter = self.stack_pop_u16();
-                },
-                0x60 =>  self.program_counter = self.stack_pop_u16() + 1, // RTS
-                0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => self.sbc(&opcode.mode), // SBC
-                0x38 => self.set_flag(StatusFlag::Carry, true), // SEC
-                0xF8 => self.set_flag(StatusFlag::DecimalMode, true), // SED
-                0x78 => self.set_flag(StatusFlag::InterruptDisable, true), // SEI
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode), // STA
-                0x86 | 0x96 | 0x8E => self.stx(&opcode.mode), // STX
-                0x84 | 0x94 | 0x8C => self.sty(&opcode.mode), // STY
-                0xAA =>  self.tax(), // TAX
-                0xA8 =>  self.tay(), // TAY
-                0xBA =>  self.tsx(), // TSX
-                0x8A =>  self.txa(), // TXA
-                0x9A =>  self.txs(), // TXS
-                0x98 =>  self.tya(), // TYA
-                0x00 => self.brk(), // BRK
-                // Unofficial opcodes
-                0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 => { }, // *NOP = DOP
-                0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => { }, // *NOP = TOP
-                0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => { }, // *NOP = NOP
-                0xA7 | 0xB7 | 0xAF | 0xBF | 0xA3 | 0xB3 => self.lax(&opcode.mode), // *LAX
-                0x87 | 0x97 | 0x8F | 0x83 => self.sax(&opcode.mode), // *SAX
-                0xEB => self.sbc(&AddressingMode::Immediate), // *SBC
-                0xC7 | 0xD7 | 0xCF | 0xDF | 0xDB | 0xC3 | 0xD3 => self.dcp(&opcode.mode), // *DCP
-                0xE7 | 0xF7 | 0xEF | 0xFF | 0xFB | 0xE3 | 0xF3 => self.isb(&opcode.mode), // *ISB
-                0x07 | 0x17 | 0x0F | 0x1F | 0x1B | 0x03 | 0x13 => self.slo(&opcode.mode), // *SLO
-                0x27 | 0x37 | 0x2F | 0x3F | 0x3B | 0x23 | 0x33 => self.rla(&opcode.mode), // *RLA
-                0x47 | 0x57 | 0x4F | 0x5F | 0x5B | 0x43 | 0x53 => self.sre(&opcode.mode), // *SRE
-                0x67 | 0x77 | 0x6F | 0x7F | 0x7B | 0x63 | 0x73 => self.rra(&opcode.mode), // *RRA
+        loop { self.step(); }
+    }
+    // Runs until the PPU finishes the current frame (i.e. until the next gameloop callback)
+    pub fn run_frame(&mut self) {
+        let frame: u64 = self.bus.frames;
+        while self.bus.frames == frame { self.step(); }
+    }
+    // Executes a single instruction, servicing a pending NMI first
+    pub fn step(&mut self) {
+        self.service_interrupts();
+        self.execute();
+    }
+    // Enters the handler of a pending NMI, or of an IRQ while they're enabled, so PC points at its
+    // first instruction; true if there was one
+    pub fn service_interrupts(&mut self) -> bool {
+        if self.bus.poll_nmi_status().is_some() {
+            self.interrupt(interrupt::NMI);
+            hooks::interrupt(self, Interrupt::Nmi);
+            hooks::frame(self);
+            return true;
+        }
+        if !self.bus.irq() || self.get_flag(StatusFlag::InterruptDisable) { return false; }
+        self.interrupt(interrupt::IRQ);
+        hooks::interrupt(self, Interrupt::Irq);
+        true
+    }
+    // Executes the instruction at PC, without checking for interrupts first
+    pub fn execute(&mut self) {
+        hooks::instruction(self);
+        //callback(self);
+     //   println!("{}", trace::trace(self));
+        let code: u8 = self.mem_read(self.program_counter);
+        //if self.program_counter != 0x8150 && self.program_counter != 0x8153 && self.program_counter != 0x8155{
+        //println!("code: {:X}, pc:{:X}", code, self.program_counter);
+        //}
+        self.program_counter += 1;
+        let program_counter_state: u16 = self.program_counter;
+        let opcode: &opcodes::OpCode = opcodes::lookup(code).unwrap_or_else(|| panic!("OpCode 0x{:X} is not recognized", code));
+        self.bus.begin_instruction(opcode.cycles);
+        // Print the current state of the CPU
+        //let v1 = self.mem_read(self.program_counter + 1);
+        //let v2 = self.mem_read(self.program_counter + 2);
+        //println!("A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X} ({:?} | {:X} {:X} {:X})",
+        //         self.register_a, self.register_x, self.register_y, self.status, self.stack_pointer, self.program_counter, opcode.mnemonic, code, v1, v2);
+        match code {
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => self.lda(&opcode.mode), // LDA
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode), // ADC
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode), // AND
+            0x0a => self.asl_accumulator(), // ASL
+            0x06 | 0x16 | 0x0e | 0x1e => { self.asl(&opcode.mode); }, // ASL
+            0x90 => self.branch(!self.get_flag(StatusFlag::Carry)), // BCC
+            0xB0 => self.branch(self.get_flag(StatusFlag::Carry)), // BCS
+            0xF0 => self.branch(self.get_flag(StatusFlag::Zero)), // BEQ
+            0x24 | 0x2C => self.bit_test(&opcode.mode), // BIT
+            0x30 => self.branch(self.get_flag(StatusFlag::Negative)), // BMI
+            0xD0 => self.branch(!self.get_flag(StatusFlag::Zero)), // BNE
+            0x10 => self.branch(!self.get_flag(StatusFlag::Negative)), // BPL
+            0x50 => self.branch(!self.get_flag(StatusFlag::Overflow)), // BVC
+            0x70 => self.branch(self.get_flag(StatusFlag::Overflow)), // BVS
+            0x18 => self.set_flag(StatusFlag::Carry, false), // CLC
+            0xD8 => self.set_flag(StatusFlag::DecimalMode, false), // CLD
+            0x58 => self.set_flag(StatusFlag::InterruptDisable, false), // CLI
+            0xB8 => self.set_flag(StatusFlag::Overflow, false), // CLV
+            0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => self.cmp(&opcode.mode), // CMP
+            0xE0 | 0xE4 | 0xEC => self.cpx(&opcode.mode), // CPX
+            0xC0 | 0xC4 | 0xCC => self.cpy(&opcode.mode), // CPY
+            0xC6 | 0xD6 | 0xCE | 0xDE => { self.dec(&opcode.mode); }, // DEC
+            0xCA => self.dex(), // DEX
+            0x88 => self.dey(), // DEY
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode), // EOR
+            0xE6 | 0xF6 | 0xEE | 0xFE => { self.inc(&opcode.mode); }, // INC
+            0xE8 => self.inx(), // INX
+            0xC8 => self.iny(), // INY
+            0x4C => { // JMP
+                let addr: u16 = self.mem_read_u16(self.program_counter);
+                self.program_counter = addr;
+            },
+            0x6C => { // JMP (indirect) - bug emulation
+                let addr: u16 = self.mem_read_u16(self.program_counter);
+                let indirect_ref: u16 = if addr & 0x00FF == 0x00FF {
+                    let lo: u8 = self.mem_read(addr);
+                    let hi: u8 = self.mem_read(addr & 0xFF00);
+                    (hi as u16) << 8 | (lo as u16)
+                } else { self.mem_read_u16(addr) };
+                self.program_counter = indirect_ref;
+            },
+            0x20 => { // JSR
+                self.stack_push_u16(self.program_counter + 2 - 1);
+                let target_address: u16 = self.mem_read_u16(self.program_counter);
+                self.program_counter = target_address
+            },
+            0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => self.ldx(&opcode.mode), // LDX
+            0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => self.ldy(&opcode.mode), // LDY
+            0x4A => self.lsr_accumulator(), // LSR
+            0x46 | 0x56 | 0x4E | 0x5E => { self.lsr(&opcode.mode); }, // LSR
+            0xEA => { }, // NOP
+            0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode), // ORA
+            0x48 => self.stack_push(self.register_a), // PHA
+            0x08 => self.stack_push(self.status | 0b0011_0000), // PHP
+            0x68 => { self.register_a = self.stack_pop(); self.update_zero_and_negative_flags(self.register_a); }, // PLA
+            0x28 => { // PLP
+                self.status = self.stack_pop();
+                self.set_flag(StatusFlag::Break, false);
+                self.set_flag(StatusFlag::Break2, true);
+            },
+            0x2A => self.rol_accumulator(), // ROL
+            0x26 | 0x36 | 0x2E | 0x3E => { self.rol(&opcode.mode); }, // ROL
+            0x6A => self.ror_accumulator(), // ROR
+            0x66 | 0x76 | 0x6E | 0x7E => { self.ror(&opcode.mode); }, // ROR
+            0x40 => { // RTI
+                self.status = self.stack_pop();
+                self.set_flag(StatusFlag::Break, false);
+                self.set_flag(StatusFlag::Break2, true);
+                self.program_counter = self.stack_pop_u16();
+            },
+            0x60 =>  self.program_counter = self.stack_pop_u16() + 1, // RTS
+            0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => self.sbc(&opcode.mode), // SBC
+            0x38 => self.set_flag(StatusFlag::Carry, true), // SEC
+            0xF8 => self.set_flag(StatusFlag::DecimalMode, true), // SED
+            0x78 => self.set_flag(StatusFlag::InterruptDisable, true), // SEI
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode), // STA
+            0x86 | 0x96 | 0x8E => self.stx(&opcode.mode), // STX
+            0x84 | 0x94 | 0x8C => self.sty(&opcode.mode), // STY
+            0xAA =>  self.tax(), // TAX
+            0xA8 =>  self.tay(), // TAY
+            0xBA =>  self.tsx(), // TSX
+            0x8A =>  self.txa(), // TXA
+            0x9A =>  self.txs(), // TXS
+            0x98 =>  self.tya(), // TYA
+            0x00 => self.brk(), // BRK
+            // Unofficial opcodes
+            0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 => { }, // *NOP = DOP
+            0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => { }, // *NOP = TOP
+            0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => { }, // *NOP = NOP
+            0xA7 | 0xB7 | 0xAF | 0xBF | 0xA3 | 0xB3 => self.lax(&opcode.mode), // *LAX
+            0x87 | 0x97 | 0x8F | 0x83 => self.sax(&opcode.mode), // *SAX
+            0xEB => self.sbc(&AddressingMode::Immediate), // *SBC
+            0xC7 | 0xD7 | 0xCF | 0xDF | 0xDB | 0xC3 | 0xD3 => self.dcp(&opcode.mode), // *DCP
+            0xE7 | 0xF7 | 0xEF | 0xFF | 0xFB | 0xE3 | 0xF3 => self.isb(&opcode.mode), // *ISB
+            0x07 | 0x17 | 0x0F | 0x1F | 0x1B | 0x03 | 0x13 => self.slo(&opcode.mode), // *SLO
+            0x27 | 0x37 | 0x2F | 0x3F | 0x3B | 0x23 | 0x33 => self.rla(&opcode.mode), // *RLA
+            0x47 | 0x57 | 0x4F | 0x5F | 0x5B | 0x43 | 0x53 => self.sre(&opcode.mode), // *SRE
+            0x67 | 0x77 | 0x6F | 0x7F | 0x7B | 0x63 | 0x73 => self.rra(&opcode.mode), // *RRA
 
-                _ => panic!("OpCode 0x{:X} is not recognized", code)
-            }
-            self.bus.tick(opcode.cycles);
-            if program_counter_state == self.program_counter { self.program_counter += (opcode.len - 1) as u16; }
+            _ => panic!("OpCode 0x{:X} is not recognized", code)
         }
+        self.bus.tick(opcode.cycles);
+        if program_counter_state == self.program_counter { self.program_counter += (opcode.len - 1) as u16; }
+        hooks::frame(self);
     }
 }
 
+// The bus and everything behind it is saved as separate sections, see Bus::save_sections
+savestate_fields!(CPU<'_> { register_a, register_x, register_y, status, program_counter, stack_pointer });
+
 #[cfg(test)]
 mod test {
     use super::*;
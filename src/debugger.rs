@@ -0,0 +1,478 @@
+use crate::prelude::*;
+use crate::cpu::{CPU, Mem};
+use crate::apu::APU;
+use crate::apu::mixer::Channel;
+use crate::callstack::CallStack;
+use crate::cheats::{CheatKind, Hold};
+use crate::disasm;
+use crate::ramsearch::{Comparison, RamSearch};
+
+const RAM_MIRRORS_END: u16 = 0x1FFF;
+const JSR: u8 = 0x20;
+const RTS: u8 = 0x60;
+
+const HELP: &str = "\
+b [ADDR]                set a breakpoint at ADDR, or list them
+d [ADDR]                delete the breakpoint at ADDR, or all of them
+watch [rwx] ADDR[-END]  stop on reads, writes and/or execution in ADDR..=END (default rw), or list
+unwatch [ADDR]          delete the watchpoints covering ADDR, or all of them
+c                       continue
+s                       step one instruction, into subroutines
+n                       step one instruction, running JSRs to their return
+out                     run until the current subroutine returns
+u ADDR                  run until PC reaches ADDR
+bt                      show the call stack: the JSRs and interrupts not yet returned from
+r [REG VALUE]           show the registers, or set one of a x y p sp pc
+dis [ADDR [COUNT]]      disassemble COUNT instructions (default 10) from ADDR (default PC)
+find new                start a RAM search over all of work RAM
+find OP [VALUE]         keep the addresses whose byte compares as OP (=, !=, >, < or +/- VALUE) to
+                        the last search, or to VALUE; `find` lists what's left
+cheat [CODE [write]]    add a raw (ADDR:VALUE), Pro Action Replay (AAAAVV) or Game Genie cheat, held
+                        every frame or, with `write`, on every write; or list them
+cheat off|on|del N      disable, enable or delete the Nth cheat
+apu                     show each sound channel's state and level, and the noise channel's period
+                        (in CPU cycles) and mode
+m ADDR [LEN]            dump LEN bytes (default 64) from ADDR
+w ADDR BYTE...          write bytes from ADDR on, through the bus like CPU writes
+Numbers are hex, with or without a leading $.";
+
+// Why Debugger::run_frame handed control back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    // The PPU finished the frame
+    Frame,
+    // The next instruction is at this breakpoint
+    Breakpoint(u16),
+    // A watched access happened: after the reading or writing instruction, before the executed one
+    Watchpoint(WatchHit),
+    // The target of a step-over, step-out or run-to command, with PC where it stopped
+    Reached(u16),
+}
+
+impl Stop {
+    pub fn describe(&self) -> String {
+        match self {
+            Stop::Frame => String::from("End of frame"),
+            Stop::Breakpoint(addr) => format!("Breakpoint at ${:04X}", addr),
+            Stop::Reached(addr) => format!("Stopped at ${:04X}", addr),
+            Stop::Watchpoint(hit) => match hit.access {
+                Access::Read => format!("Watchpoint: read ${:02X} from ${:04X}", hit.value, hit.addr),
+                Access::Write => format!("Watchpoint: wrote ${:02X} to ${:04X}", hit.value, hit.addr),
+                Access::Execute => format!("Watchpoint: executing ${:04X}", hit.addr),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+// Where a resumed debugger stops by itself, as opposed to breakpoints and watchpoints
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    // PC reaching `addr` with the stack no deeper than `stack_pointer`, so recursive calls of the
+    // subroutine being stepped over don't count
+    Reach { addr: u16, stack_pointer: u8 },
+    // An RTS with the stack at least as high as `stack_pointer`, i.e. one leaving that subroutine
+    Return { stack_pointer: u8 },
+}
+
+// Bus::watch_hit: which access tripped a watchpoint, with the byte read or written (the opcode for execution)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    pub access: Access,
+    pub addr: u16,
+    pub value: u8,
+}
+
+// An inclusive address range and the kinds of access that stop in it. PPU register mirrors are caught
+// through the base register ($2002 also catches $200A), and RAM at any of its four addresses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, access: Access, addr: u16) -> bool {
+        let wanted: bool = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        };
+        let range = self.start..=self.end;
+        if addr > RAM_MIRRORS_END { return wanted && range.contains(&addr); }
+        wanted && (0..4).any(|mirror: u16| range.contains(&((addr & 0x07FF) + mirror * 0x0800)))
+    }
+    fn describe(&self) -> String {
+        let modes: String = [(self.read, 'r'), (self.write, 'w'), (self.execute, 'x')].iter().filter(|(on, _)| *on).map(|(_, mode)| *mode).collect();
+        if self.start == self.end { format!("${:04X} {}", self.start, modes) } else { format!("${:04X}-${:04X} {}", self.start, self.end, modes) }
+    }
+}
+
+// Breakpoints and the command language of the interactive debugger. The frontend runs frames through
+// run_frame and, whenever `paused` is set, feeds typed commands to `command` until one resumes.
+// Watchpoints live on the Bus, whose memory accesses check them.
+#[derive(Debug, Clone, Default)]
+pub struct Debugger {
+    pub breakpoints: Vec<u16>,
+    pub paused: bool,
+    pub target: Option<Target>,
+    pub search: Option<RamSearch>,
+    pub calls: CallStack,
+    // Set by resume: the instruction the debugger stopped at runs without being checked again
+    resuming: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self { Debugger::default() }
+    // Like CPU::run_frame, but stops (and pauses) before executing an instruction at a breakpoint,
+    // execute watchpoint or run-to target, and after one that reads or writes a watched address or
+    // returns from the subroutine being stepped out of.
+    pub fn run_frame(&mut self, cpu: &mut CPU) -> Stop {
+        let frame: u64 = cpu.bus.frames;
+        // Left over from commands like `w` touching watched memory
+        cpu.bus.watch_hit = None;
+        while cpu.bus.frames == frame {
+            // Taken first, so a breakpoint on the NMI handler stops at its first instruction
+            self.service_interrupts(cpu);
+            let pc: u16 = cpu.program_counter;
+            if !core::mem::take(&mut self.resuming) {
+                if self.breakpoints.contains(&pc) { return self.stop(Stop::Breakpoint(pc)); }
+                if cpu.bus.watchpoints.iter().any(|watchpoint| watchpoint.matches(Access::Execute, pc)) {
+                    return self.stop(Stop::Watchpoint(WatchHit { access: Access::Execute, addr: pc, value: cpu.bus.peek(pc) }));
+                }
+                if let Some(Target::Reach { addr, stack_pointer }) = self.target {
+                    if pc == addr && cpu.stack_pointer >= stack_pointer { return self.stop(Stop::Reached(pc)); }
+                }
+            }
+            let returning: bool = match self.target {
+                Some(Target::Return { stack_pointer }) => cpu.bus.peek(pc) == RTS && cpu.stack_pointer >= stack_pointer,
+                _ => false,
+            };
+            self.execute(cpu);
+            if let Some(hit) = cpu.bus.watch_hit.take() { return self.stop(Stop::Watchpoint(hit)); }
+            if returning { return self.stop(Stop::Reached(cpu.program_counter)); }
+        }
+        Stop::Frame
+    }
+    // CPU::service_interrupts and CPU::execute, keeping track of calls and returns
+    fn service_interrupts(&mut self, cpu: &mut CPU) {
+        let (pc, stack_pointer): (u16, u8) = (cpu.program_counter, cpu.stack_pointer);
+        if cpu.service_interrupts() { self.calls.interrupted(cpu, pc, stack_pointer); }
+    }
+    fn execute(&mut self, cpu: &mut CPU) {
+        let (pc, stack_pointer, opcode): (u16, u8, u8) = (cpu.program_counter, cpu.stack_pointer, cpu.bus.peek(cpu.program_counter));
+        cpu.execute();
+        self.calls.executed(cpu, pc, opcode, stack_pointer);
+    }
+    fn step(&mut self, cpu: &mut CPU) {
+        self.service_interrupts(cpu);
+        self.execute(cpu);
+    }
+    // Leaves the pause without stopping again right away at the current instruction
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.resuming = true;
+    }
+    // Whatever stopped emulation also ends any pending step-over or run-to
+    fn stop(&mut self, stop: Stop) -> Stop {
+        self.paused = true;
+        self.target = None;
+        stop
+    }
+    fn run_to(&mut self, target: Target) -> Result<String, String> {
+        self.target = Some(target);
+        self.resume();
+        Ok(String::new())
+    }
+    // Runs one command line against the stopped machine, returning what to show for it
+    pub fn command(&mut self, cpu: &mut CPU, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((name, args)) = words.split_first() else { return Ok(String::new()); };
+        match (*name, args) {
+            ("b" | "break", []) => Ok(self.breakpoints.iter().map(|addr| format!("${:04X}", addr)).collect::<Vec<String>>().join(" ")),
+            ("b" | "break", [addr]) => {
+                let addr: u16 = parse_number(addr)?;
+                if !self.breakpoints.contains(&addr) { self.breakpoints.push(addr); }
+                Ok(format!("Breakpoint at ${:04X}", addr))
+            }
+            ("d" | "delete", []) => {
+                self.breakpoints.clear();
+                Ok(String::from("Deleted all breakpoints"))
+            }
+            ("d" | "delete", [addr]) => {
+                let addr: u16 = parse_number(addr)?;
+                let count: usize = self.breakpoints.len();
+                self.breakpoints.retain(|breakpoint| *breakpoint != addr);
+                if self.breakpoints.len() == count { return Err(format!("No breakpoint at ${:04X}", addr)); }
+                Ok(format!("Deleted breakpoint at ${:04X}", addr))
+            }
+            ("watch", []) => Ok(cpu.bus.watchpoints.iter().map(Watchpoint::describe).collect::<Vec<String>>().join("\n")),
+            ("watch", [range]) => watch(cpu, "rw", range),
+            ("watch", [modes, range]) => watch(cpu, modes, range),
+            ("unwatch", []) => {
+                cpu.bus.watchpoints.clear();
+                Ok(String::from("Deleted all watchpoints"))
+            }
+            ("unwatch", [addr]) => {
+                let addr: u16 = parse_number(addr)?;
+                let count: usize = cpu.bus.watchpoints.len();
+                cpu.bus.watchpoints.retain(|watchpoint| !(watchpoint.start..=watchpoint.end).contains(&addr));
+                if cpu.bus.watchpoints.len() == count { return Err(format!("No watchpoint at ${:04X}", addr)); }
+                Ok(format!("Deleted {} watchpoint(s)", count - cpu.bus.watchpoints.len()))
+            }
+            ("c" | "continue", []) => {
+                self.resume();
+                Ok(String::new())
+            }
+            ("s" | "step", []) => {
+                self.step(cpu);
+                Ok(registers(cpu))
+            }
+            // A JSR is run until PC is back after it, anything else just stepped
+            ("n" | "next", []) if cpu.bus.peek(cpu.program_counter) == JSR => {
+                self.run_to(Target::Reach { addr: cpu.program_counter.wrapping_add(3), stack_pointer: cpu.stack_pointer })
+            }
+            ("n" | "next", []) => {
+                self.step(cpu);
+                Ok(registers(cpu))
+            }
+            ("out" | "finish", []) => self.run_to(Target::Return { stack_pointer: cpu.stack_pointer }),
+            ("u" | "until", [addr]) => self.run_to(Target::Reach { addr: parse_number(addr)?, stack_pointer: 0 }),
+            ("bt" | "backtrace", []) => Ok(self.calls.describe(cpu.program_counter)),
+            ("r" | "regs", []) => Ok(registers(cpu)),
+            ("r" | "regs", [register, value]) => {
+                let value: u16 = parse_number(value)?;
+                let byte = || u8::try_from(value).map_err(|_| format!("{} is a byte register", register));
+                match register.to_lowercase().as_str() {
+                    "a" => cpu.register_a = byte()?,
+                    "x" => cpu.register_x = byte()?,
+                    "y" => cpu.register_y = byte()?,
+                    "p" => cpu.status = byte()?,
+                    "sp" => cpu.stack_pointer = byte()?,
+                    "pc" => cpu.program_counter = value,
+                    _ => return Err(format!("Unknown register {:?}: expected a, x, y, p, sp or pc", register)),
+                }
+                Ok(registers(cpu))
+            }
+            ("dis", []) => Ok(disassemble(cpu, cpu.program_counter, 10)),
+            ("dis", [addr]) => Ok(disassemble(cpu, parse_number(addr)?, 10)),
+            ("dis", [addr, count]) => Ok(disassemble(cpu, parse_number(addr)?, parse_number(count)? as usize)),
+            ("find", ["new"]) => {
+                self.search = Some(RamSearch::new(&cpu.bus.cpu_vram));
+                Ok(String::from("Searching 2048 addresses"))
+            }
+            ("find", []) => {
+                let search: &RamSearch = self.search.as_ref().ok_or("No RAM search, start one with find new")?;
+                let results: Vec<String> = search.results().iter().take(32).map(|(addr, value)| format!("${:04X}: {:02X}", addr, value)).collect();
+                Ok(format!("{} candidates\n{}", search.candidates().len(), results.join("\n")).trim_end().to_string())
+            }
+            ("find", [op, value @ ..]) if value.len() <= 1 => {
+                let value: Option<u8> = value.first().map(|value| parse_number(value)).transpose()?
+                    .map(|value| u8::try_from(value).map_err(|_| format!("${:X} is not a byte", value))).transpose()?;
+                let comparison: Comparison = Comparison::parse(op, value)?;
+                let search: &mut RamSearch = self.search.as_mut().ok_or("No RAM search, start one with find new")?;
+                Ok(format!("{} candidates", search.filter(&cpu.bus.cpu_vram, comparison)))
+            }
+            ("cheat", []) => Ok(cpu.bus.cheats.list.iter().enumerate().map(|(i, cheat)| {
+                let kind: String = match cheat.kind {
+                    CheatKind::Ram { addr, value, hold } => format!("${:04X} = {:02X}{}", addr, value, if hold == Hold::OnWrite { " on write" } else { "" }),
+                    CheatKind::GameGenie { addr, value, compare: Some(compare) } => format!("${:04X} = {:02X} if {:02X}", addr, value, compare),
+                    CheatKind::GameGenie { addr, value, compare: None } => format!("${:04X} = {:02X}", addr, value),
+                };
+                format!("{} {} {} ({})", i + 1, if cheat.enabled { "on " } else { "off" }, cheat.code, kind)
+            }).collect::<Vec<String>>().join("\n")),
+            ("cheat", [action @ ("off" | "on" | "del"), n]) => {
+                let index: usize = n.parse::<usize>().ok().filter(|n| (1..=cpu.bus.cheats.list.len()).contains(n)).ok_or_else(|| format!("No cheat {}", n))? - 1;
+                match *action {
+                    "del" => { cpu.bus.cheats.list.remove(index); }
+                    _ => cpu.bus.cheats.list[index].enabled = *action == "on",
+                }
+                Ok(String::new())
+            }
+            ("cheat", [code]) => cpu.bus.cheats.add(code, Hold::EveryFrame).map(|cheat| format!("Added {}", cheat.code)),
+            ("cheat", [code, "write"]) => cpu.bus.cheats.add(code, Hold::OnWrite).map(|cheat| format!("Added {}", cheat.code)),
+            ("apu", []) => Ok(apu_state(cpu)),
+            ("m" | "mem", [addr]) => Ok(dump(cpu, parse_number(addr)?, 64)),
+            ("m" | "mem", [addr, len]) => Ok(dump(cpu, parse_number(addr)?, parse_number(len)?)),
+            ("w" | "write", [addr, bytes @ ..]) if !bytes.is_empty() => {
+                let addr: u16 = parse_number(addr)?;
+                for (i, byte) in bytes.iter().enumerate() {
+                    let value: u16 = parse_number(byte)?;
+                    cpu.mem_write(addr.wrapping_add(i as u16), u8::try_from(value).map_err(|_| format!("${:X} is not a byte", value))?);
+                }
+                Ok(dump(cpu, addr, bytes.len() as u16))
+            }
+            ("h" | "help", []) => Ok(String::from(HELP)),
+            _ => Err(format!("Unknown command {:?}, try h", line.trim())),
+        }
+    }
+}
+
+// `watch rw 2000-2007`
+fn watch(cpu: &mut CPU, modes: &str, range: &str) -> Result<String, String> {
+    if modes.is_empty() || !modes.chars().all(|mode| "rwx".contains(mode)) {
+        return Err(format!("Expected a mix of r, w and x, got {:?}", modes));
+    }
+    let (start, end): (u16, u16) = match range.split_once('-') {
+        Some((start, end)) => (parse_number(start)?, parse_number(end)?),
+        None => (parse_number(range)?, parse_number(range)?),
+    };
+    if start > end { return Err(format!("{} ends before it starts", range)); }
+    let watchpoint: Watchpoint = Watchpoint { start, end, read: modes.contains('r'), write: modes.contains('w'), execute: modes.contains('x') };
+    cpu.bus.watchpoints.push(watchpoint);
+    Ok(format!("Watching {}", watchpoint.describe()))
+}
+
+// `8000`, `$8000` or `0x8000`
+fn parse_number(text: &str) -> Result<u16, String> {
+    let digits: &str = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Expected a hex number, got {:?}", text))
+}
+
+fn registers(cpu: &CPU) -> String {
+    format!("A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}", cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer, cpu.program_counter)
+}
+
+fn disassemble(cpu: &CPU, addr: u16, count: usize) -> String {
+    let read = |addr: u16| cpu.bus.peek(addr);
+    disasm::listing(&disasm::decode_count(&read, addr, count), &disasm::vector_labels(&read))
+}
+
+fn apu_state(cpu: &mut CPU) -> String {
+    let apu: &mut APU = cpu.bus.apu();
+    let levels: [f64; 6] = apu.channel_levels();
+    let playing: [bool; 5] = [apu.pulse_0.playing(), apu.pulse_1.playing(), apu.triangle.playing(), apu.noise.playing(), apu.dmc.playing()];
+    let mut lines: Vec<String> = Channel::ALL.iter().zip(playing).zip(levels).map(|((channel, playing), level)| {
+        format!("{:<9} {} level {}", channel.name(), if playing { "on " } else { "off" }, level)
+    }).collect();
+    let mode: &str = if apu.noise.tonal() { "tonal (bit 6 feedback)" } else { "noise (bit 1 feedback)" };
+    lines[Channel::Noise as usize] += &format!(", period {}, {}", apu.noise.period(), mode);
+    lines.join("\n")
+}
+
+// 16 bytes per line, through Bus::peek
+fn dump(cpu: &CPU, addr: u16, len: u16) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for row in (0..len as u32).step_by(16) {
+        let start: u16 = addr.wrapping_add(row as u16);
+        let bytes: Vec<String> = (0..(len as u32 - row).min(16)).map(|i| format!("{:02X}", cpu.bus.peek(start.wrapping_add(i as u16)))).collect();
+        lines.push(format!("${:04X}: {}", start, bytes.join(" ")));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::{test, Rom};
+
+    // PRG of `INX; JMP $8000`, starting at $8000
+    fn looping_cpu() -> CPU<'static> {
+        let rom: Rom = test::program_rom(&[0xE8, 0x4C, 0x00, 0x80]);
+        let mut cpu: CPU<'static> = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_breakpoint_stops_before_the_instruction() {
+        let mut cpu: CPU<'static> = looping_cpu();
+        let mut debugger: Debugger = Debugger::new();
+        debugger.command(&mut cpu, "b $8001").unwrap();
+        assert_eq!(debugger.run_frame(&mut cpu), Stop::Breakpoint(0x8001));
+        assert!(debugger.paused);
+        assert_eq!(cpu.register_x, 1);
+        // Continuing runs through the loop once more before stopping again
+        debugger.command(&mut cpu, "c").unwrap();
+        assert_eq!(debugger.run_frame(&mut cpu), Stop::Breakpoint(0x8001));
+        assert_eq!(cpu.register_x, 2);
+        debugger.command(&mut cpu, "d 8001").unwrap();
+        assert_eq!(debugger.run_frame(&mut cpu), Stop::Frame);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // `INX; STX $10; LDA $200A; JMP $8000`
+        let rom: Rom = test::program_rom(&[0xE8, 0x86, 0x10, 0xAD, 0x0A, 0x20, 0x4C, 0x00, 0x80]);
+        let mut cpu: CPU<'static> = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+        let mut debugger: Debugger = Debugger::new();
+        debugger.command(&mut cpu, "watch w $0810").unwrap();
+        // Stops after the write, through the RAM mirror
+        assert_eq!(debugger.run_frame(&mut cpu), Stop::Watchpoint(WatchHit { access: Access::Write, addr: 0x10, value: 1 }));
+        assert_eq!(cpu.program_counter, 0x8003);
+        debugger.command(&mut cpu, "unwatch 810").unwrap();
+        // $200A mirrors PPUSTATUS
+        debugger.command(&mut cpu, "watch r 2002").unwrap();
+        let Stop::Watchpoint(hit) = debugger.run_frame(&mut cpu) else { panic!("expected a watchpoint") };
+        assert_eq!((hit.access, hit.addr), (Access::Read, 0x2002));
+        assert_eq!(cpu.program_counter, 0x8006);
+        debugger.command(&mut cpu, "unwatch").unwrap();
+        debugger.command(&mut cpu, "watch x 8000-8001").unwrap();
+        assert_eq!(debugger.command(&mut cpu, "watch").unwrap(), "$8000-$8001 x");
+        assert_eq!(debugger.run_frame(&mut cpu), Stop::Watchpoint(WatchHit { access: Access::Execute, addr: 0x8000, value: 0xE8 }));
+        assert_eq!(cpu.register_x, 1);
+        assert!(debugger.command(&mut cpu, "watch q 10").is_err());
+    }
+
+    #[test]
+    fn test_step_over_out_and_until() {
+        // $8000: JSR $8010; INX; JMP $8000, with $8010: INY; JSR $8020; RTS and $8020: INY; RTS
+        let mut rom: Rom = test::program_rom(&[0x20, 0x10, 0x80, 0xE8, 0x4C, 0x00, 0x80]);
+        rom.prg_rom[0x10..0x15].copy_from_slice(&[0xC8, 0x20, 0x20, 0x80, 0x60]);
+        rom.prg_rom[0x20..0x22].copy_from_slice(&[0xC8, 0x60]);
+        let mut cpu: CPU<'static> = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+        let mut debugger: Debugger = Debugger::new();
+        debugger.command(&mut cpu, "n").unwrap();
+        assert!(!debugger.paused);
+        assert_eq!(debugger.run_frame(&mut cpu), Stop::Reached(0x8003));
+        assert_eq!((cpu.register_y, cpu.stack_pointer), (2, 0xFD));
+        // Into the subroutine, then out of it again from its middle
+        for _ in 0..3 { debugger.command(&mut cpu, "s").unwrap(); }
+        assert_eq!(cpu.program_counter, 0x8010);
+        debugger.command(&mut cpu, "n").unwrap();
+        assert_eq!(cpu.program_counter, 0x8011);
+        debugger.command(&mut cpu, "out").unwrap();
+        assert_eq!(debugger.run_frame(&mut cpu), Stop::Reached(0x8003));
+        assert_eq!(cpu.register_y, 4);
+        debugger.command(&mut cpu, "u 8021").unwrap();
+        assert_eq!(debugger.run_frame(&mut cpu), Stop::Reached(0x8021));
+        assert_eq!(debugger.target, None);
+    }
+
+    #[test]
+    fn test_registers_and_memory_commands() {
+        let mut cpu: CPU<'static> = looping_cpu();
+        let mut debugger: Debugger = Debugger::new();
+        assert_eq!(debugger.command(&mut cpu, "r x 7f").unwrap(), "A:00 X:7F Y:00 P:24 SP:FD PC:8000");
+        assert_eq!(debugger.command(&mut cpu, "s").unwrap(), "A:00 X:80 Y:00 P:A4 SP:FD PC:8001");
+        assert_eq!(debugger.command(&mut cpu, "w 10 ab cd").unwrap(), "$0010: AB CD");
+        assert_eq!(debugger.command(&mut cpu, "m $8000 4").unwrap(), "$8000: E8 4C 00 80");
+        assert_eq!(debugger.command(&mut cpu, "dis 8000 2").unwrap(), "RESET:\n  8000  E8        INX\n  8001  4C 00 80  JMP RESET");
+        assert!(debugger.command(&mut cpu, "find >").is_err());
+        debugger.command(&mut cpu, "find new").unwrap();
+        assert_eq!(debugger.command(&mut cpu, "find = ab").unwrap(), "1 candidates");
+        assert_eq!(debugger.command(&mut cpu, "find").unwrap(), "1 candidates\n$0010: AB");
+        debugger.command(&mut cpu, "cheat 0010:42 write").unwrap();
+        debugger.command(&mut cpu, "w 10 00").unwrap();
+        assert_eq!(debugger.command(&mut cpu, "m 10 1").unwrap(), "$0010: 42");
+        debugger.command(&mut cpu, "cheat off 1").unwrap();
+        assert_eq!(debugger.command(&mut cpu, "cheat").unwrap(), "1 off 0010:42 ($0010 = 42 on write)");
+        assert!(debugger.command(&mut cpu, "cheat del 2").is_err());
+        assert!(debugger.command(&mut cpu, "r a 100").is_err());
+        debugger.command(&mut cpu, "w 400e 85").unwrap();
+        assert!(debugger.command(&mut cpu, "apu").unwrap().contains("noise     off level 0, period 96, tonal (bit 6 feedback)"));
+        assert!(debugger.command(&mut cpu, "jump").is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use crate::prelude::*;
+use crate::cpu::AddressingMode;
+use crate::opcodes::{self, OpCode};
+
+// A name shown for an address, both on its own line before the code there and in place of the
+// address in branch, JMP and JSR operands
+pub type Label = (u16, &'static str);
+
+const JMP_INDIRECT: u8 = 0x6C;
+const JSR: u8 = 0x20;
+
+// One decoded instruction, or a lone byte that isn't a known opcode
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub op: Option<&'static OpCode>,
+}
+
+impl Instruction {
+    // Reads the instruction at `addr` through `read`, e.g. Bus::peek or a ROM slice
+    pub fn decode(read: &impl Fn(u16) -> u8, addr: u16) -> Instruction {
+        let op: Option<&'static OpCode> = opcodes::lookup(read(addr));
+        let len: u16 = op.map_or(1, |op| op.len as u16);
+        Instruction { addr, bytes: (0..len).map(|i| read(addr.wrapping_add(i))).collect(), op }
+    }
+    pub fn next(&self) -> u16 { self.addr.wrapping_add(self.bytes.len() as u16) }
+    // Where a branch, JMP or JSR goes; JMP ($nnnn) only knows where its pointer is
+    pub fn target(&self) -> Option<u16> {
+        let op: &OpCode = self.op?;
+        match (op.len, &op.mode) {
+            (2, AddressingMode::NoneAddressing) => Some(self.next().wrapping_add(self.bytes[1] as i8 as u16)),
+            (3, AddressingMode::NoneAddressing) if op.code != JMP_INDIRECT => Some(self.word()),
+            (3, AddressingMode::Absolute) if op.code == JSR => Some(self.word()),
+            _ => None,
+        }
+    }
+    fn word(&self) -> u16 { u16::from_le_bytes([self.bytes[1], self.bytes[2]]) }
+    pub fn operand(&self, labels: &[Label]) -> String {
+        let Some(op) = self.op else { return format!("${:02X}", self.bytes[0]); };
+        if let Some(target) = self.target() {
+            return match labels.iter().find(|(addr, _)| *addr == target) {
+                Some((_, name)) => String::from(*name),
+                None => format!("${:04X}", target),
+            };
+        }
+        let byte: u8 = self.bytes.get(1).copied().unwrap_or(0);
+        match op.mode {
+            AddressingMode::Accumulator => String::from("A"),
+            AddressingMode::Immediate => format!("#${:02X}", byte),
+            AddressingMode::ZeroPage => format!("${:02X}", byte),
+            AddressingMode::ZeroPage_X => format!("${:02X},X", byte),
+            AddressingMode::ZeroPage_Y => format!("${:02X},Y", byte),
+            AddressingMode::Indirect_X => format!("(${:02X},X)", byte),
+            AddressingMode::Indirect_Y => format!("(${:02X}),Y", byte),
+            AddressingMode::Absolute => format!("${:04X}", self.word()),
+            AddressingMode::Absolute_X => format!("${:04X},X", self.word()),
+            AddressingMode::Absolute_Y => format!("${:04X},Y", self.word()),
+            AddressingMode::NoneAddressing if op.code == JMP_INDIRECT => format!("(${:04X})", self.word()),
+            AddressingMode::NoneAddressing => String::new(),
+        }
+    }
+    // `LDA #$10`; unknown opcodes come out as `.db $02`
+    pub fn text(&self, labels: &[Label]) -> String {
+        let mnemonic: &str = self.op.map_or(".db", |op| op.mnemonic);
+        let operand: String = self.operand(labels);
+        if operand.is_empty() { String::from(mnemonic) } else { format!("{} {}", mnemonic, operand) }
+    }
+}
+
+// The NMI, RESET and IRQ/BRK handlers, from the vectors at $FFFA-$FFFF
+pub fn vector_labels(read: &impl Fn(u16) -> u8) -> Vec<Label> {
+    let vector = |addr: u16| u16::from_le_bytes([read(addr), read(addr + 1)]);
+    vec![(vector(0xFFFA), "NMI"), (vector(0xFFFC), "RESET"), (vector(0xFFFE), "IRQ")]
+}
+
+// `count` instructions one after another from `addr`
+pub fn decode_count(read: &impl Fn(u16) -> u8, addr: u16, count: usize) -> Vec<Instruction> {
+    let mut instructions: Vec<Instruction> = Vec::with_capacity(count);
+    let mut addr: u16 = addr;
+    for _ in 0..count {
+        let instruction: Instruction = Instruction::decode(read, addr);
+        addr = instruction.next();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+// Every instruction starting in `start..=end`; the last one may run past `end`
+pub fn decode_range(read: &impl Fn(u16) -> u8, start: u16, end: u16) -> Vec<Instruction> {
+    let mut instructions: Vec<Instruction> = Vec::new();
+    let mut addr: u32 = start as u32;
+    while addr <= end as u32 {
+        let instruction: Instruction = Instruction::decode(read, addr as u16);
+        addr += instruction.bytes.len() as u32;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+// One instruction per line as `8000  A9 10     LDA #$10`, with `RESET:` style label lines
+pub fn listing(instructions: &[Instruction], labels: &[Label]) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for instruction in instructions {
+        for (_, name) in labels.iter().filter(|(addr, _)| *addr == instruction.addr) { lines.push(format!("{}:", name)); }
+        let bytes: Vec<String> = instruction.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        lines.push(format!("  {:04X}  {:8}  {}", instruction.addr, bytes.join(" "), instruction.text(labels)));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_listing_with_vector_labels() {
+        let mut memory: Vec<u8> = vec![0; 0x10000];
+        // RESET: SEI; LDA #$10; STA $2000,X; BNE RESET; JMP ($FFFC); ASL A; $02, then NMI: JSR NMI
+        memory[0x8000..0x8010].copy_from_slice(&[0x78, 0xA9, 0x10, 0x9D, 0x00, 0x20, 0xD0, 0xF8, 0x6C, 0xFC, 0xFF, 0x0A, 0x02, 0x20, 0x0D, 0x80]);
+        memory[0xFFFA..0xFFFE].copy_from_slice(&[0x0D, 0x80, 0x00, 0x80]);
+        memory[0xFFFF] = 0xAD;
+        let read = |addr: u16| memory[addr as usize];
+        let labels: Vec<Label> = vector_labels(&read);
+        let instructions: Vec<Instruction> = decode_range(&read, 0x8000, 0x800D);
+        assert_eq!(listing(&instructions, &labels), "\
+RESET:
+  8000  78        SEI
+  8001  A9 10     LDA #$10
+  8003  9D 00 20  STA $2000,X
+  8006  D0 F8     BNE RESET
+  8008  6C FC FF  JMP ($FFFC)
+  800B  0A        ASL A
+  800C  02        .db $02
+NMI:
+  800D  20 0D 80  JSR NMI");
+        let addrs: Vec<u16> = decode_count(&read, 0x8001, 3).iter().map(|instruction| instruction.addr).collect();
+        assert_eq!(addrs, vec![0x8001, 0x8003, 0x8006]);
+        // An LDA at $FFFF takes its operand from $0000-$0001
+        let wrapping: Vec<Instruction> = decode_range(&read, 0xFFFF, 0xFFFF);
+        assert_eq!((wrapping.len(), wrapping[0].text(&[])), (1, String::from("LDA $0000")));
+    }
+}
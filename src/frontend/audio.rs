@@ -0,0 +1,319 @@
+use std::sync::Arc;
+use std::time::Duration;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use rodio::{OutputStream, Sink};
+use rodio::source::Source;
+
+use gbnes_core::apu::CHANNELS;
+
+// The mixer's master volume unless audio.volume says otherwise: its full range is loud
+pub const DEFAULT_VOLUME: f32 = 0.2;
+// Sound the queue holds at most unless audio.latency says otherwise: about three frames, enough to
+// ride out a late one, short enough that the sound doesn't noticeably lag the picture
+pub const DEFAULT_LATENCY: Duration = Duration::from_millis(50);
+// How far controlled_rate strays from the nominal sample rate at most, too little to hear as pitch
+pub const MAX_RATE_ADJUST: f64 = 0.005;
+
+// The ring buffer behind an AudioQueue. `read` and `write` count samples since the start and only
+// ever grow, a whole stereo frame at a time; the slot of sample n is n % slots.len(). Samples are
+// stored as their f32 bits.
+struct Ring {
+    slots: Box<[AtomicU32]>,
+    read: AtomicUsize,
+    write: AtomicUsize,
+    underruns: AtomicU64,
+    dropped: AtomicU64,
+    // How long the device takes to play what it was last handed, in microseconds, where it says
+    device_latency: AtomicU64,
+}
+
+// Stereo frames (a left and a right sample) on their way from the emulator to the audio device,
+// oldest first. Lock-free for one producer (the main loop pushing each video frame's samples) and
+// one consumer (the device thread).
+#[derive(Clone)]
+pub struct AudioQueue { ring: Arc<Ring> }
+
+impl AudioQueue {
+    // Room for `capacity` stereo frames
+    pub fn new(capacity: usize) -> Self {
+        let slots: Box<[AtomicU32]> = (0..capacity.max(1) * CHANNELS).map(|_| AtomicU32::new(0)).collect();
+        AudioQueue { ring: Arc::new(Ring { slots, read: AtomicUsize::new(0), write: AtomicUsize::new(0), underruns: AtomicU64::new(0), dropped: AtomicU64::new(0), device_latency: AtomicU64::new(0) }) }
+    }
+    // Holding at most `latency` of sound at `sample_rate`
+    pub fn for_latency(sample_rate: u32, latency: Duration) -> Self { AudioQueue::new((sample_rate as f64 * latency.as_secs_f64()) as usize) }
+    // In stereo frames, like len
+    pub fn capacity(&self) -> usize { self.ring.slots.len() / CHANNELS }
+    pub fn len(&self) -> usize { self.ring.write.load(Ordering::Acquire).wrapping_sub(self.ring.read.load(Ordering::Acquire)) / CHANNELS }
+    // Queues every frame of the interleaved `samples` `repeat` times, which stretches the sound over
+    // `repeat` times as long. What doesn't fit is dropped rather than letting the latency grow.
+    pub fn push(&self, samples: &[f32], repeat: usize) {
+        let ring: &Ring = &self.ring;
+        let read: usize = ring.read.load(Ordering::Acquire);
+        let mut write: usize = ring.write.load(Ordering::Relaxed);
+        let mut dropped: u64 = 0;
+        for frame in samples.chunks_exact(CHANNELS) {
+            for _ in 0..repeat {
+                if write.wrapping_sub(read) >= ring.slots.len() { dropped += 1; continue; }
+                for &sample in frame {
+                    ring.slots[write % ring.slots.len()].store(sample.to_bits(), Ordering::Relaxed);
+                    write = write.wrapping_add(1);
+                }
+            }
+        }
+        ring.write.store(write, Ordering::Release);
+        if dropped > 0 { ring.dropped.fetch_add(dropped, Ordering::Relaxed); }
+    }
+    pub fn pop(&self) -> Option<[f32; CHANNELS]> {
+        let ring: &Ring = &self.ring;
+        let read: usize = ring.read.load(Ordering::Relaxed);
+        if read == ring.write.load(Ordering::Acquire) { return None; }
+        let frame: [f32; CHANNELS] = core::array::from_fn(|channel| f32::from_bits(ring.slots[(read + channel) % ring.slots.len()].load(Ordering::Relaxed)));
+        ring.read.store(read.wrapping_add(CHANNELS), Ordering::Release);
+        Some(frame)
+    }
+    // The rate for the emulator to produce samples at, given the device plays them at `sample_rate`:
+    // a little faster while the queue is under half full and slower while it's over, so the two clocks
+    // never drift apart far enough to run it dry or fill it up
+    pub fn controlled_rate(&self, sample_rate: f64) -> f64 {
+        let half: f64 = self.capacity() as f64 / 2.0;
+        let error: f64 = ((half - self.len() as f64) / half).clamp(-1.0, 1.0);
+        sample_rate * (1.0 + MAX_RATE_ADJUST * error)
+    }
+    // How long a sample pushed now takes to be heard: the queue ahead of it, plus the device's own
+    // buffer when the backend can tell
+    pub fn latency(&self, sample_rate: f64) -> Duration {
+        Duration::from_secs_f64(self.len() as f64 / sample_rate) + Duration::from_micros(self.ring.device_latency.load(Ordering::Relaxed))
+    }
+    #[cfg(feature = "cpal")]
+    fn set_device_latency(&self, latency: Duration) { self.ring.device_latency.store(latency.as_micros() as u64, Ordering::Relaxed); }
+    // Times the device ran dry, and frames that arrived with the queue full
+    pub fn underruns(&self) -> u64 { self.ring.underruns.load(Ordering::Relaxed) }
+    pub fn dropped(&self) -> u64 { self.ring.dropped.load(Ordering::Relaxed) }
+}
+
+// Average of AudioQueue::latency over the passes since it was last taken, for the title bar along
+// with the queue's underruns and dropped samples so far
+#[derive(Default)]
+pub struct LatencyMeter { total: Duration, samples: u32 }
+
+impl LatencyMeter {
+    pub fn sample(&mut self, queue: &AudioQueue, sample_rate: f64) {
+        self.total += queue.latency(sample_rate);
+        self.samples += 1;
+    }
+    pub fn take(&mut self) -> Option<Duration> {
+        if self.samples == 0 { return None; }
+        let average: Duration = self.total / self.samples;
+        *self = LatencyMeter::default();
+        Some(average)
+    }
+    pub fn label(&mut self, queue: &AudioQueue) -> String {
+        let mut label: String = format!("audio {}ms", self.take().unwrap_or_default().as_millis());
+        if queue.underruns() > 0 { label += &format!(", {} underruns", queue.underruns()); }
+        if queue.dropped() > 0 { label += &format!(", {} dropped", queue.dropped()); }
+        label
+    }
+}
+
+// A never ending interleaved stereo source over an AudioQueue, appended to the sink once. When the
+// queue runs dry it fades the last frame out instead of clicking to silence, then waits for a third of the queue
+// (a frame's worth at the default latency) before playing again, so a slow frame costs one gap rather than a stutter.
+pub struct NesSound {
+    queue: AudioQueue,
+    sample_rate: u32,
+    // The frame being played, and the channel of it to play next
+    frame: [f32; CHANNELS],
+    channel: usize,
+    refilling: bool,
+}
+
+impl NesSound {
+    pub fn new(queue: AudioQueue, sample_rate: u32) -> Self { NesSound { queue, sample_rate, frame: [0.0; CHANNELS], channel: CHANNELS, refilling: true } }
+    fn resume_level(&self) -> usize { self.queue.capacity() / 3 }
+}
+
+impl Iterator for NesSound {
+    type Item = f32;
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == CHANNELS {
+            self.channel = 0;
+            if self.refilling && self.queue.len() >= self.resume_level() { self.refilling = false; }
+            let frame: Option<[f32; CHANNELS]> = if self.refilling { None } else { self.queue.pop() };
+            match frame {
+                Some(frame) => self.frame = frame,
+                None => {
+                    if !self.refilling { self.queue.ring.underruns.fetch_add(1, Ordering::Relaxed); }
+                    self.refilling = true;
+                    for sample in self.frame.iter_mut() { *sample *= 0.995; }
+                }
+            }
+        }
+        self.channel += 1;
+        Some(self.frame[self.channel - 1])
+    }
+}
+
+impl Source for NesSound {
+    fn current_frame_len(&self) -> Option<usize> { None }
+    fn channels(&self) -> u16 { CHANNELS as u16 }
+    fn sample_rate(&self) -> u32 { self.sample_rate }
+    fn total_duration(&self) -> Option<std::time::Duration> { None }
+}
+
+// What plays the queue on the audio device. Both drain it through NesSound on their own thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    // A rodio sink, the default
+    Rodio,
+    // A raw cpal output stream (`cpal` feature), for platforms where rodio's mixing thread misbehaves
+    Cpal,
+}
+
+impl Backend {
+    pub fn parse(name: &str) -> Result<Backend, String> {
+        match name.trim().to_lowercase().as_str() {
+            "rodio" => Ok(Backend::Rodio),
+            "cpal" if cfg!(feature = "cpal") => Ok(Backend::Cpal),
+            "cpal" => Err(String::from("this build has no cpal backend: rebuild with --features cpal")),
+            _ => Err(format!("Unknown audio backend {:?}: expected rodio or cpal", name)),
+        }
+    }
+}
+
+// The open device; sound stops when it's dropped
+pub enum AudioOutput {
+    Rodio { _stream: OutputStream, _sink: Sink },
+    #[cfg(feature = "cpal")]
+    Cpal { _stream: cpal::Stream, sample_rate: u32 },
+}
+
+impl AudioOutput {
+    // Starts playing `queue` on the default device, at `sample_rate` where the device allows it
+    // `buffer_size` is the device buffer in sample frames, or its default when None; rodio picks its
+    // own, so only cpal honours it
+    pub fn open(backend: Backend, queue: AudioQueue, sample_rate: u32, buffer_size: Option<u32>) -> Result<AudioOutput, String> {
+        match backend {
+            Backend::Rodio => {
+                if buffer_size.is_some() { eprintln!("audio.buffer_size only applies to the cpal backend, ignoring it"); }
+                let (stream, handle) = OutputStream::try_default().map_err(|err| format!("Could not open audio device: {}", err))?;
+                let sink: Sink = Sink::try_new(&handle).map_err(|err| format!("Could not open audio device: {}", err))?;
+                sink.append(NesSound::new(queue, sample_rate));
+                Ok(AudioOutput::Rodio { _stream: stream, _sink: sink })
+            }
+            #[cfg(feature = "cpal")]
+            Backend::Cpal => open_cpal(queue, sample_rate, buffer_size),
+            #[cfg(not(feature = "cpal"))]
+            Backend::Cpal => Err(String::from("this build has no cpal backend")),
+        }
+    }
+    // Samples per second the device actually plays; cpal devices may not offer the one asked for
+    pub fn sample_rate(&self, requested: u32) -> u32 {
+        match self {
+            AudioOutput::Rodio { .. } => requested,
+            #[cfg(feature = "cpal")]
+            AudioOutput::Cpal { sample_rate, .. } => *sample_rate,
+        }
+    }
+}
+
+// A 32 bit float stream on the default device, at `sample_rate` if any of its configurations
+// covers it or else at its default rate. Mono devices get the two sides averaged, and any channels
+// past the first two are left silent. Each callback
+// records how far ahead of playback it runs as the queue's device latency.
+#[cfg(feature = "cpal")]
+fn open_cpal(queue: AudioQueue, sample_rate: u32, buffer_size: Option<u32>) -> Result<AudioOutput, String> {
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    let device: cpal::Device = cpal::default_host().default_output_device().ok_or_else(|| String::from("No audio output device"))?;
+    let error = |err: &dyn std::fmt::Display| format!("Could not open audio device: {}", err);
+    let supported: Option<cpal::SupportedStreamConfig> = device.supported_output_configs().map_err(|err| error(&err))?
+        .filter(|range| range.sample_format() == cpal::SampleFormat::F32)
+        .find(|range| (range.min_sample_rate().0..=range.max_sample_rate().0).contains(&sample_rate))
+        .map(|range| range.with_sample_rate(cpal::SampleRate(sample_rate)));
+    let supported: cpal::SupportedStreamConfig = match supported {
+        Some(supported) => supported,
+        None => device.default_output_config().map_err(|err| error(&err))?,
+    };
+    if supported.sample_format() != cpal::SampleFormat::F32 { return Err(format!("Audio device wants {} samples, only f32 is supported", supported.sample_format())); }
+    let mut config: cpal::StreamConfig = supported.config();
+    if let Some(frames) = buffer_size { config.buffer_size = cpal::BufferSize::Fixed(frames); }
+    let (channels, sample_rate): (usize, u32) = (config.channels as usize, config.sample_rate.0);
+    let mut sound: NesSound = NesSound::new(queue.clone(), sample_rate);
+    let stream: cpal::Stream = device.build_output_stream(&config, move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+        let timestamp: cpal::OutputStreamTimestamp = info.timestamp();
+        if let Some(latency) = timestamp.playback.duration_since(&timestamp.callback) { queue.set_device_latency(latency); }
+        for frame in data.chunks_mut(channels) {
+            let (left, right): (f32, f32) = (sound.next().unwrap_or(0.0), sound.next().unwrap_or(0.0));
+            frame.fill(0.0);
+            if channels == 1 { frame[0] = (left + right) / 2.0; } else { frame[..2].copy_from_slice(&[left, right]); }
+        }
+    }, |err| eprintln!("Audio stream error: {}", err), None).map_err(|err| error(&err))?;
+    stream.play().map_err(|err| error(&err))?;
+    Ok(AudioOutput::Cpal { _stream: stream, sample_rate })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_queue_keeps_sample_order() {
+        let queue: AudioQueue = AudioQueue::new(4);
+        queue.push(&[0.1, -0.1, 0.2, -0.2], 1);
+        queue.push(&[0.3, -0.3], 2);
+        queue.push(&[0.4, -0.4], 1);
+        assert_eq!((queue.len(), queue.dropped()), (4, 1));
+        let popped: Vec<Option<[f32; 2]>> = (0..5).map(|_| queue.pop()).collect();
+        assert_eq!(popped, vec![Some([0.1, -0.1]), Some([0.2, -0.2]), Some([0.3, -0.3]), Some([0.3, -0.3]), None]);
+        // Wrapping around the ring
+        queue.push(&[0.5, -0.5, 0.6, -0.6, 0.7, -0.7], 1);
+        assert_eq!((queue.pop(), queue.len()), (Some([0.5, -0.5]), 2));
+    }
+
+    #[test]
+    fn test_parse_backend() {
+        assert_eq!(Backend::parse(" Rodio"), Ok(Backend::Rodio));
+        assert_eq!(Backend::parse("cpal").is_ok(), cfg!(feature = "cpal"));
+        assert!(Backend::parse("sdl").is_err());
+    }
+
+    #[test]
+    fn test_controlled_rate() {
+        let queue: AudioQueue = AudioQueue::new(100);
+        assert_eq!(queue.controlled_rate(44100.0), 44100.0 * 1.005);
+        queue.push(&[0.0; 100], 1);
+        assert_eq!(queue.controlled_rate(44100.0), 44100.0);
+        queue.push(&[0.0; 100], 1);
+        assert_eq!(queue.controlled_rate(44100.0), 44100.0 * 0.995);
+    }
+
+    #[test]
+    fn test_latency() {
+        let queue: AudioQueue = AudioQueue::for_latency(1000, Duration::from_millis(20));
+        assert_eq!(queue.capacity(), 20);
+        let mut meter: LatencyMeter = LatencyMeter::default();
+        assert_eq!(meter.take(), None);
+        queue.push(&[0.0; 20], 1);
+        meter.sample(&queue, 1000.0);
+        queue.push(&[0.0; 20], 1);
+        meter.sample(&queue, 1000.0);
+        assert_eq!(meter.take(), Some(Duration::from_millis(15)));
+        assert_eq!(meter.take(), None);
+    }
+
+    #[test]
+    fn test_underrun_fades_and_refills() {
+        let queue: AudioQueue = AudioQueue::new(6);
+        let mut sound: NesSound = NesSound::new(queue.clone(), 44100);
+        // Nothing plays until a frame's worth (a third of the queue) is in
+        queue.push(&[0.5, -0.5], 1);
+        assert_eq!((sound.next(), sound.next()), (Some(0.0), Some(0.0)));
+        queue.push(&[0.5, -0.5], 1);
+        let played: Vec<f32> = sound.by_ref().take(4).collect();
+        assert_eq!(played, vec![0.5, -0.5, 0.5, -0.5]);
+        let (left, right): (f32, f32) = (sound.next().unwrap(), sound.next().unwrap());
+        assert!(left > 0.0 && left < 0.5 && right == -left);
+        assert_eq!(queue.underruns(), 1);
+        queue.push(&[0.25, 0.25], 1);
+        assert!(sound.next().unwrap() < left);
+    }
+}
@@ -0,0 +1,206 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use gbnes_core::Frame;
+
+use super::wav::{pcm16, recording_path};
+
+const WIDTH: u32 = Frame::WIDTH as u32;
+const HEIGHT: u32 = Frame::HIGHT as u32;
+// One uncompressed 24 bit frame
+const FRAME_BYTES: u32 = WIDTH * HEIGHT * 3;
+// AVI 1.0 keeps every offset and size in 32 bits; stop a little short of that so the index fits
+const MAX_BYTES: u64 = 0xF000_0000;
+// idx1 flag of a chunk every player can start decoding at, as all uncompressed frames are
+const KEYFRAME: u32 = 0x10;
+// avih flag: the file ends with an idx1 index
+const HAS_INDEX: u32 = 0x10;
+
+fn chunk(bytes: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    bytes.extend_from_slice(id);
+    bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(body);
+}
+fn list(bytes: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    bytes.extend_from_slice(b"LIST");
+    bytes.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+    bytes.extend_from_slice(kind);
+    bytes.extend_from_slice(body);
+}
+fn words(values: &[u32]) -> Vec<u8> { values.iter().flat_map(|value| value.to_le_bytes()).collect() }
+
+// What the AVI's headers describe: a video stream of `frame_rate` frames per second, a 16 bit PCM
+// audio stream, and how much of each has been written so far
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Streams {
+    frame_rate: f64,
+    sample_rate: u32,
+    channels: u16,
+    frames: u32,
+    audio_bytes: u32,
+    // The chunks in the movi list, headers included
+    movi_bytes: u32,
+}
+
+impl Streams {
+    // Everything before the first movi chunk, ending with the movi list's own header
+    fn header(&self) -> Vec<u8> {
+        let block_align: u32 = self.channels as u32 * 2;
+        // Frames per second as a rate over a scale
+        let (rate, scale): (u32, u32) = ((self.frame_rate * 10_000.0).round() as u32, 10_000);
+        let mut avih: Vec<u8> = words(&[
+            (1_000_000.0 / self.frame_rate).round() as u32,
+            (FRAME_BYTES as f64 * self.frame_rate) as u32 + self.sample_rate * block_align,
+            0, HAS_INDEX, self.frames, 0, 2, FRAME_BYTES, WIDTH, HEIGHT,
+        ]);
+        avih.extend_from_slice(&[0; 16]);
+
+        let mut video: Vec<u8> = Vec::new();
+        let mut strh: Vec<u8> = b"vidsDIB ".to_vec();
+        strh.extend(words(&[0, 0, 0, scale, rate, 0, self.frames, FRAME_BYTES, u32::MAX, 0]));
+        strh.extend([0u16, 0, WIDTH as u16, HEIGHT as u16].iter().flat_map(|value| value.to_le_bytes()));
+        chunk(&mut video, b"strh", &strh);
+        // BITMAPINFOHEADER: a positive height makes the rows go bottom-up
+        let mut bitmap: Vec<u8> = words(&[40, WIDTH, HEIGHT]);
+        bitmap.extend_from_slice(&1u16.to_le_bytes());
+        bitmap.extend_from_slice(&24u16.to_le_bytes());
+        bitmap.extend(words(&[0, FRAME_BYTES, 0, 0, 0, 0]));
+        chunk(&mut video, b"strf", &bitmap);
+
+        let mut audio: Vec<u8> = Vec::new();
+        let mut strh: Vec<u8> = b"auds".to_vec();
+        strh.extend(words(&[0, 0, 0, block_align, self.sample_rate * block_align, 0, self.audio_bytes / block_align, self.sample_rate * block_align, u32::MAX, block_align]));
+        strh.extend_from_slice(&[0; 8]);
+        chunk(&mut audio, b"strh", &strh);
+        // WAVEFORMAT with 16 bit PCM
+        let mut format: Vec<u8> = Vec::new();
+        format.extend_from_slice(&1u16.to_le_bytes());
+        format.extend_from_slice(&self.channels.to_le_bytes());
+        format.extend(words(&[self.sample_rate, self.sample_rate * block_align]));
+        format.extend_from_slice(&(block_align as u16).to_le_bytes());
+        format.extend_from_slice(&16u16.to_le_bytes());
+        chunk(&mut audio, b"strf", &format);
+
+        let mut hdrl: Vec<u8> = Vec::new();
+        chunk(&mut hdrl, b"avih", &avih);
+        list(&mut hdrl, b"strl", &video);
+        list(&mut hdrl, b"strl", &audio);
+
+        let mut bytes: Vec<u8> = Vec::new();
+        list(&mut bytes, b"hdrl", &hdrl);
+        let index_bytes: u32 = self.chunks() * 16 + 8;
+        let mut riff: Vec<u8> = b"RIFF".to_vec();
+        riff.extend_from_slice(&(4 + bytes.len() as u32 + 12 + self.movi_bytes + index_bytes).to_le_bytes());
+        riff.extend_from_slice(b"AVI ");
+        riff.extend(bytes);
+        riff.extend_from_slice(b"LIST");
+        riff.extend_from_slice(&(4 + self.movi_bytes).to_le_bytes());
+        riff.extend_from_slice(b"movi");
+        riff
+    }
+    // A video chunk and an audio chunk per frame
+    fn chunks(&self) -> u32 { self.frames * 2 }
+}
+
+// Gameplay going into an uncompressed AVI file, every emulated frame with the audio emulated
+// along with it, so the two stay in sync whatever the speed. The headers are written up front
+// and rewritten with the real lengths by finish, which also appends the index.
+pub struct AviRecorder {
+    path: PathBuf,
+    out: BufWriter<File>,
+    streams: Streams,
+    // idx1 entries: chunk id, and offset from the movi list's type and size
+    index: Vec<(&'static [u8; 4], u32, u32)>,
+}
+
+impl AviRecorder {
+    pub fn create(path: &Path, frame_rate: f64, sample_rate: u32, channels: u16) -> Result<AviRecorder, String> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|err| format!("Could not create {}: {}", dir.display(), err))?;
+        }
+        let file: File = File::create(path).map_err(|err| format!("Could not create {}: {}", path.display(), err))?;
+        let streams: Streams = Streams { frame_rate, sample_rate, channels, frames: 0, audio_bytes: 0, movi_bytes: 0 };
+        let mut out: BufWriter<File> = BufWriter::new(file);
+        out.write_all(&streams.header()).map_err(|err| format!("Could not write {}: {}", path.display(), err))?;
+        Ok(AviRecorder { path: path.to_path_buf(), out, streams, index: Vec::new() })
+    }
+    // Where the record video hotkey saves: recordings/<rom name>-<unix time>.avi
+    pub fn default_path(rom_path: &str) -> PathBuf { recording_path(rom_path, "avi") }
+    pub fn path(&self) -> &Path { &self.path }
+    // One frame's picture, and its interleaved samples from -1.0 to 1.0
+    pub fn write(&mut self, frame: &Frame, samples: &[f32]) -> Result<(), String> {
+        let audio_bytes: u32 = samples.len() as u32 * 2;
+        let total: u64 = self.streams.header().len() as u64 + self.streams.movi_bytes as u64 + (16 + FRAME_BYTES + audio_bytes) as u64;
+        if total + (self.streams.chunks() as u64 + 2) * 16 > MAX_BYTES { return Err(format!("{} reached the AVI size limit", self.path.display())); }
+        // Rows bottom-up, BGR
+        let mut picture: Vec<u8> = Vec::with_capacity(FRAME_BYTES as usize);
+        for row in frame.data.chunks(Frame::WIDTH * 3).rev() {
+            for pixel in row.chunks(3) { picture.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]); }
+        }
+        let sound: Vec<u8> = samples.iter().flat_map(|sample| pcm16(*sample)).collect();
+        let mut bytes: Vec<u8> = Vec::with_capacity(16 + picture.len() + sound.len());
+        for (id, body) in [(b"00db", &picture), (b"01wb", &sound)] {
+            self.index.push((id, 4 + self.streams.movi_bytes + bytes.len() as u32, body.len() as u32));
+            chunk(&mut bytes, id, body);
+        }
+        self.out.write_all(&bytes).map_err(|err| format!("Could not write {}: {}", self.path.display(), err))?;
+        self.streams.frames += 1;
+        self.streams.audio_bytes += audio_bytes;
+        self.streams.movi_bytes += bytes.len() as u32;
+        Ok(())
+    }
+    // Seconds recorded
+    pub fn seconds(&self) -> f64 { self.streams.frames as f64 / self.streams.frame_rate }
+    pub fn finish(mut self) -> Result<(), String> {
+        let error = |err: std::io::Error| format!("Could not write {}: {}", self.path.display(), err);
+        let entries: Vec<u8> = self.index.iter().flat_map(|(id, offset, size)| {
+            let mut entry: Vec<u8> = id.to_vec();
+            entry.extend(words(&[KEYFRAME, *offset, *size]));
+            entry
+        }).collect();
+        let mut idx1: Vec<u8> = Vec::with_capacity(entries.len() + 8);
+        chunk(&mut idx1, b"idx1", &entries);
+        self.out.write_all(&idx1).map_err(error)?;
+        self.out.seek(SeekFrom::Start(0)).map_err(error)?;
+        self.out.write_all(&self.streams.header()).map_err(error)?;
+        self.out.flush().map_err(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn word(data: &[u8], at: usize) -> u32 { u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]) }
+
+    #[test]
+    fn test_record_avi() {
+        let path: PathBuf = std::env::temp_dir().join(format!("gbnes-avi-{}.avi", std::process::id()));
+        let mut recorder: AviRecorder = AviRecorder::create(&path, 60.0, 44100, 2).unwrap();
+        let mut frame: Frame = Frame::new();
+        frame.set_pixel(0, Frame::HIGHT - 1, (1, 2, 3));
+        recorder.write(&frame, &[0.0, 1.0]).unwrap();
+        recorder.write(&Frame::new(), &[-1.0, 0.5, 0.0, 0.0]).unwrap();
+        assert!((recorder.seconds() - 2.0 / 60.0).abs() < 1e-9);
+        recorder.finish().unwrap();
+        let data: Vec<u8> = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!((&data[..4], word(&data, 4) as usize, &data[8..12]), (&b"RIFF"[..], data.len() - 8, &b"AVI "[..]));
+        // avih: 60 fps, 2 frames, 2 streams
+        assert_eq!(&data[24..28], b"avih");
+        assert_eq!((word(&data, 32), word(&data, 48), word(&data, 56)), (16_667, 2, 2));
+        // The first chunk is the first frame, bottom row first
+        let header: usize = Streams { frame_rate: 60.0, sample_rate: 44100, channels: 2, frames: 2, audio_bytes: 12, movi_bytes: 0 }.header().len();
+        assert_eq!(&data[header - 4..header], b"movi");
+        assert_eq!((&data[header..header + 4], word(&data, header + 4)), (&b"00db"[..], FRAME_BYTES));
+        assert_eq!(data[header + 8..header + 11], [3, 2, 1]);
+        let audio: usize = header + 8 + FRAME_BYTES as usize;
+        assert_eq!(data[audio..audio + 12], [b'0', b'1', b'w', b'b', 4, 0, 0, 0, 0x00, 0x00, 0xFF, 0x7F]);
+        // And the index, of four chunks, at the end
+        let index: usize = data.len() - 8 - 4 * 16;
+        assert_eq!((&data[index..index + 4], word(&data, index + 4)), (&b"idx1"[..], 64));
+        assert_eq!((word(&data, index + 8 + 16 + 8), word(&data, index + 8 + 16 + 12)), (4 + 8 + FRAME_BYTES, 4));
+    }
+}
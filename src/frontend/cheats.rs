@@ -0,0 +1,69 @@
+use std::path::{Path, PathBuf};
+
+use gbnes_core::cheats::Cheats;
+
+// A game's cheats live in cheats/<CRC32 of the ROM>.cht under the config directory, so they follow
+// the game whatever its file is called; see Cheats::to_text for the format
+pub struct CheatFile {
+    path: PathBuf,
+    // Set when the file exists but couldn't be loaded, so saving doesn't overwrite what the user has there
+    unreadable: bool,
+    // Codes for this session only (`--cheat`), left out when saving
+    session_only: Vec<String>,
+}
+
+impl CheatFile {
+    pub fn for_rom(config_dir: &Path, rom_crc32: u32) -> Self {
+        CheatFile { path: config_dir.join("cheats").join(format!("{:08X}.cht", rom_crc32)), unreadable: false, session_only: Vec::new() }
+    }
+    pub fn path(&self) -> &Path { &self.path }
+    // No file yet just means no cheats
+    pub fn load(&mut self) -> Result<Cheats, String> {
+        let loaded: Result<Cheats, String> = match std::fs::read_to_string(&self.path) {
+            Ok(text) => Cheats::parse_text(&text).map_err(|err| format!("{}: {}", self.path.display(), err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Cheats::new()),
+            Err(err) => Err(format!("Could not read {}: {}", self.path.display(), err)),
+        };
+        self.unreadable = loaded.is_err();
+        loaded
+    }
+    // Keeps `code` out of the file, unless it was already there
+    pub fn add_session_only(&mut self, code: &str) { self.session_only.push(String::from(code)); }
+    // Removes the file once the game has no cheats left. A file that failed to load is left alone.
+    pub fn save(&self, cheats: &Cheats) -> Result<(), String> {
+        if self.unreadable { return Err(format!("{} could not be loaded, so it was left as it is", self.path.display())); }
+        let cheats: Cheats = Cheats { list: cheats.list.iter().filter(|cheat| !self.session_only.contains(&cheat.code)).cloned().collect() };
+        if cheats.is_empty() {
+            return match std::fs::remove_file(&self.path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(format!("Could not remove {}: {}", self.path.display(), err)),
+                _ => Ok(()),
+            };
+        }
+        if let Some(dir) = self.path.parent() { std::fs::create_dir_all(dir).map_err(|err| format!("Could not create {}: {}", dir.display(), err))?; }
+        std::fs::write(&self.path, cheats.to_text()).map_err(|err| format!("Could not write {}: {}", self.path.display(), err))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use gbnes_core::cheats::Hold;
+
+    #[test]
+    fn test_cheat_file_keeps_what_it_should() {
+        let dir: PathBuf = std::env::temp_dir().join(format!("gbnesmulator-test-cheats-{}", std::process::id()));
+        let mut file: CheatFile = CheatFile::for_rom(&dir, 0x1234ABCD);
+        let mut cheats: Cheats = file.load().unwrap();
+        cheats.add("0075:09", Hold::EveryFrame).unwrap();
+        cheats.add("SXIOPO", Hold::EveryFrame).unwrap();
+        file.add_session_only("SXIOPO");
+        file.save(&cheats).unwrap();
+        assert_eq!(std::fs::read_to_string(file.path()).unwrap(), "0075:09\n");
+        // A file the user broke stays as it is
+        std::fs::write(file.path(), "not a cheat\n").unwrap();
+        assert!(file.load().is_err());
+        assert!(file.save(&Cheats::new()).is_err());
+        assert_eq!(std::fs::read_to_string(file.path()).unwrap(), "not a cheat\n");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,261 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use gbnes_core::{Headless, Region, Rom, RomDb, disasm, hash, import, mapper, trace};
+use gbnes_core::coverage::Coverage;
+use gbnes_core::profiler::Profiler;
+use gbnes_core::savestate::StateInfo;
+
+use super::config::Config;
+use super::headless::parse_input_script;
+use super::slots::SaveSlots;
+
+// Value following a `--flag value` pair on the command line
+pub fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1)).cloned()
+}
+
+// Values of every `--flag value` pair, for flags that can be given more than once
+pub fn flag_values(args: &[String], flag: &str) -> Vec<String> {
+    args.windows(2).filter(|pair| pair[0] == flag).map(|pair| pair[1].clone()).collect()
+}
+
+// First positional argument after the optional subcommand, skipping flag values
+pub fn rom_path(args: &[String], skip: usize) -> Option<String> { positional(args, skip).into_iter().next() }
+
+pub fn positional(args: &[String], skip: usize) -> Vec<String> {
+    let mut found: Vec<String> = Vec::new();
+    let mut i: usize = 1 + skip;
+    while i < args.len() {
+        if VALUE_FLAGS.contains(&args[i].as_str()) { i += 2; continue; }
+        if !args[i].starts_with("--") { found.push(args[i].clone()); }
+        i += 1;
+    }
+    found
+}
+
+const VALUE_FLAGS: [&str; 22] = [
+    "--romdb", "--run-frames", "--frame-hash", "--input", "--movie", "--dump", "--slot", "--fast-forward", "--config", "--region",
+    "--context", "--cheat", "--csv", "--bench", "--record-audio", "--record-video", "--fds-bios", "--netplay-host",
+    "--netplay-connect", "--netplay-delay", "--remote", "--rpc",
+];
+
+// nes20db.xml next to the config file if there is one, then `--romdb` (a CSV or another nes20db.xml)
+pub fn load_rom_db(args: &[String]) -> RomDb {
+    let mut db: RomDb = RomDb::new();
+    let nes20db: PathBuf = Config::directory(args).join("nes20db.xml");
+    if nes20db.exists() {
+        if let Err(err) = db.load_file(&nes20db.display().to_string()) { eprintln!("{}", err); }
+    }
+    if let Some(path) = flag_value(args, "--romdb") {
+        if let Err(err) = db.load_file(&path) { eprintln!("{}", err); std::process::exit(1); }
+    }
+    db
+}
+
+// `--region`, else emulation.region: ntsc, pal or dendy force that timing, while auto (the default)
+// leaves it to the ROM header and database
+pub fn region_override(args: &[String], config: &Config) -> Option<Region> {
+    let value: String = flag_value(args, "--region").or_else(|| config.get("emulation.region").map(String::from))?;
+    if value.trim().eq_ignore_ascii_case("auto") { return None; }
+    Some(Region::parse(&value).unwrap_or_else(|err| { eprintln!("{}", err); std::process::exit(1); }))
+}
+
+// The Famicom Disk System BIOS that .fds images boot with: `--fds-bios`, else fds.bios, else
+// disksys.rom next to the config file
+pub fn fds_bios(args: &[String], config: &Config) -> PathBuf {
+    match flag_value(args, "--fds-bios").or_else(|| config.get("fds.bios").map(String::from)) {
+        Some(path) => PathBuf::from(path),
+        None => Config::directory(args).join("disksys.rom"),
+    }
+}
+
+pub fn load_rom(path: &str, db: &RomDb, region: Option<Region>, fds_bios: &Path) -> Rom {
+    try_load_rom(path, db, region, fds_bios).unwrap_or_else(|err| { eprintln!("{}", err); std::process::exit(1); })
+}
+// load_rom for a game picked while running, which mustn't exit on a bad file
+pub fn try_load_rom(path: &str, db: &RomDb, region: Option<Region>, fds_bios: &Path) -> Result<Rom, String> {
+    let loaded: Result<Rom, String> = if path.to_lowercase().ends_with(".fds") {
+        std::fs::read(path).map_err(|err| format!("Could not read {}: {}", path, err)).and_then(|disk| {
+            let bios: Vec<u8> = std::fs::read(fds_bios)
+                .map_err(|err| format!("Could not read the FDS BIOS {} (set it with --fds-bios or fds.bios): {}", fds_bios.display(), err))?;
+            Rom::from_fds(&disk, &bios)
+        })
+    } else {
+        Rom::from_file(path)
+    };
+    let mut rom: Rom = loaded.map_err(|err| format!("{}: {}", path, err))?;
+    if let Some(entry) = db.apply(&mut rom) { println!("Recognized: {} (CRC32 {:08X})", entry.title, entry.crc32); }
+    if let Some(region) = region { rom.region = region; }
+    println!("Region: {:?}", rom.region);
+    Ok(rom)
+}
+
+// A game picked in the system's file dialog, None if cancelled
+pub fn pick_rom() -> Option<String> {
+    rfd::FileDialog::new()
+        .set_title("Open a ROM")
+        .add_filter("NES ROMs", &["nes", "zip"])
+        .add_filter("Famicom Disk System images", &["fds"])
+        .pick_file()
+        .map(|path| path.display().to_string())
+}
+
+// Database title when the dump is known, else the file name without extension
+pub fn game_title(path: &str, rom: &Rom, db: &RomDb) -> String {
+    match db.lookup(rom) {
+        Some(entry) if !entry.title.is_empty() => entry.title.clone(),
+        _ => std::path::Path::new(path).file_stem().map_or(String::from(path), |stem| stem.to_string_lossy().into_owned()),
+    }
+}
+
+// --resume / --no-resume answer up front, otherwise ask on the terminal (default yes)
+pub fn ask_resume(args: &[String], last_session: &StateInfo) -> bool {
+    if args.iter().any(|arg| arg == "--resume") { return true; }
+    if args.iter().any(|arg| arg == "--no-resume") { return false; }
+    print!("Resume last session (frame {})? [Y/n] ", last_session.frame);
+    std::io::stdout().flush().ok();
+    let mut answer: String = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() { return false; }
+    !answer.trim().eq_ignore_ascii_case("n")
+}
+
+pub fn print_rom_info(path: &str, db: &RomDb) {
+    let header: Rom = match Rom::from_file(path) {
+        Ok(rom) => rom,
+        Err(err) => { eprintln!("{}: {}", path, err); std::process::exit(1); }
+    };
+    let mut rom: Rom = header.clone();
+    let entry = db.apply(&mut rom);
+    let mut data: Vec<u8> = rom.prg_rom.clone();
+    data.extend(&rom.chr_rom);
+
+    println!("File:      {}", path);
+    if let Some(entry) = entry { println!("Title:     {}", entry.title); }
+    if rom.mapper != header.mapper { println!("Header:    mapper {} (corrected by ROM database)", header.mapper); }
+    println!("Mapper:    {}", rom.mapper);
+    println!("PRG ROM:   {} KB ({} x 16KB)", rom.prg_rom.len() / 1024, rom.prg_rom.len() / 0x4000);
+    if rom.chr_rom.is_empty() { println!("CHR ROM:   none (8 KB CHR RAM)"); }
+    else { println!("CHR ROM:   {} KB ({} x 8KB)", rom.chr_rom.len() / 1024, rom.chr_rom.len() / 0x2000); }
+    println!("Mirroring: {:?}", rom.screen_mirroring);
+    println!("Battery:   {}", if rom.battery { "yes" } else { "no" });
+    println!("Trainer:   {}", if rom.trainer.is_some() { "yes" } else { "no" });
+    println!("Region:    {:?}", rom.region);
+    println!("CRC32:     {:08X}", hash::crc32(&data));
+    println!("SHA-1:     {}", hash::to_hex(&hash::sha1(&data)));
+    if let Err(err) = mapper::from_rom(rom) { println!("Warning:   {}", err); }
+}
+
+// disasm <ROM> [START[-END]]: lists the code the CPU would see there at power-on (default $8000-$FFFF),
+// with the bank layout the mapper starts in
+pub fn disassemble(args: &[String], db: &RomDb) {
+    let usage: &str = "Usage: gbnesmulator disasm <ROM file> [START[-END]] (hex addresses)";
+    let positional: Vec<String> = positional(args, 1);
+    let Some(rom_file) = positional.first() else { eprintln!("{}", usage); std::process::exit(1); };
+    let range: &str = positional.get(1).map_or("8000-FFFF", String::as_str);
+    let hex = |text: &str| u16::from_str_radix(text.trim_start_matches('$'), 16).ok();
+    let (start, end): (u16, u16) = match range.split_once('-') {
+        Some((start, end)) => hex(start).zip(hex(end)),
+        None => hex(range).map(|start| (start, 0xFFFF)),
+    }.filter(|(start, end)| start <= end).unwrap_or_else(|| { eprintln!("{}", usage); std::process::exit(1); });
+    let nes: Headless = Headless::new(load_rom(rom_file, db, None, &fds_bios(args, &Config::load(args)))).unwrap_or_else(|err| { eprintln!("{}: {}", rom_file, err); std::process::exit(1); });
+    let read = |addr: u16| nes.cpu.bus.peek(addr);
+    println!("{}", disasm::listing(&disasm::decode_range(&read, start, end), &disasm::vector_labels(&read)));
+}
+
+// trace-compare <ROM> <reference log> [--context N]: runs the ROM from the address on the log's first
+// line (nestest.log starts at $C000, its automated mode) and reports the first instruction whose
+// trace differs, after the N (default 10) traced lines before it
+pub fn trace_compare(args: &[String], db: &RomDb) {
+    let usage: &str = "Usage: gbnesmulator trace-compare <ROM file> <reference log, e.g. nestest.log> [--context N]";
+    let files: Vec<String> = positional(args, 1);
+    let (Some(rom_file), Some(log_file)) = (files.first(), files.get(1)) else { eprintln!("{}", usage); std::process::exit(1); };
+    let context: usize = flag_value(args, "--context").map_or(Some(10), |n| n.parse().ok())
+        .unwrap_or_else(|| { eprintln!("--context expects a line count"); std::process::exit(1); });
+    let reference: String = std::fs::read_to_string(log_file).unwrap_or_else(|err| { eprintln!("Could not read {}: {}", log_file, err); std::process::exit(1); });
+    let Some(start) = reference.lines().next().and_then(|line| line.get(..4)).and_then(|addr| u16::from_str_radix(addr, 16).ok()) else {
+        eprintln!("{}: expected lines starting with a hex address", log_file);
+        std::process::exit(1);
+    };
+    let mut nes: Headless = Headless::new(load_rom(rom_file, db, None, &fds_bios(args, &Config::load(args)))).unwrap_or_else(|err| { eprintln!("{}: {}", rom_file, err); std::process::exit(1); });
+    nes.cpu.program_counter = start;
+    match trace::compare(&mut nes.cpu, &reference, context) {
+        Ok(lines) => println!("All {} lines match", lines),
+        Err(divergence) => {
+            for line in &divergence.before { println!("  {}", line); }
+            println!("- {}", divergence.expected);
+            println!("+ {}", divergence.actual);
+            println!("Diverged at line {} of {}", divergence.line, log_file);
+            std::process::exit(1);
+        }
+    }
+}
+
+// profile <ROM> [--run-frames N] [--input script.txt]: runs N frames (default 600, ten seconds) headless,
+// with joypad 1 following the input script if given, and prints the cycles spent per routine and bank
+pub fn profile(args: &[String], db: &RomDb) {
+    let (rom, frames, inputs): (Rom, usize, Vec<u8>) = instrumented_run(args, db, "profile");
+    let mut nes: Headless = Headless::new(rom).unwrap_or_else(|err| { eprintln!("{}", err); std::process::exit(1); });
+    let mut profiler: Profiler = Profiler::new(&nes.cpu);
+    for frame in 0..frames {
+        nes.set_buttons(inputs.get(frame).copied().unwrap_or(0));
+        nes.cpu.bus.apu().buffer.clear();
+        profiler.run_frame(&mut nes.cpu);
+    }
+    println!("{}", profiler.report());
+}
+
+// coverage <ROM> [--run-frames N] [--input script.txt] [--csv PREFIX]: like profile, but counts runs of
+// each opcode and PRG ROM address; --csv writes them to PREFIX-opcodes.csv and PREFIX-prg.csv
+pub fn coverage(args: &[String], db: &RomDb) {
+    let (rom, frames, inputs): (Rom, usize, Vec<u8>) = instrumented_run(args, db, "coverage");
+    let mut coverage: Coverage = Coverage::new(rom.prg_rom.len());
+    let mut nes: Headless = Headless::new(rom).unwrap_or_else(|err| { eprintln!("{}", err); std::process::exit(1); });
+    for frame in 0..frames {
+        nes.set_buttons(inputs.get(frame).copied().unwrap_or(0));
+        nes.cpu.bus.apu().buffer.clear();
+        coverage.run_frame(&mut nes.cpu);
+    }
+    println!("{}", coverage.summary());
+    let Some(prefix) = flag_value(args, "--csv") else { return; };
+    for (path, csv) in [(format!("{}-opcodes.csv", prefix), coverage.opcodes_csv()), (format!("{}-prg.csv", prefix), coverage.prg_csv())] {
+        if let Err(err) = std::fs::write(&path, csv) { eprintln!("Could not write {}: {}", path, err); std::process::exit(1); }
+        println!("Wrote {}", path);
+    }
+}
+
+// The ROM, frame count (default 600) and joypad 1 input of the profile and coverage subcommands
+fn instrumented_run(args: &[String], db: &RomDb, command: &str) -> (Rom, usize, Vec<u8>) {
+    let Some(rom_file) = rom_path(args, 1) else {
+        eprintln!("Usage: gbnesmulator {} <ROM file> [--run-frames N] [--input script.txt]{}", command, if command == "coverage" { " [--csv PREFIX]" } else { "" });
+        std::process::exit(1);
+    };
+    let frames: usize = flag_value(args, "--run-frames").map_or(Some(600), |n| n.parse().ok())
+        .unwrap_or_else(|| { eprintln!("--run-frames expects a frame count"); std::process::exit(1); });
+    let inputs: Vec<u8> = match flag_value(args, "--input") {
+        Some(path) => std::fs::read_to_string(&path).map_err(|err| format!("Could not read {}: {}", path, err)).and_then(|text| parse_input_script(&text))
+            .unwrap_or_else(|err| { eprintln!("{}", err); std::process::exit(1); }),
+        None => Vec::new(),
+    };
+    (load_rom(&rom_file, db, None, &fds_bios(args, &Config::load(args))), frames, inputs)
+}
+
+// import-state <ROM> <file.fcs|file.mss> [--slot N]: converts another emulator's savestate into a slot
+pub fn import_state(args: &[String], db: &RomDb) {
+    let usage: &str = "Usage: gbnesmulator import-state <ROM file> <FCEUX .fcs or Mesen .mss file> [--slot N]";
+    let files: Vec<String> = positional(args, 1);
+    let (Some(rom_file), Some(state_file)) = (files.first(), files.get(1)) else { eprintln!("{}", usage); std::process::exit(1); };
+    let slot: u8 = flag_value(args, "--slot").map_or(Some(1), |n| n.parse().ok()).filter(|n| (1..=10).contains(n))
+        .unwrap_or_else(|| { eprintln!("--slot expects a slot number from 1 to 10"); std::process::exit(1); });
+    let result = std::fs::read(state_file).map_err(|err| format!("Could not read {}: {}", state_file, err))
+        .and_then(|data| import::import(&data))
+        .and_then(|state| {
+            let mut nes: Headless = Headless::new(load_rom(rom_file, db, None, &fds_bios(args, &Config::load(args))))?;
+            state.apply(&mut nes.cpu);
+            SaveSlots::for_rom(rom_file).save(&nes.cpu, slot)
+        });
+    match result {
+        Ok(_) => println!("Imported {} into slot {} (press F{} in game to load it)", state_file, slot, slot),
+        Err(err) => { eprintln!("{}: {}", state_file, err); std::process::exit(1); }
+    }
+}
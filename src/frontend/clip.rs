@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+use gbnes_core::Frame;
+use gbnes_core::render::png;
+
+use super::wav::recording_path;
+
+// PLTE's limit; NES games rarely show more than a few dozen colours
+const MAX_COLOURS: usize = 256;
+
+// The last few seconds of gameplay, kept all along so the save clip hotkey can write them out as an
+// animated PNG at native resolution. Frames are kept as indices into the colours seen so far (the
+// nearest one once there are 256), a quarter of their RGB size.
+pub struct Clip {
+    frames: VecDeque<Vec<u8>>,
+    capacity: usize,
+    frame_rate: f64,
+    colours: Vec<[u8; 3]>,
+}
+
+impl Clip {
+    pub fn new(seconds: u32, frame_rate: f64) -> Self {
+        let capacity: usize = (seconds as f64 * frame_rate).round() as usize;
+        Clip { frames: VecDeque::with_capacity(capacity), capacity, frame_rate, colours: Vec::new() }
+    }
+    // Where the save clip hotkey saves: recordings/<rom name>-<unix time>.png
+    pub fn default_path(rom_path: &str) -> PathBuf { recording_path(rom_path, "png") }
+    pub fn push(&mut self, frame: &Frame) {
+        let mut indices: Vec<u8> = match self.frames.len() {
+            length if length >= self.capacity => self.frames.pop_front().unwrap_or_default(),
+            _ => Vec::with_capacity(Frame::WIDTH * Frame::HIGHT),
+        };
+        indices.clear();
+        // Runs of one colour are common, so only look up where it changes
+        let mut last: Option<([u8; 3], u8)> = None;
+        for pixel in frame.data.chunks_exact(3) {
+            let rgb: [u8; 3] = [pixel[0], pixel[1], pixel[2]];
+            let index: u8 = match last {
+                Some((colour, index)) if colour == rgb => index,
+                _ => self.index(rgb),
+            };
+            last = Some((rgb, index));
+            indices.push(index);
+        }
+        self.frames.push_back(indices);
+    }
+    // Seconds kept so far
+    pub fn seconds(&self) -> f64 { self.frames.len() as f64 / self.frame_rate }
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if self.frames.is_empty() { return Err(String::from("Nothing to save yet")); }
+        let frames: Vec<&[u8]> = self.frames.iter().map(Vec::as_slice).collect();
+        let apng: Vec<u8> = png::encode_animation(Frame::WIDTH, Frame::HIGHT, &self.colours, &frames, self.frame_rate);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|err| format!("Could not create {}: {}", dir.display(), err))?;
+        }
+        std::fs::write(path, apng).map_err(|err| format!("Could not write {}: {}", path.display(), err))
+    }
+    fn index(&mut self, rgb: [u8; 3]) -> u8 {
+        if let Some(index) = self.colours.iter().position(|colour| *colour == rgb) { return index as u8; }
+        if self.colours.len() < MAX_COLOURS {
+            self.colours.push(rgb);
+            return (self.colours.len() - 1) as u8;
+        }
+        let distance = |colour: &[u8; 3]| colour.iter().zip(rgb).map(|(a, b)| (*a as i32 - b as i32).pow(2)).sum::<i32>();
+        self.colours.iter().enumerate().min_by_key(|(_, colour)| distance(colour)).map_or(0, |(index, _)| index as u8)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keeps_the_last_seconds() {
+        let mut clip: Clip = Clip::new(1, 4.0);
+        for shade in 0..6 {
+            let mut frame: Frame = Frame::new();
+            frame.set_pixel(0, 0, (shade, shade, shade));
+            clip.push(&frame);
+        }
+        assert!((clip.seconds() - 1.0).abs() < 1e-9);
+        // The first two frames are gone, and black plus six shades make six colours (shade 0 is black)
+        assert_eq!(clip.frames.front().unwrap()[0], 2);
+        assert_eq!(clip.colours.len(), 6);
+        let path: PathBuf = std::env::temp_dir().join(format!("gbnes-clip-{}.png", std::process::id()));
+        clip.save(&path).unwrap();
+        let image = png::decode(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!((image.width, image.height, image.pixel(0, 0)), (Frame::WIDTH, Frame::HIGHT, [2, 2, 2]));
+    }
+}
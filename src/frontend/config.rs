@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use gbnes_core::{PpuAccuracy, RamInit};
+use gbnes_core::apu::mixer::{Channel, Mixer};
+use gbnes_core::render::palette::Palette;
+
+use super::audio::{Backend, DEFAULT_LATENCY, DEFAULT_VOLUME};
+
+use super::cli::flag_value;
+
+const DEFAULT_PATH: &str = "gbnesmulator.ini";
+
+// Frontend settings from gbnesmulator.ini (or `--config PATH`): `key = value` lines, grouped under
+// optional `[section]` headers and looked up as `section.key`; `#` and `;` start comments.
+//
+//   [emulation]
+//   ram_init = random    # or a hex byte pattern: 00, ff, 0000ffff
+//   ppu = scanline       # or dot: slower, cycle-accurate rendering for games and test ROMs that need it
+//   oam_quirks = false   # OAMADDR corruption of the 2C02, see NesPPU::oam_quirks
+//   dmc_dma = false      # CPU stalls on DMC sample fetches and the controller bits they drop, see Bus::dmc_dma
+//   extra_scanlines = 0  # overclocking: CPU time added to each vblank, in lines, for games that slow down (see Bus::extra_scanlines)
+//   run_ahead = 0        # frames emulated ahead of the one shown, hiding as much of the game's input lag (up to 4; 1 or 2 suit most games)
+//   pause_unfocused = false  # pause (and so go quiet) while the window is in the background, resuming on focus
+//   region = auto        # or ntsc, pal, dendy to override the ROM header/database; --region overrides this
+//   [video]
+//   show_fps = false     # FPS / speed overlay, also toggled at runtime
+//   frame_skip = 0       # frames in a row left undrawn to keep up when emulation falls behind real time (sound stays smooth; off while recording video)
+//   vsync = true         # also wait for the monitor's refresh to present, against tearing; turn off if it stutters off 60Hz
+//   upscaler = none      # none, scale2x, scale3x, xbrz2x or xbrz3x
+//   crt = off            # off, scanlines or crt; cycled at runtime
+//   palettes = a.pal, b.pal   # 64 or 512-colour .pal files, cycled at runtime with the builtin one
+//   screenshot = raw     # what F12 saves: raw (256x240), filtered (upscaled / CRT, as on screen) or both
+//   clip_seconds = 10    # gameplay kept for Ctrl+F11 to save as an animated PNG; unset, 10s kept from the first Ctrl+F11; 0 is off
+//   [audio]
+//   backend = rodio      # or cpal, in builds with the cpal feature
+//   latency = 50         # ms of sound queued at most: lower responds faster, higher crackles less
+//   buffer_size = 512    # device buffer in sample frames (cpal only), the device's default when unset
+//   pulse1_pan = -0.3    # -1.0 (left) to 1.0 (right) for pulse1, pulse2, triangle, noise, dmc and expansion; 0 when unset
+//   muted = noise, dmc   # channels to start muted; toggled at runtime with Ctrl+1..6
+//   triangle_volume = 1.5  # 0.0 to 2.0 per channel, like _pan; Ctrl+Right picks one at runtime, Ctrl+Up/Down change it
+//   volume = 0.2         # 0.0 to 2.0, the whole mix (1.0 is full range); +/- change it at runtime, 0 mutes
+//   [fds]
+//   bios = disksys.rom   # the Famicom Disk System BIOS .fds images boot with; --fds-bios overrides this
+//   [remote]
+//   origins = http://localhost:8000   # web pages allowed to reach --remote and --rpc from a browser; the others are refused
+//   [input]
+//   block_opposing_directions = true
+//   [keys]
+//   a = Space, K         # see input::Bindings for the action names
+#[derive(Default)]
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut values: HashMap<String, String> = HashMap::new();
+        let mut section: String = String::new();
+        for (number, line) in text.lines().enumerate() {
+            let line: &str = line.split(['#', ';']).next().unwrap_or("").trim();
+            if line.is_empty() { continue; }
+            if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+                section = name.trim().to_lowercase();
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else { return Err(format!("line {}: expected `key = value`", number + 1)); };
+            let key: String = key.trim().to_lowercase();
+            let key: String = if section.is_empty() { key } else { format!("{}.{}", section, key) };
+            values.insert(key, value.trim().to_string());
+        }
+        Ok(Config { values })
+    }
+    // The file named by --config, which must exist, else gbnesmulator.ini if present
+    pub fn load(args: &[String]) -> Config {
+        let (path, required) = match flag_value(args, "--config") {
+            Some(path) => (path, true),
+            None => (String::from(DEFAULT_PATH), false),
+        };
+        let text: String = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) if !required => return Config::default(),
+            Err(err) => { eprintln!("Could not read {}: {}", path, err); std::process::exit(1); }
+        };
+        Config::parse(&text).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            std::process::exit(1);
+        })
+    }
+    // Where the config file lives, and so where per-game files like cheats go: the directory of
+    // --config's file, else the working directory
+    pub fn directory(args: &[String]) -> PathBuf {
+        let path: String = flag_value(args, "--config").unwrap_or(String::from(DEFAULT_PATH));
+        Path::new(&path).parent().map(Path::to_path_buf).unwrap_or_default()
+    }
+    pub fn get(&self, key: &str) -> Option<&str> { self.values.get(key).map(String::as_str) }
+    // `key = value` pairs of one [section], keys without the section prefix
+    pub fn section(&self, name: &str) -> Vec<(&str, &str)> {
+        let prefix: String = format!("{}.", name);
+        self.values.iter().filter_map(|(key, value)| Some((key.strip_prefix(&prefix)?, value.as_str()))).collect()
+    }
+    // true/yes/on/1 or false/no/off/0, `default` when unset
+    pub fn flag(&self, key: &str, default: bool) -> bool {
+        match self.get(key).map(str::to_lowercase).as_deref() {
+            None => default,
+            Some("true" | "yes" | "on" | "1") => true,
+            Some("false" | "no" | "off" | "0") => false,
+            Some(value) => { eprintln!("{}: expected true or false, got {:?}", key, value); std::process::exit(1); }
+        }
+    }
+    // A whole number, None when unset
+    pub fn number(&self, key: &str) -> Option<u32> {
+        let value: &str = self.get(key)?;
+        match value.parse::<u32>() {
+            Ok(number) => Some(number),
+            Err(_) => { eprintln!("{}: expected a whole number, got {:?}", key, value); std::process::exit(1); }
+        }
+    }
+    // video.palettes in order, then the builtin palette, named for the OSD
+    pub fn palettes(&self) -> Vec<(String, Palette)> {
+        let mut palettes: Vec<(String, Palette)> = Vec::new();
+        for path in self.get("video.palettes").unwrap_or("").split(',').map(str::trim).filter(|path| !path.is_empty()) {
+            let palette: Result<Palette, String> = std::fs::read(path).map_err(|err| err.to_string()).and_then(|data| Palette::from_pal(&data));
+            match palette {
+                Ok(palette) => palettes.push((String::from(path), palette)),
+                Err(err) => { eprintln!("Could not load palette {}: {}", path, err); std::process::exit(1); }
+            }
+        }
+        palettes.push((String::from("builtin"), Palette::default()));
+        palettes
+    }
+    // remote.origins, none when unset: only clients outside a browser
+    pub fn remote_origins(&self) -> Vec<String> {
+        self.get("remote.origins").unwrap_or("").split(',').map(str::trim).filter(|origin| !origin.is_empty()).map(String::from).collect()
+    }
+    // emulation.ppu, scanline rendering when unset
+    pub fn ppu_accuracy(&self) -> PpuAccuracy {
+        let Some(value) = self.get("emulation.ppu") else { return PpuAccuracy::Scanline; };
+        PpuAccuracy::parse(value).unwrap_or_else(|err| {
+            eprintln!("emulation.ppu: {}", err);
+            std::process::exit(1);
+        })
+    }
+    // audio.backend, rodio when unset
+    pub fn audio_backend(&self) -> Backend {
+        let Some(value) = self.get("audio.backend") else { return Backend::Rodio; };
+        Backend::parse(value).unwrap_or_else(|err| {
+            eprintln!("audio.backend: {}", err);
+            std::process::exit(1);
+        })
+    }
+    // audio.latency in milliseconds, DEFAULT_LATENCY when unset
+    pub fn audio_latency(&self) -> Duration { self.number("audio.latency").map_or(DEFAULT_LATENCY, |ms| Duration::from_millis(ms.max(1) as u64)) }
+    // A number like 0.5, None when unset
+    pub fn decimal(&self, key: &str) -> Option<f32> {
+        let value: &str = self.get(key)?;
+        match value.parse::<f32>() {
+            Ok(number) => Some(number),
+            Err(_) => { eprintln!("{}: expected a number, got {:?}", key, value); std::process::exit(1); }
+        }
+    }
+    // audio.CHANNEL_pan and audio.CHANNEL_volume for each mixer Channel (unset: centred, at the
+    // console's levels), audio.volume for all of them together (DEFAULT_VOLUME) and the audio.muted channels
+    pub fn mixer(&self) -> Mixer {
+        let mut mixer: Mixer = Mixer::default();
+        for name in self.get("audio.muted").unwrap_or("").split(',').filter(|name| !name.trim().is_empty()) {
+            match Channel::parse(name) {
+                Ok(channel) => mixer.muted[channel as usize] = true,
+                Err(err) => { eprintln!("audio.muted: {}", err); std::process::exit(1); }
+            }
+        }
+        for channel in Channel::ALL {
+            if let Some(pan) = self.decimal(&format!("audio.{}_pan", channel.name())) { mixer.set_pan(channel, pan); }
+            if let Some(gain) = self.decimal(&format!("audio.{}_volume", channel.name())) { mixer.set_gain(channel, gain); }
+        }
+        mixer.set_master(self.decimal("audio.volume").unwrap_or(DEFAULT_VOLUME));
+        mixer
+    }
+    // emulation.ram_init, all zeroes when unset; `random` picks a new seed on every call
+    pub fn ram_init(&self) -> RamInit {
+        let Some(value) = self.get("emulation.ram_init") else { return RamInit::default(); };
+        RamInit::parse(value, rand::random()).unwrap_or_else(|err| {
+            eprintln!("emulation.ram_init: {}", err);
+            std::process::exit(1);
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = Config::parse("top = 1\n# comment\n[Emulation]\nRAM_Init = 0000ffff ; stripes\n").unwrap();
+        assert_eq!(config.get("top"), Some("1"));
+        assert_eq!(config.get("emulation.ram_init"), Some("0000ffff"));
+        assert_eq!(config.ram_init(), RamInit::Pattern(vec![0x00, 0x00, 0xFF, 0xFF]));
+        assert!(!Config::parse("[input]\nblock_opposing_directions = No").unwrap().flag("input.block_opposing_directions", true));
+        assert!(config.flag("input.block_opposing_directions", true));
+        assert_eq!(Config::parse("[audio]\nlatency = 80").unwrap().audio_latency(), Duration::from_millis(80));
+        assert_eq!(config.audio_latency(), DEFAULT_LATENCY);
+        let mixer: Mixer = Config::parse("[audio]\npulse2_pan = 0.25\nmuted = Noise, dmc").unwrap().mixer();
+        assert_eq!((mixer.pan, mixer.muted), ([0.0, 0.25, 0.0, 0.0, 0.0, 0.0], [false, false, false, true, true, false]));
+        let mixer: Mixer = Config::parse("[audio]\ndmc_volume = 0.5\nvolume = 3").unwrap().mixer();
+        assert_eq!((mixer.gain[4], mixer.master), (0.5, 2.0));
+        assert!(Config::parse("[input]\nnot a pair\n").is_err());
+    }
+}
@@ -0,0 +1,26 @@
+use std::io::{BufRead, Write};
+
+use gbnes_core::{Debugger, Headless};
+
+// Reads debugger commands from the terminal until one resumes emulation. The window stops updating
+// meanwhile. Returns false when the user quits with `q`; end of input just resumes.
+pub fn prompt(debugger: &mut Debugger, nes: &mut Headless) -> bool {
+    println!("Stopped at ${:04X}, h for help", nes.cpu.program_counter);
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    while debugger.paused {
+        print!("(debug) ");
+        std::io::stdout().flush().ok();
+        let Some(Ok(line)) = lines.next() else { debugger.resume(); break; };
+        if line.trim() == "q" { return false; }
+        match debugger.command(&mut nes.cpu, &line) {
+            Ok(output) if !output.is_empty() => println!("{}", output),
+            Ok(_) => {}
+            Err(err) => eprintln!("{}", err),
+        }
+        for warning in debugger.calls.warnings.drain(..) { eprintln!("{}", warning); }
+    }
+    // Show the effect of any stepping or memory writes once emulation goes on
+    nes.redraw();
+    true
+}
@@ -0,0 +1,34 @@
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+// Presented (window) and emulated (NES) frame rates, averaged over one-second windows
+pub struct FpsCounter {
+    window_start: Instant,
+    presented: u32,
+    emulated: u32,
+    // Frames per second the console produces (Region::frame_rate), for expressing emulation speed as % of real time
+    full_speed: f64,
+    pub fps: f64,
+    // 100.0 is full speed; fast-forward and slow motion move it up and down
+    pub speed: f64,
+}
+
+impl FpsCounter {
+    pub fn start(full_speed: f64) -> Self { FpsCounter { window_start: Instant::now(), presented: 0, emulated: 0, full_speed, fps: 0.0, speed: 0.0 } }
+    // Counts one presented picture and the NES frames emulated for it; true when the averages were updated
+    pub fn present(&mut self, emulated: u32) -> bool {
+        self.presented += 1;
+        self.emulated += emulated;
+        let elapsed: Duration = self.window_start.elapsed();
+        if elapsed < WINDOW { return false; }
+        let seconds: f64 = elapsed.as_secs_f64();
+        self.fps = self.presented as f64 / seconds;
+        self.speed = self.emulated as f64 / seconds / self.full_speed * 100.0;
+        self.window_start = Instant::now();
+        self.presented = 0;
+        self.emulated = 0;
+        true
+    }
+    pub fn label(&self) -> String { format!("{:.0} FPS ({:.0}%)", self.fps, self.speed) }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use sdl2::GameControllerSubsystem;
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::Event;
+use gbnes_core::{Joypad, JoypadButton};
+
+// Stick travel (out of 32767) before it counts as a d-pad press
+const AXIS_DEADZONE: i16 = 16_000;
+
+// Every connected SDL game controller drives joypad 1. SDL reports the controllers present at
+// startup as added devices too, so opening happens only on ControllerDeviceAdded.
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+    // Keyed by joystick instance id, which is what button/axis/removed events carry
+    open: HashMap<u32, GameController>,
+    // JoypadButton bits currently held by a stick, so resting-stick noise doesn't release the d-pad
+    stick: u8,
+}
+
+impl Gamepads {
+    pub fn new(subsystem: GameControllerSubsystem) -> Self { Gamepads { subsystem, open: HashMap::new(), stick: 0 } }
+    // Applies a controller event to the joypad; false for events that aren't about controllers
+    pub fn handle(&mut self, event: &Event, joypad: &mut Joypad) -> bool {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => match self.subsystem.open(which) {
+                Ok(controller) => {
+                    println!("Controller connected: {}", controller.name());
+                    self.open.insert(controller.instance_id(), controller);
+                }
+                Err(err) => eprintln!("Could not open controller {}: {}", which, err),
+            },
+            Event::ControllerDeviceRemoved { which, .. } => {
+                if let Some(controller) = self.open.remove(&which) { println!("Controller disconnected: {}", controller.name()); }
+            }
+            Event::ControllerButtonDown { button, .. } => {
+                if let Some(button) = joypad_button(button) { joypad.set_button_pressed_status(button, true); }
+            }
+            Event::ControllerButtonUp { button, .. } => {
+                if let Some(button) = joypad_button(button) { joypad.set_button_pressed_status(button, false); }
+            }
+            Event::ControllerAxisMotion { axis, value, .. } => {
+                for (button, pressed) in axis_buttons(axis, value) {
+                    if (self.stick & button as u8 != 0) == pressed { continue; }
+                    self.stick ^= button as u8;
+                    joypad.set_button_pressed_status(button, pressed);
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+// By position rather than label: the bottom face button is NES B and the right one NES A,
+// like the two buttons on the pad
+pub fn joypad_button(button: Button) -> Option<JoypadButton> {
+    match button {
+        Button::A | Button::X => Some(JoypadButton::ButtonB),
+        Button::B | Button::Y => Some(JoypadButton::ButtonA),
+        Button::Back => Some(JoypadButton::Select),
+        Button::Start => Some(JoypadButton::Start),
+        Button::DPadUp => Some(JoypadButton::Up),
+        Button::DPadDown => Some(JoypadButton::Down),
+        Button::DPadLeft => Some(JoypadButton::Left),
+        Button::DPadRight => Some(JoypadButton::Right),
+        _ => None,
+    }
+}
+
+// The left stick as a d-pad: both directions of the moved axis, pressed or released
+pub fn axis_buttons(axis: Axis, value: i16) -> Vec<(JoypadButton, bool)> {
+    let (negative, positive) = match axis {
+        Axis::LeftX => (JoypadButton::Left, JoypadButton::Right),
+        // SDL's Y axis grows downwards
+        Axis::LeftY => (JoypadButton::Up, JoypadButton::Down),
+        _ => return Vec::new(),
+    };
+    vec![(negative, value < -AXIS_DEADZONE), (positive, value > AXIS_DEADZONE)]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_controller_mapping() {
+        assert_eq!(joypad_button(Button::A), Some(JoypadButton::ButtonB));
+        assert_eq!(joypad_button(Button::DPadLeft), Some(JoypadButton::Left));
+        assert_eq!(joypad_button(Button::Guide), None);
+        assert_eq!(axis_buttons(Axis::LeftY, -20_000), vec![(JoypadButton::Up, true), (JoypadButton::Down, false)]);
+        assert_eq!(axis_buttons(Axis::LeftX, 100), vec![(JoypadButton::Left, false), (JoypadButton::Right, false)]);
+        assert!(axis_buttons(Axis::TriggerLeft, 30_000).is_empty());
+    }
+}
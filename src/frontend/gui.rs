@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Instant;
+
+use egui::{Color32, Context, Key, Modifiers, Pos2, Rect, TextureId};
+use egui::epaint::{ClippedPrimitive, ImageData, ImageDelta, Primitive, Vertex};
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::mouse::MouseButton;
+
+use gbnes_core::{Headless, disasm};
+use gbnes_core::apu::mixer::{Channel, Mixer};
+use gbnes_core::render::frame::Image;
+use gbnes_core::savestate::StateInfo;
+
+use super::cli::pick_rom;
+use super::input::{Binding, Bindings, Command};
+use super::recent::RecentRoms;
+use super::slots::{SaveSlots, SLOTS};
+
+// Instructions listed from PC in the debugger panel
+const DISASSEMBLY_LINES: usize = 12;
+
+// What the menu asks of the main loop this pass
+#[derive(Default)]
+pub struct Actions {
+    pub commands: Vec<Command>,
+    // A ROM typed into File > Open
+    pub open: Option<String>,
+}
+
+// egui textures as plain premultiplied pixels, for the software painter
+struct Texture {
+    size: [usize; 2],
+    pixels: Vec<Color32>,
+}
+
+#[derive(Default)]
+struct Panels { slots: bool, input: bool, mixer: bool, debugger: bool }
+
+// The in-window menu (`menu` hotkey, ` by default): a File / Settings menu bar and
+// panels for the savestate slots, key bindings, audio mixer and CPU. Emulation pauses while it's
+// open (except in netplay) and it takes the keyboard and mouse, except for the hotkey closing it. egui lays it out; its
+// triangles are filled in software over the picture stretched to the window, so the SDL canvas
+// presents it like any other frame.
+pub struct Gui {
+    pub open: bool,
+    ctx: Context,
+    start: Instant,
+    events: Vec<egui::Event>,
+    modifiers: Modifiers,
+    textures: HashMap<TextureId, Texture>,
+    // Freed by egui in the last pass, once that pass has been painted
+    freed: Vec<TextureId>,
+    primitives: Vec<ClippedPrimitive>,
+    panels: Panels,
+    path: String,
+    // Slot metadata, read again when the savestates panel opens or a slot is saved
+    slot_info: Option<Vec<(u8, StateInfo)>>,
+    // The action waiting for a key in the input panel, and the ones changed this session
+    rebinding: Option<String>,
+    rebound: Vec<String>,
+}
+
+impl Default for Gui {
+    fn default() -> Self {
+        Gui {
+            open: false, ctx: Context::default(), start: Instant::now(), events: Vec::new(), modifiers: Modifiers::default(),
+            textures: HashMap::new(), freed: Vec::new(), primitives: Vec::new(), panels: Panels::default(), path: String::new(),
+            slot_info: None, rebinding: None, rebound: Vec::new(),
+        }
+    }
+}
+
+impl Gui {
+    pub fn new() -> Self { Gui::default() }
+    // Takes a window event while the menu is open, returning whether it was the menu's. Mouse
+    // positions come in the canvas' logical units, `scale` window pixels each.
+    pub fn handle(&mut self, event: &Event, bindings: &mut Bindings, scale: f32) -> bool {
+        if !self.open { return false; }
+        let point = |x: i32, y: i32| Pos2::new(x as f32 * scale, y as f32 * scale);
+        match event {
+            Event::KeyDown { keycode: Some(keycode), keymod, .. } if self.rebinding.is_some() => {
+                if is_modifier(*keycode) { return true; }
+                let name: String = self.rebinding.take().unwrap_or_default();
+                if *keycode != Keycode::Escape {
+                    bindings.rebind(&name, vec![binding(*keycode, *keymod)]);
+                    if !self.rebound.contains(&name) { self.rebound.push(name); }
+                }
+            }
+            // The hotkey that opened the menu closes it again
+            Event::KeyDown { keycode: Some(keycode), keymod, .. } if bindings.command(*keycode, *keymod) == Some(Command::ToggleMenu) => return false,
+            Event::KeyDown { keycode: Some(Keycode::Escape), .. } => self.open = false,
+            Event::KeyDown { keycode: Some(keycode), keymod, repeat, .. } => {
+                self.modifiers = modifiers(*keymod);
+                if let Some(key) = key(*keycode) {
+                    self.events.push(egui::Event::Key { key, physical_key: None, pressed: true, repeat: *repeat, modifiers: self.modifiers });
+                }
+            }
+            Event::KeyUp { keycode: Some(keycode), keymod, .. } => {
+                self.modifiers = modifiers(*keymod);
+                if let Some(key) = key(*keycode) {
+                    self.events.push(egui::Event::Key { key, physical_key: None, pressed: false, repeat: false, modifiers: self.modifiers });
+                }
+            }
+            Event::TextInput { text, .. } => self.events.push(egui::Event::Text(text.clone())),
+            Event::MouseMotion { x, y, .. } => self.events.push(egui::Event::PointerMoved(point(*x, *y))),
+            Event::MouseButtonDown { mouse_btn, x, y, .. } => self.pointer_button(*mouse_btn, point(*x, *y), true),
+            Event::MouseButtonUp { mouse_btn, x, y, .. } => self.pointer_button(*mouse_btn, point(*x, *y), false),
+            Event::MouseWheel { x, y, .. } => {
+                self.events.push(egui::Event::MouseWheel { unit: egui::MouseWheelUnit::Line, delta: egui::vec2(*x as f32, *y as f32), modifiers: self.modifiers });
+            }
+            _ => return false,
+        }
+        true
+    }
+    fn pointer_button(&mut self, button: MouseButton, pos: Pos2, pressed: bool) {
+        let button: egui::PointerButton = match button {
+            MouseButton::Left => egui::PointerButton::Primary,
+            MouseButton::Right => egui::PointerButton::Secondary,
+            MouseButton::Middle => egui::PointerButton::Middle,
+            _ => return,
+        };
+        self.events.push(egui::Event::PointerButton { pos, button, pressed, modifiers: self.modifiers });
+    }
+    // Lays the menu out for a window of `size` pixels with this pass' events, and returns what was clicked
+    pub fn run(&mut self, nes: &mut Headless, bindings: &Bindings, slots: &SaveSlots, recent: &RecentRoms, (width, height): (u32, u32)) -> Actions {
+        let mut actions: Actions = Actions::default();
+        if !self.open { return actions; }
+        for id in self.freed.drain(..) { self.textures.remove(&id); }
+        let input: egui::RawInput = egui::RawInput {
+            screen_rect: Some(Rect::from_min_size(Pos2::ZERO, egui::vec2(width as f32, height as f32))),
+            time: Some(self.start.elapsed().as_secs_f64()),
+            modifiers: self.modifiers,
+            events: std::mem::take(&mut self.events),
+            ..Default::default()
+        };
+        let ctx: Context = self.ctx.clone();
+        let output: egui::FullOutput = ctx.run(input, |ctx| self.ui(ctx, nes, bindings, slots, recent, &mut actions));
+        for (id, delta) in output.textures_delta.set { self.set_texture(id, &delta); }
+        self.freed = output.textures_delta.free;
+        self.primitives = ctx.tessellate(output.shapes, output.pixels_per_point);
+        actions
+    }
+    fn ui(&mut self, ctx: &Context, nes: &mut Headless, bindings: &Bindings, slots: &SaveSlots, recent: &RecentRoms, actions: &mut Actions) {
+        egui::TopBottomPanel::top("menu").show(ctx, |ui| {
+            egui::menu::bar(ui, |ui| {
+                ui.menu_button("File", |ui| {
+                    ui.horizontal(|ui| {
+                        ui.text_edit_singleline(&mut self.path);
+                        if ui.button("Browse").clicked() {
+                            if let Some(path) = pick_rom() {
+                                actions.open = Some(path);
+                                ui.close_menu();
+                            }
+                        }
+                        if ui.button("Open").clicked() && !self.path.trim().is_empty() {
+                            actions.open = Some(self.path.trim().to_string());
+                            ui.close_menu();
+                        }
+                    });
+                    ui.separator();
+                    for (i, rom) in recent.roms.iter().enumerate() {
+                        if ui.button(format!("{} {}", i + 1, file_name(rom))).clicked() {
+                            actions.commands.push(Command::OpenRecent(i as u8 + 1));
+                            ui.close_menu();
+                        }
+                    }
+                    if !recent.roms.is_empty() { ui.separator(); }
+                    if ui.button("Power cycle").clicked() { actions.commands.push(Command::PowerCycle); ui.close_menu(); }
+                    if ui.button("Quit").clicked() { actions.commands.push(Command::Quit); }
+                });
+                ui.menu_button("Settings", |ui| {
+                    if ui.checkbox(&mut self.panels.slots, "Savestates").changed() { self.slot_info = None; }
+                    ui.checkbox(&mut self.panels.input, "Input");
+                    ui.checkbox(&mut self.panels.mixer, "Audio mixer");
+                    ui.checkbox(&mut self.panels.debugger, "Debugger");
+                });
+                if ui.button("Resume").clicked() { self.open = false; }
+            });
+        });
+
+        let mut open: bool = self.panels.slots;
+        egui::Window::new("Savestates").open(&mut open).show(ctx, |ui| {
+            let slot_info: &Vec<(u8, StateInfo)> = self.slot_info.get_or_insert_with(|| slots.list());
+            egui::Grid::new("slots").striped(true).show(ui, |ui| {
+                for slot in 1..=SLOTS {
+                    ui.label(format!("Slot {}", slot));
+                    match slot_info.iter().find(|(n, _)| *n == slot) {
+                        Some((_, info)) => ui.label(format!("frame {}", info.frame)),
+                        None => ui.weak("empty"),
+                    };
+                    if ui.button("Save").clicked() { actions.commands.push(Command::SaveState(slot)); }
+                    if ui.button("Load").clicked() { actions.commands.push(Command::LoadState(slot)); }
+                    ui.end_row();
+                }
+            });
+        });
+        self.panels.slots = open;
+        if actions.commands.iter().any(|command| matches!(command, Command::SaveState(_))) { self.slot_info = None; }
+
+        let mut open: bool = self.panels.input;
+        egui::Window::new("Input").open(&mut open).show(ctx, |ui| {
+            egui::ScrollArea::vertical().max_height(360.0).show(ui, |ui| {
+                egui::Grid::new("keys").striped(true).show(ui, |ui| {
+                    for (name, keys) in bindings.actions() {
+                        ui.label(name);
+                        ui.monospace(keys.iter().map(Binding::name).collect::<Vec<String>>().join(", "));
+                        let waiting: bool = self.rebinding.as_deref() == Some(name);
+                        if ui.button(if waiting { "Press a key" } else { "Set" }).clicked() { self.rebinding = Some(String::from(name)); }
+                        ui.end_row();
+                    }
+                });
+            });
+            if !self.rebound.is_empty() {
+                ui.separator();
+                ui.label("Changes last until quitting; to keep them, add to the config's [keys] section:");
+                for name in &self.rebound {
+                    let keys: Vec<String> = bindings.actions().find(|(action, _)| *action == name.as_str()).map_or(Vec::new(), |(_, keys)| keys.iter().map(Binding::name).collect());
+                    ui.monospace(format!("{} = {}", name, keys.join(", ")));
+                }
+            }
+        });
+        self.panels.input = open;
+
+        let mut open: bool = self.panels.mixer;
+        egui::Window::new("Audio mixer").open(&mut open).show(ctx, |ui| {
+            let mixer: &mut Mixer = &mut nes.cpu.bus.apu().mixer;
+            ui.add(egui::Slider::new(&mut mixer.master, 0.0..=2.0).text("volume"));
+            ui.checkbox(&mut mixer.mute_all, "Mute all");
+            ui.separator();
+            egui::Grid::new("channels").show(ui, |ui| {
+                for channel in Channel::ALL {
+                    let i: usize = channel as usize;
+                    ui.checkbox(&mut mixer.muted[i], "");
+                    ui.label(channel.name());
+                    ui.add(egui::Slider::new(&mut mixer.gain[i], 0.0..=2.0).text("volume"));
+                    ui.add(egui::Slider::new(&mut mixer.pan[i], -1.0..=1.0).text("pan"));
+                    ui.end_row();
+                }
+            });
+        });
+        self.panels.mixer = open;
+
+        let mut open: bool = self.panels.debugger;
+        egui::Window::new("Debugger").open(&mut open).show(ctx, |ui| {
+            let cpu = &nes.cpu;
+            ui.monospace(format!(
+                "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}",
+                cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer, cpu.program_counter,
+            ));
+            ui.monospace(format!("Frame {}", nes.frame_count()));
+            ui.separator();
+            let read = |addr: u16| cpu.bus.peek(addr);
+            let labels = disasm::vector_labels(&read);
+            for instruction in disasm::decode_count(&read, cpu.program_counter, DISASSEMBLY_LINES) {
+                ui.monospace(format!("{:04X}  {}", instruction.addr, instruction.text(&labels)));
+            }
+            ui.separator();
+            ui.horizontal(|ui| {
+                if ui.button("Frame advance").clicked() { actions.commands.push(Command::FrameAdvance); }
+                if ui.button("Memory editor").clicked() { actions.commands.push(Command::ToggleHexEditor); }
+                if ui.button("Break to terminal").clicked() { actions.commands.push(Command::Break); }
+            });
+        });
+        self.panels.debugger = open;
+    }
+    fn set_texture(&mut self, id: TextureId, delta: &ImageDelta) {
+        let (size, pixels): ([usize; 2], Vec<Color32>) = match &delta.image {
+            ImageData::Color(image) => (image.size, image.pixels.clone()),
+            ImageData::Font(image) => (image.size, image.srgba_pixels(None).collect()),
+        };
+        let Some([x, y]) = delta.pos else {
+            self.textures.insert(id, Texture { size, pixels });
+            return;
+        };
+        let Some(texture) = self.textures.get_mut(&id) else { return; };
+        for (row, line) in pixels.chunks_exact(size[0]).enumerate() {
+            let start: usize = (y + row) * texture.size[0] + x;
+            texture.pixels[start..start + size[0]].copy_from_slice(line);
+        }
+    }
+    // The picture stretched to a window of `size` pixels, with the menu over it
+    pub fn paint(&self, picture: &Image, (width, height): (u32, u32)) -> Image {
+        let mut image: Image = Image::new(width as usize, height as usize);
+        for y in 0..image.height {
+            for x in 0..image.width { image.set_pixel(x, y, picture.pixel(x * picture.width / image.width, y * picture.height / image.height)); }
+        }
+        for primitive in &self.primitives {
+            let Primitive::Mesh(mesh) = &primitive.primitive else { continue; };
+            let Some(texture) = self.textures.get(&mesh.texture_id) else { continue; };
+            for triangle in mesh.indices.chunks_exact(3) {
+                let corners: [&Vertex; 3] = [0, 1, 2].map(|i| &mesh.vertices[triangle[i] as usize]);
+                fill_triangle(&mut image, primitive.clip_rect, texture, corners);
+            }
+        }
+        image
+    }
+}
+
+// Blends the pixels whose centres the triangle covers, interpolating its vertices' colour and
+// texture coordinates; egui's colours and textures are premultiplied
+fn fill_triangle(image: &mut Image, clip: Rect, texture: &Texture, [a, b, c]: [&Vertex; 3]) {
+    let area: f32 = edge(a.pos, b.pos, c.pos);
+    if area.abs() < f32::EPSILON { return; }
+    let min: Pos2 = a.pos.min(b.pos).min(c.pos).max(clip.min);
+    let max: Pos2 = a.pos.max(b.pos).max(c.pos).min(clip.max).min(Pos2::new(image.width as f32, image.height as f32));
+    for y in (min.y.max(0.0) as usize)..(max.y.ceil().max(0.0) as usize) {
+        for x in (min.x.max(0.0) as usize)..(max.x.ceil().max(0.0) as usize) {
+            let centre: Pos2 = Pos2::new(x as f32 + 0.5, y as f32 + 0.5);
+            let weights: [f32; 3] = [edge(b.pos, c.pos, centre) / area, edge(c.pos, a.pos, centre) / area, edge(a.pos, b.pos, centre) / area];
+            if weights.iter().any(|weight| *weight < 0.0) { continue; }
+            let u: f32 = a.uv.x * weights[0] + b.uv.x * weights[1] + c.uv.x * weights[2];
+            let v: f32 = a.uv.y * weights[0] + b.uv.y * weights[1] + c.uv.y * weights[2];
+            let texel_x: usize = ((u * texture.size[0] as f32) as usize).min(texture.size[0] - 1);
+            let texel_y: usize = ((v * texture.size[1] as f32) as usize).min(texture.size[1] - 1);
+            let texel: [u8; 4] = texture.pixels[texel_y * texture.size[0] + texel_x].to_array();
+            let colors: [[u8; 4]; 3] = [a.color.to_array(), b.color.to_array(), c.color.to_array()];
+            let source: [f32; 4] = core::array::from_fn(|i| {
+                let color: f32 = colors[0][i] as f32 * weights[0] + colors[1][i] as f32 * weights[1] + colors[2][i] as f32 * weights[2];
+                color * texel[i] as f32 / 255.0
+            });
+            let pixel: [u8; 3] = image.pixel(x, y);
+            let blended: [u8; 3] = core::array::from_fn(|i| (source[i] + pixel[i] as f32 * (1.0 - source[3] / 255.0)).min(255.0) as u8);
+            image.set_pixel(x, y, blended);
+        }
+    }
+}
+
+// Twice the signed area of the triangle a, b, p
+fn edge(a: Pos2, b: Pos2, p: Pos2) -> f32 { (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x) }
+
+fn file_name(path: &str) -> String { Path::new(path).file_name().map_or(String::from(path), |name| name.to_string_lossy().into_owned()) }
+
+fn modifiers(keymod: Mod) -> Modifiers {
+    let ctrl: bool = keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD);
+    Modifiers { alt: keymod.intersects(Mod::LALTMOD | Mod::RALTMOD), ctrl, shift: keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD), mac_cmd: false, command: ctrl }
+}
+
+fn binding(keycode: Keycode, keymod: Mod) -> Binding {
+    let Modifiers { shift, ctrl, alt, .. } = modifiers(keymod);
+    Binding { keycode, shift, ctrl, alt }
+}
+
+fn is_modifier(keycode: Keycode) -> bool {
+    matches!(keycode, Keycode::LShift | Keycode::RShift | Keycode::LCtrl | Keycode::RCtrl | Keycode::LAlt | Keycode::RAlt | Keycode::LGui | Keycode::RGui)
+}
+
+// The keys text fields and menus need; typed characters come as TextInput events
+fn key(keycode: Keycode) -> Option<Key> {
+    Some(match keycode {
+        Keycode::Backspace => Key::Backspace,
+        Keycode::Delete => Key::Delete,
+        Keycode::Return | Keycode::KpEnter => Key::Enter,
+        Keycode::Tab => Key::Tab,
+        Keycode::Left => Key::ArrowLeft,
+        Keycode::Right => Key::ArrowRight,
+        Keycode::Up => Key::ArrowUp,
+        Keycode::Down => Key::ArrowDown,
+        Keycode::Home => Key::Home,
+        Keycode::End => Key::End,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fill_triangle() {
+        let mut image: Image = Image::new(4, 4);
+        let texture: Texture = Texture { size: [1, 1], pixels: vec![Color32::WHITE] };
+        let vertex = |x: f32, y: f32, color: Color32| Vertex { pos: Pos2::new(x, y), uv: Pos2::ZERO, color };
+        let clip: Rect = Rect::from_min_max(Pos2::ZERO, Pos2::new(4.0, 4.0));
+        let red: Color32 = Color32::from_rgba_premultiplied(0xFF, 0, 0, 0xFF);
+        fill_triangle(&mut image, clip, &texture, [&vertex(0.0, 0.0, red), &vertex(4.0, 0.0, red), &vertex(0.0, 4.0, red)]);
+        assert_eq!((image.pixel(0, 0), image.pixel(3, 3)), ([0xFF, 0, 0], [0, 0, 0]));
+        // Half-transparent white over the red, clipped to the left column
+        let white: Color32 = Color32::from_rgba_premultiplied(0x80, 0x80, 0x80, 0x80);
+        let clip: Rect = Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 4.0));
+        fill_triangle(&mut image, clip, &texture, [&vertex(0.0, 0.0, white), &vertex(4.0, 0.0, white), &vertex(0.0, 4.0, white)]);
+        assert_eq!((image.pixel(0, 0), image.pixel(1, 0)), ([0xFF, 0x80, 0x80], [0xFF, 0, 0]));
+    }
+}
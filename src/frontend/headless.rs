@@ -0,0 +1,113 @@
+use std::time::Instant;
+
+use gbnes_core::{Headless, PpuAccuracy, RamInit, Region, Rom};
+use gbnes_core::movie::{self, COMMAND_POWER, COMMAND_SOFT_RESET, Fm2Movie, MovieFrame};
+
+use super::cli::flag_value;
+
+// `--headless [--run-frames N] [--frame-hash each|final] [--input script.txt | --movie run.fm2] [--dump PREFIX]`:
+// no window, no audio device; runs N frames (or forever) and exits, optionally printing the CRC32
+// of rendered frames. With --input or --movie, joypad 1 replays the recording and RAM/framebuffer
+// are dumped at exit; a movie runs to its last frame unless --run-frames is given.
+pub fn run(rom: Rom, ram_init: RamInit, accuracy: PpuAccuracy, oam_quirks: bool, dmc_dma: bool, args: &[String]) {
+    let mut frames: Option<u64> = flag_value(args, "--run-frames").map(|n| n.parse().unwrap_or_else(|_| {
+        eprintln!("--run-frames expects a frame count, got {}", n);
+        std::process::exit(1);
+    }));
+    let hash_mode: Option<String> = flag_value(args, "--frame-hash");
+    if let Some(mode) = &hash_mode {
+        if mode != "each" && mode != "final" { eprintln!("--frame-hash expects `each` or `final`, got {}", mode); std::process::exit(1); }
+    }
+    let mut inputs: Option<Vec<MovieFrame>> = flag_value(args, "--input").map(|path| {
+        read(&path).and_then(|text| parse_input_script(&text))
+            .map(|pads| pads.into_iter().map(|joypad1| MovieFrame { commands: 0, joypad1 }).collect())
+            .unwrap_or_else(|err| { eprintln!("{}", err); std::process::exit(1); })
+    });
+    if let Some(path) = flag_value(args, "--movie") {
+        let movie: Fm2Movie = read(&path).and_then(|text| Fm2Movie::parse(&text)).unwrap_or_else(|err| { eprintln!("{}: {}", path, err); std::process::exit(1); });
+        if let Some(rom_name) = movie.get("romFilename") { println!("Movie for {}, {} frames", rom_name, movie.frames.len()); }
+        frames = frames.or(Some(movie.frames.len() as u64));
+        inputs = Some(movie.frames);
+    }
+    if inputs.is_some() && frames.is_none() { eprintln!("--input needs --run-frames N"); std::process::exit(1); }
+    if hash_mode.is_some() && frames.is_none() { eprintln!("--frame-hash needs --run-frames N"); std::process::exit(1); }
+    let mut nes: Headless = Headless::with_ram_init(rom, ram_init).unwrap_or_else(|err| { eprintln!("{}", err); std::process::exit(1); });
+    nes.cpu.bus.ppu_mut().accuracy = accuracy;
+    nes.cpu.bus.ppu_mut().oam_quirks = oam_quirks;
+    nes.cpu.bus.dmc_dma = dmc_dma;
+    let Some(frames) = frames else { loop { nes.run_frame(); } };
+    for frame in 0..frames as usize {
+        if let Some(input) = inputs.as_ref().and_then(|inputs| inputs.get(frame)) {
+            if input.commands & COMMAND_POWER != 0 { nes.power_cycle(); }
+            else if input.commands & COMMAND_SOFT_RESET != 0 { nes.cpu.reset(); }
+            nes.set_buttons(input.joypad1);
+        } else if inputs.is_some() { nes.set_buttons(0); }
+        nes.run_frame();
+        if hash_mode.as_deref() == Some("each") { println!("{:6} {:08X}", nes.frame_count(), nes.frame_hash()); }
+    }
+    match hash_mode.as_deref() {
+        Some("final") => println!("{:08X}", nes.frame_hash()),
+        Some(_) => {}
+        None => println!("Ran {} frames", nes.frame_count()),
+    }
+    if inputs.is_some() {
+        let prefix: String = flag_value(args, "--dump").unwrap_or(String::from("dump"));
+        let dumps = [(format!("{}.ram", prefix), nes.ram().to_vec()), (format!("{}.ppm", prefix), nes.frame.to_ppm())];
+        for (path, data) in dumps.iter() {
+            if let Err(err) = std::fs::write(path, data) { eprintln!("Could not write {}: {}", path, err); std::process::exit(1); }
+            println!("Wrote {}", path);
+        }
+    }
+}
+
+// `--bench N`: runs N frames as fast as possible, with nothing drawn or played, and reports the
+// emulated frames and CPU cycles per second against the console's own speed
+pub fn bench(rom: Rom, ram_init: RamInit, accuracy: PpuAccuracy, oam_quirks: bool, dmc_dma: bool, frames: u64) {
+    let mut nes: Headless = Headless::with_ram_init(rom, ram_init).unwrap_or_else(|err| { eprintln!("{}", err); std::process::exit(1); });
+    nes.cpu.bus.ppu_mut().accuracy = accuracy;
+    nes.cpu.bus.ppu_mut().oam_quirks = oam_quirks;
+    nes.cpu.bus.dmc_dma = dmc_dma;
+    let region: Region = nes.cpu.bus.ppu().region;
+    let start: Instant = Instant::now();
+    nes.run_frames(frames);
+    let seconds: f64 = start.elapsed().as_secs_f64().max(f64::EPSILON);
+    let (frames_per_second, cycles_per_second): (f64, f64) = (frames as f64 / seconds, nes.cpu.bus.cycles as f64 / seconds);
+    println!("{} frames in {:.3}s", frames, seconds);
+    println!("{:.1} frames/s ({:.2}x {:?} speed)", frames_per_second, frames_per_second / region.frame_rate(), region);
+    println!("{:.3} MHz emulated CPU ({:.0} cycles/s)", cycles_per_second / 1_000_000.0, cycles_per_second);
+}
+
+fn read(path: &str) -> Result<String, String> { std::fs::read_to_string(path).map_err(|err| format!("Could not read {}: {}", path, err)) }
+
+// One line per frame with the pressed buttons, e.g. `R......A` (any char other than '.' or ' '
+// counts as pressed). A leading count repeats the line: `60 ....T...` holds Start for 60 frames.
+// Blank lines and lines starting with '#' are skipped.
+pub fn parse_input_script(text: &str) -> Result<Vec<u8>, String> {
+    let mut frames: Vec<u8> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line: &str = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+        let (count, buttons) = match line.split_once(char::is_whitespace) {
+            Some((count, buttons)) if count.chars().all(|c| c.is_ascii_digit()) => {
+                (count.parse::<usize>().map_err(|err| format!("Input script line {}: {}", number + 1, err))?, buttons.trim_start())
+            }
+            _ => (1, line),
+        };
+        let status: u8 = movie::parse_pad(buttons).ok_or(format!("Input script line {}: expected {} button columns ({}), got `{}`",
+            number + 1, movie::PAD_BUTTONS.len(), String::from_utf8_lossy(movie::PAD_BUTTONS), buttons))?;
+        frames.extend(std::iter::repeat(status).take(count));
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_input_script() {
+        let frames: Vec<u8> = parse_input_script("# warmup\n2 ........\n\nR......A\n3 ....T...\n").unwrap();
+        assert_eq!(frames, vec![0, 0, 0b1000_0001, 0b0000_1000, 0b0000_1000, 0b0000_1000]);
+        assert!(parse_input_script("RLDU").is_err());
+    }
+}
@@ -0,0 +1,68 @@
+use sdl2::keyboard::Keycode;
+use gbnes_core::{CPU, Frame};
+use gbnes_core::hexedit::HexEditor;
+use gbnes_core::render::osd;
+
+// The OSD font's 5x7 glyphs plus spacing
+const CHAR_WIDTH: usize = 6;
+const LINE_HEIGHT: usize = 10;
+const MARGIN: usize = 8;
+
+// Keys while the editor is open: arrows and PageUp/PageDown move, hex digits overwrite the byte at
+// the cursor, Space freezes or unfreezes it, M switches memory, and G, an address, then Return jumps
+// there (Escape cancels). Returns whether the key was the editor's; anything else goes on to the
+// usual bindings. `read_only` (in netplay, where the other machine wouldn't see them) ignores edits
+// and freezes.
+pub fn handle_key(editor: &mut HexEditor, cpu: &mut CPU, keycode: Keycode, read_only: bool) -> bool {
+    let page: i32 = (HexEditor::ROWS * HexEditor::COLUMNS) as i32;
+    if let Some(digit) = hex_digit(keycode) {
+        if !read_only || editor.is_entering_goto() { editor.type_digit(cpu, digit); }
+        return true;
+    }
+    match keycode {
+        Keycode::Return if editor.is_entering_goto() => editor.end_goto(true),
+        Keycode::Escape if editor.is_entering_goto() => editor.end_goto(false),
+        Keycode::G => editor.begin_goto(),
+        Keycode::Left => editor.move_cursor(-1),
+        Keycode::Right => editor.move_cursor(1),
+        Keycode::Up => editor.move_cursor(-(HexEditor::COLUMNS as i32)),
+        Keycode::Down => editor.move_cursor(HexEditor::COLUMNS as i32),
+        Keycode::PageUp => editor.move_cursor(-page),
+        Keycode::PageDown => editor.move_cursor(page),
+        Keycode::Space => if !read_only { editor.toggle_freeze(cpu) },
+        Keycode::M => editor.next_space(),
+        _ => return false,
+    }
+    true
+}
+
+fn hex_digit(keycode: Keycode) -> Option<u8> {
+    let name: String = keycode.name();
+    let mut chars = name.chars();
+    let (Some(c), None) = (chars.next(), chars.next()) else { return None; };
+    c.to_digit(16).map(|digit| digit as u8)
+}
+
+// Over the top-left of the picture, on a dimmed backdrop; the cursor byte is yellow, frozen ones cyan
+pub fn draw(editor: &HexEditor, cpu: &CPU, frame: &mut Frame) {
+    let lines: Vec<String> = editor.lines(cpu);
+    let width: usize = lines.iter().map(String::len).max().unwrap_or(0) * CHAR_WIDTH + 2 * MARGIN;
+    let height: usize = lines.len() * LINE_HEIGHT + 2 * MARGIN;
+    for y in 0..height.min(Frame::HIGHT) {
+        for x in 0..width.min(Frame::WIDTH) {
+            let base: usize = (y * Frame::WIDTH + x) * 3;
+            for channel in &mut frame.data[base..base + 3] { *channel /= 4; }
+        }
+    }
+    let text = |frame: &mut Frame, (line, column): (usize, usize), text: &str, color: (u8, u8, u8)| {
+        osd::draw_text(frame, MARGIN + column * CHAR_WIDTH, MARGIN + line * LINE_HEIGHT, text, color);
+    };
+    for (i, line) in lines.iter().enumerate() { text(frame, (i, 0), line, (0xFF, 0xFF, 0xFF)); }
+    for freeze in editor.frozen.iter().filter(|freeze| freeze.space == editor.space) {
+        if let Some(cell) = editor.cell(freeze.addr) { text(frame, cell, &format!("{:02X}", freeze.value), (0x00, 0xFF, 0xFF)); }
+    }
+    if let Some(cell) = editor.cell(editor.cursor) {
+        let value: u8 = gbnes_core::hexedit::peek(cpu, editor.space, editor.cursor);
+        text(frame, cell, &format!("{:02X}", value), (0xFF, 0xFF, 0x00));
+    }
+}
@@ -0,0 +1,274 @@
+use sdl2::keyboard::{Keycode, Mod};
+use gbnes_core::JoypadButton;
+use gbnes_core::apu::mixer::Channel;
+
+use super::config::Config;
+
+// Emulator controls raised by hotkeys and handled by the main loop between frames
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    SaveState(u8),
+    LoadState(u8),
+    TogglePause,
+    FrameAdvance,
+    FastForward(bool),
+    ToggleSlowMotion,
+    Screenshot,
+    PowerCycle,
+    Microphone(bool),
+    ToggleFps,
+    NextCrtPreset,
+    NextPalette,
+    Break,
+    ToggleHexEditor,
+    ToggleMute(Channel),
+    // Picks the channel ChannelVolume changes, and steps its volume up (1) or down (-1)
+    NextMixerChannel,
+    ChannelVolume(i8),
+    // Steps the master volume up (1) or down (-1)
+    MasterVolume(i8),
+    ToggleMuteAll,
+    ToggleAudioRecording,
+    ToggleVideoRecording,
+    // Writes out the last seconds of gameplay as an animated PNG
+    SaveClip,
+    // The NSF player's next (1) or previous (-1) song
+    Track(i8),
+    // Ejects the Famicom Disk System's disk, or inserts the next side once ejected
+    SwapDisk,
+    // Shows or hides the menu bar and settings panels (gui feature)
+    ToggleMenu,
+    // Opens or closes the recent ROMs quick menu, whose number keys raise OpenRecent
+    ToggleRecentMenu,
+    OpenRecent(u8),
+    Quit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Joypad(JoypadButton),
+    Command(Command),
+}
+
+// A key plus the modifiers held with it. Hotkeys need exactly these modifiers; joypad buttons ignore them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Binding {
+    pub keycode: Keycode,
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+impl Binding {
+    pub fn key(keycode: Keycode) -> Self { Binding { keycode, shift: false, ctrl: false, alt: false } }
+    pub fn shift(keycode: Keycode) -> Self { Binding { shift: true, ..Binding::key(keycode) } }
+    pub fn ctrl(keycode: Keycode) -> Self { Binding { ctrl: true, ..Binding::key(keycode) } }
+    // `F1`, `Shift+F1`, `Ctrl+Alt+R`: modifiers first, then an SDL key name
+    pub fn parse(text: &str) -> Result<Binding, String> {
+        let mut parts: Vec<&str> = text.split('+').map(str::trim).collect();
+        let name: &str = parts.pop().unwrap_or("");
+        let keycode: Keycode = Keycode::from_name(name).ok_or_else(|| format!("unknown key {:?}", name))?;
+        let mut binding: Binding = Binding::key(keycode);
+        for modifier in parts {
+            match modifier.to_lowercase().as_str() {
+                "shift" => binding.shift = true,
+                "ctrl" => binding.ctrl = true,
+                "alt" => binding.alt = true,
+                _ => return Err(format!("unknown modifier {:?} in {:?}", modifier, text)),
+            }
+        }
+        Ok(binding)
+    }
+    // The key as parse reads it, e.g. for the config line of a key changed in the menu
+    #[cfg(feature = "gui")]
+    pub fn name(&self) -> String {
+        let modifiers: [(bool, &str); 3] = [(self.shift, "Shift+"), (self.ctrl, "Ctrl+"), (self.alt, "Alt+")];
+        let prefix: String = modifiers.iter().filter(|(held, _)| *held).map(|(_, name)| *name).collect();
+        format!("{}{}", prefix, self.keycode.name())
+    }
+    fn matches(&self, keycode: Keycode, keymod: Mod) -> bool {
+        self.keycode == keycode
+            && self.shift == keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD)
+            && self.ctrl == keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD)
+            && self.alt == keymod.intersects(Mod::LALTMOD | Mod::RALTMOD)
+    }
+}
+
+type ActionKeys = (String, Action, Vec<Binding>);
+
+// Config names of every bindable action, with their default keys
+fn default_actions() -> Vec<ActionKeys> {
+    let joypad = |name: &str, button: JoypadButton, keycode: Keycode| (String::from(name), Action::Joypad(button), vec![Binding::key(keycode)]);
+    let command = |name: &str, command: Command, keys: Vec<Binding>| (String::from(name), Action::Command(command), keys);
+    let mut actions: Vec<ActionKeys> = vec![
+        joypad("up", JoypadButton::Up, Keycode::W),
+        joypad("down", JoypadButton::Down, Keycode::S),
+        joypad("left", JoypadButton::Left, Keycode::A),
+        joypad("right", JoypadButton::Right, Keycode::D),
+        joypad("select", JoypadButton::Select, Keycode::Backspace),
+        joypad("start", JoypadButton::Start, Keycode::Return),
+        joypad("a", JoypadButton::ButtonA, Keycode::Space),
+        joypad("b", JoypadButton::ButtonB, Keycode::Q),
+        command("pause", Command::TogglePause, vec![Binding::key(Keycode::P), Binding::key(Keycode::Pause)]),
+        command("frame_advance", Command::FrameAdvance, vec![Binding::key(Keycode::N)]),
+        command("fast_forward", Command::FastForward(true), vec![Binding::key(Keycode::Tab)]),
+        command("slow_motion", Command::ToggleSlowMotion, vec![Binding::key(Keycode::M)]),
+        command("screenshot", Command::Screenshot, vec![Binding::key(Keycode::F12)]),
+        command("power_cycle", Command::PowerCycle, vec![Binding::shift(Keycode::F12)]),
+        command("microphone", Command::Microphone(true), vec![Binding::key(Keycode::V)]),
+        command("show_fps", Command::ToggleFps, vec![Binding::key(Keycode::F)]),
+        command("crt_preset", Command::NextCrtPreset, vec![Binding::key(Keycode::C)]),
+        command("palette", Command::NextPalette, vec![Binding::key(Keycode::L)]),
+        command("break", Command::Break, vec![Binding::key(Keycode::B)]),
+        command("hex_editor", Command::ToggleHexEditor, vec![Binding::key(Keycode::H)]),
+        command("quit", Command::Quit, vec![Binding::key(Keycode::Escape)]),
+    ];
+    const SLOT_KEYS: [Keycode; 10] = [
+        Keycode::F1, Keycode::F2, Keycode::F3, Keycode::F4, Keycode::F5,
+        Keycode::F6, Keycode::F7, Keycode::F8, Keycode::F9, Keycode::F10,
+    ];
+    for (i, keycode) in SLOT_KEYS.into_iter().enumerate() {
+        let slot: u8 = i as u8 + 1;
+        actions.push(command(&format!("load_state_{}", slot), Command::LoadState(slot), vec![Binding::key(keycode)]));
+        actions.push(command(&format!("save_state_{}", slot), Command::SaveState(slot), vec![Binding::shift(keycode)]));
+    }
+    actions.push(command("volume_up", Command::MasterVolume(1), vec![Binding::key(Keycode::Equals), Binding::key(Keycode::KpPlus)]));
+    actions.push(command("volume_down", Command::MasterVolume(-1), vec![Binding::key(Keycode::Minus), Binding::key(Keycode::KpMinus)]));
+    actions.push(command("mute", Command::ToggleMuteAll, vec![Binding::key(Keycode::Num0)]));
+    actions.push(command("record_audio", Command::ToggleAudioRecording, vec![Binding::key(Keycode::F11)]));
+    actions.push(command("record_video", Command::ToggleVideoRecording, vec![Binding::shift(Keycode::F11)]));
+    actions.push(command("save_clip", Command::SaveClip, vec![Binding::ctrl(Keycode::F11)]));
+    actions.push(command("next_track", Command::Track(1), vec![Binding::key(Keycode::Right)]));
+    actions.push(command("previous_track", Command::Track(-1), vec![Binding::key(Keycode::Left)]));
+    actions.push(command("swap_disk", Command::SwapDisk, vec![Binding::key(Keycode::I)]));
+    actions.push(command("recent_roms", Command::ToggleRecentMenu, vec![Binding::key(Keycode::O)]));
+    actions.push(command("menu", Command::ToggleMenu, vec![Binding::key(Keycode::Backquote)]));
+    actions.push(command("mixer_channel", Command::NextMixerChannel, vec![Binding::ctrl(Keycode::Right)]));
+    actions.push(command("channel_louder", Command::ChannelVolume(1), vec![Binding::ctrl(Keycode::Up)]));
+    actions.push(command("channel_quieter", Command::ChannelVolume(-1), vec![Binding::ctrl(Keycode::Down)]));
+    const MUTE_KEYS: [Keycode; 6] = [Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4, Keycode::Num5, Keycode::Num6];
+    for (channel, keycode) in Channel::ALL.into_iter().zip(MUTE_KEYS) {
+        actions.push(command(&format!("mute_{}", channel.name()), Command::ToggleMute(channel), vec![Binding::ctrl(keycode)]));
+    }
+    actions
+}
+
+// What an action name stands for (see default_actions), for the remote API
+pub fn named_action(name: &str) -> Option<Action> {
+    default_actions().into_iter().find_map(|(action, kind, _)| (action == name).then_some(kind))
+}
+
+// Keyboard layout for joypad 1 and the emulator hotkeys. Defaults: WASD, Backspace/Return for
+// Select/Start, Space/Q for A/B; F1..F10 load a savestate slot and Shift+F1..F10 save it; P / Pause
+// pauses, N advances one frame, Tab fast-forwards while held, M toggles slow motion, F12 saves a
+// screenshot to screenshots/<rom name>-<time>.png, Shift+F12 power-cycles, V blows into the Famicom
+// microphone while held, F toggles the FPS counter, C cycles the CRT presets, L the palettes, B breaks into the debugger, H opens the memory editor, Ctrl+1..6 mute the
+// pulse1, pulse2, triangle, noise, DMC and expansion channels, Ctrl+Right picks a channel and
+// Ctrl+Up/Down change its volume, +/- change the master volume and 0 mutes all sound, F11 starts and
+// stops recording the audio to recordings/<rom name>-<time>.wav and Shift+F11 the video (with its
+// audio) to an uncompressed .avi there, Ctrl+F11 saves the last seconds as an animated .png there,
+// Right/Left switch songs when playing an NSF, I ejects and inserts FDS disk sides, O opens the recent ROMs menu (1..9 load one), ` shows the menu bar in builds with the gui feature, Escape quits. Each action can be rebound in the config's [keys] section to a
+// comma-separated list of keys, e.g. `a = Space, K` or `save_state_1 = Shift+F1`.
+pub struct Bindings {
+    // Kept for the menu, which lists and rebinds them
+    #[cfg(feature = "gui")]
+    actions: Vec<ActionKeys>,
+    bindings: Vec<(Binding, Action)>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self { Bindings::from_actions(default_actions()) }
+}
+
+impl Bindings {
+    pub fn from_config(config: &Config) -> Result<Bindings, String> {
+        let mut actions: Vec<ActionKeys> = default_actions();
+        for (name, value) in config.section("keys") {
+            let Some((_, _, keys)) = actions.iter_mut().find(|(action, _, _)| action == name) else {
+                return Err(format!("keys.{}: unknown action", name));
+            };
+            *keys = value.split(',').map(str::trim).filter(|key| !key.is_empty())
+                .map(Binding::parse).collect::<Result<Vec<Binding>, String>>()
+                .map_err(|err| format!("keys.{}: {}", name, err))?;
+        }
+        Ok(Bindings::from_actions(actions))
+    }
+    fn from_actions(actions: Vec<ActionKeys>) -> Bindings {
+        let bindings = actions.iter().flat_map(|(_, action, keys)| keys.iter().map(move |key| (*key, *action))).collect();
+        Bindings { #[cfg(feature = "gui")] actions, bindings }
+    }
+    // Every action's config name and keys, in default_actions order
+    #[cfg(feature = "gui")]
+    pub fn actions(&self) -> impl Iterator<Item = (&str, &[Binding])> {
+        self.actions.iter().map(|(name, _, keys)| (name.as_str(), keys.as_slice()))
+    }
+    // Replaces an action's keys for the rest of the session, as its [keys] entry would
+    #[cfg(feature = "gui")]
+    pub fn rebind(&mut self, name: &str, keys: Vec<Binding>) {
+        let mut actions: Vec<ActionKeys> = core::mem::take(&mut self.actions);
+        if let Some((_, _, old)) = actions.iter_mut().find(|(action, _, _)| action == name) { *old = keys; }
+        *self = Bindings::from_actions(actions);
+    }
+    pub fn button(&self, keycode: Keycode) -> Option<JoypadButton> {
+        self.bindings.iter().find_map(|(binding, action)| match action {
+            Action::Joypad(button) if binding.keycode == keycode => Some(*button),
+            _ => None,
+        })
+    }
+    pub fn command(&self, keycode: Keycode, keymod: Mod) -> Option<Command> {
+        self.bindings.iter().find_map(|(binding, action)| match action {
+            Action::Command(command) if binding.matches(keycode, keymod) => Some(*command),
+            _ => None,
+        })
+    }
+    // Commands for held controls end on key release, whatever modifiers are down by then
+    pub fn release(&self, keycode: Keycode) -> Option<Command> {
+        self.bindings.iter().find_map(|(binding, action)| match action {
+            Action::Command(Command::FastForward(true)) if binding.keycode == keycode => Some(Command::FastForward(false)),
+            Action::Command(Command::Microphone(true)) if binding.keycode == keycode => Some(Command::Microphone(false)),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_bindings() {
+        let bindings: Bindings = Bindings::default();
+        assert_eq!(bindings.button(Keycode::W), Some(JoypadButton::Up));
+        assert_eq!(bindings.command(Keycode::F3, Mod::NOMOD), Some(Command::LoadState(3)));
+        assert_eq!(bindings.command(Keycode::F3, Mod::RSHIFTMOD), Some(Command::SaveState(3)));
+        assert_eq!(bindings.command(Keycode::F3, Mod::LCTRLMOD), None);
+        assert_eq!(bindings.command(Keycode::Pause, Mod::NOMOD), Some(Command::TogglePause));
+        assert_eq!(bindings.command(Keycode::Num3, Mod::LCTRLMOD), Some(Command::ToggleMute(Channel::Triangle)));
+        assert_eq!(bindings.command(Keycode::KpMinus, Mod::NOMOD), Some(Command::MasterVolume(-1)));
+        assert_eq!(bindings.command(Keycode::Left, Mod::NOMOD), Some(Command::Track(-1)));
+        assert_eq!(bindings.command(Keycode::I, Mod::NOMOD), Some(Command::SwapDisk));
+        assert_eq!(bindings.release(Keycode::Tab), Some(Command::FastForward(false)));
+        assert_eq!(bindings.release(Keycode::W), None);
+    }
+
+    #[test]
+    fn test_config_rebinding() {
+        let config: Config = Config::parse("[keys]\njump = Space\n").unwrap();
+        assert!(Bindings::from_config(&config).is_err());
+        // An empty list unbinds the action
+        let config: Config = Config::parse("[keys]\nup =\n").unwrap();
+        assert_eq!(Bindings::from_config(&config).unwrap().button(Keycode::W), None);
+    }
+
+    #[test]
+    #[cfg(feature = "gui")]
+    fn test_rebind() {
+        let mut bindings: Bindings = Bindings::default();
+        let binding: Binding = Binding::parse("Ctrl+Alt+K").unwrap();
+        assert_eq!(Binding::parse(&binding.name()), Ok(binding));
+        bindings.rebind("pause", vec![binding]);
+        assert_eq!(bindings.command(Keycode::P, Mod::NOMOD), None);
+        assert_eq!(bindings.command(Keycode::K, Mod::LCTRLMOD | Mod::LALTMOD), Some(Command::TogglePause));
+        assert_eq!(bindings.actions().find(|(name, _)| *name == "pause").map(|(_, keys)| keys.len()), Some(1));
+    }
+}
@@ -0,0 +1,214 @@
+use std::fmt;
+
+// A JSON value, as much of it as the RPC server reads and writes. Objects keep their keys in
+// order, which keeps replies readable.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Json { Json::Object(fields.into_iter().map(|(key, value)| (String::from(key), value)).collect()) }
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> { if let Json::String(text) = self { Some(text) } else { None } }
+    // Whole numbers only
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(number) if *number >= 0.0 && number.fract() == 0.0 => Some(*number as u64),
+            _ => None,
+        }
+    }
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser: Parser = Parser { text: text.as_bytes(), at: 0 };
+        let value: Json = parser.value()?;
+        parser.whitespace();
+        if parser.at != text.len() { return Err(format!("unexpected text at {}", parser.at)); }
+        Ok(value)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Json { Json::Bool(value) }
+}
+impl From<&str> for Json {
+    fn from(value: &str) -> Json { Json::String(String::from(value)) }
+}
+macro_rules! json_number {
+    ($($t:ty),*) => { $(impl From<$t> for Json { fn from(value: $t) -> Json { Json::Number(value as f64) } })* };
+}
+json_number!(u8, u16, u32, u64, usize, i64, f64);
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(number) if number.is_finite() => write!(f, "{}", number),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(text) => write_string(f, text),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() { write!(f, "{}{}", if i > 0 { "," } else { "" }, item)?; }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 { write!(f, ",")?; }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, text: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn whitespace(&mut self) {
+        while self.text.get(self.at).is_some_and(u8::is_ascii_whitespace) { self.at += 1; }
+    }
+    fn expect(&mut self, literal: &str, value: Json) -> Result<Json, String> {
+        if !self.text[self.at..].starts_with(literal.as_bytes()) { return Err(format!("unexpected text at {}", self.at)); }
+        self.at += literal.len();
+        Ok(value)
+    }
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.text.get(self.at) {
+            Some(b'n') => self.expect("null", Json::Null),
+            Some(b't') => self.expect("true", Json::Bool(true)),
+            Some(b'f') => self.expect("false", Json::Bool(false)),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'[') => {
+                self.at += 1;
+                let mut items: Vec<Json> = Vec::new();
+                self.whitespace();
+                if self.text.get(self.at) == Some(&b']') {
+                    self.at += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.whitespace();
+                    match self.text.get(self.at) {
+                        Some(b',') => self.at += 1,
+                        Some(b']') => { self.at += 1; return Ok(Json::Array(items)); }
+                        _ => return Err(format!("expected , or ] at {}", self.at)),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.at += 1;
+                let mut fields: Vec<(String, Json)> = Vec::new();
+                self.whitespace();
+                if self.text.get(self.at) == Some(&b'}') {
+                    self.at += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.whitespace();
+                    if self.text.get(self.at) != Some(&b'"') { return Err(format!("expected a key at {}", self.at)); }
+                    let key: String = self.string()?;
+                    self.whitespace();
+                    if self.text.get(self.at) != Some(&b':') { return Err(format!("expected : at {}", self.at)); }
+                    self.at += 1;
+                    fields.push((key, self.value()?));
+                    self.whitespace();
+                    match self.text.get(self.at) {
+                        Some(b',') => self.at += 1,
+                        Some(b'}') => { self.at += 1; return Ok(Json::Object(fields)); }
+                        _ => return Err(format!("expected , or }} at {}", self.at)),
+                    }
+                }
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                let start: usize = self.at;
+                while self.text.get(self.at).is_some_and(|c| c.is_ascii_digit() || b"+-.eE".contains(c)) { self.at += 1; }
+                let number: &str = std::str::from_utf8(&self.text[start..self.at]).unwrap_or("");
+                number.parse::<f64>().map(Json::Number).map_err(|_| format!("bad number {:?}", number))
+            }
+            _ => Err(format!("unexpected text at {}", self.at)),
+        }
+    }
+    fn string(&mut self) -> Result<String, String> {
+        self.at += 1;
+        let mut bytes: Vec<u8> = Vec::new();
+        loop {
+            let c: u8 = *self.text.get(self.at).ok_or("unterminated string")?;
+            self.at += 1;
+            match c {
+                b'"' => return String::from_utf8(bytes).map_err(|_| String::from("string isn't UTF-8")),
+                b'\\' => {
+                    let escape: u8 = *self.text.get(self.at).ok_or("unterminated string")?;
+                    self.at += 1;
+                    let c: char = match escape {
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{C}',
+                        b'u' => {
+                            let hex: &str = self.text.get(self.at..self.at + 4).and_then(|hex| std::str::from_utf8(hex).ok()).ok_or("bad \\u escape")?;
+                            self.at += 4;
+                            // Surrogate pairs aren't needed for anything the server is asked
+                            u32::from_str_radix(hex, 16).ok().and_then(char::from_u32).unwrap_or('\u{FFFD}')
+                        }
+                        other => other as char,
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                c => bytes.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let text: &str = r#"{"jsonrpc":"2.0","method":"memory","params":{"address":768,"length":2},"id":[1,true,null,"a\"b\n"]}"#;
+        let value: Json = Json::parse(text).unwrap();
+        assert_eq!(value.get("method").and_then(Json::as_str), Some("memory"));
+        assert_eq!(value.get("params").and_then(|params| params.get("address")).and_then(Json::as_u64), Some(768));
+        assert_eq!(value.to_string(), text);
+        assert_eq!(Json::parse(" [ 1.5 , -2e3 ] ").unwrap(), Json::Array(vec![Json::Number(1.5), Json::Number(-2000.0)]));
+        assert_eq!(Json::parse(r#""é""#).unwrap(), Json::from("é"));
+        assert!(Json::parse("{\"a\":}").is_err());
+        assert!(Json::parse("[1] 2").is_err());
+    }
+}
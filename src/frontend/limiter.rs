@@ -0,0 +1,117 @@
+use std::time::{Duration, Instant};
+
+// thread::sleep can overshoot by a scheduler tick or so; the last stretch before a deadline is
+// spent yielding instead
+const SPIN: Duration = Duration::from_millis(2);
+// Further behind than this (a dropped window, the debugger, a slow ROM load) and the limiter starts
+// over from now rather than rushing frames to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+// Where the limiter reads the time and how it waits for a deadline: the system's, or in tests one
+// that only moves when told to
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn wait_until(&self, deadline: Instant);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant { Instant::now() }
+    fn wait_until(&self, deadline: Instant) {
+        loop {
+            let left: Duration = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() { break; }
+            if left > SPIN { std::thread::sleep(left - SPIN); } else { std::thread::yield_now(); }
+        }
+    }
+}
+
+// Paces the main loop at the console's own frame rate (Region::frame_rate, 60.0988 on NTSC) whatever
+// the monitor's refresh. Deadlines are kept on an absolute schedule, so oversleeping one pass is
+// made up on the next instead of adding up.
+pub struct FrameLimiter<C: Clock = SystemClock> {
+    clock: C,
+    period: Duration,
+    // When the current pass should end
+    deadline: Instant,
+}
+
+impl FrameLimiter {
+    pub fn new(frame_rate: f64) -> FrameLimiter { FrameLimiter::with_clock(frame_rate, SystemClock) }
+}
+
+impl<C: Clock> FrameLimiter<C> {
+    pub fn with_clock(frame_rate: f64, clock: C) -> FrameLimiter<C> {
+        let period: Duration = Duration::from_secs_f64(1.0 / frame_rate);
+        let deadline: Instant = clock.now() + period;
+        FrameLimiter { clock, period, deadline }
+    }
+    pub fn set_frame_rate(&mut self, frame_rate: f64) {
+        self.period = Duration::from_secs_f64(1.0 / frame_rate);
+        self.reset();
+    }
+    // Starts the schedule over, after the loop stood still
+    pub fn reset(&mut self) { self.deadline = self.clock.now() + self.period; }
+    // Whether this pass has used up its time, for fast-forward to stop emulating frames
+    pub fn over_budget(&self) -> bool { self.clock.now() >= self.deadline }
+    // Whole frames the loop has fallen behind the schedule, at the start of a pass
+    pub fn frames_behind(&self) -> u32 {
+        let late: Duration = self.clock.now().saturating_duration_since(self.deadline - self.period);
+        (late.as_secs_f64() / self.period.as_secs_f64()) as u32
+    }
+    // Counts `frames` more frames as emulated this pass, for frame skip to catch up with
+    pub fn catch_up(&mut self, frames: u32) { self.deadline += self.period * frames; }
+    // Waits for the end of this pass
+    pub fn wait(&mut self) {
+        let now: Instant = self.clock.now();
+        if now > self.deadline + MAX_LAG {
+            self.deadline = now;
+        }
+        self.clock.wait_until(self.deadline);
+        self.deadline += self.period;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Time only passes when waiting, or when the test moves it on
+    #[derive(Clone)]
+    struct TestClock { now: Rc<Cell<Instant>> }
+
+    impl TestClock {
+        fn advance(&self, duration: Duration) { self.now.set(self.now.get() + duration); }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> Instant { self.now.get() }
+        fn wait_until(&self, deadline: Instant) { self.now.set(self.now.get().max(deadline)); }
+    }
+
+    #[test]
+    fn test_paces_without_drifting() {
+        let clock: TestClock = TestClock { now: Rc::new(Cell::new(Instant::now())) };
+        let start: Instant = clock.now();
+        let mut limiter: FrameLimiter<TestClock> = FrameLimiter::with_clock(200.0, clock.clone());
+        // 10 passes of 5ms, the first starting at with_clock(); a pass that ran long is made up on the next
+        for pass in 0..10 {
+            if pass == 3 { clock.advance(Duration::from_millis(7)); }
+            limiter.wait();
+        }
+        assert_eq!(clock.now() - start, Duration::from_millis(50));
+        // Having fallen far behind, it starts over instead of rushing
+        clock.advance(MAX_LAG * 2);
+        limiter.wait();
+        assert!(!limiter.over_budget());
+        clock.advance(Duration::from_millis(12));
+        assert!(limiter.over_budget());
+        let behind: u32 = limiter.frames_behind();
+        assert_eq!(behind, 2);
+        limiter.catch_up(behind);
+        assert_eq!(limiter.frames_behind(), 0);
+    }
+}
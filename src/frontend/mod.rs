@@ -0,0 +1,29 @@
+// SDL2/rodio frontend: everything that talks to the window, keyboard and audio device
+pub mod audio;
+pub mod avi;
+pub mod cheats;
+pub mod clip;
+pub mod cli;
+pub mod config;
+pub mod debug;
+pub mod fps;
+pub mod gamepad;
+#[cfg(feature = "gui")]
+pub mod gui;
+pub mod headless;
+pub mod hexedit;
+pub mod input;
+pub mod limiter;
+#[cfg(feature = "rpc")]
+pub mod json;
+pub mod netplay;
+pub mod nsf;
+pub mod recent;
+pub mod remote;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod screenshot;
+pub mod slots;
+pub mod speed;
+pub mod wav;
+pub mod websocket;
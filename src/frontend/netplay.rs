@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use gbnes_core::Joypad;
+
+const MAGIC: &[u8; 4] = b"GBNP";
+const VERSION: u8 = 1;
+// How long to wait on the other player before giving up on the session
+const TIMEOUT: Duration = Duration::from_secs(30);
+// Each player's presses take effect this many frames later on both machines, which hides a round
+// trip of up to about as many frames
+pub const DEFAULT_DELAY: u8 = 2;
+// A compressed savestate is well under 1MB; a bigger one is a broken or hostile host
+const MAX_STATE: u32 = 16 << 20;
+
+// Two-player lockstep netplay over TCP. The host (player 1, joypad 1) sends the connecting player
+// (player 2, joypad 2) a savestate to start from, then both machines emulate the same frames with
+// the same buttons: before each frame the players swap their buttons for `delay` frames ahead,
+// along with a checksum of the RAM to catch the two machines drifting apart. Nothing else may
+// change the machine while connected (savestates, power cycles, the debugger, memory edits...) or
+// stop one of them (pausing), and both need the same ROM, region and emulation settings, which the
+// handshake checks.
+pub struct Netplay {
+    stream: TcpStream,
+    // 1 for the host, 2 for whoever connected
+    pub player: u8,
+    // The local player's controller: the keyboard and gamepads drive this one while connected, and
+    // exchange hands its buttons to the console's port for this player
+    pub input: Joypad,
+    delay: u8,
+    frame: u32,
+    // Local buttons sent but not played yet, and the checksum sent with them, oldest first
+    sent: VecDeque<(u8, u32)>,
+}
+
+impl Netplay {
+    // Waits for a player to connect on `port`. `session` describes the ROM and settings; the other
+    // side's must match.
+    pub fn host(port: u16, delay: u8, session: &str, state: &[u8]) -> Result<Netplay, String> {
+        let listener: TcpListener = TcpListener::bind(("0.0.0.0", port)).map_err(|err| format!("Could not listen on port {}: {}", port, err))?;
+        println!("Waiting for player 2 on port {}...", port);
+        Netplay::accept(&listener, delay, session, state)
+    }
+    pub fn accept(listener: &TcpListener, delay: u8, session: &str, state: &[u8]) -> Result<Netplay, String> {
+        let (mut stream, peer) = listener.accept().map_err(|err| format!("Netplay: {}", err))?;
+        setup(&stream)?;
+        let mut hello: Vec<u8> = MAGIC.to_vec();
+        hello.extend_from_slice(&[VERSION, delay]);
+        hello.extend_from_slice(&(session.len() as u16).to_le_bytes());
+        hello.extend_from_slice(session.as_bytes());
+        hello.extend_from_slice(&(state.len() as u32).to_le_bytes());
+        hello.extend_from_slice(state);
+        send(&mut stream, &hello)?;
+        let reply: String = read_text(&mut stream)?;
+        if reply != session { return Err(format!("Player 2 at {} has a different game or settings: {} (here {})", peer, reply, session)); }
+        println!("Player 2 connected from {}", peer);
+        Ok(Netplay::start(stream, 1, delay))
+    }
+    // Joins the game hosted at `address` (host:port), getting the savestate to start from
+    pub fn connect(address: &str, session: &str) -> Result<(Netplay, Vec<u8>), String> {
+        let mut stream: TcpStream = TcpStream::connect(address).map_err(|err| format!("Could not connect to {}: {}", address, err))?;
+        setup(&stream)?;
+        let hello: [u8; 6] = read_array(&mut stream)?;
+        if hello[..4] != MAGIC[..] || hello[4] != VERSION {
+            return Err(format!("{} isn't hosting a compatible netplay session", address));
+        }
+        let host_session: String = read_text(&mut stream)?;
+        let length: u32 = u32::from_le_bytes(read_array(&mut stream)?);
+        if length > MAX_STATE { return Err(format!("{} sent a {} byte savestate, more than the {} allowed", address, length, MAX_STATE)); }
+        let mut state: Vec<u8> = vec![0; length as usize];
+        stream.read_exact(&mut state).map_err(lost)?;
+        // Answered either way, so the host can tell what's wrong too
+        let mut reply: Vec<u8> = (session.len() as u16).to_le_bytes().to_vec();
+        reply.extend_from_slice(session.as_bytes());
+        send(&mut stream, &reply)?;
+        if host_session != session { return Err(format!("The host has a different game or settings: {} (here {})", host_session, session)); }
+        Ok((Netplay::start(stream, 2, hello[5]), state))
+    }
+    fn start(stream: TcpStream, player: u8, delay: u8) -> Netplay {
+        // Nobody presses anything during the first frames, before the first exchange takes effect
+        let sent: VecDeque<(u8, u32)> = (0..delay).map(|_| (0, 0)).collect();
+        Netplay { stream, player, input: Joypad::new(), delay, frame: 0, sent }
+    }
+    // Before emulating a frame: sends the local buttons and the checksum of the machine's state,
+    // and waits for the other player's for this frame. Returns joypad 1 and 2's buttons.
+    pub fn exchange(&mut self, buttons: u8, checksum: u32) -> Result<[u8; 2], String> {
+        let mut message: Vec<u8> = (self.frame + self.delay as u32).to_le_bytes().to_vec();
+        message.push(buttons);
+        message.extend_from_slice(&checksum.to_le_bytes());
+        send(&mut self.stream, &message)?;
+        self.sent.push_back((buttons, checksum));
+        let (local, local_checksum): (u8, u32) = self.sent.pop_front().unwrap_or_default();
+        let (remote, remote_checksum): (u8, u32) = match self.frame < self.delay as u32 {
+            true => (0, 0),
+            false => {
+                let message: [u8; 9] = read_array(&mut self.stream)?;
+                let frame: u32 = u32::from_le_bytes([message[0], message[1], message[2], message[3]]);
+                if frame != self.frame { return Err(format!("Netplay got frame {}'s input on frame {}", frame, self.frame)); }
+                (message[4], u32::from_le_bytes([message[5], message[6], message[7], message[8]]))
+            }
+        };
+        // Both checksums are of the state `delay` frames ago, when these buttons were sent
+        if remote_checksum != local_checksum { return Err(format!("Netplay desynced by frame {}", self.frame.saturating_sub(self.delay as u32))); }
+        self.frame += 1;
+        Ok(if self.player == 1 { [local, remote] } else { [remote, local] })
+    }
+}
+
+fn setup(stream: &TcpStream) -> Result<(), String> {
+    stream.set_nodelay(true).map_err(|err| format!("Netplay: {}", err))?;
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(|err| format!("Netplay: {}", err))
+}
+fn lost(err: std::io::Error) -> String { format!("Netplay connection lost: {}", err) }
+fn send(stream: &mut TcpStream, bytes: &[u8]) -> Result<(), String> { stream.write_all(bytes).map_err(lost) }
+fn read_array<const N: usize>(stream: &mut TcpStream) -> Result<[u8; N], String> {
+    let mut bytes: [u8; N] = [0; N];
+    stream.read_exact(&mut bytes).map_err(lost)?;
+    Ok(bytes)
+}
+// A string after its u16 length
+fn read_text(stream: &mut TcpStream) -> Result<String, String> {
+    let mut text: Vec<u8> = vec![0; u16::from_le_bytes(read_array(stream)?) as usize];
+    stream.read_exact(&mut text).map_err(lost)?;
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type Joined = Result<(Netplay, Vec<u8>), String>;
+
+    fn session(host_session: &'static str, client_session: &'static str) -> (Result<Netplay, String>, Joined) {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address: String = listener.local_addr().unwrap().to_string();
+        let client = std::thread::spawn(move || Netplay::connect(&address, client_session));
+        let host: Result<Netplay, String> = Netplay::accept(&listener, 1, host_session, &[1, 2, 3]);
+        (host, client.join().unwrap())
+    }
+
+    #[test]
+    fn test_lockstep() {
+        let (host, client) = session("ROM 12345678", "ROM 12345678");
+        let (mut host, (mut client, state)): (Netplay, (Netplay, Vec<u8>)) = (host.unwrap(), client.unwrap());
+        assert_eq!((state, host.player, client.player), (vec![1, 2, 3], 1, 2));
+        let player2 = std::thread::spawn(move || {
+            let pads: Vec<[u8; 2]> = [0x20, 0x40, 0x80].iter().map(|buttons| client.exchange(*buttons, 7).unwrap()).collect();
+            let error: Result<[u8; 2], String> = client.exchange(0, 8);
+            (pads, error, client)
+        });
+        let pads: Vec<[u8; 2]> = [0x01, 0x02, 0x04].iter().map(|buttons| host.exchange(*buttons, 7).unwrap()).collect();
+        // One frame of delay: nothing on the first frame, then each side's buttons a frame late
+        assert_eq!(pads, vec![[0, 0], [0x01, 0x20], [0x02, 0x40]]);
+        let error: Result<[u8; 2], String> = host.exchange(0, 9);
+        let (client_pads, client_error, _client) = player2.join().unwrap();
+        assert_eq!(client_pads, pads);
+        // The checksums sent on the frame before disagree
+        assert!(error.is_ok() && client_error.is_ok());
+        assert!(host.exchange(0, 0).unwrap_err().contains("desynced"));
+    }
+
+    #[test]
+    fn test_different_games() {
+        let (host, client) = session("ROM 12345678", "ROM 87654321");
+        assert!(host.is_err() && client.is_err());
+    }
+
+    #[test]
+    fn test_oversized_state() {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address: String = listener.local_addr().unwrap().to_string();
+        let client = std::thread::spawn(move || Netplay::connect(&address, "ROM 12345678"));
+        let mut host: TcpStream = listener.accept().unwrap().0;
+        let mut hello: Vec<u8> = MAGIC.to_vec();
+        hello.extend_from_slice(&[VERSION, 1, 12, 0]);
+        hello.extend_from_slice(b"ROM 12345678");
+        hello.extend_from_slice(&u32::MAX.to_le_bytes());
+        host.write_all(&hello).unwrap();
+        let joined: Joined = client.join().unwrap();
+        assert!(joined.is_err_and(|err| err.contains("savestate")));
+    }
+}
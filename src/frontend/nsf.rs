@@ -0,0 +1,170 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use sdl2::event::Event;
+use sdl2::pixels::PixelFormatEnum;
+
+use gbnes_core::{Frame, Nsf, NsfPlayer};
+use gbnes_core::apu::CHANNELS;
+use gbnes_core::apu::mixer::{Channel, Mixer};
+use gbnes_core::render::osd::{self, Osd};
+
+use super::audio::{AudioOutput, AudioQueue};
+use super::cli::flag_value;
+use super::config::Config;
+use super::input::{Bindings, Command};
+use super::wav::WavRecorder;
+use crate::{finish_recording, notify, warn};
+
+const BAR_WIDTH: usize = 32;
+const BAR_SPACING: usize = 44;
+const BAR_BOTTOM: usize = 200;
+const BAR_HEIGHT: usize = 120;
+// How much of a bar is left a frame after its channel goes quiet
+const BAR_DECAY: f32 = 0.85;
+
+// The NSF player's picture: the tune's title, artist and copyright, the song number, and one level
+// bar per APU channel (the player has no expansion sound chips). Bars jump up to a channel's
+// loudest level in a frame and fall back slowly.
+#[derive(Default)]
+pub struct Visualizer {
+    bars: [f32; 5],
+}
+
+impl Visualizer {
+    pub fn draw(&mut self, player: &NsfPlayer, mixer: &Mixer, frame: &mut Frame) {
+        *frame = Frame::new();
+        osd::draw_text(frame, 16, 16, &player.nsf.title, (0xFF, 0xFF, 0xFF));
+        osd::draw_text(frame, 16, 28, &player.nsf.artist, (0xB0, 0xB0, 0xB0));
+        osd::draw_text(frame, 16, 40, &player.nsf.copyright, (0xB0, 0xB0, 0xB0));
+        osd::draw_text(frame, 16, 60, &format!("Song {}/{}", player.song(), player.nsf.songs), (0xFF, 0xFF, 0x00));
+        for (i, (channel, level)) in Channel::ALL.into_iter().zip(player.levels()).take(5).enumerate() {
+            let full: f64 = if channel == Channel::Dmc { 127.0 } else { 15.0 };
+            self.bars[i] = (level / full).max((self.bars[i] * BAR_DECAY) as f64) as f32;
+            let color: (u8, u8, u8) = if mixer.muted[i] || mixer.mute_all { (0x50, 0x50, 0x50) } else { (0x40, 0xC0, 0x60) };
+            let left: usize = 20 + i * BAR_SPACING;
+            let height: usize = (self.bars[i].min(1.0) * BAR_HEIGHT as f32) as usize;
+            for y in BAR_BOTTOM - height..BAR_BOTTOM {
+                for x in left..left + BAR_WIDTH { frame.set_pixel(x, y, color); }
+            }
+            osd::draw_text(frame, left, BAR_BOTTOM + 6, channel.name(), (0xFF, 0xFF, 0xFF));
+        }
+    }
+}
+
+// The window for an .nsf file instead of a game: plays the tune and draws the Visualizer, with the
+// next/previous track keys switching songs and the pause, volume, mute and recording keys as usual
+pub fn play(path: &str, nsf: Nsf, config: &Config, args: &[String]) {
+    println!("NSF: {} by {} ({} songs)", nsf.title, nsf.artist, nsf.songs);
+    let title: String = format!("{} - GBNesmulator", if nsf.title.is_empty() { path } else { &nsf.title });
+    let frame_rate: f64 = nsf.region.frame_rate();
+    let mut player: NsfPlayer = NsfPlayer::new(nsf);
+    player.cpu.bus.apu().mixer = config.mixer();
+    let bindings: Bindings = Bindings::from_config(config).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+
+    let sdl_context: sdl2::Sdl = sdl2::init().unwrap();
+    let window = sdl_context.video().unwrap()
+        .window(&title, (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
+        .position_centered()
+        .build()
+        .unwrap();
+    let mut canvas: sdl2::render::Canvas<sdl2::video::Window> = window.into_canvas().present_vsync().build().unwrap();
+    let mut event_pump: sdl2::EventPump = sdl_context.event_pump().unwrap();
+    canvas.set_scale(3.0, 3.0).unwrap();
+    let creator = canvas.texture_creator();
+    let mut texture = creator.create_texture_target(PixelFormatEnum::RGB24, 256, 240).unwrap();
+
+    let audio: AudioQueue = AudioQueue::for_latency(44100, config.audio_latency());
+    let audio_output: AudioOutput = AudioOutput::open(config.audio_backend(), audio.clone(), 44100, config.number("audio.buffer_size")).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let audio_rate: f64 = audio_output.sample_rate(44100) as f64;
+    let mut recorder: Option<WavRecorder> = flag_value(args, "--record-audio").map(|path| {
+        WavRecorder::create(Path::new(&path), audio_rate as u32, CHANNELS as u16).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    });
+    let vsync: Duration = Duration::from_secs_f64(1.0 / frame_rate);
+    let mut last_frame: Instant = Instant::now();
+    let mut osd: Osd = Osd::new();
+    let mut visualizer: Visualizer = Visualizer::default();
+    let mut screen: Frame = Frame::new();
+    let mut paused: bool = false;
+    loop {
+        let mut commands: Vec<Command> = Vec::new();
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } => commands.push(Command::Quit),
+                Event::KeyDown { keycode: Some(keycode), keymod, repeat: false, .. } => commands.extend(bindings.command(keycode, keymod)),
+                _ => {}
+            }
+        }
+        for command in commands {
+            match command {
+                Command::Track(step) => {
+                    player.start(player.song().wrapping_add_signed(step));
+                    notify(&mut osd, format!("Song {}/{}", player.song(), player.nsf.songs));
+                }
+                Command::TogglePause => {
+                    paused = !paused;
+                    notify(&mut osd, String::from(if paused { "Paused" } else { "Resumed" }));
+                }
+                Command::ToggleMute(channel) => {
+                    let muted: bool = player.cpu.bus.apu().mixer.toggle_mute(channel);
+                    notify(&mut osd, format!("{}: {}", channel.name(), if muted { "muted" } else { "on" }));
+                }
+                Command::MasterVolume(step) => {
+                    let mixer: &mut Mixer = &mut player.cpu.bus.apu().mixer;
+                    mixer.set_master(((mixer.master + step as f32 * 0.05) * 20.0).round() / 20.0);
+                    mixer.mute_all = false;
+                    notify(&mut osd, format!("Volume: {:.0}%", mixer.master * 100.0));
+                }
+                Command::ToggleMuteAll => {
+                    let mixer: &mut Mixer = &mut player.cpu.bus.apu().mixer;
+                    mixer.mute_all = !mixer.mute_all;
+                    notify(&mut osd, String::from(if mixer.mute_all { "Sound muted" } else { "Sound on" }));
+                }
+                Command::ToggleAudioRecording => match recorder.take() {
+                    Some(recording) => finish_recording(&mut osd, recording),
+                    None => match WavRecorder::create(&WavRecorder::default_path(path), audio_rate as u32, CHANNELS as u16) {
+                        Ok(recording) => {
+                            notify(&mut osd, format!("Recording audio to {}", recording.path().display()));
+                            recorder = Some(recording);
+                        }
+                        Err(err) => warn(&mut osd, err),
+                    },
+                },
+                Command::Quit => {
+                    if let Some(recording) = recorder.take() { finish_recording(&mut osd, recording); }
+                    std::process::exit(0);
+                }
+                // Savestates, video and debugging keys have no game to work on
+                _ => {}
+            }
+        }
+        if !paused {
+            player.cpu.bus.apu().set_sample_rate(audio.controlled_rate(audio_rate));
+            player.run_frame();
+            if let Some(Err(err)) = recorder.as_mut().map(|recorder| recorder.write(player.audio())) {
+                warn(&mut osd, format!("Audio recording stopped: {}", err));
+                recorder = None;
+            }
+            audio.push(player.audio(), 1);
+        }
+        let mixer: Mixer = player.cpu.bus.apu().mixer.clone();
+        visualizer.draw(&player, &mixer, &mut screen);
+        osd.draw(&mut screen);
+        osd.tick();
+        texture.update(None, &screen.data, 256 * 3).unwrap();
+        canvas.copy(&texture, None, None).unwrap();
+        canvas.present();
+        let elapsed: Duration = last_frame.elapsed();
+        if elapsed < vsync { std::thread::sleep(vsync - elapsed); }
+        last_frame = Instant::now();
+    }
+}
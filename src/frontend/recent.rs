@@ -0,0 +1,98 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use sdl2::keyboard::Keycode;
+use gbnes_core::Frame;
+use gbnes_core::render::osd;
+
+// One per number key of the quick menu
+pub const MAX_RECENT: usize = 9;
+const LINE_HEIGHT: usize = 10;
+const MARGIN: usize = 8;
+
+// The last games played, newest first, kept in recent.txt under the config directory as one
+// absolute path per line, for `--recent` and the in-game quick menu (O, then 1..9). NSF files
+// aren't added, since the quick menu can only swap in cartridges.
+pub struct RecentRoms {
+    path: PathBuf,
+    pub roms: Vec<String>,
+}
+
+impl RecentRoms {
+    // No file yet just means nothing played
+    pub fn load(config_dir: &Path) -> Self {
+        let path: PathBuf = config_dir.join("recent.txt");
+        let roms: Vec<String> = std::fs::read_to_string(&path).map(|text| parse(&text)).unwrap_or_default();
+        RecentRoms { path, roms }
+    }
+    // The N-th most recent game, from 1
+    pub fn get(&self, number: u8) -> Option<&str> { self.roms.get((number as usize).checked_sub(1)?).map(String::as_str) }
+    // Moves `rom_path` to the top of the list and writes it out
+    pub fn add(&mut self, rom_path: &str) -> Result<(), String> {
+        let rom_path: String = std::fs::canonicalize(rom_path).map_or(String::from(rom_path), |path| path.display().to_string());
+        self.roms.retain(|rom| *rom != rom_path);
+        self.roms.insert(0, rom_path);
+        self.roms.truncate(MAX_RECENT);
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|err| format!("Could not create {}: {}", dir.display(), err))?;
+        }
+        std::fs::write(&self.path, self.roms.join("\n") + "\n").map_err(|err| format!("Could not write {}: {}", self.path.display(), err))
+    }
+    // `--recent N` loads the N-th game; a bare `--recent` lists them on the terminal and asks (default 1)
+    pub fn pick(&self, args: &[String]) -> Result<String, String> {
+        if self.roms.is_empty() { return Err(String::from("No recent ROMs yet")); }
+        let number: u8 = match args.iter().position(|arg| arg == "--recent").and_then(|i| args.get(i + 1)).and_then(|n| n.parse().ok()) {
+            Some(number) => number,
+            None => {
+                for (i, rom) in self.roms.iter().enumerate() { println!("{}: {}", i + 1, rom); }
+                print!("Load which? [1] ");
+                std::io::stdout().flush().ok();
+                let mut answer: String = String::new();
+                std::io::stdin().read_line(&mut answer).map_err(|err| err.to_string())?;
+                if answer.trim().is_empty() { 1 } else { answer.trim().parse().map_err(|_| format!("Expected a number, got {:?}", answer.trim()))? }
+            }
+        };
+        self.get(number).map(String::from).ok_or(format!("No recent ROM {} (there are {})", number, self.roms.len()))
+    }
+}
+
+fn parse(text: &str) -> Vec<String> {
+    text.lines().map(str::trim).filter(|line| !line.is_empty()).take(MAX_RECENT).map(String::from).collect()
+}
+
+// The quick menu's pick for a number key, if it is one
+pub fn number_key(keycode: Keycode) -> Option<u8> {
+    const KEYS: [Keycode; MAX_RECENT] = [
+        Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4, Keycode::Num5,
+        Keycode::Num6, Keycode::Num7, Keycode::Num8, Keycode::Num9,
+    ];
+    KEYS.iter().position(|key| *key == keycode).map(|i| i as u8 + 1)
+}
+
+// The games' file names, numbered over a dimmed picture like the memory editor
+pub fn draw(recent: &RecentRoms, frame: &mut Frame) {
+    for channel in frame.data.iter_mut() { *channel /= 4; }
+    osd::draw_text(frame, MARGIN, MARGIN, "RECENT ROMS (1-9, ESC CLOSES)", (0xFF, 0xFF, 0x00));
+    if recent.roms.is_empty() { osd::draw_text(frame, MARGIN, MARGIN + 2 * LINE_HEIGHT, "NONE YET", (0xFF, 0xFF, 0xFF)); }
+    for (i, rom) in recent.roms.iter().enumerate() {
+        let name = Path::new(rom).file_name().map_or(rom.clone(), |name| name.to_string_lossy().into_owned());
+        osd::draw_text(frame, MARGIN, MARGIN + (i + 2) * LINE_HEIGHT, &format!("{} {}", i + 1, name), (0xFF, 0xFF, 0xFF));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_recent_list() {
+        let mut recent: RecentRoms = RecentRoms { path: std::env::temp_dir().join(format!("gbnesmulator-test-recent-{}.txt", std::process::id())), roms: parse("a.nes\n\n b.nes \n") };
+        assert_eq!(recent.roms, ["a.nes", "b.nes"]);
+        recent.add("b.nes").unwrap();
+        assert_eq!((recent.get(1), recent.get(2), recent.get(0), recent.get(3)), (Some("b.nes"), Some("a.nes"), None, None));
+        for n in 0..MAX_RECENT { recent.add(&format!("{}.nes", n)).unwrap(); }
+        assert_eq!((recent.roms.len(), recent.get(1)), (MAX_RECENT, Some("8.nes")));
+        std::fs::remove_file(&recent.path).ok();
+        assert_eq!(number_key(Keycode::Num3), Some(3));
+    }
+}
@@ -0,0 +1,181 @@
+use std::net::{SocketAddr, TcpListener};
+
+use gbnes_core::{movie, JoypadButton};
+
+use super::input::{named_action, Action, Command};
+use super::websocket::{Handshake, WebSocket};
+
+// What a remote client asked for; see Remote
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    // Joypad 1's buttons at once, or some pressed (true) or released
+    Buttons(u8),
+    Press(Vec<JoypadButton>, bool),
+    // Bytes of CPU memory from an address
+    Read(u16, usize),
+    SaveState(u8),
+    LoadState(u8),
+    LoadRom(String),
+    Hotkey(Command),
+    // Whether to stream frame events
+    Frames(bool),
+    Status,
+}
+
+impl Request {
+    pub fn parse(text: &str) -> Result<Request, String> {
+        let text: &str = text.trim();
+        let (name, rest): (&str, &str) = text.split_once(char::is_whitespace).map_or((text, ""), |(name, rest)| (name, rest.trim()));
+        let words: Vec<&str> = rest.split_whitespace().collect();
+        let slot = || words.first().and_then(|slot| slot.parse::<u8>().ok()).filter(|slot| (1..=10).contains(slot)).ok_or(String::from("expected a slot from 1 to 10"));
+        match name {
+            "buttons" => words.first().and_then(|pad| movie::parse_pad(pad)).map(Request::Buttons)
+                .ok_or(format!("expected {} button columns ({})", movie::PAD_BUTTONS.len(), String::from_utf8_lossy(movie::PAD_BUTTONS))),
+            "press" | "release" => {
+                let buttons: Vec<JoypadButton> = words.iter().map(|word| match named_action(word) {
+                    Some(Action::Joypad(button)) => Ok(button),
+                    _ => Err(format!("unknown button {:?}", word)),
+                }).collect::<Result<Vec<JoypadButton>, String>>()?;
+                if buttons.is_empty() { return Err(String::from("expected button names")); }
+                Ok(Request::Press(buttons, name == "press"))
+            }
+            "read" => {
+                let addr: u16 = words.first().and_then(|addr| u16::from_str_radix(addr.trim_start_matches('$'), 16).ok()).ok_or(String::from("expected a hex address"))?;
+                let length: usize = match words.get(1) {
+                    Some(length) => length.parse::<usize>().map_err(|_| format!("expected a length, got {:?}", length))?,
+                    None => 1,
+                };
+                Ok(Request::Read(addr, length.min(0x10000 - addr as usize)))
+            }
+            "save" => Ok(Request::SaveState(slot()?)),
+            "load" => Ok(Request::LoadState(slot()?)),
+            // The rest of the line, so paths can have spaces
+            "load_rom" if !rest.is_empty() => Ok(Request::LoadRom(String::from(rest))),
+            "load_rom" => Err(String::from("expected a ROM path")),
+            "hotkey" => match words.first().and_then(|action| named_action(action)) {
+                Some(Action::Command(command)) => Ok(Request::Hotkey(command)),
+                _ => Err(format!("unknown hotkey action {:?}", rest)),
+            },
+            "frames" => match rest {
+                "on" => Ok(Request::Frames(true)),
+                "off" => Ok(Request::Frames(false)),
+                _ => Err(String::from("expected on or off")),
+            },
+            "status" => Ok(Request::Status),
+            _ => Err(format!("unknown request {:?}", name)),
+        }
+    }
+}
+
+struct Client {
+    id: u32,
+    socket: WebSocket,
+    frames: bool,
+}
+
+// `--remote PORT`: a WebSocket server on localhost for bots and test orchestration. Every text
+// message is one request and gets one reply, `ok` plus any result or `error` plus why:
+//
+//   buttons R......A   joypad 1's buttons, in input script columns (RLDUTSBA)
+//   press a start      press buttons by their [keys] names; `release` lets go of them
+//   read 0300 16       bytes of CPU memory as hex (hex address, length 1 by default), side-effect free
+//   save 1 / load 1    a savestate slot
+//   load_rom PATH      swaps in another game
+//   hotkey pause       anything a hotkey does, by its [keys] action name
+//   frames on          streams `frame N CRC32` (frame count and picture checksum) after every frame
+//   status             `ok frame N running` (or paused) and the ROM path
+//
+// Requests are handled between frames, like hotkeys. Browser pages may connect only from the
+// origins in remote.origins.
+pub struct Remote {
+    listener: TcpListener,
+    origins: Vec<String>,
+    handshakes: Vec<(SocketAddr, Handshake)>,
+    clients: Vec<Client>,
+    next_id: u32,
+}
+
+impl Remote {
+    pub fn listen(port: u16, origins: Vec<String>) -> Result<Remote, String> {
+        let listener: TcpListener = TcpListener::bind(("127.0.0.1", port)).map_err(|err| format!("Could not listen on port {}: {}", port, err))?;
+        listener.set_nonblocking(true).map_err(|err| format!("Remote: {}", err))?;
+        println!("Remote control on ws://127.0.0.1:{}", port);
+        Ok(Remote { listener, origins, handshakes: Vec::new(), clients: Vec::new(), next_id: 0 })
+    }
+    // Takes new connections and returns the requests that arrived, by client; bad requests are
+    // answered here
+    pub fn poll(&mut self) -> Vec<(u32, Request)> {
+        while let Ok((stream, peer)) = self.listener.accept() {
+            match Handshake::new(stream) {
+                Ok(handshake) => self.handshakes.push((peer, handshake)),
+                Err(err) => eprintln!("Remote client {}: {}", peer, err),
+            }
+        }
+        let mut i: usize = 0;
+        while i < self.handshakes.len() {
+            match self.handshakes[i].1.poll(&self.origins) {
+                Ok(false) => i += 1,
+                Ok(true) => {
+                    let (peer, handshake): (SocketAddr, Handshake) = self.handshakes.remove(i);
+                    println!("Remote client {} connected", peer);
+                    self.clients.push(Client { id: self.next_id, socket: handshake.into_socket(), frames: false });
+                    self.next_id += 1;
+                }
+                Err(err) => eprintln!("Remote client {}: {}", self.handshakes.remove(i).0, err),
+            }
+        }
+        let mut requests: Vec<(u32, Request)> = Vec::new();
+        self.clients.retain_mut(|client| {
+            let messages: Vec<String> = match client.socket.poll() {
+                Ok(messages) => messages,
+                Err(_) => return false,
+            };
+            for message in messages {
+                match Request::parse(&message) {
+                    Ok(request) => requests.push((client.id, request)),
+                    Err(err) => if client.socket.send(&format!("error {}", err)).is_err() { return false; },
+                }
+            }
+            true
+        });
+        requests
+    }
+    pub fn answer(&mut self, id: u32, answer: Result<String, String>) {
+        let text: String = match answer {
+            Ok(result) if result.is_empty() => String::from("ok"),
+            Ok(result) => format!("ok {}", result),
+            Err(err) => format!("error {}", err),
+        };
+        self.send(|client| client.id == id, &text);
+    }
+    pub fn subscribe(&mut self, id: u32, frames: bool) {
+        if let Some(client) = self.clients.iter_mut().find(|client| client.id == id) { client.frames = frames; }
+    }
+    // After each emulated frame, for the clients streaming frames
+    pub fn frame(&mut self, number: u64, hash: u32) {
+        if self.clients.iter().any(|client| client.frames) { self.send(|client| client.frames, &format!("frame {} {:08X}", number, hash)); }
+    }
+    fn send(&mut self, to: impl Fn(&Client) -> bool, text: &str) {
+        self.clients.retain_mut(|client| !to(client) || client.socket.send(text).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_requests() {
+        assert_eq!(Request::parse("buttons R......A"), Ok(Request::Buttons(0b1000_0001)));
+        assert_eq!(Request::parse("press a start"), Ok(Request::Press(vec![JoypadButton::ButtonA, JoypadButton::Start], true)));
+        assert_eq!(Request::parse("read $FFFC 16"), Ok(Request::Read(0xFFFC, 4)));
+        assert_eq!(Request::parse("read 300"), Ok(Request::Read(0x300, 1)));
+        assert_eq!(Request::parse("load_rom games/Some Game.nes"), Ok(Request::LoadRom(String::from("games/Some Game.nes"))));
+        assert_eq!(Request::parse("hotkey pause"), Ok(Request::Hotkey(Command::TogglePause)));
+        assert_eq!(Request::parse(" frames on "), Ok(Request::Frames(true)));
+        assert!(Request::parse("save 11").is_err());
+        assert!(Request::parse("hotkey a").is_err());
+        assert!(Request::parse("press jump").is_err());
+        assert!(Request::parse("reboot").is_err());
+    }
+}
@@ -0,0 +1,365 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+use gbnes_core::apu::APU;
+use gbnes_core::apu::mixer::Channel;
+use gbnes_core::hexedit::{self, Space};
+use gbnes_core::Headless;
+
+use super::json::Json;
+
+// How long a connection has to send its request and take in the answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+// Requests are a few hundred bytes; anything past this is refused
+const MAX_REQUEST: usize = 1 << 16;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// `--rpc PORT` (in builds with the rpc feature): JSON-RPC 2.0 over HTTP POST on localhost, for
+// dashboards and tools to look at the running game. Methods, all side-effect free:
+//
+//   status                       frame count, ROM CRC32 and region
+//   cpu                          registers and cycle count
+//   memory {space, address, length}  bytes of cpu (default), ppu, oam or palette memory
+//   ppu                          PPUCTRL/MASK/STATUS, position, and the loopy v/t/x/w scroll registers
+//   apu                          each channel's state and output level
+//
+// e.g. `curl -d '{"jsonrpc":"2.0","method":"cpu","id":1}' localhost:PORT`. Batches work too.
+// Requests are answered between frames, so they see the machine at a frame boundary. Browsers only
+// get in from the pages in remote.origins, like with --remote.
+pub struct RpcServer {
+    listener: TcpListener,
+    origins: Vec<String>,
+    connections: Vec<Connection>,
+}
+
+// A connection being served a piece at a time, so a slow or idle client never holds up the frame loop
+struct Connection {
+    stream: TcpStream,
+    // The request so far
+    request: Vec<u8>,
+    // What's left of the answer, once the whole request is in
+    response: Option<Vec<u8>>,
+    started: Instant,
+}
+
+impl RpcServer {
+    pub fn listen(port: u16, origins: Vec<String>) -> Result<RpcServer, String> {
+        let listener: TcpListener = TcpListener::bind(("127.0.0.1", port)).map_err(|err| format!("Could not listen on port {}: {}", port, err))?;
+        listener.set_nonblocking(true).map_err(|err| format!("RPC: {}", err))?;
+        println!("JSON-RPC on http://127.0.0.1:{}", port);
+        Ok(RpcServer { listener, origins, connections: Vec::new() })
+    }
+    // Takes in what the clients sent since the last poll, answers the requests that are complete and
+    // sends what it can of the answers, all without blocking. Connections not done within
+    // REQUEST_TIMEOUT are dropped.
+    pub fn poll(&mut self, nes: &mut Headless) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            // Accepted sockets don't inherit a non-blocking listener's mode on every platform
+            match stream.set_nonblocking(true) {
+                Ok(()) => self.connections.push(Connection { stream, request: Vec::new(), response: None, started: Instant::now() }),
+                Err(err) => eprintln!("RPC: {}", err),
+            }
+        }
+        let origins: &[String] = &self.origins;
+        self.connections.retain_mut(|connection| match connection.poll(origins, nes) {
+            Ok(false) if connection.started.elapsed() > REQUEST_TIMEOUT => {
+                eprintln!("RPC: dropped a connection that took longer than {:?}", REQUEST_TIMEOUT);
+                false
+            }
+            Ok(done) => !done,
+            Err(err) => {
+                eprintln!("RPC: {}", err);
+                false
+            }
+        });
+    }
+}
+
+impl Connection {
+    // Whether the answer is all sent
+    fn poll(&mut self, origins: &[String], nes: &mut Headless) -> Result<bool, String> {
+        if self.response.is_none() {
+            let Some((head, body)) = self.read()? else { return Ok(false) };
+            self.response = Some(answer(&head, &body, origins, nes).into_bytes());
+        }
+        let Some(response) = self.response.as_mut() else { return Ok(false) };
+        while !response.is_empty() {
+            match self.stream.write(response) {
+                Ok(0) => return Err(String::from("connection closed mid-response")),
+                Ok(length) => { response.drain(..length); }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+        Ok(true)
+    }
+    // The head and body, once the whole request is in
+    fn read(&mut self) -> Result<Option<(String, Vec<u8>)>, String> {
+        let mut buffer: [u8; 4096] = [0; 4096];
+        let closed: bool = loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => break true,
+                Ok(length) => self.request.extend_from_slice(&buffer[..length]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break false,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.to_string()),
+            }
+            if self.request.len() > MAX_REQUEST {
+                // Small enough to go out whole into an empty send buffer
+                self.stream.write_all(response("413 Payload Too Large", "", None).as_bytes()).ok();
+                return Err(format!("refused a request over {} bytes", MAX_REQUEST));
+            }
+        };
+        let complete: Option<(String, Vec<u8>)> = self.request.windows(4).position(|window| window == b"\r\n\r\n").and_then(|end| {
+            let head: String = String::from_utf8_lossy(&self.request[..end]).into_owned();
+            let content_length: usize = head.lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim().eq_ignore_ascii_case("content-length").then(|| value.trim().parse::<usize>().ok())?
+            }).unwrap_or(0);
+            let body: &[u8] = self.request.get(end + 4..end + 4 + content_length)?;
+            Some((head, body.to_vec()))
+        });
+        match complete {
+            None if closed => Err(String::from("connection closed mid-request")),
+            complete => Ok(complete),
+        }
+    }
+}
+
+// The HTTP response to a whole request
+fn answer(head: &str, body: &[u8], origins: &[String], nes: &mut Headless) -> String {
+    // Only browsers send an Origin; pages not in remote.origins are turned away before anything runs,
+    // since a plain form POST doesn't wait for the answer to be allowed
+    let origin: Option<&str> = head.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("origin").then_some(value.trim())
+    });
+    if let Some(origin) = origin.filter(|origin| !origins.iter().any(|allowed| allowed == origin)) {
+        eprintln!("RPC: refused a request from {}, which is not in remote.origins", origin);
+        return response("403 Forbidden", "", None);
+    }
+    match head.split_whitespace().next() {
+        Some("POST") => response("200 OK", &handle(&String::from_utf8_lossy(body), nes), origin),
+        // Browsers ask before posting JSON from another origin
+        Some("OPTIONS") => response("204 No Content", "", origin),
+        _ => response("405 Method Not Allowed", "", origin),
+    }
+}
+
+// `origin` is the allowed page the request came from, if any, which the browser is told may read the answer
+fn response(status: &str, body: &str, origin: Option<&str>) -> String {
+    let cors: String = origin.map_or(String::new(), |origin| {
+        format!("Access-Control-Allow-Origin: {}\r\nAccess-Control-Allow-Headers: Content-Type\r\nVary: Origin\r\n", origin)
+    });
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status, body.len(), cors, body,
+    )
+}
+
+// The reply to a request body: one response, a batch of them, or nothing for notifications only
+fn handle(body: &str, nes: &mut Headless) -> String {
+    match Json::parse(body) {
+        Err(err) => failure(Json::Null, PARSE_ERROR, &err).to_string(),
+        Ok(Json::Array(calls)) if calls.is_empty() => failure(Json::Null, INVALID_REQUEST, "empty batch").to_string(),
+        Ok(Json::Array(calls)) => {
+            let replies: Vec<Json> = calls.iter().filter_map(|call| call_method(call, nes)).collect();
+            if replies.is_empty() { String::new() } else { Json::Array(replies).to_string() }
+        }
+        Ok(call) => call_method(&call, nes).map(|reply| reply.to_string()).unwrap_or_default(),
+    }
+}
+
+// None for a notification (no id), which gets no reply
+fn call_method(call: &Json, nes: &mut Headless) -> Option<Json> {
+    let id: Option<Json> = call.get("id").cloned();
+    let method: Option<&str> = call.get("method").and_then(Json::as_str);
+    let result: Result<Json, (i64, String)> = match method {
+        _ if call.get("jsonrpc").and_then(Json::as_str) != Some("2.0") => Err((INVALID_REQUEST, String::from("expected jsonrpc 2.0"))),
+        None => Err((INVALID_REQUEST, String::from("expected a method"))),
+        Some(method) => inspect(method, call.get("params").unwrap_or(&Json::Null), nes),
+    };
+    let id: Json = id?;
+    Some(match result {
+        Ok(result) => Json::object(vec![("jsonrpc", Json::from("2.0")), ("result", result), ("id", id)]),
+        Err((code, message)) => failure(id, code, &message),
+    })
+}
+
+fn failure(id: Json, code: i64, message: &str) -> Json {
+    let error: Json = Json::object(vec![("code", Json::from(code)), ("message", Json::from(message))]);
+    Json::object(vec![("jsonrpc", Json::from("2.0")), ("error", error), ("id", id)])
+}
+
+fn inspect(method: &str, params: &Json, nes: &mut Headless) -> Result<Json, (i64, String)> {
+    let cpu = &mut nes.cpu;
+    match method {
+        "status" => Ok(Json::object(vec![
+            ("frame", Json::from(cpu.bus.frames)),
+            ("rom_crc32", Json::from(format!("{:08X}", cpu.bus.rom_crc32).as_str())),
+            ("region", Json::from(format!("{:?}", cpu.bus.ppu().region).as_str())),
+        ])),
+        "cpu" => Ok(Json::object(vec![
+            ("a", Json::from(cpu.register_a)),
+            ("x", Json::from(cpu.register_x)),
+            ("y", Json::from(cpu.register_y)),
+            ("p", Json::from(cpu.status)),
+            ("sp", Json::from(cpu.stack_pointer)),
+            ("pc", Json::from(cpu.program_counter)),
+            ("cycles", Json::from(cpu.bus.cycles)),
+        ])),
+        "memory" => {
+            let space: Space = match params.get("space") {
+                Some(name) => Space::parse(name.as_str().ok_or_else(|| invalid("space: expected a string"))?).map_err(|err| invalid(&err))?,
+                None => Space::Cpu,
+            };
+            let address: u64 = params.get("address").and_then(Json::as_u64).ok_or_else(|| invalid("address: expected a number"))?;
+            let length: u64 = params.get("length").map_or(Some(1), Json::as_u64).ok_or_else(|| invalid("length: expected a number"))?;
+            if address >= space.size() as u64 { return Err(invalid(&format!("address: {} memory ends at {}", space.name(), space.size()))); }
+            let length: u64 = length.min(space.size() as u64 - address);
+            let data: Vec<Json> = (address..address + length).map(|addr| Json::from(hexedit::peek(cpu, space, addr as u16))).collect();
+            Ok(Json::object(vec![("space", Json::from(space.name())), ("address", Json::from(address)), ("data", Json::Array(data))]))
+        }
+        "ppu" => {
+            let ppu = cpu.bus.ppu();
+            Ok(Json::object(vec![
+                ("ctrl", Json::from(ppu.ctrl)),
+                ("mask", Json::from(ppu.mask)),
+                ("status", Json::from(ppu.status)),
+                ("scanline", Json::from(ppu.scanline)),
+                ("dot", Json::from(ppu.cycles)),
+                ("oam_addr", Json::from(ppu.oam_addr)),
+                ("v", Json::from(ppu.loopy.v)),
+                ("t", Json::from(ppu.loopy.t)),
+                ("x", Json::from(ppu.loopy.x)),
+                ("w", Json::from(ppu.loopy.w)),
+            ]))
+        }
+        "apu" => {
+            let apu: &mut APU = cpu.bus.apu();
+            let levels: [f64; 6] = apu.channel_levels();
+            let playing: [bool; 6] = [apu.pulse_0.playing(), apu.pulse_1.playing(), apu.triangle.playing(), apu.noise.playing(), apu.dmc.playing(), levels[5] != 0.0];
+            let channels: Vec<Json> = Channel::ALL.iter().zip(playing).zip(levels).map(|((channel, playing), level)| {
+                let mut fields: Vec<(&str, Json)> = vec![("name", Json::from(channel.name())), ("playing", Json::from(playing)), ("level", Json::from(level))];
+                if *channel == Channel::Noise {
+                    fields.push(("period", Json::from(apu.noise.period())));
+                    fields.push(("tonal", Json::from(apu.noise.tonal())));
+                }
+                Json::object(fields)
+            }).collect();
+            Ok(Json::object(vec![("channels", Json::Array(channels))]))
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("unknown method {:?}", method))),
+    }
+}
+
+fn invalid(message: &str) -> (i64, String) { (INVALID_PARAMS, String::from(message)) }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use gbnes_core::Rom;
+
+    fn nes() -> Headless {
+        // NROM, 32KB of PRG and 8KB of CHR
+        let mut raw: Vec<u8> = vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        raw.extend(vec![0xEA; 0x8000 + 0x2000]);
+        Headless::new(Rom::new(&raw).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_methods() {
+        let mut nes: Headless = nes();
+        nes.cpu.register_a = 0x42;
+        nes.cpu.bus.cpu_vram[0x300..0x303].copy_from_slice(&[1, 2, 3]);
+        let reply: Json = Json::parse(&handle(r#"{"jsonrpc":"2.0","method":"cpu","id":7}"#, &mut nes)).unwrap();
+        assert_eq!(reply.get("id"), Some(&Json::from(7u8)));
+        assert_eq!(reply.get("result").and_then(|cpu| cpu.get("a")), Some(&Json::from(0x42u8)));
+        let reply: String = handle(r#"{"jsonrpc":"2.0","method":"memory","params":{"address":768,"length":3},"id":"m"}"#, &mut nes);
+        assert_eq!(reply, r#"{"jsonrpc":"2.0","result":{"space":"cpu","address":768,"data":[1,2,3]},"id":"m"}"#);
+        // Reads stop at the end of the space
+        let reply: Json = Json::parse(&handle(r#"{"jsonrpc":"2.0","method":"memory","params":{"space":"oam","address":254,"length":9},"id":1}"#, &mut nes)).unwrap();
+        assert_eq!(reply.get("result").and_then(|memory| memory.get("data")), Some(&Json::Array(vec![Json::from(0u8); 2])));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut nes: Headless = nes();
+        let code = |reply: &Json| reply.get("error").and_then(|error| error.get("code")).cloned();
+        assert_eq!(code(&Json::parse(&handle("{", &mut nes)).unwrap()), Some(Json::from(PARSE_ERROR)));
+        let batch: String = handle(r#"[{"jsonrpc":"1.0","method":"cpu","id":1},{"jsonrpc":"2.0","method":"reset","id":2},
+            {"jsonrpc":"2.0","method":"memory","params":{"address":-1},"id":3},{"jsonrpc":"2.0","method":"cpu"}]"#, &mut nes);
+        let Ok(Json::Array(replies)) = Json::parse(&batch) else { panic!("expected a batch reply, got {}", batch) };
+        // The notification (no id) isn't answered
+        let codes: Vec<Option<Json>> = replies.iter().map(code).collect();
+        assert_eq!(codes, vec![Some(Json::from(INVALID_REQUEST)), Some(Json::from(METHOD_NOT_FOUND)), Some(Json::from(INVALID_PARAMS))]);
+        assert_eq!(handle(r#"{"jsonrpc":"2.0","method":"cpu"}"#, &mut nes), "");
+    }
+
+    fn server() -> RpcServer {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        RpcServer { listener, origins: vec![String::from("http://localhost:8000")], connections: Vec::new() }
+    }
+
+    // Polls like the frame loop does until every connection is answered
+    fn answer_all(server: &mut RpcServer) {
+        let mut nes: Headless = nes();
+        server.poll(&mut nes);
+        while !server.connections.is_empty() { server.poll(&mut nes); }
+    }
+
+    #[test]
+    fn test_http() {
+        let mut server: RpcServer = server();
+        let mut client: TcpStream = TcpStream::connect(server.listener.local_addr().unwrap()).unwrap();
+        let body: &str = r#"{"jsonrpc":"2.0","method":"status","id":1}"#;
+        // The body arriving after the headers
+        client.write_all(format!("POST / HTTP/1.1\r\nContent-Type: application/json\r\ncontent-length: {}\r\n\r\n", body.len()).as_bytes()).unwrap();
+        client.write_all(body.as_bytes()).unwrap();
+        answer_all(&mut server);
+        let mut response: String = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(r#"{"jsonrpc":"2.0","result":{"frame":0,"rom_crc32":"#));
+        assert!(!response.contains("Access-Control-Allow-Origin"));
+        // From a browser, only the allowed pages get an answer, and only they may read it
+        let mut post = |origin: &str| -> String {
+            let mut client: TcpStream = TcpStream::connect(server.listener.local_addr().unwrap()).unwrap();
+            client.write_all(format!("POST / HTTP/1.1\r\nOrigin: {}\r\nContent-Length: {}\r\n\r\n{}", origin, body.len(), body).as_bytes()).unwrap();
+            answer_all(&mut server);
+            let mut response: String = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        assert!(post("http://localhost:8000").contains("Access-Control-Allow-Origin: http://localhost:8000\r\n"));
+        assert!(post("http://evil.example").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    }
+
+    #[test]
+    fn test_idle_connections_dont_block() {
+        let mut server: RpcServer = server();
+        let mut nes: Headless = nes();
+        let mut idle: TcpStream = TcpStream::connect(server.listener.local_addr().unwrap()).unwrap();
+        idle.write_all(b"POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\n{").unwrap();
+        let started: Instant = Instant::now();
+        server.poll(&mut nes);
+        server.poll(&mut nes);
+        assert!(started.elapsed() < REQUEST_TIMEOUT);
+        assert_eq!(server.connections.len(), 1);
+        // Past the timeout, the request is given up on
+        server.connections[0].started = Instant::now().checked_sub(REQUEST_TIMEOUT * 2).unwrap();
+        server.poll(&mut nes);
+        assert!(server.connections.is_empty());
+        let mut response: Vec<u8> = Vec::new();
+        idle.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+    }
+}
@@ -0,0 +1,84 @@
+use std::path::{Path, PathBuf};
+
+use gbnes_core::render::frame::Image;
+use gbnes_core::render::png;
+
+use super::wav::timestamped_path;
+
+// What the screenshot hotkey saves: the PPU's picture as is, as presented (upscaled and through
+// the CRT filter, without the OSD), or both
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Screenshot {
+    Raw,
+    Filtered,
+    Both,
+}
+
+impl Screenshot {
+    pub const ALL: [Screenshot; 3] = [Screenshot::Raw, Screenshot::Filtered, Screenshot::Both];
+    pub fn parse(name: &str) -> Result<Screenshot, String> {
+        Screenshot::ALL.into_iter().find(|kind| kind.name() == name.trim().to_lowercase())
+            .ok_or_else(|| format!("Unknown screenshot kind {:?}: expected raw, filtered or both", name))
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Screenshot::Raw => "raw",
+            Screenshot::Filtered => "filtered",
+            Screenshot::Both => "both",
+        }
+    }
+    // Saves the raw and / or filtered picture at `path`, the filtered one at <name>-filtered.png
+    // when saving both, and returns the paths written
+    pub fn save(self, raw: &Image, filtered: impl FnOnce() -> Image, path: &Path) -> Result<Vec<PathBuf>, String> {
+        let filtered_path: PathBuf = match self {
+            Screenshot::Both => path.with_file_name(format!("{}-filtered.png", path.file_stem().unwrap_or_default().to_string_lossy())),
+            _ => path.to_path_buf(),
+        };
+        let mut saved: Vec<PathBuf> = Vec::new();
+        if self != Screenshot::Filtered {
+            save(raw, path)?;
+            saved.push(path.to_path_buf());
+        }
+        if self != Screenshot::Raw {
+            save(&filtered(), &filtered_path)?;
+            saved.push(filtered_path);
+        }
+        Ok(saved)
+    }
+}
+
+// Where the screenshot hotkey saves: screenshots/<rom name>-<unix time>.png, with -2, -3... after
+// the time for more than one in the same second
+pub fn default_path(rom_path: &str) -> PathBuf {
+    let path: PathBuf = timestamped_path("screenshots", rom_path, "png");
+    let stem: String = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    if !path.exists() { return path; }
+    (2..).map(|n| path.with_file_name(format!("{}-{}.png", stem, n))).find(|path| !path.exists()).unwrap_or(path)
+}
+
+pub fn save(image: &Image, path: &Path) -> Result<(), String> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).map_err(|err| format!("Could not create {}: {}", dir.display(), err))?;
+    }
+    std::fs::write(path, png::encode(image)).map_err(|err| format!("Could not write {}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_raw_and_filtered() {
+        let dir: PathBuf = std::env::temp_dir().join(format!("gbnes-screenshots-{}", std::process::id()));
+        let path: PathBuf = dir.join("game-1.png");
+        let (raw, filtered): (Image, Image) = (Image::new(2, 2), Image::new(4, 4));
+        assert_eq!(Screenshot::Raw.save(&raw, || unreachable!(), &path).unwrap(), vec![path.clone()]);
+        let saved: Vec<PathBuf> = Screenshot::Both.save(&raw, || filtered.clone(), &path).unwrap();
+        assert_eq!(saved, vec![path.clone(), dir.join("game-1-filtered.png")]);
+        let image: Image = png::decode(&std::fs::read(&saved[1]).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+        assert_eq!((image.width, image.height), (4, 4));
+        assert_eq!(Screenshot::parse(" Filtered"), Ok(Screenshot::Filtered));
+        assert!(Screenshot::parse("scaled").is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use gbnes_core::CPU;
+use gbnes_core::savestate::{self, StateInfo};
+
+pub const SLOTS: u8 = 10;
+
+// Savestate slots live in saves/<rom name>/slot<N>.state, one directory per game, next to
+// last.state which is written on quit and offered for resuming on the next launch, and for
+// Famicom Disk System games disk.sav, the disks with what the game saved to them
+pub struct SaveSlots { dir: PathBuf }
+
+impl SaveSlots {
+    pub fn for_rom(rom_path: &str) -> Self {
+        let game: String = Path::new(rom_path).file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or(String::from("unknown"));
+        SaveSlots { dir: Path::new("saves").join(game) }
+    }
+    pub fn path(&self, slot: u8) -> PathBuf { self.dir.join(format!("slot{}.state", slot)) }
+    pub fn last_session_path(&self) -> PathBuf { self.dir.join("last.state") }
+    pub fn save(&self, cpu: &CPU, slot: u8) -> Result<StateInfo, String> { self.write(cpu, &self.path(slot)) }
+    pub fn load(&self, cpu: &mut CPU, slot: u8) -> Result<StateInfo, String> {
+        let data: Vec<u8> = std::fs::read(self.path(slot)).map_err(|_| format!("Slot {} is empty", slot))?;
+        savestate::load(cpu, &data)
+    }
+    pub fn save_last_session(&self, cpu: &CPU) -> Result<StateInfo, String> { self.write(cpu, &self.last_session_path()) }
+    pub fn last_session(&self) -> Option<StateInfo> {
+        std::fs::read(self.last_session_path()).ok().and_then(|data| savestate::info(&data).ok())
+    }
+    pub fn load_last_session(&self, cpu: &mut CPU) -> Result<StateInfo, String> {
+        let data: Vec<u8> = std::fs::read(self.last_session_path()).map_err(|_| String::from("No previous session"))?;
+        savestate::load(cpu, &data)
+    }
+    pub fn disk_path(&self) -> PathBuf { self.dir.join("disk.sav") }
+    // Nothing to write for cartridges
+    pub fn save_disk(&self, cpu: &CPU) -> Result<(), String> {
+        let image: Vec<u8> = cpu.bus.mapper().borrow().disk_image();
+        if image.is_empty() { return Ok(()); }
+        std::fs::create_dir_all(&self.dir).map_err(|err| format!("Could not create {}: {}", self.dir.display(), err))?;
+        std::fs::write(self.disk_path(), &image).map_err(|err| format!("Could not write {}: {}", self.disk_path().display(), err))
+    }
+    // No file yet just means the disks are as the image came
+    pub fn load_disk(&self, cpu: &mut CPU) -> Result<(), String> {
+        let Ok(image) = std::fs::read(self.disk_path()) else { return Ok(()); };
+        cpu.bus.mapper().borrow_mut().load_disk_image(&image).map_err(|err| format!("{}: {}", self.disk_path().display(), err))
+    }
+    // Metadata of every occupied slot, without loading them
+    pub fn list(&self) -> Vec<(u8, StateInfo)> {
+        (1..=SLOTS).filter_map(|slot| {
+            let data: Vec<u8> = std::fs::read(self.path(slot)).ok()?;
+            savestate::info(&data).ok().map(|info| (slot, info))
+        }).collect()
+    }
+    fn write(&self, cpu: &CPU, path: &Path) -> Result<StateInfo, String> {
+        let timestamp: u64 = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let data: Vec<u8> = savestate::save(cpu, timestamp);
+        std::fs::create_dir_all(&self.dir).map_err(|err| format!("Could not create {}: {}", self.dir.display(), err))?;
+        std::fs::write(path, &data).map_err(|err| format!("Could not write {}: {}", path.display(), err))?;
+        savestate::info(&data)
+    }
+}
@@ -0,0 +1,53 @@
+// Emulation speed relative to the 60Hz pace of the main loop, which presents one picture per pass
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Speed {
+    Normal,
+    SlowMotion,
+    Fast(u32),
+    Uncapped,
+}
+
+impl Speed {
+    // Value of --fast-forward: a multiplier ("2", "4", ...) or "max" to run as fast as possible
+    pub fn parse_fast_forward(value: &str) -> Result<Speed, String> {
+        if value == "max" { return Ok(Speed::Uncapped); }
+        match value.parse::<u32>() {
+            Ok(n) if n >= 1 => Ok(Speed::Fast(n)),
+            _ => Err(format!("Invalid --fast-forward value {:?}: expected a multiplier like 2 or 4, or max", value)),
+        }
+    }
+    // Frames to emulate on the `tick`th pass; Uncapped is bounded by the pass's time budget instead
+    pub fn frames(self, tick: u64) -> u32 {
+        match self {
+            Speed::Normal => 1,
+            Speed::SlowMotion => (tick % 2 == 0) as u32,
+            Speed::Fast(n) => n,
+            Speed::Uncapped => u32::MAX,
+        }
+    }
+    // How many times to queue each of a frame's samples. Slow motion doubles them so each frame's sound
+    // stretches over both passes; faster speeds are muted, since queueing every frame's samples would back
+    // the audio up.
+    pub fn audio_repeat(self) -> Option<usize> {
+        match self {
+            Speed::Normal => Some(1),
+            Speed::SlowMotion => Some(2),
+            Speed::Fast(_) | Speed::Uncapped => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_speeds() {
+        assert_eq!(Speed::parse_fast_forward("4"), Ok(Speed::Fast(4)));
+        assert_eq!(Speed::parse_fast_forward("max"), Ok(Speed::Uncapped));
+        assert!(Speed::parse_fast_forward("0").is_err());
+        assert_eq!((0..4).map(|tick| Speed::SlowMotion.frames(tick)).sum::<u32>(), 2);
+        assert_eq!(Speed::SlowMotion.audio_repeat(), Some(2));
+        assert_eq!(Speed::Fast(2).audio_repeat(), None);
+    }
+}
@@ -0,0 +1,100 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The RIFF header of a 16 bit PCM WAV file holding `data_bytes` bytes of samples
+pub fn header(sample_rate: u32, channels: u16, data_bytes: u32) -> [u8; 44] {
+    let block_align: u16 = channels * 2;
+    let mut bytes: Vec<u8> = Vec::with_capacity(44);
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_bytes).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&channels.to_le_bytes());
+    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    bytes.extend_from_slice(&block_align.to_le_bytes());
+    bytes.extend_from_slice(&16u16.to_le_bytes());
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_bytes.to_le_bytes());
+    let mut header: [u8; 44] = [0; 44];
+    header.copy_from_slice(&bytes);
+    header
+}
+
+// A sample from -1.0 to 1.0 as 16 bit PCM; anything louder is clipped
+pub fn pcm16(sample: f32) -> [u8; 2] { ((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes() }
+
+// recordings/<rom name>-<unix time>.<extension>, where the record hotkeys save
+pub fn recording_path(rom_path: &str, extension: &str) -> PathBuf { timestamped_path("recordings", rom_path, extension) }
+
+// <dir>/<rom name>-<unix time>.<extension>
+pub fn timestamped_path(dir: &str, rom_path: &str, extension: &str) -> PathBuf {
+    let game: String = Path::new(rom_path).file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or(String::from("unknown"));
+    let timestamp: u64 = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    Path::new(dir).join(format!("{}-{}.{}", game, timestamp, extension))
+}
+
+// The mixed audio going into a WAV file, as it is emulated. The header is written up front with
+// no samples and rewritten with the real sizes by finish.
+pub struct WavRecorder {
+    path: PathBuf,
+    out: BufWriter<File>,
+    sample_rate: u32,
+    channels: u16,
+    data_bytes: u32,
+}
+
+impl WavRecorder {
+    pub fn create(path: &Path, sample_rate: u32, channels: u16) -> Result<WavRecorder, String> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|err| format!("Could not create {}: {}", dir.display(), err))?;
+        }
+        let file: File = File::create(path).map_err(|err| format!("Could not create {}: {}", path.display(), err))?;
+        let mut out: BufWriter<File> = BufWriter::new(file);
+        out.write_all(&header(sample_rate, channels, 0)).map_err(|err| format!("Could not write {}: {}", path.display(), err))?;
+        Ok(WavRecorder { path: path.to_path_buf(), out, sample_rate, channels, data_bytes: 0 })
+    }
+    // Where the record hotkey saves: recordings/<rom name>-<unix time>.wav
+    pub fn default_path(rom_path: &str) -> PathBuf { recording_path(rom_path, "wav") }
+    pub fn path(&self) -> &Path { &self.path }
+    // Interleaved samples from -1.0 to 1.0; anything louder is clipped
+    pub fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        for sample in samples {
+            self.out.write_all(&pcm16(*sample)).map_err(|err| format!("Could not write {}: {}", self.path.display(), err))?;
+        }
+        self.data_bytes = self.data_bytes.saturating_add(samples.len() as u32 * 2);
+        Ok(())
+    }
+    // Seconds recorded
+    pub fn seconds(&self) -> f64 { self.data_bytes as f64 / (self.sample_rate as f64 * self.channels as f64 * 2.0) }
+    pub fn finish(mut self) -> Result<(), String> {
+        let error = |err: std::io::Error| format!("Could not write {}: {}", self.path.display(), err);
+        self.out.seek(SeekFrom::Start(0)).map_err(error)?;
+        self.out.write_all(&header(self.sample_rate, self.channels, self.data_bytes)).map_err(error)?;
+        self.out.flush().map_err(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_wav() {
+        let path: PathBuf = std::env::temp_dir().join(format!("gbnes-wav-{}.wav", std::process::id()));
+        let mut recorder: WavRecorder = WavRecorder::create(&path, 44100, 2).unwrap();
+        recorder.write(&[0.0, 1.0, -2.0, 0.5]).unwrap();
+        assert!((recorder.seconds() - 2.0 / 44100.0).abs() < 1e-9);
+        recorder.finish().unwrap();
+        let data: Vec<u8> = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(data[..44], header(44100, 2, 8));
+        assert_eq!(u32::from_le_bytes([data[4], data[5], data[6], data[7]]), 44);
+        assert_eq!(u32::from_le_bytes([data[28], data[29], data[30], data[31]]), 44100 * 4);
+        assert_eq!(data[44..], [0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x3F]);
+    }
+}
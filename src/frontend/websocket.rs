@@ -0,0 +1,281 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use gbnes_core::hash;
+
+// Appended to the client's key to prove the server speaks WebSocket (RFC 6455 section 1.3)
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Nothing the remote API is sent comes close; anything bigger is a confused client
+const MAX_MESSAGE: usize = 1 << 20;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a write may wait on a full send buffer before the client counts as gone, so a client that
+// stops reading can't hang the main loop
+const WRITE_TIMEOUT: Duration = Duration::from_millis(5);
+
+// Opcodes
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+// The server side of a WebSocket connection, as much as the remote API needs: the upgrade
+// handshake, text messages both ways, pings and closing. Reads never block, so the main loop can
+// poll between frames.
+pub struct WebSocket {
+    stream: TcpStream,
+    // Bytes received but not yet a whole frame
+    incoming: Vec<u8>,
+    // A message arriving in fragments
+    message: Vec<u8>,
+}
+
+// A connection that hasn't finished its HTTP upgrade request yet. The request is read as it
+// arrives, between frames, so a client that stalls can't hold up the game.
+pub struct Handshake {
+    stream: TcpStream,
+    request: Vec<u8>,
+    started: Instant,
+}
+
+impl Handshake {
+    pub fn new(stream: TcpStream) -> Result<Handshake, String> {
+        // Accepted sockets don't inherit a non-blocking listener's mode on every platform
+        stream.set_nonblocking(true).map_err(|err| format!("WebSocket handshake failed: {}", err))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT)).map_err(|err| format!("WebSocket handshake failed: {}", err))?;
+        Ok(Handshake { stream, request: Vec::new(), started: Instant::now() })
+    }
+    // Whether the whole request is in and answered, when into_socket takes over. Browsers name the
+    // page asking in Origin, and only pages in `origins` get through: any site open in the browser
+    // could otherwise drive the emulator. Clients outside a browser send none.
+    pub fn poll(&mut self, origins: &[String]) -> Result<bool, String> {
+        let error = |err: std::io::Error| format!("WebSocket handshake failed: {}", err);
+        let mut byte: [u8; 1] = [0];
+        // A byte at a time, so nothing the client sends after the request is taken with it
+        while !self.request.ends_with(b"\r\n\r\n") {
+            if self.request.len() > 8192 { return Err(String::from("WebSocket handshake failed: request too long")); }
+            match self.stream.read(&mut byte) {
+                Ok(0) => return Err(String::from("WebSocket handshake failed: connection closed")),
+                Ok(_) => self.request.push(byte[0]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if self.started.elapsed() > HANDSHAKE_TIMEOUT { return Err(String::from("WebSocket handshake failed: timed out")); }
+                    return Ok(false);
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(error(err)),
+            }
+        }
+        let request: String = String::from_utf8_lossy(&self.request).into_owned();
+        let header = |wanted: &str| request.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim().eq_ignore_ascii_case(wanted).then(|| value.trim())
+        });
+        self.stream.set_nonblocking(false).map_err(error)?;
+        if let Some(origin) = header("origin").filter(|origin| !origins.iter().any(|allowed| allowed == origin)) {
+            self.stream.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n").ok();
+            return Err(format!("WebSocket handshake failed: origin {} is not in remote.origins", origin));
+        }
+        let Some(key) = header("sec-websocket-key") else {
+            self.stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n").ok();
+            return Err(String::from("WebSocket handshake failed: not a WebSocket request"));
+        };
+        let response: String = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept_key(key),
+        );
+        self.stream.write_all(response.as_bytes()).map_err(error)?;
+        self.stream.set_nonblocking(true).map_err(error)?;
+        self.stream.set_nodelay(true).map_err(error)?;
+        Ok(true)
+    }
+    pub fn into_socket(self) -> WebSocket { WebSocket { stream: self.stream, incoming: Vec::new(), message: Vec::new() } }
+}
+
+impl WebSocket {
+    // The text messages that arrived since the last poll; an error once the connection is closed
+    pub fn poll(&mut self) -> Result<Vec<String>, String> {
+        let mut buffer: [u8; 4096] = [0; 4096];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err(String::from("connection closed")),
+                Ok(length) => self.incoming.extend_from_slice(&buffer[..length]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+        let mut messages: Vec<String> = Vec::new();
+        while let Some((fin, opcode, payload, length)) = parse_frame(&self.incoming)? {
+            self.incoming.drain(..length);
+            match opcode {
+                CONTINUATION | TEXT | BINARY => {
+                    self.message.extend(payload);
+                    if self.message.len() > MAX_MESSAGE { return Err(String::from("message too long")); }
+                    if fin { messages.push(String::from_utf8_lossy(&std::mem::take(&mut self.message)).into_owned()); }
+                }
+                CLOSE => {
+                    self.write_frame(CLOSE, &payload).ok();
+                    return Err(String::from("connection closed"));
+                }
+                PING => self.write_frame(PONG, &payload)?,
+                _ => {}
+            }
+        }
+        Ok(messages)
+    }
+    pub fn send(&mut self, text: &str) -> Result<(), String> { self.write_frame(TEXT, text.as_bytes()) }
+    // Server frames go out unmasked. The socket blocks for the write, but only up to WRITE_TIMEOUT: a
+    // client whose send buffer is still full by then is left with half a frame, so the error drops it.
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), String> {
+        let mut frame: Vec<u8> = vec![0x80 | opcode];
+        match payload.len() {
+            length @ 0..=125 => frame.push(length as u8),
+            length @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.stream.set_nonblocking(false).map_err(|err| err.to_string())?;
+        let written: std::io::Result<()> = self.stream.write_all(&frame);
+        self.stream.set_nonblocking(true).map_err(|err| err.to_string())?;
+        written.map_err(|err| err.to_string())
+    }
+}
+
+// Sec-WebSocket-Accept for a client's Sec-WebSocket-Key
+fn accept_key(key: &str) -> String { base64(&hash::sha1(format!("{}{}", key, GUID).as_bytes())) }
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text: String = String::new();
+    for group in bytes.chunks(3) {
+        let bits: u32 = group.iter().enumerate().fold(0, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            text.push(if i <= group.len() { ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char } else { '=' });
+        }
+    }
+    text
+}
+
+// A frame: whether it ends its message, its opcode, its unmasked payload and how many bytes it took up
+type Parsed = (bool, u8, Vec<u8>, usize);
+
+// The first whole frame in `data`, None until all of it has arrived
+fn parse_frame(data: &[u8]) -> Result<Option<Parsed>, String> {
+    if data.len() < 2 { return Ok(None); }
+    let (fin, opcode, masked): (bool, u8, bool) = (data[0] & 0x80 != 0, data[0] & 0x0F, data[1] & 0x80 != 0);
+    // Clients must mask everything they send
+    if !masked { return Err(String::from("unmasked frame from the client")); }
+    let (length, mut at): (u64, usize) = match data[1] & 0x7F {
+        126 if data.len() >= 4 => (u16::from_be_bytes([data[2], data[3]]) as u64, 4),
+        127 if data.len() >= 10 => (u64::from_be_bytes(data[2..10].try_into().unwrap_or_default()), 10),
+        126 | 127 => return Ok(None),
+        length => (length as u64, 2),
+    };
+    if length > MAX_MESSAGE as u64 { return Err(String::from("message too long")); }
+    if data.len() < at + 4 + length as usize { return Ok(None); }
+    let mask: [u8; 4] = [data[at], data[at + 1], data[at + 2], data[at + 3]];
+    at += 4;
+    let payload: Vec<u8> = data[at..at + length as usize].iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+    Ok(Some((fin, opcode, payload, at + length as usize)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_handshake_key() {
+        // The example in RFC 6455 section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b"ab"), "YWI=");
+    }
+
+    #[test]
+    fn test_connection() {
+        let listener: std::net::TcpListener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client: TcpStream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n").unwrap();
+        let mut handshake: Handshake = Handshake::new(listener.accept().unwrap().0).unwrap();
+        while !handshake.poll(&[]).unwrap() {}
+        let mut socket: WebSocket = handshake.into_socket();
+        let mut response: [u8; 129] = [0; 129];
+        client.read_exact(&mut response).unwrap();
+        assert!(String::from_utf8_lossy(&response).contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        // "Hello" in two fragments, then a ping
+        client.write_all(&[0x01, 0x83, 0, 0, 0, 0, b'H', b'e', b'l', 0x80, 0x82, 0, 0, 0, 0, b'l', b'o', 0x89, 0x80, 0, 0, 0, 0]).unwrap();
+        let mut messages: Vec<String> = Vec::new();
+        while messages.is_empty() { messages = socket.poll().unwrap(); }
+        assert_eq!(messages, vec![String::from("Hello")]);
+        socket.send("ok").unwrap();
+        let mut replies: [u8; 6] = [0; 6];
+        client.read_exact(&mut replies).unwrap();
+        assert_eq!(replies, [0x8A, 0, 0x81, 2, b'o', b'k']);
+        client.write_all(&[0x88, 0x80, 0, 0, 0, 0]).unwrap();
+        let mut closed: Result<Vec<String>, String> = Ok(Vec::new());
+        while closed.as_ref().is_ok_and(|messages| messages.is_empty()) { closed = socket.poll(); }
+        assert!(closed.is_err());
+    }
+
+    #[test]
+    fn test_handshake_checks_the_origin() {
+        let listener: std::net::TcpListener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let request = |origin: &str| {
+            let mut client: TcpStream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            client.write_all(format!("GET / HTTP/1.1\r\nOrigin: {}\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n", origin).as_bytes()).unwrap();
+            let mut handshake: Handshake = Handshake::new(listener.accept().unwrap().0).unwrap();
+            let allowed: [String; 1] = [String::from("http://localhost:8000")];
+            loop {
+                match handshake.poll(&allowed) {
+                    Ok(false) => continue,
+                    result => return (result, client),
+                }
+            }
+        };
+        assert_eq!(request("http://localhost:8000").0, Ok(true));
+        let (result, mut client) = request("http://evil.example");
+        assert!(result.unwrap_err().contains("http://evil.example"));
+        let mut response: String = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 403"));
+    }
+
+    #[test]
+    fn test_handshake_waits_for_the_request() {
+        let listener: std::net::TcpListener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client: TcpStream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut handshake: Handshake = Handshake::new(listener.accept().unwrap().0).unwrap();
+        assert_eq!(handshake.poll(&[]), Ok(false));
+        client.write_all(b"GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n").unwrap();
+        while !handshake.poll(&[]).unwrap() {}
+    }
+
+    #[test]
+    fn test_send_gives_up_on_a_client_that_stops_reading() {
+        let listener: std::net::TcpListener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client: TcpStream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n").unwrap();
+        let mut handshake: Handshake = Handshake::new(listener.accept().unwrap().0).unwrap();
+        while !handshake.poll(&[]).unwrap() {}
+        let mut socket: WebSocket = handshake.into_socket();
+        // The client never reads, so the send buffers fill up and a send has to fail rather than wait
+        let text: String = "x".repeat(64 * 1024);
+        let started: Instant = Instant::now();
+        while socket.send(&text).is_ok() { assert!(started.elapsed() < Duration::from_secs(10)); }
+    }
+
+    #[test]
+    fn test_parse_masked_frames() {
+        // "Hello", masked, from RFC 6455 section 5.7
+        let frame: [u8; 11] = [0x81, 0x85, 0x37, 0xFA, 0x21, 0x3D, 0x7F, 0x9F, 0x4D, 0x51, 0x58];
+        assert_eq!(parse_frame(&frame), Ok(Some((true, TEXT, b"Hello".to_vec(), 11))));
+        assert_eq!(parse_frame(&frame[..8]), Ok(None));
+        assert!(parse_frame(&[0x81, 0x05, b'H', b'e', b'l', b'l', b'o']).is_err());
+    }
+}
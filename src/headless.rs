@@ -0,0 +1,228 @@
+use crate::prelude::*;
+use crate::apu::Output;
+use crate::apu::mixer::Mixer;
+use crate::bus::{Bus, RamInit};
+use crate::cartridge::Rom;
+use crate::cheats::Cheats;
+use crate::cpu::CPU;
+use crate::debugger::{Debugger, Stop, Watchpoint};
+use crate::hash;
+use crate::hooks::Hooks;
+use crate::joypad::Joypad;
+use crate::ppu::dot::PpuAccuracy;
+use crate::render::{self, frame::Frame, palette::Palette};
+use crate::savestate;
+
+// Runs the core with no window or audio device: the caller drives it one frame at a time
+// (CI test ROM runs, scripting, the wasm and SDL frontends) instead of through a gameloop callback.
+pub struct Headless {
+    pub cpu: CPU<'static>,
+    pub frame: Frame,
+    // Used again on every power cycle
+    pub ram_init: RamInit,
+    pub palette: Palette,
+    rom: Rom,
+}
+
+impl Headless {
+    pub fn new(rom: Rom) -> Result<Self, String> { Headless::with_ram_init(rom, RamInit::default()) }
+    pub fn with_ram_init(rom: Rom, ram_init: RamInit) -> Result<Self, String> {
+        let cpu: CPU<'static> = Headless::power_on(rom.clone(), &ram_init)?;
+        Ok(Headless { cpu, frame: Frame::new(), ram_init, palette: Palette::default(), rom })
+    }
+    fn power_on(rom: Rom, ram_init: &RamInit) -> Result<CPU<'static>, String> {
+        let mut bus: Bus<'static> = Bus::try_new(rom, |_, _, _| {})?;
+        ram_init.fill(&mut bus.cpu_vram);
+        let mut cpu: CPU<'static> = CPU::new(bus);
+        cpu.reset();
+        Ok(cpu)
+    }
+    // Rebuilds the whole machine (cartridge RAM and mapper state included) as if switched off and on,
+    // unlike `cpu.reset()` which is the console's reset button. The controllers aren't part of the
+    // console, so their held buttons and settings carry over; the game re-strobes them anyway. So do
+    // the PPU and DMC accuracy settings, overclocking, audio mixer, debugger watchpoints, cheats and hooks, which belong to the emulator.
+    // A Famicom Disk System disk keeps what was written to it.
+    pub fn power_cycle(&mut self) {
+        let disk: Vec<u8> = self.cpu.bus.mapper().borrow().disk_image();
+        let cpu: CPU<'static> = Headless::power_on(self.rom.clone(), &self.ram_init).expect("ROM was already loaded once");
+        self.replace_cpu(cpu);
+        self.cpu.bus.mapper().borrow_mut().load_disk_image(&disk).expect("same disks as before");
+    }
+    // Takes the cartridge out and powers on with another, keeping the same emulator settings as a
+    // power cycle except the cheats, which belong to the old game. The old game keeps running if
+    // the new one can't be loaded.
+    pub fn swap_rom(&mut self, rom: Rom) -> Result<(), String> {
+        let cpu: CPU<'static> = Headless::power_on(rom.clone(), &self.ram_init)?;
+        self.rom = rom;
+        self.replace_cpu(cpu);
+        self.cpu.bus.cheats = Cheats::new();
+        Ok(())
+    }
+    fn replace_cpu(&mut self, cpu: CPU<'static>) {
+        let (joypad1, joypad2): (Joypad, Joypad) = (*self.cpu.bus.joypad1(), *self.cpu.bus.joypad2());
+        let (accuracy, oam_quirks): (PpuAccuracy, bool) = (self.cpu.bus.ppu().accuracy, self.cpu.bus.ppu().oam_quirks);
+        let (dmc_dma, extra_scanlines): (bool, u16) = (self.cpu.bus.dmc_dma, self.cpu.bus.extra_scanlines);
+        let watchpoints: Vec<Watchpoint> = core::mem::take(&mut self.cpu.bus.watchpoints);
+        let cheats: Cheats = core::mem::take(&mut self.cpu.bus.cheats);
+        let hooks: Hooks<'static> = core::mem::take(&mut self.cpu.bus.hooks);
+        let mixer: Mixer = core::mem::take(&mut self.cpu.bus.apu().mixer);
+        self.cpu = cpu;
+        *self.cpu.bus.joypad1() = joypad1;
+        *self.cpu.bus.joypad2() = joypad2;
+        self.cpu.bus.ppu_mut().accuracy = accuracy;
+        self.cpu.bus.ppu_mut().oam_quirks = oam_quirks;
+        self.cpu.bus.dmc_dma = dmc_dma;
+        self.cpu.bus.extra_scanlines = extra_scanlines;
+        self.cpu.bus.watchpoints = watchpoints;
+        self.cpu.bus.cheats = cheats;
+        self.cpu.bus.hooks = hooks;
+        self.cpu.bus.apu().mixer = mixer;
+    }
+    // Emulates until the next vblank and renders the finished picture into `frame`, unless the PPU
+    // skips rendering (NesPPU::skip_render), when `frame` keeps the last one drawn
+    pub fn run_frame(&mut self) {
+        self.cpu.bus.apu().buffer.clear();
+        self.cpu.run_frame();
+        if !self.cpu.bus.ppu().skip_render { self.redraw(); }
+    }
+    // run_frame, stopping early at the debugger's breakpoints. The picture is redrawn either way, so a
+    // stopped frame shows how far the PPU got.
+    pub fn run_frame_with(&mut self, debugger: &mut Debugger) -> Stop {
+        self.cpu.bus.apu().buffer.clear();
+        let stop: Stop = debugger.run_frame(&mut self.cpu);
+        if !self.cpu.bus.ppu().skip_render { self.redraw(); }
+        stop
+    }
+    // Run-ahead: emulates the next frame, then `ahead` more on the same input from a snapshot, shows
+    // the last of those and rolls the machine back to the snapshot. A button press shows up to `ahead`
+    // frames sooner than on the console, for games whose reaction lag is at least that long; each
+    // frame shown costs `ahead + 1` emulated. `audio` and the frame count stay the real frame's.
+    pub fn run_frame_ahead(&mut self, ahead: u32) {
+        if ahead == 0 { return self.run_frame(); }
+        let skip_render: bool = self.cpu.bus.ppu().skip_render;
+        self.cpu.bus.ppu_mut().skip_render = true;
+        self.run_frame();
+        let audio: Vec<f32> = core::mem::take(&mut self.cpu.bus.apu().buffer);
+        let output: Output = self.cpu.bus.apu().output();
+        let state: Vec<u8> = savestate::snapshot(&self.cpu);
+        for n in 0..ahead {
+            self.cpu.bus.ppu_mut().skip_render = skip_render || n + 1 < ahead;
+            self.run_frame();
+        }
+        savestate::restore(&mut self.cpu, &state).expect("restoring a snapshot of the same machine");
+        self.cpu.bus.ppu_mut().skip_render = skip_render;
+        self.cpu.bus.apu().set_output(output);
+        self.cpu.bus.apu().buffer = audio;
+    }
+    // Colours the last emulated picture into `frame` again, e.g. after switching palettes
+    pub fn redraw(&mut self) { render::draw_picture(&self.cpu.bus.ppu().picture, &mut self.frame, &self.palette); }
+    pub fn run_frames(&mut self, frames: u64) { for _ in 0..frames { self.run_frame(); } }
+    // Joypad 1 buttons for the following frames, one bit each in JoypadButton order
+    pub fn set_buttons(&mut self, status: u8) { self.cpu.bus.joypad1().set_button_status(status); }
+    // Audio samples generated during the last frame, interleaved left and right
+    pub fn audio(&mut self) -> &[f32] { &self.cpu.bus.apu().buffer }
+    pub fn frame_count(&self) -> u64 { self.cpu.bus.frames }
+    // CRC32 of the rendered RGB picture, for comparing output across builds
+    pub fn frame_hash(&self) -> u32 { hash::crc32(&self.frame.data) }
+    pub fn ram(&self) -> &[u8] { &self.cpu.bus.cpu_vram }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_run_frames_without_frontend() {
+        // `JMP $8000`, so the CPU spins while the PPU keeps producing frames
+        let mut nes = Headless::new(test::spinning_rom()).unwrap();
+        nes.run_frames(3);
+        assert_eq!(nes.frame_count(), 3);
+        assert!(!nes.audio().is_empty());
+    }
+
+    #[test]
+    fn test_skipped_frames_keep_the_last_picture() {
+        let mut nes = Headless::new(test::spinning_rom()).unwrap();
+        nes.frame.set_pixel(0, 0, (0xFF, 0, 0));
+        let hash: u32 = nes.frame_hash();
+        nes.cpu.bus.ppu_mut().skip_render = true;
+        nes.run_frames(2);
+        assert_eq!((nes.frame_count(), nes.frame_hash()), (2, hash));
+        nes.cpu.bus.ppu_mut().skip_render = false;
+        nes.run_frame();
+        assert_ne!(nes.frame_hash(), hash);
+    }
+
+    #[test]
+    fn test_run_ahead_shows_a_later_frame() {
+        let rom: Rom = test::spinning_rom();
+        let mut ahead = Headless::new(rom.clone()).unwrap();
+        let mut real = Headless::new(rom).unwrap();
+        ahead.run_frame_ahead(2);
+        real.run_frame();
+        assert_eq!((ahead.frame_count(), ahead.ram()), (1, real.ram()));
+        assert_eq!(ahead.audio(), real.audio());
+        real.run_frames(2);
+        assert_eq!(ahead.frame_hash(), real.frame_hash());
+    }
+
+    #[test]
+    fn test_run_ahead_keeps_the_sound() {
+        // A square wave on pulse 1: enable it, duty 50% at full constant volume, period $080, then spin
+        let code: [u8; 23] = [
+            0xA9, 0x01, 0x8D, 0x15, 0x40, 0xA9, 0xBF, 0x8D, 0x00, 0x40, 0xA9, 0x80, 0x8D, 0x02, 0x40,
+            0xA9, 0x00, 0x8D, 0x03, 0x40, 0x4C, 0x14, 0x80,
+        ];
+        let rom: Rom = test::program_rom(&code);
+        let mut ahead = Headless::new(rom.clone()).unwrap();
+        let mut real = Headless::new(rom).unwrap();
+        for _ in 0..5 {
+            ahead.run_frame_ahead(1);
+            real.run_frame();
+            assert!(real.audio().iter().any(|sample| *sample != 0.0));
+            assert_eq!(ahead.audio(), real.audio());
+        }
+    }
+
+    #[test]
+    fn test_frame_hash_is_deterministic() {
+        let mut first = Headless::new(test::spinning_rom()).unwrap();
+        let mut second = Headless::new(test::spinning_rom()).unwrap();
+        // A rendered frame paints over the whole picture, leftovers included
+        first.frame.set_pixel(0, 0, (0xFF, 0, 0));
+        first.run_frames(2);
+        second.run_frames(2);
+        assert_eq!(first.frame_hash(), second.frame_hash());
+        first.frame.set_pixel(0, 0, (0xFF, 0, 0));
+        assert_ne!(first.frame_hash(), second.frame_hash());
+        second.frame.set_pixel(0, 0, (0xFF, 0, 0));
+        assert_eq!(first.frame_hash(), second.frame_hash());
+    }
+
+    #[test]
+    fn test_power_cycle_reinitializes_ram() {
+        let mut nes = Headless::with_ram_init(test::test_rom(), RamInit::Pattern(vec![0xFF])).unwrap();
+        assert!(nes.ram().iter().all(|byte| *byte == 0xFF));
+        nes.cpu.bus.cpu_vram[0x10] = 0x42;
+        nes.ram_init = RamInit::Pattern(vec![0x00, 0xFF]);
+        nes.power_cycle();
+        assert_eq!(&nes.ram()[0x10..0x13], &[0x00, 0xFF, 0x00]);
+        assert_eq!(nes.frame_count(), 0);
+    }
+
+    #[test]
+    fn test_unsupported_mapper_is_an_error() {
+        let mut rom: Rom = test::test_rom();
+        rom.mapper = 250;
+        assert!(Headless::new(rom.clone()).is_err());
+        // Swapping it in leaves the running game alone
+        let mut nes = Headless::new(test::test_rom()).unwrap();
+        nes.cpu.bus.dmc_dma = true;
+        nes.cpu.bus.cpu_vram[0x10] = 0x42;
+        assert!(nes.swap_rom(rom).is_err());
+        assert_eq!(nes.ram()[0x10], 0x42);
+        assert!(nes.swap_rom(test::test_rom()).is_ok());
+        assert_eq!((nes.ram()[0x10], nes.cpu.bus.dmc_dma), (0, true));
+    }
+}
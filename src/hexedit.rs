@@ -0,0 +1,213 @@
+use crate::prelude::*;
+use crate::cpu::CPU;
+
+// The memories the editor can show, each addressed from 0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Space {
+    // The CPU address space through Bus::peek / Bus::poke
+    Cpu,
+    // The PPU address space: pattern tables, nametables and palettes
+    Ppu,
+    Oam,
+    Palette,
+}
+
+impl Space {
+    pub const ALL: [Space; 4] = [Space::Cpu, Space::Ppu, Space::Oam, Space::Palette];
+    pub fn parse(name: &str) -> Result<Space, String> {
+        Space::ALL.into_iter().find(|space| space.name() == name.trim().to_lowercase())
+            .ok_or_else(|| format!("Unknown memory {:?}: expected cpu, ppu, oam or palette", name))
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Space::Cpu => "cpu",
+            Space::Ppu => "ppu",
+            Space::Oam => "oam",
+            Space::Palette => "palette",
+        }
+    }
+    pub fn next(self) -> Space {
+        let index: usize = Space::ALL.iter().position(|space| *space == self).unwrap_or(0);
+        Space::ALL[(index + 1) % Space::ALL.len()]
+    }
+    // One past the last address
+    pub fn size(self) -> u32 {
+        match self {
+            Space::Cpu => 0x10000,
+            Space::Ppu => 0x4000,
+            Space::Oam => 0x100,
+            Space::Palette => 0x20,
+        }
+    }
+}
+
+// Side-effect free access to any Space: no PPU register accesses, joypad shifts or mapper writes
+pub fn peek(cpu: &CPU, space: Space, addr: u16) -> u8 {
+    match space {
+        Space::Cpu => cpu.bus.peek(addr),
+        Space::Ppu => cpu.bus.ppu().peek_vram(addr),
+        Space::Oam => cpu.bus.ppu().oam_data[addr as usize & 0xFF],
+        Space::Palette => cpu.bus.ppu().palette_table[addr as usize & 0x1F],
+    }
+}
+
+pub fn poke(cpu: &mut CPU, space: Space, addr: u16, value: u8) {
+    match space {
+        Space::Cpu => cpu.bus.poke(addr, value),
+        Space::Ppu => cpu.bus.ppu_mut().poke_vram(addr, value),
+        Space::Oam => cpu.bus.ppu_mut().oam_data[addr as usize & 0xFF] = value,
+        Space::Palette => cpu.bus.ppu_mut().palette_table[addr as usize & 0x1F] = value,
+    }
+}
+
+// A byte held at a value: written back after every frame, whatever the game stores there
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Freeze {
+    pub space: Space,
+    pub addr: u16,
+    pub value: u8,
+}
+
+// A hex editor over one Space at a time, as text for the frontend to draw: a title line, then
+// ROWS lines of COLUMNS bytes. Two hex digits typed at the cursor write its byte; `goto` input
+// collects digits until confirmed.
+#[derive(Debug, Clone)]
+pub struct HexEditor {
+    pub space: Space,
+    pub cursor: u16,
+    // First address shown
+    pub top: u16,
+    pub frozen: Vec<Freeze>,
+    // The high nibble typed so far
+    nibble: Option<u8>,
+    goto: Option<String>,
+}
+
+impl Default for HexEditor {
+    fn default() -> Self { HexEditor { space: Space::Cpu, cursor: 0, top: 0, frozen: Vec::new(), nibble: None, goto: None } }
+}
+
+impl HexEditor {
+    pub const ROWS: u16 = 16;
+    pub const COLUMNS: u16 = 8;
+    pub fn new() -> Self { HexEditor::default() }
+    pub fn next_space(&mut self) {
+        self.space = self.space.next();
+        self.goto_addr(0);
+    }
+    // Puts `addr` on the top row
+    pub fn goto_addr(&mut self, addr: u16) {
+        self.cursor = ((addr as u32) % self.space.size()) as u16;
+        self.top = self.cursor - self.cursor % HexEditor::COLUMNS;
+        self.scroll_to_cursor();
+    }
+    // Moves by `delta` bytes, stopping at either end of the space, and scrolls only as far as needed
+    pub fn move_cursor(&mut self, delta: i32) {
+        self.cursor = (self.cursor as i32 + delta).clamp(0, self.space.size() as i32 - 1) as u16;
+        self.scroll_to_cursor();
+    }
+    fn scroll_to_cursor(&mut self) {
+        self.nibble = None;
+        let row: u16 = self.cursor - self.cursor % HexEditor::COLUMNS;
+        let page: u16 = HexEditor::ROWS * HexEditor::COLUMNS;
+        if row < self.top { self.top = row; }
+        if row >= self.top.saturating_add(page) { self.top = row + HexEditor::COLUMNS - page; }
+        let last_top: u32 = self.space.size().saturating_sub(page as u32);
+        self.top = (self.top as u32).min(last_top) as u16;
+    }
+    pub fn begin_goto(&mut self) { self.goto = Some(String::new()); }
+    pub fn is_entering_goto(&self) -> bool { self.goto.is_some() }
+    // Enter: jumps to the typed address; Escape (`jump` false) drops it
+    pub fn end_goto(&mut self, jump: bool) {
+        let Some(text) = self.goto.take() else { return; };
+        if let Ok(addr) = u16::from_str_radix(&text, 16) { if jump { self.goto_addr(addr); } }
+    }
+    // A hex digit 0..=15, for the goto address or the byte at the cursor; the second digit of a
+    // byte writes it and moves on
+    pub fn type_digit(&mut self, cpu: &mut CPU, digit: u8) {
+        if let Some(text) = self.goto.as_mut() {
+            if text.len() < 4 { text.push(char::from_digit(digit as u32, 16).unwrap_or('0')); }
+            return;
+        }
+        match self.nibble.take() {
+            None => self.nibble = Some(digit),
+            Some(high) => {
+                let value: u8 = high << 4 | digit;
+                poke(cpu, self.space, self.cursor, value);
+                // Editing a frozen byte changes what it's held at
+                for freeze in self.frozen.iter_mut().filter(|freeze| freeze.space == self.space && freeze.addr == self.cursor) { freeze.value = value; }
+                self.move_cursor(1);
+            }
+        }
+    }
+    // Holds the byte at the cursor at its current value, or lets it go again
+    pub fn toggle_freeze(&mut self, cpu: &CPU) {
+        let (space, addr): (Space, u16) = (self.space, self.cursor);
+        let count: usize = self.frozen.len();
+        self.frozen.retain(|freeze| !(freeze.space == space && freeze.addr == addr));
+        if self.frozen.len() == count { self.frozen.push(Freeze { space, addr, value: peek(cpu, space, addr) }); }
+    }
+    pub fn is_frozen(&self, addr: u16) -> bool { self.frozen.iter().any(|freeze| freeze.space == self.space && freeze.addr == addr) }
+    // Called by the frontend after each emulated frame
+    pub fn apply_freezes(&self, cpu: &mut CPU) {
+        for freeze in &self.frozen { poke(cpu, freeze.space, freeze.addr, freeze.value); }
+    }
+    pub fn lines(&self, cpu: &CPU) -> Vec<String> {
+        let title: String = match &self.goto {
+            Some(text) => format!("{} goto: {}", self.space.name(), text),
+            None => format!("{} {:04X}", self.space.name(), self.cursor),
+        };
+        let mut lines: Vec<String> = vec![title];
+        for row in 0..HexEditor::ROWS as u32 {
+            let start: u32 = self.top as u32 + row * HexEditor::COLUMNS as u32;
+            if start >= self.space.size() { break; }
+            let bytes: Vec<String> = (start..start + HexEditor::COLUMNS as u32).map(|addr| format!("{:02X}", peek(cpu, self.space, addr as u16))).collect();
+            lines.push(format!("{:04X} {}", start, bytes.join(" ")));
+        }
+        lines
+    }
+    // Line and character column of the byte at `addr` in `lines`, if it's on screen
+    pub fn cell(&self, addr: u16) -> Option<(usize, usize)> {
+        let offset: u16 = addr.checked_sub(self.top)?;
+        if offset >= HexEditor::ROWS * HexEditor::COLUMNS { return None; }
+        Some((1 + (offset / HexEditor::COLUMNS) as usize, 5 + (offset % HexEditor::COLUMNS) as usize * 3))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_edit_freeze_and_goto() {
+        let mut cpu: CPU<'static> = CPU::new(Bus::new(test::test_rom(), |_, _, _| {}));
+        let mut editor: HexEditor = HexEditor::new();
+        editor.goto_addr(0x0812);
+        for digit in [0xA, 0xB] { editor.type_digit(&mut cpu, digit); }
+        // Through the RAM mirror, then on to the next byte
+        assert_eq!(cpu.bus.cpu_vram[0x12], 0xAB);
+        assert_eq!(editor.cursor, 0x0813);
+        assert_eq!(editor.lines(&cpu)[1], "0810 00 00 AB 00 00 00 00 00");
+        assert_eq!(editor.cell(0x0813), Some((1, 14)));
+        editor.move_cursor(-1);
+        editor.toggle_freeze(&cpu);
+        cpu.bus.cpu_vram[0x12] = 0;
+        editor.apply_freezes(&mut cpu);
+        assert_eq!(cpu.bus.cpu_vram[0x12], 0xAB);
+        // Writes to registers and ROM don't go through
+        editor.goto_addr(0x2000);
+        for digit in [0x8, 0x0] { editor.type_digit(&mut cpu, digit); }
+        assert_eq!(cpu.bus.ppu().ctrl, 0);
+        editor.space = Space::Palette;
+        editor.begin_goto();
+        for digit in [0x1, 0x3] { editor.type_digit(&mut cpu, digit); }
+        editor.end_goto(true);
+        assert_eq!(editor.cursor, 0x13);
+        assert_eq!(editor.top, 0x00);
+        for digit in [0x2, 0x1] { editor.type_digit(&mut cpu, digit); }
+        assert_eq!(cpu.bus.ppu().palette_table[0x13], 0x21);
+        assert!(!editor.is_frozen(0x12));
+    }
+}
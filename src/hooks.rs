@@ -0,0 +1,118 @@
+use crate::prelude::*;
+use crate::cpu::CPU;
+use crate::debugger::Access;
+
+// The interrupt an interrupt hook is called for. Irq is the cartridge's IRQ line; APU IRQs don't
+// reach the CPU.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+    Brk,
+}
+
+pub type InstructionHook<'h> = Box<dyn FnMut(&mut CPU) + 'h>;
+pub type MemoryHook<'h> = Box<dyn FnMut(Access, u16, &mut u8) + 'h>;
+pub type InterruptHook<'h> = Box<dyn FnMut(&mut CPU, Interrupt) + 'h>;
+pub type FrameHook<'h> = Box<dyn FnMut(&mut CPU) + 'h>;
+
+// Callbacks for embedders of the core, kept in `Bus::hooks` and called in the order registered:
+//   instruction - before each instruction, with PC on its opcode; changing PC runs another one instead
+//   memory      - on every CPU read, after it, and write, before it; changing the value changes what
+//                 the CPU reads or what gets written. Accesses to PPU register mirrors are reported
+//                 again for the base register.
+//   interrupt   - once an NMI, IRQ or BRK entered its handler, with PC on the handler's first instruction
+//   frame       - after the instruction during which the PPU finished a frame
+// Everything but memory hooks gets the whole CPU, so it can read and change registers, memory
+// (through Mem or Bus::peek / Bus::poke) and the PPU / APU state.
+#[derive(Default)]
+pub struct Hooks<'h> {
+    instruction: Vec<InstructionHook<'h>>,
+    memory: Vec<MemoryHook<'h>>,
+    interrupt: Vec<InterruptHook<'h>>,
+    frame: Vec<FrameHook<'h>>,
+    // Set by the Bus when a frame ends, for the CPU to call the frame hooks once the instruction is done
+    frame_ended: bool,
+}
+
+impl<'h> Hooks<'h> {
+    pub fn on_instruction(&mut self, hook: impl FnMut(&mut CPU) + 'h) { self.instruction.push(Box::new(hook)); }
+    pub fn on_memory(&mut self, hook: impl FnMut(Access, u16, &mut u8) + 'h) { self.memory.push(Box::new(hook)); }
+    pub fn on_interrupt(&mut self, hook: impl FnMut(&mut CPU, Interrupt) + 'h) { self.interrupt.push(Box::new(hook)); }
+    pub fn on_frame(&mut self, hook: impl FnMut(&mut CPU) + 'h) { self.frame.push(Box::new(hook)); }
+    // Drops every hook
+    pub fn clear(&mut self) { *self = Hooks::default(); }
+    pub fn is_empty(&self) -> bool { self.instruction.is_empty() && self.memory.is_empty() && self.interrupt.is_empty() && self.frame.is_empty() }
+    pub(crate) fn memory(&mut self, access: Access, addr: u16, value: &mut u8) {
+        for hook in &mut self.memory { hook(access, addr, value); }
+    }
+    pub(crate) fn end_frame(&mut self) { self.frame_ended = !self.frame.is_empty(); }
+}
+
+// The CPU's hooks are taken out of its bus while they run, so they can borrow the CPU. Hooks they
+// register in the meantime are kept, after the others.
+pub(crate) fn instruction(cpu: &mut CPU) {
+    if cpu.bus.hooks.instruction.is_empty() { return; }
+    let mut hooks: Vec<InstructionHook> = core::mem::take(&mut cpu.bus.hooks.instruction);
+    for hook in &mut hooks { hook(cpu); }
+    hooks.append(&mut cpu.bus.hooks.instruction);
+    cpu.bus.hooks.instruction = hooks;
+}
+
+pub(crate) fn interrupt(cpu: &mut CPU, interrupt: Interrupt) {
+    if cpu.bus.hooks.interrupt.is_empty() { return; }
+    let mut hooks: Vec<InterruptHook> = core::mem::take(&mut cpu.bus.hooks.interrupt);
+    for hook in &mut hooks { hook(cpu, interrupt); }
+    hooks.append(&mut cpu.bus.hooks.interrupt);
+    cpu.bus.hooks.interrupt = hooks;
+}
+
+pub(crate) fn frame(cpu: &mut CPU) {
+    if !core::mem::take(&mut cpu.bus.hooks.frame_ended) { return; }
+    let mut hooks: Vec<FrameHook> = core::mem::take(&mut cpu.bus.hooks.frame);
+    for hook in &mut hooks { hook(cpu); }
+    hooks.append(&mut cpu.bus.hooks.frame);
+    cpu.bus.hooks.frame = hooks;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::{test, Rom};
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_hooks_see_and_change_state() {
+        // `INX; JMP $8000` at $8000, which is also the NMI handler
+        let mut rom: Rom = test::program_rom(&[0xE8, 0x4C, 0x00, 0x80]);
+        let len: usize = rom.prg_rom.len();
+        rom.prg_rom[len - 6..len - 4].copy_from_slice(&[0x00, 0x80]);
+        let events: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu: CPU<'static> = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+        cpu.mem_write(0x2000, 0x80);
+        let log = events.clone();
+        cpu.bus.hooks.on_interrupt(move |cpu, interrupt| log.borrow_mut().push(format!("{:?} {:04X}", interrupt, cpu.program_counter)));
+        let log = events.clone();
+        cpu.bus.hooks.on_frame(move |cpu| log.borrow_mut().push(format!("frame {}", cpu.bus.frames)));
+        let count: Rc<RefCell<u32>> = Rc::new(RefCell::new(0));
+        let counter = count.clone();
+        cpu.bus.hooks.on_instruction(move |cpu| {
+            *counter.borrow_mut() += 1;
+            cpu.register_a = 0x42;
+        });
+        cpu.run_frame();
+        cpu.run_frame();
+        assert_eq!(*events.borrow(), vec!["Nmi 8000", "frame 1", "Nmi 8000", "frame 2"]);
+        assert!(*count.borrow() > 1000);
+        assert_eq!(cpu.register_a, 0x42);
+        // Reading $8000 as INY instead of INX
+        cpu.bus.hooks.clear();
+        cpu.bus.hooks.on_memory(|access, addr, value| if access == Access::Read && addr == 0x8000 { *value = 0xC8; });
+        let (x, y): (u8, u8) = (cpu.register_x, cpu.register_y);
+        cpu.run_frame();
+        assert_eq!(cpu.register_x, x);
+        assert!(cpu.register_y > y);
+    }
+}
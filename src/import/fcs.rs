@@ -0,0 +1,112 @@
+use super::{ForeignState, le_u32, le_value, zlib_decompress};
+
+// FCEUX .fcs ("FCSX" header, FCEUX 2.x):
+//   "FCSX" | uncompressed size: u32 | version: u32 | compressed size: u32 (0xFFFFFFFF = stored)
+// followed by (zlib) chunks `type: u8 | size: u32 | entries`, each entry `name: [u8; 4] | size: u32 | data`.
+const HEADER_SIZE: usize = 16;
+const CHUNK_CPU: u8 = 1;
+const CHUNK_PPU: u8 = 3;
+
+pub fn parse(data: &[u8]) -> Result<ForeignState, String> {
+    if !data.starts_with(b"FCSX") { return Err(String::from("Only FCEUX 2.x (FCSX) savestates are supported")); }
+    let total_size: usize = le_u32(data, 4)? as usize;
+    let compressed_size: u32 = le_u32(data, 12)?;
+    let body: &[u8] = &data[HEADER_SIZE..];
+    let chunks: Vec<u8> = if compressed_size == u32::MAX { body.to_vec() } else { zlib_decompress(body, total_size)? };
+
+    let mut state: ForeignState = ForeignState::default();
+    let mut pos: usize = 0;
+    while pos + 5 <= chunks.len() {
+        let kind: u8 = chunks[pos];
+        let size: usize = le_u32(&chunks, pos + 1)? as usize;
+        let chunk: &[u8] = chunks.get(pos + 5..pos + 5 + size).ok_or("FCEUX savestate is truncated")?;
+        for (name, value) in entries(chunk)? {
+            match (kind, name) {
+                (CHUNK_CPU, b"PC\0\0") => state.pc = le_value(value) as u16,
+                (CHUNK_CPU, b"A\0\0\0") => state.a = value[0],
+                (CHUNK_CPU, b"X\0\0\0") => state.x = value[0],
+                (CHUNK_CPU, b"Y\0\0\0") => state.y = value[0],
+                (CHUNK_CPU, b"S\0\0\0") => state.sp = value[0],
+                (CHUNK_CPU, b"P\0\0\0") => state.status = value[0],
+                (CHUNK_CPU, b"RAM\0") => state.ram = Some(value.to_vec()),
+                (CHUNK_PPU, b"NTAR") => state.nametables = Some(value.to_vec()),
+                (CHUNK_PPU, b"PRAM") => state.palette = Some(value.to_vec()),
+                (CHUNK_PPU, b"SPRA") => state.oam = Some(value.to_vec()),
+                (CHUNK_PPU, b"PPUR") if value.len() >= 4 => {
+                    state.ppu_ctrl = Some(value[0]);
+                    state.ppu_mask = Some(value[1]);
+                    state.ppu_status = Some(value[2]);
+                    state.oam_addr = Some(value[3]);
+                }
+                (CHUNK_PPU, b"RADD") => state.ppu_addr = Some(le_value(value) as u16 & 0x3FFF),
+                (CHUNK_PPU, b"TADD") => {
+                    // loopy t: fine Y in bits 12-14, coarse Y in 5-9, coarse X in 0-4 (fine X is XOFF)
+                    let t: u16 = le_value(value) as u16;
+                    let fine_x: u8 = state.scroll.map_or(0, |(x, _)| x & 0x07);
+                    state.scroll = Some((((t & 0x1F) << 3) as u8 | fine_x, (((t >> 5) & 0x1F) << 3 | ((t >> 12) & 0x07)) as u8));
+                }
+                (CHUNK_PPU, b"XOFF") => {
+                    let (x, y) = state.scroll.unwrap_or((0, 0));
+                    state.scroll = Some(((x & !0x07) | (value[0] & 0x07), y));
+                }
+                _ => {}
+            }
+        }
+        pos += 5 + size;
+    }
+    if state.ram.is_none() { return Err(String::from("FCEUX savestate has no CPU chunk")); }
+    Ok(state)
+}
+
+// (name, data) of one chunk entry
+type Entry<'a> = (&'a [u8; 4], &'a [u8]);
+
+fn entries(chunk: &[u8]) -> Result<Vec<Entry<'_>>, String> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut pos: usize = 0;
+    while pos + 8 <= chunk.len() {
+        let name: &[u8; 4] = chunk[pos..pos + 4].try_into().unwrap();
+        let size: usize = le_u32(chunk, pos + 4)? as usize;
+        let value: &[u8] = chunk.get(pos + 8..pos + 8 + size).ok_or("FCEUX savestate is truncated")?;
+        if !value.is_empty() { entries.push((name, value)); }
+        pos += 8 + size;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(kind: u8, entries: &[Entry]) -> Vec<u8> {
+        let mut body: Vec<u8> = Vec::new();
+        for (name, value) in entries {
+            body.extend_from_slice(*name);
+            body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            body.extend_from_slice(value);
+        }
+        let mut chunk: Vec<u8> = vec![kind];
+        chunk.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        chunk.extend(body);
+        chunk
+    }
+
+    #[test]
+    fn test_parse_stored_fcs() {
+        let mut chunks: Vec<u8> = chunk(CHUNK_CPU, &[(b"PC\0\0", &[0x34, 0x12]), (b"A\0\0\0", &[7]), (b"S\0\0\0", &[0xF0]), (b"RAM\0", &[0x55; 0x800])]);
+        chunks.extend(chunk(CHUNK_PPU, &[(b"PRAM", &[0x0F; 32]), (b"PPUR", &[0x80, 0x1E, 0, 4]), (b"TADD", &[0x45, 0x00]), (b"XOFF", &[3])]));
+        let mut data: Vec<u8> = b"FCSX".to_vec();
+        data.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        data.extend_from_slice(&22020u32.to_le_bytes());
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend(chunks);
+
+        let state: ForeignState = parse(&data).unwrap();
+        assert_eq!((state.pc, state.a, state.sp), (0x1234, 7, 0xF0));
+        assert_eq!(state.ram, Some(vec![0x55; 0x800]));
+        assert_eq!(state.palette, Some(vec![0x0F; 32]));
+        assert_eq!((state.ppu_ctrl, state.ppu_mask, state.oam_addr), (Some(0x80), Some(0x1E), Some(4)));
+        assert_eq!(state.scroll, Some((5 * 8 + 3, 2 * 8)));
+        assert!(parse(b"FCSX\0\0\0\0\0\0\0\0\xFF\xFF\xFF\xFF").is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use crate::cpu::CPU;
+
+pub mod fcs;
+pub mod mss;
+
+// Savestates from other emulators, reduced to the fields both machines share: CPU registers,
+// work RAM and the PPU memories/registers. Mapper, APU and timing state is not carried over,
+// so the game resumes from the imported RAM/VRAM with this emulator's power-on APU and banks.
+#[derive(Debug, Default, PartialEq)]
+pub struct ForeignState {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub status: u8,
+    pub ram: Option<Vec<u8>>,
+    pub nametables: Option<Vec<u8>>,
+    pub palette: Option<Vec<u8>>,
+    pub oam: Option<Vec<u8>>,
+    pub ppu_ctrl: Option<u8>,
+    pub ppu_mask: Option<u8>,
+    pub ppu_status: Option<u8>,
+    pub oam_addr: Option<u8>,
+    pub ppu_addr: Option<u16>,
+    pub scroll: Option<(u8, u8)>,
+}
+
+impl ForeignState {
+    pub fn apply(&self, cpu: &mut CPU) {
+        cpu.program_counter = self.pc;
+        cpu.register_a = self.a;
+        cpu.register_x = self.x;
+        cpu.register_y = self.y;
+        cpu.stack_pointer = self.sp;
+        cpu.status = self.status;
+        copy_into(&mut cpu.bus.cpu_vram, &self.ram);
+        let ppu = cpu.bus.ppu_mut();
+        copy_into(&mut ppu.vram, &self.nametables);
+        copy_into(&mut ppu.palette_table, &self.palette);
+        copy_into(&mut ppu.oam_data, &self.oam);
+        if let Some(ctrl) = self.ppu_ctrl {
+            ppu.ctrl = ctrl;
+            ppu.loopy.write_ctrl(ctrl);
+        }
+        if let Some(mask) = self.ppu_mask { ppu.mask = mask; }
+        if let Some(status) = self.ppu_status { ppu.status = status; }
+        if let Some(oam_addr) = self.oam_addr { ppu.oam_addr = oam_addr; }
+        if let Some(addr) = self.ppu_addr { ppu.loopy.v = addr; }
+        if let Some((x, y)) = self.scroll { ppu.loopy.set_scroll(x, y); }
+    }
+}
+
+// Shorter or longer foreign arrays are copied as far as they overlap
+fn copy_into(target: &mut [u8], source: &Option<Vec<u8>>) {
+    if let Some(source) = source {
+        let len: usize = target.len().min(source.len());
+        target[..len].copy_from_slice(&source[..len]);
+    }
+}
+
+// Picks the format from the file's magic bytes
+pub fn import(data: &[u8]) -> Result<ForeignState, String> {
+    if data.starts_with(b"FCS") { fcs::parse(data) }
+    else if data.starts_with(b"MSS") { mss::parse(data) }
+    else { Err(String::from("Not an FCEUX (.fcs) or Mesen (.mss) savestate")) }
+}
+
+fn zlib_decompress(data: &[u8], size_hint: usize) -> Result<Vec<u8>, String> {
+    use std::io::Read;
+    let mut out: Vec<u8> = Vec::with_capacity(size_hint);
+    flate2::read::ZlibDecoder::new(data).read_to_end(&mut out).map_err(|err| format!("Corrupt compressed data: {}", err))?;
+    Ok(out)
+}
+
+fn le_u32(data: &[u8], pos: usize) -> Result<u32, String> {
+    data.get(pos..pos + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).ok_or(String::from("Savestate is truncated"))
+}
+
+// Little-endian value of up to 8 bytes
+fn le_value(data: &[u8]) -> u64 { data.iter().rev().fold(0, |value, byte| value << 8 | *byte as u64) }
@@ -0,0 +1,94 @@
+use super::{ForeignState, le_u32, le_value, zlib_decompress};
+
+// Mesen 2 .mss:
+//   "MSS" | emu version: u32 | format version: u32 | console type: u32 (0 = NES)
+//   | screenshot: buffer size, width, height, scale, compressed size: u32 each, zlib data
+//   | ROM name length: u32 | ROM name | compressed: u8 | (original size: u32, compressed size: u32, zlib) or raw
+// The state itself is a key/value list, each entry `key\0 | size: u32 | value`, with keys such as
+// "cpu.pc" or "memoryManager.internalRam". Keys are matched case-insensitively.
+const CONSOLE_NES: u32 = 0;
+
+pub fn parse(data: &[u8]) -> Result<ForeignState, String> {
+    if !data.starts_with(b"MSS") { return Err(String::from("Not a Mesen savestate")); }
+    let console: u32 = le_u32(data, 11)?;
+    if console != CONSOLE_NES { return Err(format!("Mesen savestate is for console type {}, not the NES", console)); }
+    let mut pos: usize = 15;
+    let screenshot_size: usize = le_u32(data, pos + 16)? as usize;
+    pos += 20 + screenshot_size;
+    let name_len: usize = le_u32(data, pos)? as usize;
+    pos += 4 + name_len;
+    let compressed: bool = *data.get(pos).ok_or("Mesen savestate is truncated")? != 0;
+    let entries: Vec<u8> = if compressed {
+        let original_size: usize = le_u32(data, pos + 1)? as usize;
+        let compressed_size: usize = le_u32(data, pos + 5)? as usize;
+        let body: &[u8] = data.get(pos + 9..pos + 9 + compressed_size).ok_or("Mesen savestate is truncated")?;
+        zlib_decompress(body, original_size)?
+    } else {
+        data[pos + 1..].to_vec()
+    };
+
+    let mut state: ForeignState = ForeignState::default();
+    let mut found_cpu: bool = false;
+    let mut pos: usize = 0;
+    while pos < entries.len() {
+        let key_end: usize = entries[pos..].iter().position(|byte| *byte == 0).ok_or("Mesen savestate is corrupt")? + pos;
+        let key: String = String::from_utf8_lossy(&entries[pos..key_end]).to_lowercase();
+        let size: usize = le_u32(&entries, key_end + 1)? as usize;
+        let value: &[u8] = entries.get(key_end + 5..key_end + 5 + size).ok_or("Mesen savestate is truncated")?;
+        pos = key_end + 5 + size;
+        if value.is_empty() { continue; }
+        match key.as_str() {
+            "cpu.pc" => { state.pc = le_value(value) as u16; found_cpu = true; }
+            "cpu.a" => state.a = value[0],
+            "cpu.x" => state.x = value[0],
+            "cpu.y" => state.y = value[0],
+            "cpu.sp" => state.sp = value[0],
+            "cpu.ps" => state.status = value[0],
+            "ppu.control" | "ppu.controlreg" => state.ppu_ctrl = Some(value[0]),
+            "ppu.mask" | "ppu.maskreg" => state.ppu_mask = Some(value[0]),
+            "ppu.spriteramaddr" => state.oam_addr = Some(value[0]),
+            "ppu.videoramaddr" => state.ppu_addr = Some(le_value(value) as u16 & 0x3FFF),
+            _ if key.ends_with("internalram") => state.ram = Some(value.to_vec()),
+            _ if key.ends_with("nametableram") => state.nametables = Some(value.to_vec()),
+            _ if key.ends_with("paletteram") => state.palette = Some(value.to_vec()),
+            _ if key.ends_with(".spriteram") => state.oam = Some(value.to_vec()),
+            _ => {}
+        }
+    }
+    if !found_cpu { return Err(String::from("Mesen savestate has no CPU state")); }
+    Ok(state)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(key: &str, value: &[u8]) -> Vec<u8> {
+        let mut entry: Vec<u8> = key.as_bytes().to_vec();
+        entry.push(0);
+        entry.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        entry.extend_from_slice(value);
+        entry
+    }
+
+    #[test]
+    fn test_parse_uncompressed_mss() {
+        let mut data: Vec<u8> = b"MSS".to_vec();
+        for value in [0x0200_0000u32, 4, CONSOLE_NES, 0, 256, 240, 100, 0] { data.extend_from_slice(&value.to_le_bytes()); }
+        data.extend_from_slice(&8u32.to_le_bytes());
+        data.extend_from_slice(b"game.nes");
+        data.push(0);
+        data.extend(entry("cpu.pc", &[0x00, 0xC0]));
+        data.extend(entry("cpu.sp", &[0xFD]));
+        data.extend(entry("memoryManager.internalRam", &[0x11; 0x800]));
+        data.extend(entry("ppu.paletteRam", &[0x22; 32]));
+        data.extend(entry("apu.something", &[1, 2, 3]));
+
+        let state: ForeignState = parse(&data).unwrap();
+        assert_eq!((state.pc, state.sp), (0xC000, 0xFD));
+        assert_eq!(state.ram, Some(vec![0x11; 0x800]));
+        assert_eq!(state.palette, Some(vec![0x22; 32]));
+        data[11] = 1;
+        assert!(parse(&data).is_err());
+    }
+}
@@ -16,7 +16,7 @@ bitflags! {
     }
 }
 */
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum JoypadButton {
     ButtonA = (1 << 0),
     ButtonB = (1 << 1),
@@ -28,11 +28,30 @@ pub enum JoypadButton {
     Right = (1 << 7),
 }
 
-#[derive(Default, Copy, Clone)]
+#[derive(Copy, Clone)]
 pub struct Joypad {
     strobe: bool,
     button_index: u8,
     button_status: u8,
+    // Buttons physically held, before opposing directions are masked out of `button_status`
+    held: u8,
+    // A real d-pad can't press Left+Right or Up+Down together and some games glitch if they see it:
+    // the newest direction wins, and the older one comes back if it is still held when that is released
+    pub block_opposing: bool,
+}
+
+impl Default for Joypad {
+    fn default() -> Self { Joypad::new() }
+}
+
+fn opposite(button: u8) -> u8 {
+    match button {
+        b if b == JoypadButton::Up as u8 => JoypadButton::Down as u8,
+        b if b == JoypadButton::Down as u8 => JoypadButton::Up as u8,
+        b if b == JoypadButton::Left as u8 => JoypadButton::Right as u8,
+        b if b == JoypadButton::Right as u8 => JoypadButton::Left as u8,
+        _ => 0,
+    }
 }
 
 impl Joypad {
@@ -41,6 +60,8 @@ impl Joypad {
             strobe: false,
             button_index: 0,
             button_status: 0,
+            held: 0,
+            block_opposing: true,
         }
     }
 
@@ -63,11 +84,27 @@ impl Joypad {
     }
 
     pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
-        if pressed { self.button_status |= button as u8; }
-        else { self.button_status &= !(button as u8); }
+        let bit: u8 = button as u8;
+        let opposite: u8 = if self.block_opposing { opposite(bit) } else { 0 };
+        if pressed {
+            self.held |= bit;
+            self.button_status = (self.button_status | bit) & !opposite;
+        } else {
+            self.held &= !bit;
+            self.button_status = (self.button_status & !bit) | (self.held & opposite);
+        }
     }
+    // All eight buttons at once, one bit per button in JoypadButton order, exactly as given (movies
+    // and scripts replay recorded states, opposing directions included)
+    pub fn set_button_status(&mut self, status: u8) {
+        self.button_status = status;
+        self.held = status;
+    }
+    pub fn button_status(&self) -> u8 { self.button_status }
 }
 
+savestate_fields!(Joypad { strobe, button_index, button_status });
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -82,9 +119,23 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_opposing_directions_are_blocked() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::Left, true);
+        joypad.set_button_pressed_status(JoypadButton::Right, true);
+        assert_eq!(joypad.button_status(), JoypadButton::Right as u8);
+        joypad.set_button_pressed_status(JoypadButton::Right, false);
+        assert_eq!(joypad.button_status(), JoypadButton::Left as u8);
+        joypad.block_opposing = false;
+        joypad.set_button_pressed_status(JoypadButton::Right, true);
+        assert_eq!(joypad.button_status(), JoypadButton::Left as u8 | JoypadButton::Right as u8);
+    }
+
     #[test]
     fn test_strobe_mode_on_off() {
         let mut joypad = Joypad::new();
+        joypad.block_opposing = false;
 
         joypad.write(0);
         joypad.set_button_pressed_status(JoypadButton::Right, true);
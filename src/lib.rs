@@ -0,0 +1,64 @@
+// Without the `std` feature the core is no_std + alloc: no file/zip loading, ROM database or logging.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+extern crate alloc;
+
+// Prints through std when available, compiles to nothing on no_std targets
+macro_rules! log {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "std")]
+        std::println!($($arg)*);
+        #[cfg(not(feature = "std"))]
+        let _ = core::format_args!($($arg)*);
+    }};
+}
+
+mod prelude;
+#[macro_use]
+pub mod savestate;
+
+// gbnes_core: the emulation core (CPU, bus, PPU, APU, cartridge/mappers, rendering into a Frame).
+// It has no windowing or audio-device dependencies; frontends drive it through `Bus`'s gameloop
+// callback and read the PPU/APU/joypad state they are handed there.
+pub mod cpu;
+pub mod bus;
+pub mod opcodes;
+pub mod disasm;
+pub mod cartridge;
+pub mod mapper;
+pub mod trace;
+pub mod ppu;
+pub mod render;
+#[cfg(feature = "std")]
+pub mod snake;
+pub mod joypad;
+pub mod apu;
+pub mod hash;
+pub mod headless;
+pub mod movie;
+pub mod debugger;
+pub mod callstack;
+pub mod profiler;
+pub mod coverage;
+pub mod testrom;
+pub mod hooks;
+pub mod hexedit;
+pub mod cheats;
+pub mod nsf;
+pub mod ramsearch;
+#[cfg(feature = "std")]
+pub mod romdb;
+#[cfg(feature = "std")]
+pub mod import;
+
+pub use cpu::{CPU, Mem};
+pub use bus::{Bus, RamInit};
+pub use cartridge::{Mirroring, Region, Rom};
+pub use ppu::{NesPPU, dot::PpuAccuracy};
+pub use apu::APU;
+pub use joypad::{Joypad, JoypadButton};
+pub use render::frame::Frame;
+pub use headless::Headless;
+pub use nsf::{Nsf, NsfPlayer};
+pub use debugger::Debugger;
+#[cfg(feature = "std")]
+pub use romdb::RomDb;
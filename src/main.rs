@@ -1,150 +1,726 @@
-use std::time::{Duration, Instant};
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-use apu::APU;
-use sdl2::event::Event;
-use sdl2::EventPump;
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
+use sdl2::EventPump;
 use sdl2::pixels::PixelFormatEnum;
 
-use rodio::{OutputStream, source::Source, Sink};
+use gbnes_core::{hash, savestate, Debugger, Frame, Headless, Joypad, Nsf, Region, Rom, RomDb};
+use gbnes_core::apu::CHANNELS;
+use gbnes_core::apu::mixer::{Channel, Mixer};
+use gbnes_core::cheats::Hold;
+use gbnes_core::debugger::Stop;
+use gbnes_core::hexedit::HexEditor;
+use gbnes_core::render::crt::{self, CrtPreset};
+use gbnes_core::render::frame::Image;
+use gbnes_core::render::osd::{self, Osd};
+use gbnes_core::render::palette::Palette;
+use gbnes_core::render::scale::Upscaler;
 
-pub mod cpu;
-pub mod bus;
-pub mod opcodes;
-pub mod cartridge;
-pub mod trace;
-pub mod ppu;
-pub mod render;
-pub mod snake;
-pub mod joypad;
-pub mod apu;
+mod frontend;
+use frontend::audio::{AudioOutput, AudioQueue, LatencyMeter};
+use frontend::avi::AviRecorder;
+use frontend::cheats::CheatFile;
+use frontend::clip::Clip;
+use frontend::cli::{ask_resume, coverage, disassemble, fds_bios, flag_value, flag_values, game_title, import_state, load_rom, load_rom_db, pick_rom, print_rom_info, profile, region_override, rom_path, trace_compare, try_load_rom};
+use frontend::config::Config;
+use frontend::fps::FpsCounter;
+use frontend::gamepad::Gamepads;
+#[cfg(feature = "gui")]
+use frontend::gui::Gui;
+use frontend::input::{Bindings, Command};
+use frontend::limiter::FrameLimiter;
+use frontend::netplay::{self, Netplay};
+use frontend::recent::RecentRoms;
+use frontend::remote::{Remote, Request};
+#[cfg(feature = "rpc")]
+use frontend::rpc::RpcServer;
+use frontend::screenshot::Screenshot;
+use frontend::slots::SaveSlots;
+use frontend::speed::Speed;
+use frontend::wav::WavRecorder;
 
-use cpu::CPU;
-use bus::Bus;
-use ppu::NesPPU;
-use cartridge::Rom;
-use render::frame::Frame;
-use joypad::JoypadButton;
-
-struct NesSound { buffer: Vec<f32> }
-impl Iterator for NesSound {
-    type Item = f32;
-    fn next(&mut self) -> Option<f32> { self.buffer.pop() }
-}
-impl Source for NesSound {
-    fn current_frame_len(&self) -> Option<usize> { Some(self.buffer.len()) }
-    fn channels(&self) -> u16 { 1 }
-    fn sample_rate(&self) -> u32 { 44100 }
-    fn total_duration(&self) -> Option<std::time::Duration> { None }
-}
+// emulation.run_ahead is capped here: each frame further ahead is another frame emulated per frame shown
+const MAX_RUN_AHEAD: u32 = 4;
+// What the save clip hotkey keeps when video.clip_seconds isn't set
+const DEFAULT_CLIP_SECONDS: u32 = 10;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let db: RomDb = load_rom_db(&args);
+    let config: Config = Config::load(&args);
+    let region: Option<Region> = region_override(&args, &config);
+    let fds_bios: PathBuf = fds_bios(&args, &config);
+    if args.get(1).map(String::as_str) == Some("rom-info") {
+        let path: String = rom_path(&args, 1).expect("Usage: gbnesmulator rom-info <ROM file> [--romdb file.csv|nes20db.xml]");
+        return print_rom_info(&path, &db);
+    }
+    if args.get(1).map(String::as_str) == Some("import-state") { return import_state(&args, &db); }
+    if args.get(1).map(String::as_str) == Some("disasm") { return disassemble(&args, &db); }
+    if args.get(1).map(String::as_str) == Some("trace-compare") { return trace_compare(&args, &db); }
+    if args.get(1).map(String::as_str) == Some("profile") { return profile(&args, &db); }
+    if args.get(1).map(String::as_str) == Some("coverage") { return coverage(&args, &db); }
+    if let Some(frames) = flag_value(&args, "--bench") {
+        let frames: u64 = frames.parse().unwrap_or_else(|_| { eprintln!("--bench expects a frame count, got {}", frames); std::process::exit(1); });
+        let filename: String = rom_path(&args, 0).expect("Usage: gbnesmulator --bench N <ROM file>");
+        return frontend::headless::bench(load_rom(&filename, &db, region, &fds_bios), config.ram_init(), config.ppu_accuracy(), config.flag("emulation.oam_quirks", false), config.flag("emulation.dmc_dma", false), frames);
+    }
+    if args.iter().any(|arg| arg == "--headless" || arg == "--frame-hash" || arg == "--input" || arg == "--movie") {
+        let filename: String = rom_path(&args, 0)
+            .expect("Usage: gbnesmulator --headless [--run-frames N] [--frame-hash each|final] [--input script.txt | --movie run.fm2] [--dump PREFIX] <ROM file>");
+        return frontend::headless::run(load_rom(&filename, &db, region, &fds_bios), config.ram_init(), config.ppu_accuracy(), config.flag("emulation.oam_quirks", false), config.flag("emulation.dmc_dma", false), &args);
+    }
+
+    //load the game: the one named, with `--recent [N]` one played before, else one picked in a file dialog
+    let mut recent: RecentRoms = RecentRoms::load(&Config::directory(&args));
+    let mut filename: String = if args.iter().any(|arg| arg == "--recent") {
+        recent.pick(&args).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    } else {
+        rom_path(&args, 0).or_else(pick_rom).unwrap_or_else(|| {
+            eprintln!("No ROM to play: give one as an argument, or pick one with --recent");
+            std::process::exit(1);
+        })
+    };
+    if filename.to_lowercase().ends_with(".nsf") {
+        let mut nsf: Nsf = Nsf::from_file(&filename).unwrap_or_else(|err| {
+            eprintln!("{}: {}", filename, err);
+            std::process::exit(1);
+        });
+        if let Some(region) = region { nsf.region = region; }
+        return frontend::nsf::play(&filename, nsf, &config, &args);
+    }
+    let rom: Rom = load_rom(&filename, &db, region, &fds_bios);
+    if let Err(err) = recent.add(&filename) { eprintln!("{}", err); }
+    let mut title: String = format!("{} - GBNesmulator", game_title(&filename, &rom, &db));
+    let mut slots: SaveSlots = SaveSlots::for_rom(&filename);
+    for (slot, info) in slots.list() { println!("Savestate slot {}: frame {}, saved at {}", slot, info.frame, info.timestamp); }
+    let resume: bool = slots.last_session().is_some_and(|info| ask_resume(&args, &info));
+    let fast_forward_speed: Speed = Speed::parse_fast_forward(&flag_value(&args, "--fast-forward").unwrap_or(String::from("4")))
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+
     let sdl_context: sdl2::Sdl = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
-        .window("GBNesmulator", (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
+        .window(&title, (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
         .position_centered()
         .build()
         .unwrap();
-    let mut canvas: sdl2::render::Canvas<sdl2::video::Window> = window.into_canvas().present_vsync().build().unwrap();
+    // The frame limiter sets the pace either way; vsync only holds each present for the monitor's refresh
+    let canvas: sdl2::render::CanvasBuilder = if config.flag("video.vsync", true) { window.into_canvas().present_vsync() } else { window.into_canvas() };
+    let mut canvas: sdl2::render::Canvas<sdl2::video::Window> = canvas.build().unwrap();
     let mut event_pump: EventPump = sdl_context.event_pump().unwrap();
+    let mut gamepads: Gamepads = Gamepads::new(sdl_context.game_controller().unwrap());
     canvas.set_scale(3.0, 3.0).unwrap();
     let creator = canvas.texture_creator();
     let mut texture = creator
         .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
         .unwrap();
+    // Recreated whenever the upscaler / CRT settings change the size of the presented picture
+    let mut texture_size: (usize, usize) = (256, 240);
 
-    //load the game
-    let filename: String = std::env::args()
-        .nth(1)
-        .expect("Please provide a ROM file as an argument");
-    let bytes: Vec<u8> = std::fs::read(filename).unwrap();
-    let rom: Rom = Rom::new(&bytes).unwrap();
-    
-    let mut frame: Frame = Frame::new();
-    let mut key_map = HashMap::new();
-    key_map.insert(Keycode::S, JoypadButton::Down);
-    key_map.insert(Keycode::W, JoypadButton::Up);
-    key_map.insert(Keycode::D, JoypadButton::Right);
-    key_map.insert(Keycode::A, JoypadButton::Left);
-    key_map.insert(Keycode::Backspace, JoypadButton::Select);
-    key_map.insert(Keycode::Return, JoypadButton::Start);
-    key_map.insert(Keycode::Space, JoypadButton::ButtonA);
-    key_map.insert(Keycode::Q, JoypadButton::ButtonB);
+    let bindings: Bindings = Bindings::from_config(&config).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
 
-    // Get handle to physical audio device
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    let sink: Sink = Sink::try_new(&stream_handle).unwrap();
+    // The frontend drives the machine one frame at a time, so it can stop or step emulation
+    // while still pumping events and presenting the last picture
+    let mut nes: Headless = Headless::with_ram_init(rom, config.ram_init()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    nes.cpu.bus.ppu_mut().accuracy = config.ppu_accuracy();
+    nes.cpu.bus.ppu_mut().oam_quirks = config.flag("emulation.oam_quirks", false);
+    nes.cpu.bus.dmc_dma = config.flag("emulation.dmc_dma", false);
+    nes.cpu.bus.extra_scanlines = config.number("emulation.extra_scanlines").unwrap_or(0).min(1000) as u16;
+    nes.cpu.bus.apu().mixer = config.mixer();
+    if let Err(err) = slots.load_disk(&mut nes.cpu) { eprintln!("Could not load the saved disk: {}", err); }
+    // The cheats saved for this game last time, then `--cheat CODE`, as often as needed: raw ADDR:VALUE, Pro Action Replay AAAAVV or Game Genie codes.
+    // These last for the session; only the ones added in the debugger are saved with the file's.
+    let mut cheat_file: CheatFile = CheatFile::for_rom(&Config::directory(&args), nes.cpu.bus.rom_crc32);
+    load_cheats(&mut cheat_file, &mut nes);
+    for code in flag_values(&args, "--cheat") {
+        let saved: Vec<String> = nes.cpu.bus.cheats.list.iter().map(|cheat| cheat.code.clone()).collect();
+        match nes.cpu.bus.cheats.add(&code, Hold::EveryFrame) {
+            Ok(cheat) => {
+                println!("Cheat: {}", cheat.code);
+                if !saved.contains(&cheat.code) { cheat_file.add_session_only(&cheat.code); }
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut osd: Osd = Osd::new();
+    nes.cpu.bus.joypad1().block_opposing = config.flag("input.block_opposing_directions", true);
+    if resume {
+        match slots.load_last_session(&mut nes.cpu) {
+            Ok(info) => notify(&mut osd, format!("Resumed last session at frame {}", info.frame)),
+            Err(err) => warn(&mut osd, format!("Could not resume last session: {}", err)),
+        }
+    }
 
-    // * Save to a file the audio buffer for debugging
-    //let filename = "audio.bin";
-    //let mut file = std::fs::File::create(filename).unwrap();
     // * Code for timing the game loop (VSYNC)
     // ****************
-    let vsync: Duration = Duration::from_secs_f32(1.0 / 60.0);
-    let mut last_frame: Instant = Instant::now();
+    // The loaded ROM's, which follow_frame_rate keeps up to date
+    let mut frame_rate: f64 = nes.cpu.bus.ppu().region.frame_rate();
+    // The last `video.clip_seconds` of frames, for the save clip hotkey. Left unset, nothing is kept
+    // (the frames add up to tens of MB) until the hotkey is first pressed.
+    let clip_seconds: Option<u32> = config.number("video.clip_seconds");
+    let mut clip: Option<Clip> = clip_seconds.filter(|seconds| *seconds > 0).map(|seconds| Clip::new(seconds, frame_rate));
+    let mut limiter: FrameLimiter = FrameLimiter::new(frame_rate);
+    // Frames in a row that may go undrawn to catch up with real time
+    let frame_skip: u32 = config.number("video.frame_skip").unwrap_or(0);
+    // Frames emulated ahead of the one shown, to hide the game's input lag
+    let run_ahead: u32 = config.number("emulation.run_ahead").unwrap_or(0).min(MAX_RUN_AHEAD);
     // ****************
-    let bus: Bus<'_> = Bus::new(rom, move |ppu: &NesPPU, apu: &mut APU, joypad: &mut joypad::Joypad| {
-        // * Code for timing the game loop (VSYNC)
-        // ****************
-        let now: Instant = Instant::now();
-        let elapsed: Duration = now - last_frame;
-        if elapsed < vsync { std::thread::sleep(vsync - elapsed); }
-        last_frame = Instant::now();
-        // ****************
-        // * Code for rendering the game to the screen
-        // ****************
-        render::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 3).unwrap();
-        canvas.copy(&texture, None, None).unwrap();
-        canvas.present();
-        // ****************
+    // One continuous stream on the audio device for the whole session, fed each frame's samples through a queue
+    let audio: AudioQueue = AudioQueue::for_latency(44100, config.audio_latency());
+    let audio_output: AudioOutput = AudioOutput::open(config.audio_backend(), audio.clone(), 44100, config.number("audio.buffer_size")).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let audio_rate: f64 = audio_output.sample_rate(44100) as f64;
+    // `--record-audio PATH` records from the start; the record hotkey starts and stops it at any time
+    let mut recorder: Option<WavRecorder> = flag_value(&args, "--record-audio").map(|path| {
+        WavRecorder::create(Path::new(&path), audio_rate as u32, CHANNELS as u16).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    });
+    // `--record-video PATH` likewise, for the picture and sound together
+    let mut video: Option<AviRecorder> = flag_value(&args, "--record-video").map(|path| {
+        AviRecorder::create(Path::new(&path), frame_rate, audio_rate as u32, CHANNELS as u16).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    });
+    let mut audio_latency: LatencyMeter = LatencyMeter::default();
+    let mut paused: bool = false;
+    // With emulation.pause_unfocused, the game holds still while another window has the focus
+    let pause_unfocused: bool = config.flag("emulation.pause_unfocused", false);
+    let mut unfocused: bool = false;
+    let mut fast_forward: bool = false;
+    let mut slow_motion: bool = false;
+    let mut tick: u64 = 0;
+    let mut fps: FpsCounter = FpsCounter::start(frame_rate);
+    let mut show_fps: bool = config.flag("video.show_fps", false);
+    let upscaler: Upscaler = Upscaler::parse(config.get("video.upscaler").unwrap_or("none")).unwrap_or_else(|err| {
+        eprintln!("video.upscaler: {}", err);
+        std::process::exit(1);
+    });
+    let palettes: Vec<(String, Palette)> = config.palettes();
+    let mut palette: usize = 0;
+    nes.palette = palettes[palette].1.clone();
+    // `--debug` starts paused in the terminal debugger; the break hotkey gets there at any time
+    let mut netplay: Option<Netplay> = start_netplay(&args, &mut nes);
+    let mut remote: Option<Remote> = flag_value(&args, "--remote").map(|port| {
+        port.parse::<u16>().map_err(|_| format!("--remote: expected a port, got {:?}", port)).and_then(|port| Remote::listen(port, config.remote_origins())).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    });
+    #[cfg(feature = "rpc")]
+    let mut rpc: Option<RpcServer> = flag_value(&args, "--rpc").map(|port| {
+        port.parse::<u16>().map_err(|_| format!("--rpc: expected a port, got {:?}", port)).and_then(|port| RpcServer::listen(port, config.remote_origins())).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    });
+    let mut debugger: Option<Debugger> = args.iter().any(|arg| arg == "--debug").then(|| {
+        let mut debugger: Debugger = Debugger::new();
+        debugger.paused = true;
+        debugger
+    });
+    let mut hex_editor: HexEditor = HexEditor::new();
+    let mut show_hex_editor: bool = false;
+    let mut show_recent_menu: bool = false;
+    // The menu's input panel rebinds keys
+    #[cfg(feature = "gui")]
+    let (mut gui, mut bindings): (Gui, Bindings) = (Gui::new(), bindings);
+    // The channel the channel volume hotkeys change
+    let mut mixer_channel: Channel = Channel::Pulse1;
+    let mut crt_preset: CrtPreset = CrtPreset::parse(config.get("video.crt").unwrap_or("off")).unwrap_or_else(|err| {
+        eprintln!("video.crt: {}", err);
+        std::process::exit(1);
+    });
+    let screenshot: Screenshot = Screenshot::parse(config.get("video.screenshot").unwrap_or("raw")).unwrap_or_else(|err| {
+        eprintln!("video.screenshot: {}", err);
+        std::process::exit(1);
+    });
+    loop {
         // * Code for handling input
         // ****************
+        let mut commands: Vec<Command> = Vec::new();
         for event in event_pump.poll_iter() {
+            #[cfg(feature = "gui")]
+            if gui.handle(&event, &mut bindings, canvas.scale().0) { continue; }
+            if gamepads.handle(&event, local_pad(&mut nes, &mut netplay)) { continue; }
+            // While open, the memory editor gets first pick of the keyboard
+            if let Event::KeyDown { keycode: Some(keycode), .. } = event {
+                if show_hex_editor && frontend::hexedit::handle_key(&mut hex_editor, &mut nes.cpu, keycode, netplay.is_some()) { continue; }
+                // and the recent ROMs menu its number keys, with Escape closing it
+                if show_recent_menu {
+                    if let Some(number) = frontend::recent::number_key(keycode) { commands.push(Command::OpenRecent(number)); continue; }
+                    if keycode == Keycode::Escape { show_recent_menu = false; continue; }
+                }
+            }
             match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                } => std::process::exit(0),
-                Event::KeyDown { keycode, .. } => {
-                    if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, true);
+                Event::Quit { .. } => commands.push(Command::Quit),
+                Event::Window { win_event: WindowEvent::FocusLost, .. } if pause_unfocused && !unfocused => {
+                    unfocused = true;
+                    notify(&mut osd, String::from("Paused in the background"));
+                }
+                Event::Window { win_event: WindowEvent::FocusGained, .. } if unfocused => {
+                    unfocused = false;
+                    notify(&mut osd, String::from(if paused { "Back in focus (still paused)" } else { "Resumed" }));
+                }
+                Event::KeyDown { keycode: Some(keycode), keymod, repeat, .. } if bindings.command(keycode, keymod).is_some() => {
+                    if !repeat { commands.extend(bindings.command(keycode, keymod)); }
+                }
+                Event::KeyDown { keycode: Some(keycode), .. } => {
+                    if let Some(button) = bindings.button(keycode) {
+                        local_pad(&mut nes, &mut netplay).set_button_pressed_status(button, true);
                     }
                 }
-                Event::KeyUp { keycode, .. } => {
-                    if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, false);
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    commands.extend(bindings.release(keycode));
+                    if let Some(button) = bindings.button(keycode) {
+                        local_pad(&mut nes, &mut netplay).set_button_pressed_status(button, false);
                     }
                 }
                 _ => { /* do nothing */ }
             }
         }
+        // A game to switch to, picked from the recent ROMs or the menu
+        let mut open: Option<String> = None;
+        #[cfg(feature = "gui")]
+        {
+            let actions: frontend::gui::Actions = gui.run(&mut nes, &bindings, &slots, &recent, canvas.window().size());
+            commands.extend(actions.commands);
+            if actions.open.is_some() { open = actions.open; }
+        }
+        // * Code for the remote control API, whose requests are handled here like hotkeys
+        if let Some(server) = remote.as_mut() {
+            for (client, request) in server.poll() {
+                let answer: Result<String, String> = match request {
+                    Request::Buttons(status) => { local_pad(&mut nes, &mut netplay).set_button_status(status); Ok(String::new()) }
+                    Request::Press(buttons, pressed) => {
+                        for button in buttons { local_pad(&mut nes, &mut netplay).set_button_pressed_status(button, pressed); }
+                        Ok(String::new())
+                    }
+                    Request::Read(addr, length) => Ok((0..length).map(|i| format!("{:02X}", nes.cpu.bus.peek(addr.wrapping_add(i as u16)))).collect()),
+                    Request::SaveState(slot) => slots.save(&nes.cpu, slot).map(|info| format!("frame {}", info.frame)),
+                    Request::LoadState(_) | Request::LoadRom(_) if netplay.is_some() => Err(String::from("not during netplay")),
+                    Request::LoadState(slot) => slots.load(&mut nes.cpu, slot).map(|info| format!("frame {}", info.frame)),
+                    Request::LoadRom(path) => {
+                        save_session(&slots, &cheat_file, &nes);
+                        open_rom(&path, &mut nes, &db, region, &fds_bios, &Config::directory(&args)).map(|(game, game_slots, game_cheats)| {
+                            if let Err(err) = recent.add(&path) { eprintln!("{}", err); }
+                            (filename, title, slots, cheat_file) = (path, game, game_slots, game_cheats);
+                            canvas.window_mut().set_title(&title).ok();
+                            follow_frame_rate(&mut nes, &mut frame_rate, &mut limiter, &mut fps, &mut clip, clip_seconds);
+                            notify(&mut osd, format!("Loaded {}", filename));
+                            String::new()
+                        })
+                    }
+                    Request::Hotkey(command) => { commands.push(command); Ok(String::new()) }
+                    Request::Frames(on) => { server.subscribe(client, on); Ok(String::new()) }
+                    Request::Status => Ok(format!("frame {} {} {}", nes.frame_count(), if paused { "paused" } else { "running" }, filename)),
+                };
+                server.answer(client, answer);
+            }
+        }
+        #[cfg(feature = "rpc")]
+        if let Some(server) = rpc.as_mut() { server.poll(&mut nes); }
+        if let Some(debugger) = debugger.as_mut().filter(|debugger| debugger.paused) {
+            if !frontend::debug::prompt(debugger, &mut nes) { commands.push(Command::Quit); }
+            limiter.reset();
+        }
+        let mut advance: bool = false;
+        for command in commands {
+            if netplay.is_some() && matches!(command, Command::LoadState(_) | Command::PowerCycle | Command::SwapDisk | Command::Break | Command::Microphone(true) | Command::OpenRecent(_) | Command::TogglePause | Command::FrameAdvance) {
+                warn(&mut osd, String::from("Not during netplay: the other player's machine would go out of sync"));
+                continue;
+            }
+            match command {
+                Command::SaveState(slot) => match slots.save(&nes.cpu, slot) {
+                    Ok(info) => notify(&mut osd, format!("Saved slot {} (frame {})", slot, info.frame)),
+                    Err(err) => warn(&mut osd, format!("Could not save slot {}: {}", slot, err)),
+                },
+                Command::LoadState(slot) => match slots.load(&mut nes.cpu, slot) {
+                    Ok(info) => notify(&mut osd, format!("Loaded slot {} (frame {})", slot, info.frame)),
+                    Err(err) => warn(&mut osd, format!("Could not load slot {}: {}", slot, err)),
+                },
+                Command::TogglePause => {
+                    paused = !paused;
+                    notify(&mut osd, String::from(if paused { "Paused" } else { "Resumed" }));
+                }
+                Command::FrameAdvance => {
+                    paused = true;
+                    advance = true;
+                }
+                Command::FastForward(held) => {
+                    if held { notify(&mut osd, String::from("Fast forward")); }
+                    fast_forward = held;
+                }
+                Command::ToggleSlowMotion => {
+                    slow_motion = !slow_motion;
+                    notify(&mut osd, format!("Slow motion {}", if slow_motion { "on" } else { "off" }));
+                }
+                Command::Screenshot => {
+                    let path: PathBuf = frontend::screenshot::default_path(&filename);
+                    match screenshot.save(&Image::from_frame(&nes.frame), || filter(&nes.frame, upscaler, crt_preset), &path) {
+                        Ok(saved) => {
+                            let paths: Vec<String> = saved.iter().map(|path| path.display().to_string()).collect();
+                            notify(&mut osd, format!("Saved screenshot to {}", paths.join(" and ")));
+                        }
+                        Err(err) => warn(&mut osd, err),
+                    }
+                }
+                Command::PowerCycle => {
+                    nes.ram_init = config.ram_init();
+                    nes.power_cycle();
+                    notify(&mut osd, String::from("Power cycled"));
+                }
+                Command::Microphone(active) => nes.cpu.bus.set_microphone(active),
+                Command::ToggleFps => show_fps = !show_fps,
+                Command::NextPalette => {
+                    palette = (palette + 1) % palettes.len();
+                    nes.palette = palettes[palette].1.clone();
+                    // Redraw right away so a paused picture changes colour too
+                    nes.redraw();
+                    notify(&mut osd, format!("Palette: {}", palettes[palette].0));
+                }
+                Command::NextCrtPreset => {
+                    crt_preset = crt_preset.next();
+                    notify(&mut osd, format!("CRT filter: {}", crt_preset.name()));
+                }
+                Command::Break => debugger.get_or_insert_with(Debugger::new).paused = true,
+                Command::ToggleHexEditor => show_hex_editor = !show_hex_editor,
+                Command::ToggleMute(channel) => {
+                    let muted: bool = nes.cpu.bus.apu().mixer.toggle_mute(channel);
+                    notify(&mut osd, format!("{}: {}", channel.name(), if muted { "muted" } else { "on" }));
+                }
+                Command::MasterVolume(step) => {
+                    let mixer: &mut Mixer = &mut nes.cpu.bus.apu().mixer;
+                    mixer.set_master(((mixer.master + step as f32 * 0.05) * 20.0).round() / 20.0);
+                    mixer.mute_all = false;
+                    notify(&mut osd, format!("Volume: {:.0}%", mixer.master * 100.0));
+                }
+                Command::ToggleMuteAll => {
+                    let mixer: &mut Mixer = &mut nes.cpu.bus.apu().mixer;
+                    mixer.mute_all = !mixer.mute_all;
+                    notify(&mut osd, String::from(if mixer.mute_all { "Sound muted" } else { "Sound on" }));
+                }
+                Command::NextMixerChannel => {
+                    mixer_channel = mixer_channel.next();
+                    notify(&mut osd, format!("{} volume: {:.0}%", mixer_channel.name(), nes.cpu.bus.apu().mixer.gain[mixer_channel as usize] * 100.0));
+                }
+                Command::ChannelVolume(step) => {
+                    let mixer: &mut Mixer = &mut nes.cpu.bus.apu().mixer;
+                    let gain: f32 = ((mixer.gain[mixer_channel as usize] + step as f32 * 0.1) * 10.0).round() / 10.0;
+                    mixer.set_gain(mixer_channel, gain);
+                    notify(&mut osd, format!("{} volume: {:.0}%", mixer_channel.name(), mixer.gain[mixer_channel as usize] * 100.0));
+                }
+                Command::ToggleAudioRecording => match recorder.take() {
+                    Some(recording) => finish_recording(&mut osd, recording),
+                    None => match WavRecorder::create(&WavRecorder::default_path(&filename), audio_rate as u32, CHANNELS as u16) {
+                        Ok(recording) => {
+                            notify(&mut osd, format!("Recording audio to {}", recording.path().display()));
+                            recorder = Some(recording);
+                        }
+                        Err(err) => warn(&mut osd, err),
+                    },
+                },
+                Command::ToggleVideoRecording => match video.take() {
+                    Some(recording) => finish_video(&mut osd, recording),
+                    None => match AviRecorder::create(&AviRecorder::default_path(&filename), frame_rate, audio_rate as u32, CHANNELS as u16) {
+                        Ok(recording) => {
+                            notify(&mut osd, format!("Recording video to {}", recording.path().display()));
+                            video = Some(recording);
+                        }
+                        Err(err) => warn(&mut osd, err),
+                    },
+                },
+                Command::SaveClip => match clip.as_ref() {
+                    Some(clip) => {
+                        let path: PathBuf = Clip::default_path(&filename);
+                        match clip.save(&path) {
+                            Ok(()) => notify(&mut osd, format!("Saved {:.1}s clip to {}", clip.seconds(), path.display())),
+                            Err(err) => warn(&mut osd, err),
+                        }
+                    }
+                    None if clip_seconds.is_none() => {
+                        clip = Some(Clip::new(DEFAULT_CLIP_SECONDS, frame_rate));
+                        notify(&mut osd, format!("Keeping the last {}s from now on: press again to save them", DEFAULT_CLIP_SECONDS));
+                    }
+                    None => warn(&mut osd, String::from("Clips are off (video.clip_seconds = 0)")),
+                },
+                // Only the NSF player has songs to switch between
+                Command::Track(_) => {}
+                Command::SwapDisk => {
+                    let mapper = nes.cpu.bus.mapper();
+                    let sides: usize = mapper.borrow().disk_sides();
+                    let message: String = match mapper.borrow_mut().swap_disk() {
+                        _ if sides == 0 => String::from("No disk drive"),
+                        Some(side) => format!("Disk {} side {} inserted", side / 2 + 1, if side % 2 == 0 { 'A' } else { 'B' }),
+                        None => String::from("Disk ejected"),
+                    };
+                    notify(&mut osd, message);
+                }
+                Command::ToggleRecentMenu => show_recent_menu = !show_recent_menu,
+                Command::OpenRecent(number) => {
+                    show_recent_menu = false;
+                    if let Some(path) = recent.get(number) { open = Some(String::from(path)); }
+                }
+                #[cfg(feature = "gui")]
+                Command::ToggleMenu => gui.open = !gui.open,
+                #[cfg(not(feature = "gui"))]
+                Command::ToggleMenu => warn(&mut osd, String::from("No menu in this build (see the gui feature)")),
+                Command::Quit => {
+                    if let Some(recording) = recorder.take() { finish_recording(&mut osd, recording); }
+                    if let Some(recording) = video.take() { finish_video(&mut osd, recording); }
+                    save_session(&slots, &cheat_file, &nes);
+                    std::process::exit(0);
+                }
+            }
+        }
+        if open.is_some() && netplay.is_some() {
+            warn(&mut osd, String::from("Not during netplay: the other player's machine would go out of sync"));
+        } else if let Some(path) = open {
+            save_session(&slots, &cheat_file, &nes);
+            match open_rom(&path, &mut nes, &db, region, &fds_bios, &Config::directory(&args)) {
+                Ok((game, game_slots, game_cheats)) => {
+                    if let Err(err) = recent.add(&path) { eprintln!("{}", err); }
+                    (filename, title, slots, cheat_file) = (path, game, game_slots, game_cheats);
+                    canvas.window_mut().set_title(&title).ok();
+                    follow_frame_rate(&mut nes, &mut frame_rate, &mut limiter, &mut fps, &mut clip, clip_seconds);
+                    notify(&mut osd, format!("Loaded {}", filename));
+                }
+                Err(err) => warn(&mut osd, err),
+            }
+        }
         // ****************
-        // * Code for saving buffer to file for debugging
+        // * Code for running this pass's frames: none while paused, one when stepping, else per speed
         // ****************
-        //let last_timing: Duration = timing.elapsed();
-        //let audio_buffer_size: usize = apu.buffer.len();
-        //println!("Time: {:?}ms\t|FPS: {:2.2}\t|BufSize: {}", last_timing.as_millis(), 1.0_f32 / timing.elapsed().as_secs_f32(), audio_buffer_size);
-        //timing = Instant::now();
-        //for f in apu.buffer.iter() {
-        //    let bytes: [u8; 4] = unsafe { std::mem::transmute(*f) };
-        //    file.write_all(&bytes).unwrap();
-        //}
+        let speed: Speed = if advance { Speed::Normal } else if fast_forward { fast_forward_speed } else if slow_motion { Speed::SlowMotion } else { Speed::Normal };
+        // The menu holds the game still while open, except in netplay, where the other player would be left waiting
+        #[cfg(feature = "gui")]
+        let paused: bool = paused || (gui.open && netplay.is_none());
+        // Not in netplay, where the other player would be left waiting; no frames means no sound either
+        let background: bool = unfocused && netplay.is_none();
+        let frames: u32 = if advance { 1 } else if paused || background { 0 } else { speed.frames(tick) };
+        // * Code for frame skip: when behind at normal speed, run the missed frames first without drawing them.
+        // Not while recording video, which would otherwise repeat the last drawn picture for them
+        let skipped: u32 = if frames == 1 && speed == Speed::Normal && !advance && video.is_none() { limiter.frames_behind().min(frame_skip) } else { 0 };
+        limiter.catch_up(skipped);
+        let frames: u32 = frames + skipped;
+        let mut emulated: u32 = 0;
+        nes.cpu.bus.apu().set_sample_rate(audio.controlled_rate(audio_rate));
+        for n in 0..frames {
+            // Never run past the pass's time budget, so fast-forward can't fall behind the display
+            if n > skipped && limiter.over_budget() { break; }
+            nes.cpu.bus.ppu_mut().skip_render = n < skipped;
+            // * Code for netplay: both players' buttons for this frame, waiting on the other's
+            if let Some(session) = netplay.as_mut() {
+                match session.exchange(session.input.button_status(), hash::crc32(nes.ram())) {
+                    Ok([pad1, pad2]) => {
+                        nes.cpu.bus.joypad1().set_button_status(pad1);
+                        nes.cpu.bus.joypad2().set_button_status(pad2);
+                    }
+                    Err(err) => {
+                        warn(&mut osd, err);
+                        netplay = None;
+                    }
+                }
+            }
+            // * Code for run-ahead, on the frame that gets drawn; not in netplay, where both machines must run the same frames
+            let stop: Stop = match debugger.as_mut() {
+                Some(debugger) => nes.run_frame_with(debugger),
+                None if n + 1 == frames && netplay.is_none() => { nes.run_frame_ahead(run_ahead); Stop::Frame }
+                None => { nes.run_frame(); Stop::Frame }
+            };
+            hex_editor.apply_freezes(&mut nes.cpu);
+            if let Some(debugger) = debugger.as_mut() {
+                for warning in debugger.calls.warnings.drain(..) { eprintln!("{}", warning); }
+            }
+            if stop != Stop::Frame {
+                println!("{}", stop.describe());
+                break;
+            }
+            emulated += 1;
+            // * Code for recording every emulated frame's audio, whatever the speed
+            if let Some(Err(err)) = recorder.as_mut().map(|recorder| recorder.write(nes.audio())) {
+                warn(&mut osd, format!("Audio recording stopped: {}", err));
+                recorder = None;
+            }
+            if let Some(Err(err)) = video.as_mut().map(|video| video.write(&nes.frame, nes.cpu.bus.apu().buffer.as_slice())) {
+                warn(&mut osd, format!("Video recording stopped: {}", err));
+                if let Some(recording) = video.take() { finish_video(&mut osd, recording); }
+            }
+            if let Some(clip) = clip.as_mut() { clip.push(&nes.frame); }
+            if let Some(server) = remote.as_mut() { server.frame(nes.frame_count(), nes.frame_hash()); }
+            // * Code for playing audio
+            if let Some(repeat) = speed.audio_repeat() { audio.push(nes.audio(), repeat); }
+        }
+        nes.cpu.bus.ppu_mut().skip_render = false;
+        tick += 1;
         // ****************
-        // * Code for playing audio
+        // * Code for rendering the game to the screen, with the OSD over a copy of the picture
         // ****************
-        let sound: NesSound = NesSound { buffer: apu.buffer.clone() };
-        sink.append(sound.amplify(0.2));
+        audio_latency.sample(&audio, audio_rate);
+        if fps.present(emulated) { canvas.window_mut().set_title(&format!("{} - {} - {}", title, fps.label(), audio_latency.label(&audio))).ok(); }
+        let mut screen: Frame = nes.frame.clone();
+        if show_hex_editor { frontend::hexedit::draw(&hex_editor, &nes.cpu, &mut screen); }
+        if show_recent_menu { frontend::recent::draw(&recent, &mut screen); }
+        osd.draw(&mut screen);
+        osd.tick();
+        if show_fps {
+            let label: String = fps.label();
+            osd::draw_text(&mut screen, Frame::WIDTH - 8 - label.len() * 6, 8, &label, (0xFF, 0xFF, 0x00));
+        }
+        let image: Image = filter(&screen, upscaler, crt_preset);
+        #[cfg(feature = "gui")]
+        let image: Image = if gui.open { gui.paint(&image, canvas.window().size()) } else { image };
+        if (image.width, image.height) != texture_size {
+            texture_size = (image.width, image.height);
+            texture = creator.create_texture_target(PixelFormatEnum::RGB24, image.width as u32, image.height as u32).unwrap();
+        }
+        texture.update(None, &image.data, image.width * 3).unwrap();
+        canvas.copy(&texture, None, None).unwrap();
+        canvas.present();
         // ****************
-        // * Code for clearing the audio buffer
+        // * Code for timing the game loop at the console's frame rate
         // ****************
-        apu.buffer.clear();
+        limiter.wait();
         // ****************
-    });
-    let mut cpu: CPU = CPU::new(bus);
-    cpu.reset();
-    cpu.run();
+    }
+}
+
+// The picture as presented: upscaled, then through the CRT filter
+fn filter(screen: &Frame, upscaler: Upscaler, crt_preset: CrtPreset) -> Image {
+    let image: Image = upscaler.apply(screen);
+    match crt_preset.settings() {
+        Some(settings) => crt::apply(&image, 256 * 3, 240 * 3, &settings),
+        None => image,
+    }
+}
+
+// `--netplay-host PORT` waits for a second player and sends them the machine as it is;
+// `--netplay-connect HOST:PORT` joins one and starts from that. `--netplay-delay N` (host only)
+// is the frames of input delay, 2 by default: raise it if the game stutters over a slow connection.
+fn start_netplay(args: &[String], nes: &mut Headless) -> Option<Netplay> {
+    let (host, address): (Option<String>, Option<String>) = (flag_value(args, "--netplay-host"), flag_value(args, "--netplay-connect"));
+    if host.is_none() && address.is_none() { return None; }
+    if args.iter().any(|arg| arg == "--debug") {
+        eprintln!("--debug can't be used with netplay");
+        std::process::exit(1);
+    }
+    let cheats: Vec<&str> = nes.cpu.bus.cheats.list.iter().map(|cheat| cheat.code.as_str()).collect();
+    let session: String = format!(
+        "ROM {:08X}, region {:?}, ppu {:?}, oam_quirks {}, dmc_dma {}, extra_scanlines {}, cheats [{}]", nes.cpu.bus.rom_crc32, nes.cpu.bus.ppu().region, nes.cpu.bus.ppu().accuracy,
+        nes.cpu.bus.ppu().oam_quirks, nes.cpu.bus.dmc_dma, nes.cpu.bus.extra_scanlines, cheats.join(", "),
+    );
+    let result: Result<Netplay, String> = match (host, address) {
+        (Some(port), _) => {
+            let delay: u8 = flag_value(args, "--netplay-delay").map_or(Ok(netplay::DEFAULT_DELAY), |delay| delay.parse())
+                .unwrap_or_else(|_| { eprintln!("--netplay-delay: expected a number of frames"); std::process::exit(1); });
+            port.parse::<u16>().map_err(|_| format!("--netplay-host: expected a port, got {:?}", port))
+                .and_then(|port| Netplay::host(port, delay, &session, &savestate::save(&nes.cpu, 0)))
+        }
+        (None, address) => Netplay::connect(&address.unwrap_or_default(), &session).and_then(|(netplay, state)| {
+            savestate::load(&mut nes.cpu, &state).map(|_| netplay)
+        }),
+    };
+    match result {
+        Ok(mut netplay) => {
+            netplay.input.block_opposing = nes.cpu.bus.joypad1().block_opposing;
+            println!("Netplay: you are player {}", netplay.player);
+            Some(netplay)
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+// The game's cheats file, which `--cheat` codes are added to
+fn load_cheats(cheat_file: &mut CheatFile, nes: &mut Headless) {
+    match cheat_file.load() {
+        Ok(cheats) if !cheats.is_empty() => {
+            println!("Cheats from {}: {}", cheat_file.path().display(), cheats.list.iter().map(|cheat| cheat.code.as_str()).collect::<Vec<&str>>().join(", "));
+            nes.cpu.bus.cheats = cheats;
+        }
+        Ok(_) => {}
+        Err(err) => eprintln!("{}", err),
+    }
+}
+// What's kept of a game when quitting or switching to another: the session to resume, its cheats
+// and, on the Famicom Disk System, what it saved to disk
+fn save_session(slots: &SaveSlots, cheat_file: &CheatFile, nes: &Headless) {
+    if let Err(err) = slots.save_last_session(&nes.cpu) { eprintln!("Could not save session: {}", err); }
+    if let Err(err) = slots.save_disk(&nes.cpu) { eprintln!("Could not save the disk: {}", err); }
+    if let Err(err) = cheat_file.save(&nes.cpu.bus.cheats) { eprintln!("Could not save cheats: {}", err); }
+}
+// Swaps in the game at `path` (see Headless::swap_rom), returning its window title, savestate slots
+// and cheats file
+fn open_rom(path: &str, nes: &mut Headless, db: &RomDb, region: Option<Region>, fds_bios: &Path, config_dir: &Path) -> Result<(String, SaveSlots, CheatFile), String> {
+    let rom: Rom = try_load_rom(path, db, region, fds_bios)?;
+    let title: String = format!("{} - GBNesmulator", game_title(path, &rom, db));
+    nes.swap_rom(rom)?;
+    let slots: SaveSlots = SaveSlots::for_rom(path);
+    if let Err(err) = slots.load_disk(&mut nes.cpu) { eprintln!("Could not load the saved disk: {}", err); }
+    let mut cheat_file: CheatFile = CheatFile::for_rom(config_dir, nes.cpu.bus.rom_crc32);
+    load_cheats(&mut cheat_file, nes);
+    Ok((title, slots, cheat_file))
+}
+// The controller the keyboard and gamepads drive: joypad 1, or while in netplay the local player's
+// After a ROM is opened: its region's frame rate for the limiter, the FPS counter and the clips and
+// AVIs recorded from then on. A clip kept so far was at the old rate, so it starts over.
+fn follow_frame_rate(nes: &mut Headless, frame_rate: &mut f64, limiter: &mut FrameLimiter, fps: &mut FpsCounter, clip: &mut Option<Clip>, clip_seconds: Option<u32>) {
+    let rate: f64 = nes.cpu.bus.ppu().region.frame_rate();
+    if rate == *frame_rate { return; }
+    *frame_rate = rate;
+    limiter.set_frame_rate(rate);
+    *fps = FpsCounter::start(rate);
+    if clip.is_some() { *clip = Some(Clip::new(clip_seconds.unwrap_or(DEFAULT_CLIP_SECONDS), rate)); }
+}
+
+fn local_pad<'a>(nes: &'a mut Headless, netplay: &'a mut Option<Netplay>) -> &'a mut Joypad {
+    match netplay {
+        Some(netplay) => &mut netplay.input,
+        None => nes.cpu.bus.joypad1(),
+    }
+}
+
+// Status messages go to the terminal and the on-screen display
+fn notify(osd: &mut Osd, text: String) {
+    println!("{}", text);
+    osd.show(&text);
+}
+fn warn(osd: &mut Osd, text: String) {
+    eprintln!("{}", text);
+    osd.show(&text);
+}
+fn finish_recording(osd: &mut Osd, recording: WavRecorder) {
+    let (path, seconds): (String, f64) = (recording.path().display().to_string(), recording.seconds());
+    match recording.finish() {
+        Ok(()) => notify(osd, format!("Saved {:.1}s of audio to {}", seconds, path)),
+        Err(err) => warn(osd, err),
+    }
+}
+fn finish_video(osd: &mut Osd, recording: AviRecorder) {
+    let (path, seconds): (String, f64) = (recording.path().display().to_string(), recording.seconds());
+    match recording.finish() {
+        Ok(()) => notify(osd, format!("Saved {:.1}s of video to {}", seconds, path)),
+        Err(err) => warn(osd, err),
+    }
 }
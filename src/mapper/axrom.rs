@@ -0,0 +1,68 @@
+use crate::prelude::*;
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use super::{Mapper, chr_memory};
+
+// Mapper 7: switchable 32KB PRG bank, CHR RAM, single-screen mirroring selected by bit 4
+pub struct AxRom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_bank: usize,
+    mirroring: Mirroring,
+}
+
+impl AxRom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        AxRom { prg_rom: rom.prg_rom, chr, chr_is_ram, prg_bank: 0, mirroring: Mirroring::ONESCREENLOWER }
+    }
+}
+
+impl Mapper for AxRom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => 0,
+            _ => self.prg_rom[(self.prg_bank * 0x8000 + (addr - 0x8000) as usize) % self.prg_rom.len()],
+        }
+    }
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        if addr < 0x8000 { return; }
+        self.prg_bank = data as usize & 0b111;
+        self.mirroring = if data & 0b1_0000 == 0 { Mirroring::ONESCREENLOWER } else { Mirroring::ONESCREENUPPER };
+    }
+    fn read_chr(&self, addr: u16) -> u8 { self.chr[addr as usize % self.chr.len()] }
+    fn write_chr(&mut self, addr: u16, data: u8) { if self.chr_is_ram { self.chr[addr as usize] = data; } }
+    fn mirroring(&self) -> Mirroring { self.mirroring.clone() }
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> { (addr >= 0x8000).then(|| (self.prg_bank * 0x8000 + (addr - 0x8000) as usize) % self.prg_rom.len()) }
+}
+
+impl Savestate for AxRom {
+    fn save_state(&self, w: &mut StateWriter) {
+        if self.chr_is_ram { self.chr.save_state(w); }
+        self.prg_bank.save_state(w);
+        self.mirroring.save_state(w);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        if self.chr_is_ram { self.chr.load_state(r)?; }
+        self.prg_bank.load_state(r)?;
+        self.mirroring.load_state(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_bank_register_selects_nametable() {
+        let mut axrom = AxRom::new(test::test_rom());
+        assert_eq!(axrom.mirroring(), Mirroring::ONESCREENLOWER);
+        axrom.write_prg(0x8000, 0b1_0000);
+        assert_eq!(axrom.mirroring(), Mirroring::ONESCREENUPPER);
+        axrom.write_prg(0x8000, 0);
+        assert_eq!(axrom.mirroring(), Mirroring::ONESCREENLOWER);
+    }
+}
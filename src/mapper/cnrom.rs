@@ -0,0 +1,55 @@
+use crate::prelude::*;
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use super::{Mapper, PRG_RAM_SIZE, chr_memory, prg_ram};
+
+// Mapper 3: fixed PRG like NROM, switchable 8KB CHR bank
+pub struct CnRom {
+    prg_rom: Vec<u8>,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+    chr_bank: usize,
+}
+
+impl CnRom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        let prg_ram: [u8; PRG_RAM_SIZE] = prg_ram(&rom);
+        CnRom { prg_rom: rom.prg_rom, prg_ram, chr, chr_is_ram, mirroring: rom.screen_mirroring, chr_bank: 0 }
+    }
+}
+
+impl Mapper for CnRom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            _ => self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()],
+        }
+    }
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = data,
+            _ => self.chr_bank = (data as usize & 0b11) % (self.chr.len() / 0x2000),
+        }
+    }
+    fn read_chr(&self, addr: u16) -> u8 { self.chr[self.chr_bank * 0x2000 + addr as usize] }
+    fn write_chr(&mut self, addr: u16, data: u8) { if self.chr_is_ram { self.chr[addr as usize] = data; } }
+    fn mirroring(&self) -> Mirroring { self.mirroring.clone() }
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> { (addr >= 0x8000).then(|| (addr - 0x8000) as usize % self.prg_rom.len()) }
+}
+
+impl Savestate for CnRom {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        if self.chr_is_ram { self.chr.save_state(w); }
+        self.chr_bank.save_state(w);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.prg_ram.load_state(r)?;
+        if self.chr_is_ram { self.chr.load_state(r)?; }
+        self.chr_bank.load_state(r)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+use crate::prelude::*;
+use crate::cartridge::{Mirroring, Rom, FDS_BIOS_SIZE, FDS_SIDE_SIZE};
+use super::fds_audio::FdsAudio;
+use super::{Mapper, CHR_RAM_SIZE};
+
+const PRG_RAM_SIZE: usize = 0x8000;
+// Bits of blank disk before the first block and after each one
+const LEADING_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+// CPU cycles the drive takes to get a byte under the head, and from the end of the disk back to its start
+const BYTE_CYCLES: u32 = 150;
+const REWIND_CYCLES: u32 = 50000;
+
+// Mapper 20: the Famicom Disk System, a RAM adapter on the cartridge port with a disk drive
+// behind it. The console boots its BIOS ($E000-$FFFF), which loads the game off the disk into
+// 32KB of PRG RAM at $6000 and 8KB of CHR RAM. Besides the drive the adapter has a timer IRQ and
+// a wavetable sound channel. Disk sides are kept as the drive sees them, with the gaps between
+// blocks and a start mark before each; writes change them in memory only.
+pub struct Fds {
+    bios: Vec<u8>,
+    // Every side, each side_len bytes long
+    disks: Vec<u8>,
+    side_len: usize,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+    inserted: Option<usize>,
+    next_side: usize,
+    // $4020-$4023: the timer IRQ and which halves of the adapter are enabled
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_repeat: bool,
+    timer_irq: bool,
+    disk_registers: bool,
+    sound_registers: bool,
+    // $4025
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    horizontal: bool,
+    crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+    // The drive: the head's position in the side, and how long until it gets to the next byte
+    disk_irq: bool,
+    transfer_complete: bool,
+    read_data: u8,
+    write_data: u8,
+    position: usize,
+    delay: u32,
+    end_of_head: bool,
+    gap_ended: bool,
+    scanning: bool,
+    audio: FdsAudio,
+}
+
+impl Fds {
+    // The BIOS is the first FDS_BIOS_SIZE bytes of `rom.prg_rom` and the .fds sides follow (see Rom::from_fds)
+    pub fn new(rom: Rom) -> Self {
+        let bios: Vec<u8> = rom.prg_rom[..FDS_BIOS_SIZE].to_vec();
+        let sides: Vec<Vec<u8>> = rom.prg_rom[FDS_BIOS_SIZE..].chunks(FDS_SIDE_SIZE).map(with_gaps).collect();
+        let side_len: usize = sides.iter().map(Vec::len).max().unwrap_or(0).max(FDS_SIDE_SIZE);
+        let disks: Vec<u8> = sides.into_iter().flat_map(|mut side| { side.resize(side_len, 0); side }).collect();
+        Fds {
+            bios, disks, side_len,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            chr_ram: vec![0; CHR_RAM_SIZE],
+            inserted: Some(0), next_side: 1,
+            irq_reload: 0, irq_counter: 0, irq_enabled: false, irq_repeat: false, timer_irq: false,
+            disk_registers: false, sound_registers: false,
+            motor_on: false, reset_transfer: false, read_mode: true, horizontal: false, crc_control: false, disk_ready: false, disk_irq_enabled: false,
+            disk_irq: false, transfer_complete: false, read_data: 0, write_data: 0,
+            position: 0, delay: 0, end_of_head: true, gap_ended: false, scanning: false,
+            audio: FdsAudio::new(),
+        }
+    }
+    fn clock_timer(&mut self) {
+        if !self.irq_enabled || !self.disk_registers { return; }
+        if self.irq_counter > 0 {
+            self.irq_counter -= 1;
+            return;
+        }
+        self.timer_irq = true;
+        self.irq_counter = self.irq_reload;
+        if !self.irq_repeat { self.irq_enabled = false; }
+    }
+    // One CPU cycle of the drive: while the motor runs the head moves along the side, reading a byte
+    // (or writing one) every BYTE_CYCLES. Reading skips the gap up to a block's start mark first.
+    fn clock_drive(&mut self) {
+        let Some(side) = self.inserted.filter(|_| self.motor_on) else {
+            (self.end_of_head, self.scanning) = (true, false);
+            return;
+        };
+        if self.reset_transfer && !self.scanning { return; }
+        if self.end_of_head {
+            (self.delay, self.end_of_head, self.position, self.gap_ended) = (REWIND_CYCLES, false, 0, false);
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+        self.scanning = true;
+        let at: usize = side * self.side_len + self.position;
+        let mut irq: bool = self.disk_irq_enabled;
+        if self.read_mode {
+            let data: u8 = self.disks[at];
+            if !self.disk_ready {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // The start mark doesn't raise an IRQ
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                if irq { self.disk_irq = true; }
+            }
+        } else {
+            let mut data: u8 = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                if irq { self.disk_irq = true; }
+            }
+            self.disks[at] = if self.disk_ready { data } else { 0 };
+            self.gap_ended = false;
+        }
+        self.position += 1;
+        if self.position >= self.side_len {
+            self.motor_on = false;
+            self.disk_irq = false;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+}
+
+// A side of the .fds file as the drive sees it: each block behind a gap and a start mark and
+// followed by a (made up) CRC. The file stores just the blocks: the disk info block (1), the file
+// count (2), then a header (3) and the data (4) per file, whose size the header gives.
+fn with_gaps(side: &[u8]) -> Vec<u8> {
+    let mut disk: Vec<u8> = vec![0; LEADING_GAP];
+    let mut i: usize = 0;
+    while i < side.len() {
+        let len: usize = match side[i] {
+            1 => 56,
+            2 => 2,
+            3 => 16,
+            4 if i >= 3 => 1 + side[i - 3] as usize + side[i - 2] as usize * 0x100,
+            _ => break,
+        };
+        let Some(block) = side.get(i..i + len) else { break; };
+        disk.push(0x80);
+        disk.extend_from_slice(block);
+        disk.extend_from_slice(&[0x4D, 0x62]);
+        disk.extend(core::iter::repeat_n(0, BLOCK_GAP));
+        i += len;
+    }
+    disk
+}
+
+impl Mapper for Fds {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0xDFFF => self.prg_ram[(addr - 0x6000) as usize],
+            _ => self.bios[(addr - 0xE000) as usize],
+        }
+    }
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0xDFFF = addr { self.prg_ram[(addr - 0x6000) as usize] = data; }
+    }
+    fn read_chr(&self, addr: u16) -> u8 { self.chr_ram[addr as usize % CHR_RAM_SIZE] }
+    fn write_chr(&mut self, addr: u16, data: u8) { self.chr_ram[addr as usize % CHR_RAM_SIZE] = data; }
+    fn mirroring(&self) -> Mirroring { if self.horizontal { Mirroring::HORIZONTAL } else { Mirroring::VERTICAL } }
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> { (addr >= 0xE000).then(|| (addr - 0xE000) as usize) }
+    fn read_expansion(&mut self, addr: u16) -> u8 {
+        match addr {
+            // Reading the status acknowledges both IRQs
+            0x4030 if self.disk_registers => {
+                let status: u8 = self.timer_irq as u8 | (self.transfer_complete as u8) << 1 | (self.end_of_head as u8) << 6;
+                (self.timer_irq, self.transfer_complete, self.disk_irq) = (false, false, false);
+                status
+            }
+            0x4031 if self.disk_registers => {
+                (self.transfer_complete, self.disk_irq) = (false, false);
+                self.read_data
+            }
+            // Not inserted, not ready, write protected; a disk is never write protected here
+            0x4032 if self.disk_registers => match self.inserted {
+                None => 0x40 | 0x07,
+                Some(_) => 0x40 | if self.scanning { 0 } else { 0x02 },
+            },
+            // Expansion port; bit 7 is the battery being good
+            0x4033 if self.disk_registers => 0x80,
+            0x4040..=0x4097 if self.sound_registers => self.audio.read(addr),
+            _ => 0,
+        }
+    }
+    fn write_expansion(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020 => self.irq_reload = (self.irq_reload & 0xFF00) | data as u16,
+            0x4021 => self.irq_reload = (self.irq_reload & 0x00FF) | (data as u16) << 8,
+            0x4022 if self.disk_registers => {
+                self.irq_repeat = data & 0x01 != 0;
+                self.irq_enabled = data & 0x02 != 0;
+                if self.irq_enabled { self.irq_counter = self.irq_reload; } else { self.timer_irq = false; }
+            }
+            0x4023 => {
+                self.disk_registers = data & 0x01 != 0;
+                self.sound_registers = data & 0x02 != 0;
+                if !self.disk_registers { (self.irq_enabled, self.timer_irq, self.disk_irq) = (false, false, false); }
+            }
+            0x4024 if self.disk_registers => {
+                self.write_data = data;
+                (self.transfer_complete, self.disk_irq) = (false, false);
+            }
+            0x4025 if self.disk_registers => {
+                self.motor_on = data & 0x01 != 0;
+                self.reset_transfer = data & 0x02 != 0;
+                self.read_mode = data & 0x04 != 0;
+                self.horizontal = data & 0x08 != 0;
+                self.crc_control = data & 0x10 != 0;
+                self.disk_ready = data & 0x40 != 0;
+                self.disk_irq_enabled = data & 0x80 != 0;
+                self.disk_irq = false;
+            }
+            0x4040..=0x4097 if self.sound_registers => self.audio.write(addr, data),
+            _ => {}
+        }
+    }
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.clock_timer();
+            self.clock_drive();
+            self.audio.clock();
+        }
+    }
+    fn irq(&self) -> bool { self.timer_irq || self.disk_irq }
+    fn audio(&self) -> f32 { if self.sound_registers { self.audio.output() } else { 0.0 } }
+    fn disk_sides(&self) -> usize { self.disks.len() / self.side_len }
+    fn swap_disk(&mut self) -> Option<usize> {
+        self.inserted = match self.inserted {
+            Some(_) => None,
+            None => Some(self.next_side),
+        };
+        if let Some(side) = self.inserted { self.next_side = (side + 1) % self.disk_sides(); }
+        self.inserted
+    }
+    fn disk_image(&self) -> Vec<u8> { self.disks.clone() }
+    // Only an image of these same disks fits
+    fn load_disk_image(&mut self, image: &[u8]) -> Result<(), String> {
+        if image.len() != self.disks.len() {
+            return Err(format!("Disk image is {} bytes, expected {}", image.len(), self.disks.len()));
+        }
+        self.disks.copy_from_slice(image);
+        Ok(())
+    }
+}
+
+savestate_fields!(Fds {
+    disks, prg_ram, chr_ram, inserted, next_side, irq_reload, irq_counter, irq_enabled, irq_repeat, timer_irq,
+    disk_registers, sound_registers, motor_on, reset_transfer, read_mode, horizontal, crc_control, disk_ready,
+    disk_irq_enabled, disk_irq, transfer_complete, read_data, write_data, position, delay, end_of_head, gap_ended,
+    scanning, audio,
+});
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_gaps_between_blocks() {
+        let mut side: Vec<u8> = vec![1; 56];
+        side.extend([2, 1]);
+        side.extend([3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0]);
+        side.extend([4, 0xAA, 0xBB, 0]);
+        let disk: Vec<u8> = with_gaps(&side);
+        let block = |n: usize| LEADING_GAP + n * (3 + BLOCK_GAP);
+        assert_eq!(&disk[LEADING_GAP - 1..LEADING_GAP + 2], &[0, 0x80, 1]);
+        assert_eq!(disk[block(1) + 56], 0x80);
+        assert_eq!(&disk[block(2) + 56 + 2..block(2) + 56 + 2 + 3], &[0x80, 3, 0]);
+        assert_eq!(&disk[disk.len() - BLOCK_GAP - 5..disk.len() - BLOCK_GAP - 2], &[4, 0xAA, 0xBB]);
+        // The trailing 0 isn't a block: parsing stops there
+        assert_eq!(disk.len(), LEADING_GAP + 4 * (3 + BLOCK_GAP) + 56 + 2 + 16 + 3);
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut fds = Fds::new(test::test_fds_rom());
+        fds.write_expansion(0x4023, 0x01);
+        fds.write_expansion(0x4020, 10);
+        fds.write_expansion(0x4021, 0);
+        fds.write_expansion(0x4022, 0x02);
+        fds.tick(10);
+        assert!(!fds.irq());
+        fds.tick(1);
+        assert!(fds.irq());
+        assert_eq!(fds.read_expansion(0x4030) & 0x01, 0x01);
+        assert!(!fds.irq());
+        // Not repeating: the timer stopped
+        fds.tick(100);
+        assert!(!fds.irq());
+    }
+
+    #[test]
+    fn test_drive_reads_blocks_and_swaps_sides() {
+        let mut fds = Fds::new(test::test_fds_rom());
+        fds.write_expansion(0x4023, 0x01);
+        assert_eq!(fds.read_expansion(0x4032) & 0x03, 0x02);
+        // Motor on, read mode, looking for the first block, with an IRQ per byte
+        fds.write_expansion(0x4025, 0xC5);
+        let mut read: Vec<u8> = Vec::new();
+        for _ in 0..REWIND_CYCLES + (LEADING_GAP as u32 + 4) * (BYTE_CYCLES + 1) {
+            fds.tick(1);
+            if fds.irq() { read.push(fds.read_expansion(0x4031)); }
+        }
+        assert_eq!(&read[..3], &[0x01, b'*', b'N']);
+        assert_eq!(fds.disk_sides(), 2);
+        assert_eq!(fds.swap_disk(), None);
+        assert_eq!(fds.read_expansion(0x4032) & 0x07, 0x07);
+        assert_eq!(fds.swap_disk(), Some(1));
+        fds.swap_disk();
+        assert_eq!(fds.swap_disk(), Some(0));
+    }
+
+    #[test]
+    fn test_disk_image_keeps_writes() {
+        let mut fds = Fds::new(test::test_fds_rom());
+        let mut image: Vec<u8> = fds.disk_image();
+        image[LEADING_GAP + 10] ^= 0xFF;
+        fds.load_disk_image(&image).unwrap();
+        assert_eq!(fds.disk_image(), image);
+        let mut other = Fds::new(test::test_fds_rom());
+        assert!(other.load_disk_image(&image[1..]).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+// A full volume FDS wave against the console's full output range: about 2.4 times a pulse channel
+const FULL_SCALE: f32 = 0.36;
+// $4089 bits 0-1
+const MASTER_VOLUMES: [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+// What each 3 bit mod table entry does to the mod counter; 4 resets it to 0 instead
+const MOD_STEPS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+// The volume and mod depth envelopes ($4080 and $4084)
+#[derive(Default)]
+struct Envelope {
+    control: u8,
+    gain: u8,
+    timer: u32,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.control = value;
+        self.timer = 0;
+        if value & 0x80 != 0 { self.gain = value & 0x3F; }
+    }
+    // Steps the gain once every 8 * (speed + 1) * master speed CPU cycles; bit 7 set means the
+    // gain is just the low bits, bit 6 whether it rises or falls
+    fn clock(&mut self, master_speed: u8) {
+        if self.control & 0x80 != 0 || master_speed == 0 { return; }
+        self.timer += 1;
+        if self.timer < 8 * ((self.control & 0x3F) as u32 + 1) * master_speed as u32 { return; }
+        self.timer = 0;
+        if self.control & 0x40 != 0 { self.gain = (self.gain + 1).min(32); } else { self.gain = self.gain.saturating_sub(1); }
+    }
+}
+
+savestate_fields!(Envelope { control, gain, timer });
+
+// The FDS's sound channel: a 64 step, 6 bit wavetable played at a pitch that a second table of
+// deltas (the mod unit) can bend, for vibrato and FM-like timbres
+pub struct FdsAudio {
+    wave: [u8; 64],
+    mod_table: [u8; 64],
+    volume: Envelope,
+    modulation: Envelope,
+    // $4082/$4083: the 12 bit wave pitch; bit 7 halts the wave, bit 6 the envelopes
+    frequency: u16,
+    wave_halted: bool,
+    envelopes_halted: bool,
+    wave_accumulator: u32,
+    wave_position: u8,
+    // $4086/$4087: the 12 bit mod pitch; bit 7 halts the mod unit so its table can be written
+    mod_frequency: u16,
+    mod_halted: bool,
+    mod_accumulator: u32,
+    mod_position: u8,
+    // 7 bit signed, $4085
+    mod_counter: i8,
+    // $4089
+    wave_write: bool,
+    master_volume: u8,
+    // $408A, what every envelope's period is multiplied by
+    envelope_speed: u8,
+    output: f32,
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        FdsAudio {
+            wave: [0; 64], mod_table: [0; 64], volume: Envelope::default(), modulation: Envelope::default(),
+            frequency: 0, wave_halted: false, envelopes_halted: false, wave_accumulator: 0, wave_position: 0,
+            mod_frequency: 0, mod_halted: false, mod_accumulator: 0, mod_position: 0, mod_counter: 0,
+            wave_write: false, master_volume: 0, envelope_speed: 0xE8, output: 0.0,
+        }
+    }
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x4040..=0x407F => self.wave[(addr - 0x4040) as usize],
+            0x4090 => self.volume.gain | 0x40,
+            0x4092 => self.modulation.gain | 0x40,
+            _ => 0,
+        }
+    }
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write => self.wave[(addr - 0x4040) as usize] = value & 0x3F,
+            0x4080 => self.volume.write(value),
+            0x4082 => self.frequency = (self.frequency & 0x0F00) | value as u16,
+            0x4083 => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.wave_halted = value & 0x80 != 0;
+                self.envelopes_halted = value & 0x40 != 0;
+                if self.wave_halted { (self.wave_accumulator, self.wave_position) = (0, 0); }
+                if self.envelopes_halted { (self.volume.timer, self.modulation.timer) = (0, 0); }
+            }
+            0x4084 => self.modulation.write(value),
+            // Sign extended from 7 bits
+            0x4085 => self.mod_counter = ((value << 1) as i8) >> 1,
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | value as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.mod_halted = value & 0x80 != 0;
+                if self.mod_halted { self.mod_accumulator = 0; }
+            }
+            // Each write fills two steps of the table
+            0x4088 if self.mod_halted => {
+                self.mod_table[self.mod_position as usize] = value & 0x07;
+                self.mod_table[(self.mod_position as usize + 1) & 0x3F] = value & 0x07;
+                self.mod_position = (self.mod_position + 2) & 0x3F;
+            }
+            0x4089 => {
+                self.wave_write = value & 0x80 != 0;
+                self.master_volume = value & 0x03;
+            }
+            0x408A => self.envelope_speed = value,
+            _ => {}
+        }
+    }
+    // One CPU cycle
+    pub fn clock(&mut self) {
+        if !self.wave_halted && !self.envelopes_halted {
+            self.volume.clock(self.envelope_speed);
+            self.modulation.clock(self.envelope_speed);
+        }
+        let modulating: bool = !self.mod_halted && self.mod_frequency > 0;
+        if modulating {
+            self.mod_accumulator += self.mod_frequency as u32;
+            if self.mod_accumulator >= 0x10000 {
+                self.mod_accumulator -= 0x10000;
+                let step: u8 = self.mod_table[self.mod_position as usize];
+                self.mod_position = (self.mod_position + 1) & 0x3F;
+                self.mod_counter = if step == 4 { 0 } else { wrap_mod_counter(self.mod_counter as i16 + MOD_STEPS[step as usize] as i16) };
+            }
+        }
+        if !self.wave_halted {
+            let pitch: i32 = if modulating { self.modulated_pitch() } else { self.frequency as i32 };
+            if pitch > 0 {
+                self.wave_accumulator += pitch as u32;
+                if self.wave_accumulator >= 0x10000 {
+                    self.wave_accumulator &= 0xFFFF;
+                    self.wave_position = (self.wave_position + 1) & 0x3F;
+                }
+            }
+        }
+        // The output holds still while the wavetable is being written
+        if !self.wave_write {
+            let level: f32 = self.wave[self.wave_position as usize] as f32 * self.volume.gain.min(32) as f32 / (63.0 * 32.0);
+            self.output = level * MASTER_VOLUMES[self.master_volume as usize] * FULL_SCALE;
+        }
+    }
+    pub fn output(&self) -> f32 { self.output }
+    // The wave pitch bent by the mod counter times the mod depth, rounded the way the hardware does
+    // (from the nesdev wiki's FDS audio page)
+    fn modulated_pitch(&self) -> i32 {
+        let mut temp: i32 = self.mod_counter as i32 * self.modulation.gain as i32;
+        let remainder: i32 = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 { temp += if self.mod_counter < 0 { -1 } else { 2 }; }
+        if temp >= 192 { temp -= 256; } else if temp < -64 { temp += 256; }
+        let mut temp: i32 = self.frequency as i32 * temp;
+        let remainder: i32 = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 { temp += 1; }
+        self.frequency as i32 + temp
+    }
+}
+
+// Back into the counter's 7 bit range, -64..=63
+fn wrap_mod_counter(counter: i16) -> i8 {
+    (if counter >= 64 { counter - 128 } else if counter < -64 { counter + 128 } else { counter }) as i8
+}
+
+savestate_fields!(FdsAudio {
+    wave, mod_table, volume, modulation, frequency, wave_halted, envelopes_halted, wave_accumulator, wave_position,
+    mod_frequency, mod_halted, mod_accumulator, mod_position, mod_counter, wave_write, master_volume, envelope_speed, output,
+});
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wave_steps_at_its_pitch() {
+        let mut audio: FdsAudio = FdsAudio::new();
+        audio.write(0x4089, 0x80);
+        for i in 0..64 { audio.write(0x4040 + i, if i < 32 { 63 } else { 0 }); }
+        audio.write(0x4089, 0x00);
+        audio.write(0x4080, 0x80 | 32);
+        // Pitch $400: one wave step every 64 cycles
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x04);
+        audio.clock();
+        assert!((audio.output() - FULL_SCALE).abs() < 1e-6);
+        for _ in 0..32 * 64 { audio.clock(); }
+        assert_eq!(audio.output(), 0.0);
+        assert_eq!(audio.read(0x4090), 0x40 | 32);
+        audio.write(0x4083, 0x80);
+        assert_eq!((audio.wave_position, audio.wave_accumulator), (0, 0));
+    }
+
+    #[test]
+    fn test_mod_unit_bends_the_pitch() {
+        let mut audio: FdsAudio = FdsAudio::new();
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x01);
+        audio.write(0x4087, 0x80);
+        for _ in 0..32 { audio.write(0x4088, 1); }
+        audio.write(0x4084, 0x80 | 16);
+        audio.write(0x4085, 0x10);
+        assert!(audio.modulated_pitch() > 0x100);
+        audio.write(0x4085, 0x70);
+        assert!(audio.modulated_pitch() < 0x100);
+        assert_eq!(wrap_mod_counter(64), -64);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::prelude::*;
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use super::{Mapper, PRG_RAM_SIZE, chr_memory, prg_ram};
+
+// Mapper 1: registers are loaded serially, one bit per write, through a 5-bit shift register
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    shift_register: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        let prg_ram: [u8; PRG_RAM_SIZE] = prg_ram(&rom);
+        Mmc1 {
+            prg_rom: rom.prg_rom,
+            prg_ram,
+            chr,
+            chr_is_ram,
+            shift_register: 0,
+            shift_count: 0,
+            control: 0x0C, // PRG mode 3 at power-on: last bank fixed at $C000
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xBFFF => self.chr_bank_0 = value,
+            0xC000..=0xDFFF => self.chr_bank_1 = value,
+            _ => self.prg_bank = value & 0x0F,
+        }
+    }
+    fn prg_offset(&self, addr: u16) -> usize {
+        let banks: usize = self.prg_rom.len() / 0x4000;
+        let bank: usize = self.prg_bank as usize % banks;
+        let offset: usize = addr as usize & 0x3FFF;
+        match ((self.control >> 2) & 0b11, addr) {
+            (0, _) | (1, _) => (bank & !1) * 0x4000 + (addr as usize - 0x8000),
+            (2, 0x8000..=0xBFFF) => offset,
+            (2, _) => bank * 0x4000 + offset,
+            (_, 0x8000..=0xBFFF) => bank * 0x4000 + offset,
+            (_, _) => (banks - 1) * 0x4000 + offset,
+        }
+    }
+    fn chr_offset(&self, addr: u16) -> usize {
+        let offset: usize = if self.control & 0b1_0000 == 0 {
+            (self.chr_bank_0 as usize & !1) * 0x1000 + addr as usize
+        } else if addr < 0x1000 {
+            self.chr_bank_0 as usize * 0x1000 + addr as usize
+        } else {
+            self.chr_bank_1 as usize * 0x1000 + (addr as usize - 0x1000)
+        };
+        offset % self.chr.len()
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            _ => self.prg_rom[self.prg_offset(addr) % self.prg_rom.len()],
+        }
+    }
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            self.prg_ram[(addr - 0x6000) as usize] = data;
+            return;
+        }
+        if data & 0x80 != 0 {
+            self.shift_register = 0;
+            self.shift_count = 0;
+            self.control |= 0x0C;
+            return;
+        }
+        self.shift_register |= (data & 1) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count == 5 {
+            self.write_register(addr, self.shift_register);
+            self.shift_register = 0;
+            self.shift_count = 0;
+        }
+    }
+    fn read_chr(&self, addr: u16) -> u8 { self.chr[self.chr_offset(addr)] }
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let offset: usize = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::ONESCREENLOWER,
+            1 => Mirroring::ONESCREENUPPER,
+            2 => Mirroring::VERTICAL,
+            _ => Mirroring::HORIZONTAL,
+        }
+    }
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> { (addr >= 0x8000).then(|| self.prg_offset(addr) % self.prg_rom.len()) }
+}
+
+impl Savestate for Mmc1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        if self.chr_is_ram { self.chr.save_state(w); }
+        self.shift_register.save_state(w);
+        self.shift_count.save_state(w);
+        self.control.save_state(w);
+        self.chr_bank_0.save_state(w);
+        self.chr_bank_1.save_state(w);
+        self.prg_bank.save_state(w);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.prg_ram.load_state(r)?;
+        if self.chr_is_ram { self.chr.load_state(r)?; }
+        self.shift_register.load_state(r)?;
+        self.shift_count.load_state(r)?;
+        self.control.load_state(r)?;
+        self.chr_bank_0.load_state(r)?;
+        self.chr_bank_1.load_state(r)?;
+        self.prg_bank.load_state(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+
+    fn write_serial(mmc1: &mut Mmc1, addr: u16, value: u8) {
+        for i in 0..5 { mmc1.write_prg(addr, (value >> i) & 1); }
+    }
+
+    #[test]
+    fn test_control_register_switches_mirroring() {
+        let mut mmc1 = Mmc1::new(test::test_rom());
+        write_serial(&mut mmc1, 0x8000, 0b0_1110);
+        assert_eq!(mmc1.mirroring(), Mirroring::VERTICAL);
+        write_serial(&mut mmc1, 0x8000, 0b0_1111);
+        assert_eq!(mmc1.mirroring(), Mirroring::HORIZONTAL);
+        write_serial(&mut mmc1, 0x8000, 0b0_1101);
+        assert_eq!(mmc1.mirroring(), Mirroring::ONESCREENUPPER);
+    }
+
+    #[test]
+    fn test_reset_bit_clears_shift_register() {
+        let mut mmc1 = Mmc1::new(test::test_rom());
+        mmc1.write_prg(0x8000, 1);
+        mmc1.write_prg(0x8000, 1);
+        mmc1.write_prg(0x8000, 0x80);
+        write_serial(&mut mmc1, 0x8000, 0b0_1110);
+        assert_eq!(mmc1.mirroring(), Mirroring::VERTICAL);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::prelude::*;
+pub mod nrom;
+pub mod mmc1;
+pub mod uxrom;
+pub mod cnrom;
+pub mod axrom;
+pub mod nsf;
+pub mod fds;
+mod fds_audio;
+pub mod vrc7;
+mod vrc7_audio;
+
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::Savestate;
+use nrom::Nrom;
+use mmc1::Mmc1;
+use uxrom::UxRom;
+use cnrom::CnRom;
+use axrom::AxRom;
+use fds::Fds;
+use vrc7::Vrc7;
+
+pub const PRG_RAM_SIZE: usize = 0x2000;
+pub const CHR_RAM_SIZE: usize = 0x2000;
+
+// Cartridge hardware as seen by the CPU ($6000-$FFFF) and the PPU ($0000-$1FFF).
+// Mirroring is owned by the mapper because boards like MMC1 and AxROM switch it at runtime.
+// Bank registers and cartridge RAM are part of savestates; ROM contents are not
+pub trait Mapper: Savestate {
+    fn read_prg(&self, addr: u16) -> u8;
+    fn write_prg(&mut self, addr: u16, data: u8);
+    fn read_chr(&self, addr: u16) -> u8;
+    fn write_chr(&mut self, addr: u16, data: u8);
+    fn mirroring(&self) -> Mirroring;
+    // Where in PRG ROM a CPU read of `addr` lands with the current banks, for profilers and coverage
+    // tools; None outside $8000-$FFFF
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize>;
+    // The expansion area at $4020-$5FFF, which only some boards decode; reads of nothing give 0
+    fn read_expansion(&mut self, _addr: u16) -> u8 { 0 }
+    fn write_expansion(&mut self, _addr: u16, _data: u8) {}
+    // Runs the board's own timers and sound for `cycles` CPU cycles, on boards that have them
+    fn tick(&mut self, _cycles: u8) {}
+    // Whether the board is pulling the CPU's IRQ line
+    fn irq(&self) -> bool { false }
+    // The board's sound chip output (expansion audio), from 0.0 to 1.0 of the console's full range
+    fn audio(&self) -> f32 { 0.0 }
+    // Disk drives: how many disk sides there are (0 without a drive), and swapping them, which ejects
+    // the inserted side or, once ejected, inserts the next one; the side now in the drive
+    fn disk_sides(&self) -> usize { 0 }
+    fn swap_disk(&mut self) -> Option<usize> { None }
+    // Every disk side as the drive sees it, with what the game wrote to it, for keeping across
+    // sessions (empty without a drive); and putting such an image back
+    fn disk_image(&self) -> Vec<u8> { Vec::new() }
+    fn load_disk_image(&mut self, _image: &[u8]) -> Result<(), String> { Ok(()) }
+}
+
+pub fn from_rom(rom: Rom) -> Result<Rc<RefCell<dyn Mapper>>, String> {
+    match rom.mapper {
+        0 => Ok(Rc::new(RefCell::new(Nrom::new(rom)))),
+        1 => Ok(Rc::new(RefCell::new(Mmc1::new(rom)))),
+        2 => Ok(Rc::new(RefCell::new(UxRom::new(rom)))),
+        3 => Ok(Rc::new(RefCell::new(CnRom::new(rom)))),
+        7 => Ok(Rc::new(RefCell::new(AxRom::new(rom)))),
+        20 => Ok(Rc::new(RefCell::new(Fds::new(rom)))),
+        85 => Ok(Rc::new(RefCell::new(Vrc7::new(rom)))),
+        n => Err(format!("Mapper {} is not supported", n)),
+    }
+}
+
+// Battery/work RAM at $6000-$7FFF, preloaded with the iNES trainer at $7000 when present
+pub fn prg_ram(rom: &Rom) -> [u8; PRG_RAM_SIZE] {
+    let mut ram: [u8; PRG_RAM_SIZE] = [0; PRG_RAM_SIZE];
+    if let Some(trainer) = &rom.trainer { ram[0x1000..0x1000 + trainer.len()].copy_from_slice(trainer); }
+    ram
+}
+
+// Carts without CHR ROM ship 8KB of CHR RAM instead
+pub fn chr_memory(rom: &Rom) -> (Vec<u8>, bool) {
+    if rom.chr_rom.is_empty() { (vec![0; CHR_RAM_SIZE], true) } else { (rom.chr_rom.clone(), false) }
+}
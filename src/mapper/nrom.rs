@@ -0,0 +1,73 @@
+use crate::prelude::*;
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use super::{Mapper, PRG_RAM_SIZE, chr_memory, prg_ram};
+
+// Mapper 0: fixed 16/32KB PRG, fixed 8KB CHR, mirroring soldered on the board
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        let prg_ram: [u8; PRG_RAM_SIZE] = prg_ram(&rom);
+        Nrom { prg_rom: rom.prg_rom, prg_ram, chr, chr_is_ram, mirroring: rom.screen_mirroring }
+    }
+}
+
+impl Mapper for Nrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            // Mirror down if ROM is 16KB instead of 32KB
+            _ => self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()],
+        }
+    }
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = data,
+            _ => panic!("Attempt to write to Cartridge ROM space"),
+        }
+    }
+    fn read_chr(&self, addr: u16) -> u8 { self.chr[addr as usize % self.chr.len()] }
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram { self.chr[addr as usize] = data; }
+        else { log!("attempt to write to chr rom space {:X}", addr); }
+    }
+    fn mirroring(&self) -> Mirroring { self.mirroring.clone() }
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> { (addr >= 0x8000).then(|| (addr - 0x8000) as usize % self.prg_rom.len()) }
+}
+
+impl Savestate for Nrom {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        if self.chr_is_ram { self.chr.save_state(w); }
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.prg_ram.load_state(r)?;
+        if self.chr_is_ram { self.chr.load_state(r)?; }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_trainer_is_loaded_at_7000() {
+        let mut rom: Rom = test::test_rom();
+        rom.trainer = Some(vec![0x42; 512]);
+        let nrom = Nrom::new(rom);
+        assert_eq!(nrom.read_prg(0x6FFF), 0);
+        assert_eq!(nrom.read_prg(0x7000), 0x42);
+        assert_eq!(nrom.read_prg(0x71FF), 0x42);
+        assert_eq!(nrom.read_prg(0x7200), 0);
+    }
+}
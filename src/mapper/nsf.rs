@@ -0,0 +1,94 @@
+use crate::prelude::*;
+use crate::cartridge::Mirroring;
+use crate::nsf::Nsf;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use super::{Mapper, PRG_RAM_SIZE};
+
+const BANK_SIZE: usize = 0x1000;
+
+// The cartridge an NSF player puts the tune's code in: 8KB of RAM at $6000 and the data in 4KB
+// banks at $8000-$FFFF, switched by writes to $5FF8-$5FFF. Tunes that don't bankswitch get the
+// data at their load address with the banks fixed in order.
+pub struct NsfMapper {
+    // Whole banks, the first padded so the data starts at the right place within it
+    data: Vec<u8>,
+    banks: [u8; 8],
+    initial_banks: [u8; 8],
+    prg_ram: [u8; PRG_RAM_SIZE],
+}
+
+impl NsfMapper {
+    pub fn new(nsf: &Nsf) -> Self {
+        let (padding, initial_banks): (usize, [u8; 8]) = match nsf.banks {
+            Some(banks) => ((nsf.load_addr & 0x0FFF) as usize, banks),
+            None => ((nsf.load_addr - 0x8000) as usize, [0, 1, 2, 3, 4, 5, 6, 7]),
+        };
+        let mut data: Vec<u8> = vec![0; padding];
+        data.extend_from_slice(&nsf.data);
+        data.resize(data.len().div_ceil(BANK_SIZE).max(1) * BANK_SIZE, 0);
+        NsfMapper { data, banks: initial_banks, initial_banks, prg_ram: [0; PRG_RAM_SIZE] }
+    }
+    // Back to the banks the tune starts with and cleared RAM, as the player does before each INIT
+    pub fn reset(&mut self) {
+        self.banks = self.initial_banks;
+        self.prg_ram = [0; PRG_RAM_SIZE];
+    }
+    fn offset(&self, addr: u16) -> usize {
+        let bank: usize = self.banks[(addr as usize - 0x8000) / BANK_SIZE] as usize % (self.data.len() / BANK_SIZE);
+        bank * BANK_SIZE + (addr as usize & (BANK_SIZE - 1))
+    }
+}
+
+impl Mapper for NsfMapper {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            _ => self.data[self.offset(addr)],
+        }
+    }
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr { self.prg_ram[(addr - 0x6000) as usize] = data; }
+    }
+    // No PPU side: the player never shows what a tune's code might draw
+    fn read_chr(&self, _addr: u16) -> u8 { 0 }
+    fn write_chr(&mut self, _addr: u16, _data: u8) {}
+    fn mirroring(&self) -> Mirroring { Mirroring::HORIZONTAL }
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> { (addr >= 0x8000).then(|| self.offset(addr)) }
+    fn write_expansion(&mut self, addr: u16, data: u8) {
+        if let 0x5FF8..=0x5FFF = addr { self.banks[(addr - 0x5FF8) as usize] = data; }
+    }
+}
+
+impl Savestate for NsfMapper {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.banks.save_state(w);
+        self.prg_ram.save_state(w);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.banks.load_state(r)?;
+        self.prg_ram.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nsf::test;
+
+    #[test]
+    fn test_bankswitching() {
+        let mut nsf: Nsf = test::test_nsf();
+        nsf.load_addr = 0x8010;
+        nsf.data = vec![0x11; 0x1000];
+        nsf.data.extend(vec![0x22; 0x1000]);
+        let fixed = NsfMapper::new(&nsf);
+        assert_eq!((fixed.read_prg(0x800F), fixed.read_prg(0x8010), fixed.read_prg(0x9010)), (0x00, 0x11, 0x22));
+        nsf.banks = Some([1, 0, 0, 0, 0, 0, 0, 0]);
+        let mut mapper = NsfMapper::new(&nsf);
+        assert_eq!((mapper.read_prg(0x8010), mapper.read_prg(0x9010)), (0x22, 0x11));
+        mapper.write_expansion(0x5FFF, 1);
+        assert_eq!(mapper.read_prg(0xF010), 0x22);
+        mapper.reset();
+        assert_eq!(mapper.read_prg(0xF010), 0x11);
+    }
+}
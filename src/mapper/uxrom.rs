@@ -0,0 +1,63 @@
+use crate::prelude::*;
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use super::{Mapper, PRG_RAM_SIZE, chr_memory, prg_ram};
+
+// Mapper 2: switchable 16KB bank at $8000, last bank fixed at $C000, CHR RAM
+pub struct UxRom {
+    prg_rom: Vec<u8>,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+    prg_bank: usize,
+}
+
+impl UxRom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        let prg_ram: [u8; PRG_RAM_SIZE] = prg_ram(&rom);
+        UxRom { prg_rom: rom.prg_rom, prg_ram, chr, chr_is_ram, mirroring: rom.screen_mirroring, prg_bank: 0 }
+    }
+    fn prg_banks(&self) -> usize { self.prg_rom.len() / 0x4000 }
+}
+
+impl Mapper for UxRom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xBFFF => self.prg_rom[self.prg_bank * 0x4000 + (addr - 0x8000) as usize],
+            _ => self.prg_rom[(self.prg_banks() - 1) * 0x4000 + (addr - 0xC000) as usize],
+        }
+    }
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = data,
+            _ => self.prg_bank = (data as usize & 0x0F) % self.prg_banks(),
+        }
+    }
+    fn read_chr(&self, addr: u16) -> u8 { self.chr[addr as usize % self.chr.len()] }
+    fn write_chr(&mut self, addr: u16, data: u8) { if self.chr_is_ram { self.chr[addr as usize] = data; } }
+    fn mirroring(&self) -> Mirroring { self.mirroring.clone() }
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xBFFF => Some(self.prg_bank * 0x4000 + (addr - 0x8000) as usize),
+            0xC000..=0xFFFF => Some((self.prg_banks() - 1) * 0x4000 + (addr - 0xC000) as usize),
+            _ => None,
+        }
+    }
+}
+
+impl Savestate for UxRom {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        if self.chr_is_ram { self.chr.save_state(w); }
+        self.prg_bank.save_state(w);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.prg_ram.load_state(r)?;
+        if self.chr_is_ram { self.chr.load_state(r)?; }
+        self.prg_bank.load_state(r)?;
+        Ok(())
+    }
+}
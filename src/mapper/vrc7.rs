@@ -0,0 +1,219 @@
+use crate::prelude::*;
+use crate::cartridge::{Mirroring, Rom};
+use super::vrc7_audio::Vrc7Audio;
+use super::{Mapper, PRG_RAM_SIZE, chr_memory, prg_ram};
+
+// Mapper 85: Konami's VRC7, with 8KB PRG banks at $8000, $A000 and $C000 (the last bank fixed at
+// $E000), 1KB CHR banks, 8KB of RAM, the VRC scanline IRQ and an FM sound chip (see Vrc7Audio).
+// Lagrange Point's board (VRC7a) tells registers apart by A4, Tiny Toon Adventures 2's (VRC7b,
+// which has no sound) by A3; both are decoded.
+pub struct Vrc7 {
+    prg_rom: Vec<u8>,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    // $E000: mirroring, whether RAM is enabled and whether the sound chip is held in reset
+    control: u8,
+    irq: VrcIrq,
+    audio: Vrc7Audio,
+}
+
+impl Vrc7 {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(&rom);
+        let prg_ram: [u8; PRG_RAM_SIZE] = prg_ram(&rom);
+        Vrc7 {
+            prg_rom: rom.prg_rom, prg_ram, chr, chr_is_ram,
+            prg_banks: [0; 3], chr_banks: [0; 8], control: 0,
+            irq: VrcIrq::default(),
+            audio: Vrc7Audio::new(),
+        }
+    }
+    fn prg_offset(&self, addr: u16) -> usize {
+        let banks: usize = self.prg_rom.len() / 0x2000;
+        let bank: usize = match addr {
+            0x8000..=0xDFFF => self.prg_banks[(addr as usize - 0x8000) / 0x2000] as usize,
+            _ => banks - 1,
+        };
+        (bank % banks) * 0x2000 + (addr as usize & 0x1FFF)
+    }
+    fn chr_offset(&self, addr: u16) -> usize {
+        (self.chr_banks[addr as usize / 0x400] as usize * 0x400 + (addr as usize & 0x3FF)) % self.chr.len()
+    }
+    fn ram_enabled(&self) -> bool { self.control & 0x40 != 0 }
+}
+
+impl Mapper for Vrc7 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => if self.ram_enabled() { self.prg_ram[(addr - 0x6000) as usize] } else { 0 },
+            _ => self.prg_rom[self.prg_offset(addr)],
+        }
+    }
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            if self.ram_enabled() { self.prg_ram[(addr - 0x6000) as usize] = data; }
+            return;
+        }
+        // Down to $x000 or $x008, except the sound registers which also need A5
+        let register: u16 = match addr & 0xF000 {
+            0x9000 if addr & 0x10 != 0 => addr & 0xF030,
+            _ => (addr & 0xF000) | if addr & 0x18 != 0 { 0x08 } else { 0 },
+        };
+        match register {
+            0x8000 => self.prg_banks[0] = data & 0x3F,
+            0x8008 => self.prg_banks[1] = data & 0x3F,
+            0x9000 | 0x9008 => self.prg_banks[2] = data & 0x3F,
+            0x9010 => self.audio.select(data),
+            0x9030 if self.control & 0x80 == 0 => self.audio.write(data),
+            0xA000..=0xD008 => self.chr_banks[((register - 0xA000) >> 11) as usize | (register & 0x08 != 0) as usize] = data,
+            0xE000 => {
+                self.control = data;
+                if data & 0x80 != 0 { self.audio.reset(); }
+            }
+            0xE008 => self.irq.latch = data,
+            0xF000 => self.irq.write_control(data),
+            0xF008 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+    fn read_chr(&self, addr: u16) -> u8 { self.chr[self.chr_offset(addr)] }
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let offset: usize = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::VERTICAL,
+            1 => Mirroring::HORIZONTAL,
+            2 => Mirroring::ONESCREENLOWER,
+            _ => Mirroring::ONESCREENUPPER,
+        }
+    }
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> { (addr >= 0x8000).then(|| self.prg_offset(addr)) }
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.irq.clock();
+            self.audio.clock();
+        }
+    }
+    fn irq(&self) -> bool { self.irq.pending }
+    fn audio(&self) -> f32 { if self.control & 0x80 != 0 { 0.0 } else { self.audio.output() } }
+}
+
+// The IRQ counter Konami's VRC4, VRC6 and VRC7 share: an 8 bit counter that counts up from the
+// latch and raises the IRQ when it wraps, clocked once a scanline (by a prescaler 341 PPU dots
+// long) or, in cycle mode, every CPU cycle
+#[derive(Default)]
+struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: u16,
+    enabled: bool,
+    enable_on_acknowledge: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    fn write_control(&mut self, data: u8) {
+        self.enable_on_acknowledge = data & 0x01 != 0;
+        self.enabled = data & 0x02 != 0;
+        self.cycle_mode = data & 0x04 != 0;
+        self.pending = false;
+        if self.enabled { (self.counter, self.prescaler) = (self.latch, 341); }
+    }
+    fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_on_acknowledge;
+    }
+    fn clock(&mut self) {
+        if !self.enabled { return; }
+        if !self.cycle_mode {
+            // Three PPU dots per CPU cycle
+            if self.prescaler > 3 {
+                self.prescaler -= 3;
+                return;
+            }
+            self.prescaler += 338;
+        }
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
+
+savestate_fields!(VrcIrq { latch, counter, prescaler, enabled, enable_on_acknowledge, cycle_mode, pending });
+savestate_fields!(Vrc7 { prg_ram, chr, prg_banks, chr_banks, control, irq, audio });
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+
+    fn test_vrc7() -> Vrc7 {
+        let mut rom: Rom = test::test_rom();
+        rom.prg_rom = (0..16).flat_map(|bank| vec![bank as u8; 0x2000]).collect();
+        Vrc7::new(rom)
+    }
+
+    #[test]
+    fn test_banks_on_both_boards() {
+        let mut vrc7: Vrc7 = test_vrc7();
+        vrc7.write_prg(0x8000, 3);
+        vrc7.write_prg(0x8010, 4);
+        vrc7.write_prg(0x9000, 5);
+        assert_eq!((vrc7.read_prg(0x8000), vrc7.read_prg(0xA000), vrc7.read_prg(0xC000), vrc7.read_prg(0xFFFF)), (3, 4, 5, 15));
+        // VRC7b's A3
+        vrc7.write_prg(0x8008, 6);
+        assert_eq!(vrc7.read_prg(0xA000), 6);
+        vrc7.write_prg(0xD010, 9);
+        assert_eq!(vrc7.chr_banks[7], 9);
+        vrc7.write_prg(0xE000, 0x03);
+        assert_eq!(vrc7.mirroring(), Mirroring::ONESCREENUPPER);
+        // RAM reads as nothing until enabled
+        vrc7.write_prg(0x6000, 0x42);
+        assert_eq!(vrc7.read_prg(0x6000), 0);
+        vrc7.write_prg(0xE000, 0x40);
+        vrc7.write_prg(0x6000, 0x42);
+        assert_eq!(vrc7.read_prg(0x6000), 0x42);
+    }
+
+    #[test]
+    fn test_irq_and_sound() {
+        let mut vrc7: Vrc7 = test_vrc7();
+        vrc7.write_prg(0xE010, 0xFE);
+        vrc7.write_prg(0xF000, 0x07);
+        vrc7.tick(1);
+        assert!(!vrc7.irq());
+        vrc7.tick(1);
+        assert!(vrc7.irq());
+        // Scanline mode: a count every 341 PPU dots
+        vrc7.write_prg(0xF010, 0);
+        vrc7.write_prg(0xF000, 0x02);
+        for _ in 0..2 * 341 / 3 { vrc7.tick(1); }
+        assert!(!vrc7.irq());
+        vrc7.tick(3);
+        assert!(vrc7.irq());
+        // A note on channel 0, then the sound chip held in reset
+        for (register, value) in [(0x30, 0x10), (0x10, 0x80), (0x20, 0x18)] {
+            vrc7.write_prg(0x9010, register);
+            vrc7.write_prg(0x9030, value);
+        }
+        let mut peak: f32 = 0.0;
+        for _ in 0..100 {
+            vrc7.tick(255);
+            peak = peak.max(vrc7.audio().abs());
+        }
+        assert!(peak > 0.01);
+        vrc7.write_prg(0xE000, 0x80);
+        assert_eq!(vrc7.audio(), 0.0);
+    }
+}
@@ -0,0 +1,355 @@
+use core::f32::consts::FRAC_PI_2;
+use crate::prelude::*;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// A full volume channel against the console's full output range, a little under a pulse channel
+const CHANNEL_SCALE: f32 = 0.12;
+// CPU cycles per OPLL sample: its 3.58MHz clock divided by 72
+const SAMPLE_CYCLES: u8 = 36;
+// The VRC7's 15 built in instruments, as read off the die; instrument 0 is the custom one in $00-$07
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+// Twice each operator's frequency multiple, so the 1/2 setting stays whole
+const MULTIPLIERS: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+// Key scale attenuation by the top 4 F-number bits, for the highest octave
+const KEY_SCALE: [u16; 16] = [0, 32, 40, 45, 48, 51, 53, 55, 56, 58, 59, 60, 61, 62, 63, 64];
+// The vibrato's 8 steps, in quarters of F-number >> 6
+const VIBRATO: [i32; 8] = [0, 1, 2, 1, 0, -1, -2, -1];
+// Attenuation is counted in 0.375dB steps; the envelope alone goes to 127, 48dB
+const ATTENUATION_STEP: f32 = 0.957_745_24;
+const SILENT: u16 = 127;
+// One sine cycle of operator phase
+const PHASE_BITS: u32 = 20;
+
+#[derive(Clone, Copy, PartialEq, Default)]
+enum Stage { Attack, Decay, Sustain, Release, #[default] Off }
+
+impl Savestate for Stage {
+    fn save_state(&self, w: &mut StateWriter) { (*self as u8).save_state(w); }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let mut id: u8 = 0;
+        id.load_state(r)?;
+        *self = match id {
+            0 => Stage::Attack,
+            1 => Stage::Decay,
+            2 => Stage::Sustain,
+            3 => Stage::Release,
+            4 => Stage::Off,
+            _ => return Err(format!("Savestate has invalid envelope stage {}", id)),
+        };
+        Ok(())
+    }
+}
+
+// One of a channel's two operators: a sine oscillator with its own envelope
+struct Operator {
+    phase: u32,
+    stage: Stage,
+    envelope: u16,
+    // Fractions of an envelope step owed at the current rate, in 1/65536ths
+    rate_accumulator: u32,
+    output: f32,
+}
+
+impl Default for Operator {
+    fn default() -> Self { Operator { phase: 0, stage: Stage::Off, envelope: SILENT, rate_accumulator: 0, output: 0.0 } }
+}
+
+impl Operator {
+    fn key_on(&mut self) { (self.phase, self.stage, self.rate_accumulator) = (0, Stage::Attack, 0); }
+    fn key_off(&mut self) { if self.stage != Stage::Off { self.stage = Stage::Release; } }
+    // Envelope steps for one sample at rate 4 * R + key scaling: each 4 more doubles the speed
+    fn steps(&mut self, rate: u8) -> u32 {
+        if rate < 4 { return 0; }
+        let rate: u8 = rate.min(63);
+        self.rate_accumulator += (4 + (rate & 3) as u32) << (rate >> 2);
+        let steps: u32 = self.rate_accumulator >> 16;
+        self.rate_accumulator &= 0xFFFF;
+        steps
+    }
+    // Attack curves down exponentially, the other stages go up linearly in dB
+    fn clock_envelope(&mut self, patch: &Patch, key_scale: u8, sustain: bool) {
+        let rate = |r: u8| if r == 0 { 0 } else { r * 4 + key_scale };
+        match self.stage {
+            Stage::Attack if patch.attack == 15 => (self.envelope, self.stage) = (0, Stage::Decay),
+            Stage::Attack => {
+                for _ in 0..self.steps(rate(patch.attack)) { self.envelope = self.envelope.saturating_sub((self.envelope >> 3) + 1); }
+                if self.envelope == 0 { self.stage = Stage::Decay; }
+            }
+            Stage::Decay => {
+                self.envelope = (self.envelope + self.steps(rate(patch.decay)) as u16).min(SILENT);
+                if self.envelope >= patch.sustain_level as u16 * 8 {
+                    self.envelope = self.envelope.max(patch.sustain_level as u16 * 8);
+                    self.stage = Stage::Sustain;
+                }
+            }
+            // Sustained instruments hold while the key is down, percussive ones keep fading
+            Stage::Sustain if patch.sustained => {}
+            Stage::Sustain => self.envelope = (self.envelope + self.steps(rate(patch.release)) as u16).min(SILENT),
+            Stage::Release => {
+                let release: u8 = if sustain { 5 } else if patch.sustained { patch.release } else { 7 };
+                self.envelope = (self.envelope + self.steps(rate(release)) as u16).min(SILENT);
+                if self.envelope >= SILENT { self.stage = Stage::Off; }
+            }
+            Stage::Off => self.envelope = SILENT,
+        }
+    }
+}
+
+savestate_fields!(Operator { phase, stage, envelope, rate_accumulator, output });
+
+// One operator's half of an instrument
+struct Patch {
+    tremolo: bool,
+    vibrato: bool,
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: u8,
+    key_scale_level: u8,
+    rectified: bool,
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+}
+
+impl Patch {
+    // `carrier` picks which operator's bits of the 8 instrument bytes
+    fn decode(data: &[u8; 8], carrier: bool) -> Patch {
+        let op: usize = carrier as usize;
+        Patch {
+            tremolo: data[op] & 0x80 != 0,
+            vibrato: data[op] & 0x40 != 0,
+            sustained: data[op] & 0x20 != 0,
+            key_scale_rate: data[op] & 0x10 != 0,
+            multiplier: data[op] & 0x0F,
+            key_scale_level: data[2 + op] >> 6,
+            rectified: data[3] & if carrier { 0x10 } else { 0x08 } != 0,
+            attack: data[4 + op] >> 4,
+            decay: data[4 + op] & 0x0F,
+            sustain_level: data[6 + op] >> 4,
+            release: data[6 + op] & 0x0F,
+        }
+    }
+}
+
+// $10-$35 for one channel, and its modulator and carrier
+#[derive(Default)]
+struct Channel {
+    fnum: u16,
+    block: u8,
+    key: bool,
+    sustain: bool,
+    instrument: u8,
+    volume: u8,
+    modulator: Operator,
+    carrier: Operator,
+    // The modulator's last two outputs, fed back into its own phase
+    feedback: [f32; 2],
+}
+
+savestate_fields!(Channel { fnum, block, key, sustain, instrument, volume, modulator, carrier, feedback });
+
+// The VRC7's sound: a cut down Yamaha OPLL (YM2413) with 6 two-operator FM channels, 15 fixed
+// instruments and one custom instrument, and no rhythm mode. Writes to $9010 pick a register and
+// writes to $9030 set it. The envelope and phase math follows the OPLL's at its rates, in floats.
+pub struct Vrc7Audio {
+    address: u8,
+    custom: [u8; 8],
+    channels: [Channel; 6],
+    divider: u8,
+    // The tremolo's triangle (0-13 attenuation steps) and the vibrato's step, and samples until they move
+    tremolo_step: u8,
+    vibrato_step: u8,
+    lfo_samples: u16,
+    output: f32,
+    sine: [f32; 1024],
+    attenuation: [f32; 256],
+}
+
+impl Vrc7Audio {
+    pub fn new() -> Self {
+        let mut sine: [f32; 1024] = [0.0; 1024];
+        for (i, value) in sine.iter_mut().enumerate() {
+            let step: f32 = (i % 256) as f32 / 256.0;
+            *value = match i / 256 { 0 => quarter_sine(step), 1 => quarter_sine(1.0 - step), 2 => -quarter_sine(step), _ => -quarter_sine(1.0 - step) };
+        }
+        let mut attenuation: [f32; 256] = [0.0; 256];
+        let mut level: f32 = 1.0;
+        for value in attenuation.iter_mut() {
+            *value = level;
+            level *= ATTENUATION_STEP;
+        }
+        Vrc7Audio {
+            address: 0, custom: [0; 8], channels: Default::default(), divider: 0,
+            tremolo_step: 0, vibrato_step: 0, lfo_samples: 0, output: 0.0, sine, attenuation,
+        }
+    }
+    pub fn select(&mut self, address: u8) { self.address = address; }
+    pub fn write(&mut self, value: u8) {
+        let index: usize = (self.address & 0x0F) as usize;
+        match self.address {
+            0x00..=0x07 => self.custom[index] = value,
+            0x10..=0x15 => self.channels[index].fnum = (self.channels[index].fnum & 0x100) | value as u16,
+            0x20..=0x25 => {
+                let channel: &mut Channel = &mut self.channels[index];
+                channel.fnum = (channel.fnum & 0xFF) | (value as u16 & 0x01) << 8;
+                channel.block = (value >> 1) & 0x07;
+                channel.sustain = value & 0x20 != 0;
+                let key: bool = value & 0x10 != 0;
+                if key && !channel.key {
+                    channel.modulator.key_on();
+                    channel.carrier.key_on();
+                } else if !key && channel.key {
+                    channel.modulator.key_off();
+                    channel.carrier.key_off();
+                }
+                channel.key = key;
+            }
+            0x30..=0x35 => {
+                self.channels[index].instrument = value >> 4;
+                self.channels[index].volume = value & 0x0F;
+            }
+            _ => {}
+        }
+    }
+    // $E000 bit 7 holds the chip in reset: every register cleared and no sound
+    pub fn reset(&mut self) { *self = Vrc7Audio::new(); }
+    // One CPU cycle; the chip makes a sample every SAMPLE_CYCLES
+    pub fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider < SAMPLE_CYCLES { return; }
+        self.divider = 0;
+        self.lfo_samples = self.lfo_samples.wrapping_add(1);
+        if self.lfo_samples.is_multiple_of(512) { self.tremolo_step = (self.tremolo_step + 1) % 26; }
+        if self.lfo_samples.is_multiple_of(1024) { self.vibrato_step = (self.vibrato_step + 1) % 8; }
+        let mut mix: f32 = 0.0;
+        for i in 0..6 { mix += self.sample(i); }
+        self.output = mix * CHANNEL_SCALE;
+    }
+    pub fn output(&self) -> f32 { self.output }
+    fn instrument(&self, instrument: u8) -> [u8; 8] {
+        if instrument == 0 { self.custom } else { PATCHES[instrument as usize - 1] }
+    }
+    // Advances channel `i` by one sample and gives its carrier's output
+    fn sample(&mut self, i: usize) -> f32 {
+        let data: [u8; 8] = self.instrument(self.channels[i].instrument);
+        let (modulator, carrier): (Patch, Patch) = (Patch::decode(&data, false), Patch::decode(&data, true));
+        let tremolo: u16 = (if self.tremolo_step < 13 { self.tremolo_step } else { 26 - self.tremolo_step }) as u16;
+        let vibrato: i32 = VIBRATO[self.vibrato_step as usize];
+        let (sine, attenuation) = (&self.sine, &self.attenuation);
+        let channel: &mut Channel = &mut self.channels[i];
+        let (fnum, block, sustain) = (channel.fnum, channel.block, channel.sustain);
+        let key_scale_level: u16 = ((KEY_SCALE[fnum as usize >> 5] << 1) as i16 - ((8 - block as i16) << 4)).max(0) as u16;
+        let run = |op: &mut Operator, patch: &Patch, level: u16, offset: i32| -> f32 {
+            let fnum4: i32 = fnum as i32 * 4 + if patch.vibrato { vibrato * (fnum as i32 >> 6) } else { 0 };
+            op.phase = (op.phase + ((fnum4 as u32 * MULTIPLIERS[patch.multiplier as usize]) << block >> 2)) & ((1 << PHASE_BITS) - 1);
+            let key_scale: u8 = if patch.key_scale_rate { block << 1 | (fnum >> 8) as u8 } else { block >> 1 };
+            op.clock_envelope(patch, key_scale, sustain);
+            let scaled: u16 = match patch.key_scale_level { 0 => 0, 1 => key_scale_level >> 2, 2 => key_scale_level >> 1, _ => key_scale_level };
+            let total: u16 = op.envelope + level + scaled + if patch.tremolo { tremolo } else { 0 };
+            let index: usize = ((op.phase as i32 + offset) >> (PHASE_BITS - 10)) as usize & 1023;
+            let wave: f32 = if patch.rectified && index >= 512 { 0.0 } else { sine[index] };
+            op.output = if op.stage == Stage::Off || total as usize >= attenuation.len() { 0.0 } else { wave * attenuation[total as usize] };
+            op.output
+        };
+        // Feedback n bends the modulator's phase by up to 2^(n-1)/32 of a cycle, the modulator the
+        // carrier's by up to two cycles
+        let feedback_level: u8 = data[3] & 0x07;
+        let feedback: i32 = if feedback_level == 0 { 0 } else {
+            ((channel.feedback[0] + channel.feedback[1]) / 2.0 * (1 << (feedback_level - 1)) as f32 / 32.0 * (1 << PHASE_BITS) as f32) as i32
+        };
+        let modulated: f32 = run(&mut channel.modulator, &modulator, (data[2] & 0x3F) as u16 * 2, feedback);
+        channel.feedback = [channel.feedback[1], modulated];
+        run(&mut channel.carrier, &carrier, channel.volume as u16 * 8, (modulated * (2 << PHASE_BITS) as f32) as i32)
+    }
+}
+
+// sin(x * pi/2) for x in 0..1, by its Taylor series (no libm in no_std builds)
+fn quarter_sine(x: f32) -> f32 {
+    let x: f32 = x * FRAC_PI_2;
+    let x2: f32 = x * x;
+    x * (1.0 - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0 * (1.0 - x2 / 72.0))))
+}
+
+savestate_fields!(Vrc7Audio { address, custom, channels, divider, tremolo_step, vibrato_step, lfo_samples, output });
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(audio: &mut Vrc7Audio, address: u8, value: u8) {
+        audio.select(address);
+        audio.write(value);
+    }
+
+    #[test]
+    fn test_sine_table() {
+        let audio: Vrc7Audio = Vrc7Audio::new();
+        assert_eq!(audio.sine[0], 0.0);
+        assert!((audio.sine[256] - 1.0).abs() < 1e-4 && (audio.sine[768] + 1.0).abs() < 1e-4);
+        assert!((audio.sine[128] - core::f32::consts::FRAC_1_SQRT_2).abs() < 1e-4);
+        assert!((audio.sine[384] - audio.sine[128]).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_custom_instrument_pitch_and_release() {
+        let mut audio: Vrc7Audio = Vrc7Audio::new();
+        // Both operators at 1x with instant attack and no decay; the modulator all the way down
+        for (address, value) in [(0x00, 0x21), (0x01, 0x21), (0x02, 0x3F), (0x03, 0x00), (0x04, 0xF0), (0x05, 0xF0), (0x06, 0x0F), (0x07, 0x0F)] {
+            write(&mut audio, address, value);
+        }
+        // 437Hz: 288 * 49716Hz * 2^(4-1) / 2^18
+        write(&mut audio, 0x30, 0x00);
+        write(&mut audio, 0x10, 0x20);
+        write(&mut audio, 0x20, 0x10 | 4 << 1 | 1);
+        let mut crossings: u32 = 0;
+        let mut last: f32 = 0.0;
+        for _ in 0..SAMPLE_CYCLES as u32 * 12429 {
+            audio.clock();
+            if (audio.output() > 0.0) != (last > 0.0) { crossings += 1; }
+            last = audio.output();
+        }
+        // A quarter second's worth
+        assert!((105..=113).contains(&(crossings / 2)), "{} cycles", crossings / 2);
+        // Key off: RR 15 fades it out within a few milliseconds
+        write(&mut audio, 0x20, 4 << 1 | 1);
+        for _ in 0..SAMPLE_CYCLES as u32 * 500 { audio.clock(); }
+        assert_eq!(audio.output(), 0.0);
+    }
+
+    #[test]
+    fn test_built_in_instrument_decays() {
+        let mut audio: Vrc7Audio = Vrc7Audio::new();
+        // Instrument 3 is percussive: it fades while the key is held
+        write(&mut audio, 0x32, 0x30);
+        write(&mut audio, 0x12, 0x80);
+        write(&mut audio, 0x22, 0x10 | 3 << 1);
+        fn peak(audio: &mut Vrc7Audio, samples: u32) -> f32 {
+            let mut peak: f32 = 0.0;
+            for _ in 0..samples * SAMPLE_CYCLES as u32 { audio.clock(); peak = peak.max(audio.output().abs()); }
+            peak
+        }
+        let start: f32 = peak(&mut audio, 2000);
+        assert!(start > 0.01);
+        peak(&mut audio, 40000);
+        assert!(peak(&mut audio, 2000) < start / 2.0);
+        audio.reset();
+        assert_eq!((audio.output(), audio.channels[2].instrument), (0.0, 0));
+    }
+}
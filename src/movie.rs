@@ -0,0 +1,78 @@
+use crate::prelude::*;
+
+// FCEUX .fm2 movies (text format only): `key value` header lines, then one input line per frame
+//   |commands|RLDUTSBA|RLDUTSBA||
+// commands bit 0 = soft reset, bit 1 = power cycle. Only joypad 1 (port 0) is replayed.
+pub const PAD_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+
+pub const COMMAND_SOFT_RESET: u8 = 1 << 0;
+pub const COMMAND_POWER: u8 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MovieFrame {
+    pub commands: u8,
+    pub joypad1: u8,
+}
+
+pub struct Fm2Movie {
+    pub header: Vec<(String, String)>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Fm2Movie {
+    pub fn parse(text: &str) -> Result<Fm2Movie, String> {
+        let mut header: Vec<(String, String)> = Vec::new();
+        let mut frames: Vec<MovieFrame> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line: &str = line.trim_end_matches('\r');
+            if let Some(fields) = line.strip_prefix('|') {
+                let mut fields = fields.split('|');
+                let commands: u8 = fields.next().unwrap_or("0").trim().parse().unwrap_or(0);
+                let joypad1: u8 = match fields.next() {
+                    None | Some("") => 0,
+                    Some(pad) => parse_pad(pad).ok_or(format!("FM2 line {}: bad joypad field `{}`", number + 1, pad))?,
+                };
+                frames.push(MovieFrame { commands, joypad1 });
+            } else if let Some((key, value)) = line.split_once(' ') {
+                header.push((String::from(key), String::from(value.trim())));
+            } else if !line.trim().is_empty() {
+                header.push((String::from(line.trim()), String::new()));
+            }
+        }
+        let movie: Fm2Movie = Fm2Movie { header, frames };
+        if movie.get("version").is_none() { return Err(String::from("Not an FM2 movie (no version header)")); }
+        if movie.get("binary") == Some("1") { return Err(String::from("Binary FM2 movies are not supported")); }
+        Ok(movie)
+    }
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.header.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+}
+
+// One pad in RLDUTSBA column order: '.' or ' ' is released, anything else pressed.
+// Returns the JoypadButton bit mask (Right = bit 7 ... A = bit 0).
+pub fn parse_pad(pad: &str) -> Option<u8> {
+    if pad.len() != PAD_BUTTONS.len() { return None; }
+    Some(pad.bytes().enumerate()
+        .filter(|(_, c)| *c != b'.' && *c != b' ')
+        .fold(0, |status, (i, _)| status | 1 << (7 - i)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_fm2() {
+        let text: &str = "version 3\nemuVersion 22020\nromFilename smb\nguid 1234\n|1|........|||\n|0|R......A|||\n|0|...U....|........||\n";
+        let movie: Fm2Movie = Fm2Movie::parse(text).unwrap();
+        assert_eq!(movie.get("romFilename"), Some("smb"));
+        assert_eq!(movie.frames, vec![
+            MovieFrame { commands: COMMAND_SOFT_RESET, joypad1: 0 },
+            MovieFrame { commands: 0, joypad1: 0b1000_0001 },
+            MovieFrame { commands: 0, joypad1: 0b0001_0000 },
+        ]);
+        assert!(Fm2Movie::parse("|0|........|||\n").is_err());
+        assert!(Fm2Movie::parse("version 3\nbinary 1\n").is_err());
+    }
+}
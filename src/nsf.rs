@@ -0,0 +1,192 @@
+use crate::prelude::*;
+use crate::bus::Bus;
+use crate::cartridge::Region;
+use crate::cpu::{CPU, Mem};
+use crate::mapper::nsf::NsfMapper;
+
+const NSF_TAG: [u8; 5] = [0x4E, 0x45, 0x53, 0x4D, 0x1A];
+const HEADER_SIZE: usize = 0x80;
+// Where INIT and PLAY return to. Nothing runs there: the player stops stepping the CPU once it
+// gets back, and waits out the time until the next PLAY.
+const RETURN_ADDR: u16 = 0x4100;
+
+// An NSF rip: a game's sound driver and music data with the rest of the game cut away, plus the
+// addresses a player calls to start a song (INIT) and advance it by one tick (PLAY)
+#[derive(Debug, Clone)]
+pub struct Nsf {
+    pub songs: u8,
+    // 1 based
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    // Microseconds between two PLAY calls on the tune's region
+    pub play_period: u16,
+    // The banks at $8000-$FFFF when a song starts, for tunes that bankswitch
+    pub banks: Option<[u8; 8]>,
+    pub region: Region,
+    // Expansion sound chip bits (VRC6, VRC7, FDS, MMC5, Namco 163, Sunsoft 5B)
+    pub expansion: u8,
+    pub data: Vec<u8>,
+}
+
+impl Nsf {
+    pub fn new(raw: &[u8]) -> Result<Nsf, String> {
+        if raw.len() < HEADER_SIZE || raw[0..5] != NSF_TAG { return Err("File is not in NSF file format".to_string()); }
+        let word = |at: usize| u16::from_le_bytes([raw[at], raw[at + 1]]);
+        let text = |at: usize| String::from_utf8_lossy(&raw[at..at + 32]).split('\0').next().unwrap_or("").trim().to_string();
+        let mut banks: [u8; 8] = [0; 8];
+        banks.copy_from_slice(&raw[0x70..0x78]);
+        // Bit 0 is PAL, bit 1 "works on both", which plays as NTSC
+        let region: Region = if raw[0x7A] & 0b11 == 0b01 { Region::PAL } else { Region::NTSC };
+        let nsf: Nsf = Nsf {
+            songs: raw[0x06].max(1),
+            starting_song: raw[0x07].clamp(1, raw[0x06].max(1)),
+            load_addr: word(0x08),
+            init_addr: word(0x0A),
+            play_addr: word(0x0C),
+            title: text(0x0E),
+            artist: text(0x2E),
+            copyright: text(0x4E),
+            play_period: if region == Region::PAL { word(0x78) } else { word(0x6E) },
+            banks: banks.iter().any(|bank| *bank != 0).then_some(banks),
+            region,
+            expansion: raw[0x7B],
+            data: raw[HEADER_SIZE..].to_vec(),
+        };
+        if nsf.load_addr < 0x8000 { return Err(format!("NSF load address ${:04X} is below $8000", nsf.load_addr)); }
+        if nsf.expansion != 0 { log!("NSF expansion audio {:#04X} is not supported, those channels stay silent", nsf.expansion); }
+        Ok(nsf)
+    }
+    #[cfg(feature = "std")]
+    pub fn from_file(path: &str) -> Result<Nsf, String> {
+        Nsf::new(&std::fs::read(path).map_err(|err| format!("Could not read {}: {}", path, err))?)
+    }
+}
+
+// Plays an Nsf on the CPU and APU. The PPU is there, since the Bus drives it, but no tune code
+// looks at it and nothing is drawn; the joypad isn't read either. The frontend runs it one
+// video frame at a time like Headless and draws its own picture.
+pub struct NsfPlayer {
+    pub cpu: CPU<'static>,
+    pub nsf: Nsf,
+    mapper: Rc<RefCell<NsfMapper>>,
+    song: u8,
+    // CPU cycles between PLAY calls, until the next one, and where the current video frame ends
+    play_cycles: f64,
+    until_play: f64,
+    frame_end: f64,
+    // Every channel's loudest level during the last frame
+    peaks: [f64; 6],
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf) -> Self {
+        let mapper: Rc<RefCell<NsfMapper>> = Rc::new(RefCell::new(NsfMapper::new(&nsf)));
+        let region: Region = nsf.region;
+        let play_cycles: f64 = match nsf.play_period {
+            0 => region.cpu_clock() / region.frame_rate(),
+            period => period as f64 * region.cpu_clock() / 1_000_000.0,
+        };
+        let cpu: CPU<'static> = CPU::new(Bus::with_mapper(mapper.clone(), region, |_, _, _| {}));
+        let song: u8 = nsf.starting_song;
+        let mut player: NsfPlayer = NsfPlayer { cpu, nsf, mapper, song, play_cycles, until_play: 0.0, frame_end: 0.0, peaks: [0.0; 6] };
+        player.start(song);
+        player
+    }
+    // The song playing, from 1 to nsf.songs
+    pub fn song(&self) -> u8 { self.song }
+    // Starts song `song` from the top, wrapping around past either end
+    pub fn start(&mut self, song: u8) {
+        self.song = (song as i16 - 1).rem_euclid(self.nsf.songs as i16) as u8 + 1;
+        // A fresh machine for every song, as if the console was switched off and on
+        let mixer = core::mem::take(&mut self.cpu.bus.apu().mixer);
+        let sample_rate: f64 = self.cpu.bus.apu().sample_rate();
+        self.mapper.borrow_mut().reset();
+        self.cpu = CPU::new(Bus::with_mapper(self.mapper.clone(), self.nsf.region, |_, _, _| {}));
+        self.cpu.bus.apu().mixer = mixer;
+        self.cpu.bus.apu().set_sample_rate(sample_rate);
+        for addr in 0x4000..=0x4013 { self.cpu.mem_write(addr, 0x00); }
+        self.cpu.mem_write(0x4015, 0x0F);
+        self.cpu.mem_write(0x4017, 0x40);
+        self.cpu.register_a = self.song - 1;
+        self.cpu.register_x = (self.nsf.region == Region::PAL) as u8;
+        self.cpu.call(self.nsf.init_addr, RETURN_ADDR);
+        (self.until_play, self.frame_end) = (0.0, 0.0);
+    }
+    pub fn next_song(&mut self) { self.start(self.song.wrapping_add(1)); }
+    pub fn previous_song(&mut self) { self.start(self.song.wrapping_sub(1)); }
+    // Emulates one video frame's worth of CPU cycles. PLAY is called as soon as INIT returns, then
+    // whenever its period is up and the last call has returned; an INIT that never returns (tunes
+    // that play from a loop) just runs.
+    pub fn run_frame(&mut self) {
+        self.cpu.bus.apu().buffer.clear();
+        self.peaks = [0.0; 6];
+        let region: Region = self.nsf.region;
+        self.frame_end += region.cpu_clock() / region.frame_rate();
+        while (self.cpu.bus.cycles as f64) < self.frame_end {
+            let start: usize = self.cpu.bus.cycles;
+            if self.cpu.program_counter != RETURN_ADDR {
+                self.cpu.execute();
+            } else if self.until_play <= 0.0 {
+                self.until_play += self.play_cycles;
+                self.cpu.call(self.nsf.play_addr, RETURN_ADDR);
+            } else {
+                self.cpu.bus.tick((self.until_play as u8).clamp(1, 64));
+            }
+            self.until_play -= (self.cpu.bus.cycles - start) as f64;
+            for (peak, level) in self.peaks.iter_mut().zip(self.cpu.bus.apu().channel_levels()) { *peak = peak.max(level); }
+        }
+    }
+    // Audio samples generated during the last frame, interleaved left and right
+    pub fn audio(&mut self) -> &[f32] { &self.cpu.bus.apu().buffer }
+    // Per mixer::Channel, its loudest output during the last frame, in APU::channel_levels' units
+    pub fn levels(&self) -> [f64; 6] { self.peaks }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    // A two song tune: INIT stores the song number at $6000, PLAY counts its calls at $6001
+    pub fn test_nsf() -> Nsf {
+        let mut raw: Vec<u8> = NSF_TAG.to_vec();
+        raw.extend([1, 2, 1, 0x00, 0x80, 0x00, 0x80, 0x04, 0x80]);
+        let mut title: Vec<u8> = b"Test Tune".to_vec();
+        title.resize(96, 0);
+        raw.extend(title);
+        raw.extend(16639u16.to_le_bytes());
+        raw.resize(HEADER_SIZE, 0);
+        raw.extend([0x8D, 0x00, 0x60, 0x60, 0xEE, 0x01, 0x60, 0x60]);
+        Nsf::new(&raw).unwrap()
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let nsf: Nsf = test_nsf();
+        assert_eq!((nsf.songs, nsf.starting_song, nsf.init_addr, nsf.play_addr), (2, 1, 0x8000, 0x8004));
+        assert_eq!((nsf.title.as_str(), nsf.artist.as_str()), ("Test Tune", ""));
+        assert_eq!((nsf.region, nsf.banks, nsf.data.len()), (Region::NTSC, None, 8));
+        assert!(Nsf::new(b"NES\x1A").is_err());
+    }
+
+    #[test]
+    fn test_init_and_play() {
+        let mut player: NsfPlayer = NsfPlayer::new(test_nsf());
+        for _ in 0..10 { player.run_frame(); }
+        // INIT got A = 0, and PLAY ran right after it and then at 60Hz
+        assert_eq!(player.cpu.bus.peek(0x6000), 0);
+        assert!((10..=11).contains(&player.cpu.bus.peek(0x6001)));
+        assert!(!player.audio().is_empty());
+        player.next_song();
+        player.run_frame();
+        assert_eq!((player.song(), player.cpu.bus.peek(0x6000), player.cpu.bus.peek(0x6001)), (2, 1, 1));
+        player.next_song();
+        assert_eq!(player.song(), 1);
+        player.previous_song();
+        assert_eq!(player.song(), 2);
+    }
+}
@@ -1,6 +1,4 @@
 use crate::cpu::AddressingMode;
-use std::collections::HashMap;
-use lazy_static::lazy_static;
 
 #[derive(Debug)]
 pub struct OpCode {
@@ -10,12 +8,14 @@ pub struct OpCode {
     pub cycles: u8,
     pub mode: AddressingMode,
 }
-impl OpCode { fn new(code: u8, mnemonic: &'static str, len: u8, cycles: u8, mode: AddressingMode) -> Self { OpCode { code, mnemonic, len, cycles, mode } } }
+impl OpCode {
+    const fn new(code: u8, mnemonic: &'static str, len: u8, cycles: u8, mode: AddressingMode) -> Self { OpCode { code, mnemonic, len, cycles, mode } }
+    // Unofficial opcodes are the ones whose mnemonic starts with `*`, as in nestest.log
+    pub fn is_unofficial(&self) -> bool { self.mnemonic.starts_with('*') }
+}
 
-lazy_static! {
-    pub static ref CPU_OPS_CODES: Vec<OpCode> = vec![
+pub static CPU_OPS_CODES: &[OpCode] = &[
         OpCode::new(0x00, "BRK", 1, 7, AddressingMode::NoneAddressing),
-        OpCode::new(0xaa, "TAX", 1, 2, AddressingMode::NoneAddressing),
 
         OpCode::new(0xa9, "LDA", 2, 2, AddressingMode::Immediate),
         OpCode::new(0xa5, "LDA", 2, 3, AddressingMode::ZeroPage),
@@ -294,10 +294,34 @@ lazy_static! {
         OpCode::new(0x73, "*RRA", 2, 8, AddressingMode::Indirect_Y),
 
 
-    ];
-    pub static ref OPCODES_MAP: HashMap<u8, &'static OpCode> = {
-        let mut map = HashMap::new();
-        for cpuop in &*CPU_OPS_CODES { map.insert(cpuop.code, cpuop); }
-        map
-    };
+];
+
+// Every opcode byte's entry of CPU_OPS_CODES, built at compile time: the CPU looks up each instruction
+// it executes here, so it's a plain array index instead of a hash lookup
+pub static OPCODE_TABLE: [Option<&OpCode>; 256] = {
+    let mut table: [Option<&OpCode>; 256] = [None; 256];
+    let mut i: usize = 0;
+    while i < CPU_OPS_CODES.len() {
+        table[CPU_OPS_CODES[i].code as usize] = Some(&CPU_OPS_CODES[i]);
+        i += 1;
+    }
+    table
+};
+
+#[inline]
+pub fn lookup(code: u8) -> Option<&'static OpCode> { OPCODE_TABLE[code as usize] }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_table_holds_every_opcode_once() {
+        for op in CPU_OPS_CODES {
+            assert_eq!(CPU_OPS_CODES.iter().filter(|other| other.code == op.code).count(), 1, "{:02X} is listed twice", op.code);
+            assert!(core::ptr::eq(lookup(op.code).unwrap(), op));
+        }
+        assert_eq!(OPCODE_TABLE.iter().filter(|op| op.is_some()).count(), CPU_OPS_CODES.len());
+        assert!(lookup(0x02).is_none());
+    }
 }
@@ -0,0 +1,294 @@
+use crate::prelude::*;
+use crate::render;
+use crate::cartridge::Region;
+use super::NesPPU;
+use super::registers::control::FlagArithmetic;
+use super::registers::mask::{MaskArithmetic, MaskFlags};
+use super::registers::status::{StatusArithmetic, StatusFlags};
+
+// How NesPPU::tick turns PPU time into pictures
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PpuAccuracy {
+    // A whole line at once as it ends (render::render_scanline): fast, and right for nearly every game
+    Scanline,
+    // The real fetch pipeline, one dot at a time: background shift registers fed through v/t/x/w,
+    // per-line sprite evaluation (8 per line, overflow flag, 8x16 sprites) and pixel-exact sprite 0 hits
+    Dot,
+}
+
+impl PpuAccuracy {
+    pub fn parse(name: &str) -> Result<PpuAccuracy, String> {
+        match name.trim().to_lowercase().as_str() {
+            "scanline" => Ok(PpuAccuracy::Scanline),
+            "dot" => Ok(PpuAccuracy::Dot),
+            _ => Err(format!("Unknown PPU accuracy {:?}: expected scanline or dot", name)),
+        }
+    }
+}
+
+const MAX_LINE_SPRITES: usize = 8;
+
+// Latches and shift registers of the dot pipeline, carried from one dot to the next
+#[derive(Debug, Clone, Default)]
+pub struct DotPipeline {
+    next_tile: u8,
+    next_attribute: u8,
+    next_low: u8,
+    next_high: u8,
+    pattern_low: u16,
+    pattern_high: u16,
+    attribute_low: u16,
+    attribute_high: u16,
+    // Sprites fetched for the line being drawn: pattern rows (already flipped), attributes and X
+    sprite_count: usize,
+    sprite_low: [u8; MAX_LINE_SPRITES],
+    sprite_high: [u8; MAX_LINE_SPRITES],
+    sprite_attributes: [u8; MAX_LINE_SPRITES],
+    sprite_x: [u8; MAX_LINE_SPRITES],
+    sprite_zero_on_line: bool,
+    odd_frame: bool,
+}
+
+impl NesPPU {
+    fn read_name_table(&self, addr: u16) -> u8 { self.vram[self.mirror_vram_addr(0x2000 | (addr & 0x0FFF)) as usize] }
+
+    // Runs `cycles` dots; true when a frame was finished
+    pub(super) fn tick_dots(&mut self, cycles: u8) -> bool {
+        let mut new_frame: bool = false;
+        for _ in 0..cycles {
+            self.dot();
+            self.cycles += 1;
+            // With rendering on, the NTSC pre-render line of every other frame is one dot shorter
+            let skip: bool = self.region == Region::NTSC && self.scanline == 261 && self.cycles == 340 && self.dot_pipeline.odd_frame && self.rendering_enabled();
+            if self.cycles >= 341 || skip {
+                self.cycles = 0;
+                if self.end_scanline() {
+                    self.dot_pipeline.odd_frame = !self.dot_pipeline.odd_frame;
+                    new_frame = true;
+                }
+            }
+        }
+        new_frame
+    }
+
+    fn dot(&mut self) {
+        let (line, dot) = (self.scanline, self.cycles);
+        if !(line < 240 || line == self.pre_render_line()) { return; }
+        if self.rendering_enabled() { self.fetch(line, dot); }
+        if line < 240 && (1..=256).contains(&dot) { self.output_pixel(line as usize, dot - 1); }
+    }
+
+    // The background tile fetches and v updates of one dot, and the sprites for the next line at its end
+    fn fetch(&mut self, line: u16, dot: usize) {
+        let pre_render: bool = line == self.pre_render_line();
+        if (2..258).contains(&dot) || (321..338).contains(&dot) {
+            self.shift_background();
+            match (dot - 1) % 8 {
+                0 => {
+                    self.reload_background();
+                    self.dot_pipeline.next_tile = self.read_name_table(self.loopy.v);
+                }
+                2 => {
+                    let v: u16 = self.loopy.v;
+                    let attribute: u8 = self.read_name_table(0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07));
+                    let shift: u16 = (self.loopy.coarse_y() & 2) << 1 | (self.loopy.coarse_x() & 2);
+                    self.dot_pipeline.next_attribute = (attribute >> shift) & 0b11;
+                }
+                4 => self.dot_pipeline.next_low = self.read_chr(self.background_row_addr()),
+                6 => self.dot_pipeline.next_high = self.read_chr(self.background_row_addr() + 8),
+                7 => self.loopy.increment_x(),
+                _ => {}
+            }
+        }
+        if dot == 256 { self.loopy.increment_y(); }
+        if dot == 257 {
+            self.reload_background();
+            self.loopy.copy_x();
+            // Sprite pattern fetches (dots 257-320) leave OAMADDR at 0
+            if self.oam_quirks { self.oam_addr = 0; }
+            if line < 240 { self.evaluate_sprites(line as usize); }
+            else { self.dot_pipeline.sprite_count = 0; }
+        }
+        if pre_render && (280..305).contains(&dot) { self.loopy.copy_y(); }
+    }
+
+    fn background_row_addr(&self) -> u16 { self.bknd_pattern_addr() + self.dot_pipeline.next_tile as u16 * 16 + self.loopy.fine_y() }
+
+    // Runs with either layer on, so switching the background on mid-line finds the tiles in place
+    fn shift_background(&mut self) {
+        let pipeline: &mut DotPipeline = &mut self.dot_pipeline;
+        pipeline.pattern_low <<= 1;
+        pipeline.pattern_high <<= 1;
+        pipeline.attribute_low <<= 1;
+        pipeline.attribute_high <<= 1;
+    }
+
+    fn reload_background(&mut self) {
+        let pipeline: &mut DotPipeline = &mut self.dot_pipeline;
+        pipeline.pattern_low = (pipeline.pattern_low & 0xFF00) | pipeline.next_low as u16;
+        pipeline.pattern_high = (pipeline.pattern_high & 0xFF00) | pipeline.next_high as u16;
+        let spread = |bit: u8| -> u16 { if bit != 0 { 0xFF } else { 0x00 } };
+        pipeline.attribute_low = (pipeline.attribute_low & 0xFF00) | spread(pipeline.next_attribute & 0b01);
+        pipeline.attribute_high = (pipeline.attribute_high & 0xFF00) | spread(pipeline.next_attribute & 0b10);
+    }
+
+    // Picks the first 8 sprites on the next line (OAM Y is the line above the sprite's top row)
+    // and fetches their pattern rows
+    fn evaluate_sprites(&mut self, line: usize) {
+        let height: usize = self.sprite_size() as usize;
+        let mut count: usize = 0;
+        self.dot_pipeline.sprite_zero_on_line = false;
+        for i in 0..64 {
+            let top: usize = self.oam_data[i * 4] as usize;
+            if line < top || line - top >= height { continue; }
+            if count == MAX_LINE_SPRITES {
+                self.set_status(StatusFlags::SpriteOverflow, true);
+                break;
+            }
+            let (tile, attributes, x) = (self.oam_data[i * 4 + 1], self.oam_data[i * 4 + 2], self.oam_data[i * 4 + 3]);
+            let row: usize = if attributes & 0x80 != 0 { height - 1 - (line - top) } else { line - top };
+            let addr: u16 = if height == 16 {
+                // 8x16 sprites pick their pattern table with bit 0 and span two consecutive tiles
+                (tile as u16 & 1) * 0x1000 + ((tile & 0xFE) as u16 + (row / 8) as u16) * 16 + (row % 8) as u16
+            } else {
+                self.sprt_pattern_addr() + tile as u16 * 16 + row as u16
+            };
+            let (mut low, mut high) = (self.read_chr(addr), self.read_chr(addr + 8));
+            if attributes & 0x40 != 0 { (low, high) = (low.reverse_bits(), high.reverse_bits()); }
+            let pipeline: &mut DotPipeline = &mut self.dot_pipeline;
+            pipeline.sprite_low[count] = low;
+            pipeline.sprite_high[count] = high;
+            pipeline.sprite_attributes[count] = attributes;
+            pipeline.sprite_x[count] = x;
+            if i == 0 { pipeline.sprite_zero_on_line = true; }
+            count += 1;
+        }
+        self.dot_pipeline.sprite_count = count;
+    }
+
+    fn output_pixel(&mut self, y: usize, x: usize) {
+        // Undrawn frames only need the pixels a sprite 0 hit could come from
+        if self.skip_render && !self.dot_pipeline.sprite_zero_on_line { return; }
+        let mut background: u8 = 0;
+        let mut background_palette: u8 = 0;
+        if self.get_mask(MaskFlags::ShowBackground) && (x >= 8 || self.get_mask(MaskFlags::Leftmost8PixelBackground)) {
+            let bit: u16 = 0x8000 >> self.loopy.x;
+            let pipeline: &DotPipeline = &self.dot_pipeline;
+            background = ((pipeline.pattern_high & bit != 0) as u8) << 1 | (pipeline.pattern_low & bit != 0) as u8;
+            background_palette = ((pipeline.attribute_high & bit != 0) as u8) << 1 | (pipeline.attribute_low & bit != 0) as u8;
+        }
+
+        // The first opaque sprite pixel wins, whatever its priority bit says
+        let mut sprite: Option<(usize, u8, u8)> = None;
+        if self.get_mask(MaskFlags::ShowSprites) && (x >= 8 || self.get_mask(MaskFlags::Leftmost8PixelSprite)) {
+            let pipeline: &DotPipeline = &self.dot_pipeline;
+            sprite = (0..pipeline.sprite_count).find_map(|i| {
+                let column: usize = x.checked_sub(pipeline.sprite_x[i] as usize).filter(|column| *column < 8)?;
+                let value: u8 = (pipeline.sprite_high[i] >> (7 - column) & 1) << 1 | (pipeline.sprite_low[i] >> (7 - column) & 1);
+                (value != 0).then_some((i, value, pipeline.sprite_attributes[i]))
+            });
+        }
+
+        if let Some((i, _, _)) = sprite {
+            if i == 0 && self.dot_pipeline.sprite_zero_on_line && background != 0 && x != 255 {
+                self.set_status(StatusFlags::SpriteZeroHit, true);
+            }
+        }
+        let palette_index: usize = match sprite {
+            Some((_, value, attributes)) if background == 0 || attributes & 0x20 == 0 => 0x10 + (attributes as usize & 0b11) * 4 + value as usize,
+            _ if background != 0 => background_palette as usize * 4 + background as usize,
+            // With rendering off and v pointing into palette RAM, the PPU shows that entry instead of
+            // the backdrop: set through $2006 between lines, it draws raster colour bars
+            _ if !self.rendering_enabled() && self.loopy.v & 0x3F00 == 0x3F00 => super::palette_index(self.loopy.v),
+            _ => 0,
+        };
+        if !self.skip_render { self.picture[y * 256 + x] = render::color(self, self.palette_table[palette_index]); }
+    }
+}
+
+savestate_fields!(DotPipeline {
+    next_tile, next_attribute, next_low, next_high, pattern_low, pattern_high, attribute_low, attribute_high,
+    sprite_count, sprite_low, sprite_high, sprite_attributes, sprite_x, sprite_zero_on_line, odd_frame
+});
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+    use crate::ppu::PPU;
+    use crate::ppu::registers::status::StatusArithmetic;
+    use crate::render::{frame::Frame, palette::Palette};
+
+    // Tile 0 is solid colour 1, tile 1 has colour 2 in its left half, tile 2 is blank
+    fn striped_ppu() -> NesPPU {
+        let mut chr: Vec<u8> = vec![0; 2048];
+        chr[0..8].fill(0xFF);
+        chr[24..32].fill(0xF0);
+        let mut ppu: NesPPU = NesPPU::new(chr, Mirroring::VERTICAL);
+        for (i, tile) in ppu.vram.iter_mut().enumerate() { *tile = (i % 3) as u8; }
+        ppu.palette_table[..4].copy_from_slice(&[0x0F, 0x01, 0x16, 0x30]);
+        ppu.accuracy = PpuAccuracy::Dot;
+        ppu.write_to_mask(0b0001_1110);
+        ppu
+    }
+
+    fn run_frame(ppu: &mut NesPPU) { while !ppu.tick(100) {} }
+
+    #[test]
+    fn test_dot_background_matches_scanline_rendering() {
+        let mut ppu: NesPPU = striped_ppu();
+        ppu.oam_data.fill(0xFF);
+        ppu.write_to_ctrl(0b01);
+        ppu.write_to_scroll(13);
+        ppu.write_to_scroll(21);
+        run_frame(&mut ppu);
+        run_frame(&mut ppu);
+        let mut frame: Frame = Frame::new();
+        render::render(&ppu, &mut frame);
+        let mut dot_frame: Frame = Frame::new();
+        render::draw_picture(&ppu.picture, &mut dot_frame, &Palette::default());
+        assert!(frame.data == dot_frame.data);
+    }
+
+    #[test]
+    fn test_dot_sprite_zero_hit_and_overflow() {
+        let mut ppu: NesPPU = striped_ppu();
+        ppu.vram.fill(0);
+        ppu.oam_data.fill(0xFF);
+        ppu.palette_table[0x11] = 0x16;
+        // Sprite 0 at (40, 30) over the solid background, then 8 more sprites on its lines
+        for i in 0..9 { ppu.oam_data[i * 4..i * 4 + 4].copy_from_slice(&[30, 0, 0, 40 + i as u8]); }
+        run_frame(&mut ppu);
+        while ppu.scanline < 30 { ppu.tick(100); }
+        assert!(!ppu.get_status(StatusFlags::SpriteZeroHit));
+        while ppu.scanline < 32 { ppu.tick(100); }
+        assert!(ppu.get_status(StatusFlags::SpriteZeroHit));
+        assert!(ppu.get_status(StatusFlags::SpriteOverflow));
+        // Drawn from the line after OAM Y
+        assert_eq!(ppu.picture[30 * 256 + 40], 0x01);
+        assert_eq!(ppu.picture[31 * 256 + 40], 0x16);
+    }
+
+    #[test]
+    fn test_palette_writes_land_on_their_scanline() {
+        let mut ppu: NesPPU = striped_ppu();
+        ppu.vram.fill(0);
+        ppu.oam_data.fill(0xFF);
+        run_frame(&mut ppu);
+        while ppu.scanline < 100 { ppu.tick(100); }
+        // Forced blank for a few lines while a new colour goes into $3F01, pointing v at $3F02 after it
+        ppu.write_to_mask(0);
+        ppu.write_to_ppu_addr(0x3F);
+        ppu.write_to_ppu_addr(0x01);
+        ppu.write_to_data(0x2A);
+        let blank_line: u16 = ppu.scanline + 1;
+        while ppu.scanline < 110 { ppu.tick(100); }
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_mask(0b0001_1110);
+        run_frame(&mut ppu);
+        assert_eq!(ppu.picture[99 * 256 + 50], 0x01);
+        assert_eq!(ppu.picture[blank_line as usize * 256 + 50], 0x16);
+        assert_eq!(ppu.picture[200 * 256 + 50], 0x2A);
+    }
+}
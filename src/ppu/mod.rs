@@ -1,10 +1,15 @@
+use crate::prelude::*;
 pub mod registers;
-use crate::cartridge::Mirroring;
+pub mod dot;
+use crate::cartridge::{Mirroring, Region, Rom};
+use crate::mapper::{self, Mapper};
+use crate::render;
 use registers::mask::{MaskFlags, MaskArithmetic};
 use registers::control::{ControlFlags, FlagArithmetic};
-use registers::addr::AddrRegister;
 use registers::status::{StatusFlags, StatusArithmetic};
-use registers::scroll::ScrollRegister;
+use registers::loopy::LoopyRegisters;
+use dot::{DotPipeline, PpuAccuracy};
+use crate::savestate::{Savestate, StateReader, StateWriter};
 
 
 pub trait PPU {
@@ -13,7 +18,7 @@ pub trait PPU {
     fn read_status(&mut self) -> u8; 
     fn write_to_oam_addr(&mut self, value: u8);
     fn write_to_oam_data(&mut self, value: u8);
-    fn read_oam_data(&self) -> u8;
+    fn read_oam_data(&mut self) -> u8;
     fn write_to_scroll(&mut self, value: u8);
     fn write_to_ppu_addr(&mut self, value: u8);
     fn write_to_data(&mut self, value: u8);
@@ -21,54 +26,114 @@ pub trait PPU {
     fn write_oam_dma(&mut self, value: &[u8; 256]);
 }
 pub struct NesPPU {
-    pub chr_rom: Vec<u8>,
+    pub mapper: Rc<RefCell<dyn Mapper>>,
     pub palette_table: [u8; 32],
     pub vram: [u8; 2048],
     pub oam_addr: u8,
     pub oam_data: [u8; 64 * 4],
-    pub addr: AddrRegister,
-    pub mirroring: Mirroring,
     pub ctrl: u8,
     pub mask: u8,
     pub status: u8,
-    pub scroll: ScrollRegister,
     pub scanline: u16,
     pub cycles: usize,
+    // The picture drawn so far, one colour index per pixel as each visible scanline ends (see render::render_scanline)
+    pub picture: Vec<u16>,
+    pub accuracy: PpuAccuracy,
+    // The frame's length in lines and the odd-frame dot skip follow the console's region
+    pub region: Region,
+    // Emulate how the 2C02 mangles OAM around OAMADDR: reset to 0 by each line's sprite fetches, a row
+    // copied to the start of OAM when rendering starts with OAMADDR >= 8, and a row copied over the new
+    // one by $2003 writes during rendering. Off by default, since games that trip over it are rare and
+    // it differs between PPU revisions; oam_stress style test ROMs need it.
+    pub oam_quirks: bool,
+    // Frame skip: leaves `picture` as it was, with timing, NMI and sprite 0 hits unchanged. Saves most
+    // in scanline accuracy, where drawing is a separate pass over each line.
+    pub skip_render: bool,
+    // Scroll and VRAM address, shared by $2005/$2006/$2007 and rendering
+    pub loopy: LoopyRegisters,
+    dot_pipeline: DotPipeline,
     internal_data_buf: u8,
+    // The data bus between CPU and PPU holds the last value written to or read from any PPU register;
+    // write-only registers and unused status bits read back from it. Each bit fades to 0 once it
+    // hasn't been driven for OPEN_BUS_DECAY_FRAMES (about 600ms); frames left per bit in `open_bus_decay`.
+    open_bus: u8,
+    open_bus_decay: [u8; 8],
     pub nmi_interrupt: Option<u8>,
 }
+
+const OPEN_BUS_DECAY_FRAMES: u8 = 36;
 impl NesPPU {
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let rom: Rom = Rom { prg_rom: vec![0; 0x4000], chr_rom, mapper: 0, screen_mirroring: mirroring, trainer: None, battery: false, region: Region::NTSC };
+        NesPPU::with_mapper(mapper::from_rom(rom).unwrap())
+    }
+    pub fn with_mapper(mapper: Rc<RefCell<dyn Mapper>>) -> Self {
         NesPPU {
-            chr_rom,
+            mapper,
             palette_table: [0; 32],
             vram: [0; 2048],
             oam_addr: 0,
             oam_data: [0; 64 * 4],
-            addr: AddrRegister::new(),
-            mirroring,
             ctrl: 0,
             mask: 0,
             status: 0,
-            scroll: ScrollRegister::new(),
             internal_data_buf: 0,
+            open_bus: 0,
+            open_bus_decay: [0; 8],
             scanline: 0,
             cycles: 0,
+            picture: vec![0; 256 * 240],
+            accuracy: PpuAccuracy::Scanline,
+            region: Region::NTSC,
+            oam_quirks: false,
+            skip_render: false,
+            loopy: LoopyRegisters::new(),
+            dot_pipeline: DotPipeline::default(),
             nmi_interrupt: None,
         }
     }
     pub fn new_empty_rom() -> Self { NesPPU::new(vec![0; 2048], Mirroring::HORIZONTAL) }
+    // Queried on every nametable access, since mappers can switch it mid-game
+    pub fn mirroring(&self) -> Mirroring { self.mapper.borrow().mirroring() }
+    pub fn read_chr(&self, addr: u16) -> u8 { self.mapper.borrow().read_chr(addr) }
+    pub fn chr_tile(&self, addr: u16) -> [u8; 16] {
+        let mapper = self.mapper.borrow();
+        let mut tile: [u8; 16] = [0; 16];
+        for (i, byte) in tile.iter_mut().enumerate() { *byte = mapper.read_chr(addr + i as u16); }
+        tile
+    }
     fn vram_addr_increment(&self) -> u8 { if !self.get_flag(ControlFlags::VramAddIncrement) { 1 } else { 32 } }
-    fn increment_vram_addr(&mut self) { self.addr.increment(self.vram_addr_increment()); }
+    // After a $2007 access. While the PPU is rendering, v is its fetch address, and the access
+    // bumps it the way rendering does (coarse X and Y at once) instead: a glitch some games rely on.
+    fn increment_vram_addr(&mut self) {
+        if self.is_rendering() {
+            self.loopy.increment_x();
+            self.loopy.increment_y();
+        } else {
+            self.loopy.increment(self.vram_addr_increment());
+        }
+    }
+    pub fn rendering_enabled(&self) -> bool { self.get_mask(MaskFlags::ShowBackground) || self.get_mask(MaskFlags::ShowSprites) }
+    // Rendering enabled on a line the PPU fetches for: the visible ones and the pre-render line
+    pub fn is_rendering(&self) -> bool { self.rendering_enabled() && (self.scanline < 240 || self.scanline == self.pre_render_line()) }
+    // 261 on NTSC, 311 on PAL
+    pub fn pre_render_line(&self) -> u16 { self.region.scanlines() - 1 }
+    // The OAM row (8 bytes) holding `from` copied over the one holding `to`
+    fn copy_oam_row(&mut self, from: u8, to: u8) {
+        let (from, to) = ((from & 0xF8) as usize, (to & 0xF8) as usize);
+        self.oam_data.copy_within(from..from + 8, to);
+    }
     fn mirror_vram_addr(&self, addr: u16) -> u16 {
         let mirrored_vram: u16 = addr & 0b10111111111111; // mirror down 0x3000-0x3eff to 0x2000 - 0x2eff
         let vram_index: u16 = mirrored_vram - 0x2000; // to vram vector
         let name_table: u16 = vram_index / 0x400; // to the name table index
-        match (&self.mirroring, name_table) {
+        match (self.mirroring(), name_table) {
             (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
             (Mirroring::HORIZONTAL, 2) => vram_index - 0x400,
             (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
             (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
+            (Mirroring::ONESCREENLOWER, _) => vram_index & 0x3FF,
+            (Mirroring::ONESCREENUPPER, _) => 0x400 | (vram_index & 0x3FF),
             _ => vram_index,
         }
     }
@@ -97,105 +162,180 @@ impl NesPPU {
     }
     */
     pub fn tick(&mut self, cycles: u8) -> bool {
+        if self.accuracy == PpuAccuracy::Dot { return self.tick_dots(cycles); }
         self.cycles += cycles as usize;
         if self.cycles >= 341 {
             if self.is_sprite_0_hit(self.cycles) { self.set_status(StatusFlags::SpriteZeroHit, true); }
+            if self.scanline < 240 && !self.skip_render {
+                let y: usize = self.scanline as usize;
+                let mut line: [u16; 256] = [0; 256];
+                render::render_scanline(self, y, &self.loopy, &mut line);
+                self.picture[y * 256..(y + 1) * 256].copy_from_slice(&line);
+            }
+            // What the fetch pipeline does to v over a line: down one row, back to the left edge
+            // (and, on the pre-render line, the top) set up in t
+            if self.is_rendering() {
+                if self.scanline == self.pre_render_line() { self.loopy.copy_y(); } else { self.loopy.increment_y(); }
+                self.loopy.copy_x();
+                if self.oam_quirks { self.oam_addr = 0; }
+            }
 
             self.cycles = self.cycles - 341;
-            self.scanline += 1;
+            return self.end_scanline();
+        }
+        return false;
+    }
+    // Moves on to the next scanline, raising vblank and its NMI at the start of line 241 (291 on Dendy) and dropping
+    // the flags again at the start of the pre-render line; true when the frame wrapped around
+    fn end_scanline(&mut self) -> bool {
+        self.scanline += 1;
+
+        if self.scanline == self.region.vblank_line() {
+            self.set_status(StatusFlags::VBlankStarted, true);
+            if self.get_flag(ControlFlags::GenerateNMI) { self.nmi_interrupt = Some(1); }
+        }
 
-            if self.scanline == 241 {
-                self.set_status(StatusFlags::VBlankStarted, true);
-                // self.set_status(StatusFlags::SpriteZeroHit, false);
-                if self.get_flag(ControlFlags::GenerateNMI) { self.nmi_interrupt = Some(1); }
-            }
+        if self.scanline == self.pre_render_line() {
+            self.set_status(StatusFlags::SpriteZeroHit, false);
+            self.set_status(StatusFlags::SpriteOverflow, false);
+            self.set_status(StatusFlags::VBlankStarted, false);
+            // Rendering starts with sprite evaluation reading OAM from OAMADDR's row
+            if self.oam_quirks && self.rendering_enabled() && self.oam_addr >= 8 { self.copy_oam_row(self.oam_addr, 0); }
+        }
 
-            if self.scanline >= 262 {
-                self.scanline = 0;
-                self.nmi_interrupt = None;
-                self.set_status(StatusFlags::SpriteZeroHit, false);
-                self.set_status(StatusFlags::VBlankStarted, false);
-                return true;
-            }
+        if self.scanline >= self.region.scanlines() {
+            self.scanline = 0;
+            self.nmi_interrupt = None;
+            self.decay_open_bus();
+            return true;
+        }
+        false
+    }
+    // Any CPU write to $2000-$3FFF, read-only $2002 included
+    pub fn write_open_bus(&mut self, value: u8) { self.refresh_open_bus(value, 0xFF); }
+    // Reads of the write-only registers
+    pub fn read_open_bus(&self) -> u8 { self.open_bus }
+    // Drives the `bits` of the latch to `value`, restarting their decay
+    fn refresh_open_bus(&mut self, value: u8, bits: u8) {
+        self.open_bus = (self.open_bus & !bits) | (value & bits);
+        for (bit, frames) in self.open_bus_decay.iter_mut().enumerate() {
+            if bits & (1 << bit) != 0 { *frames = OPEN_BUS_DECAY_FRAMES; }
+        }
+    }
+    fn decay_open_bus(&mut self) {
+        for (bit, frames) in self.open_bus_decay.iter_mut().enumerate() {
+            *frames = frames.saturating_sub(1);
+            if *frames == 0 { self.open_bus &= !(1 << bit); }
         }
-        return false;
     }
+    // The PPU address space as $2007 sees it, minus the read buffer and address increment: for memory viewers
+    pub fn peek_vram(&self, addr: u16) -> u8 {
+        let addr: u16 = addr & 0x3FFF;
+        match addr {
+            0..=0x1fff => self.read_chr(addr),
+            0x2000..=0x3eff => self.vram[self.mirror_vram_addr(addr) as usize],
+            _ => self.palette_table[palette_index(addr)],
+        }
+    }
+    pub fn poke_vram(&mut self, addr: u16, value: u8) {
+        let addr: u16 = addr & 0x3FFF;
+        match addr {
+            0..=0x1fff => self.mapper.borrow_mut().write_chr(addr, value),
+            0x2000..=0x3eff => { self.vram[self.mirror_vram_addr(addr) as usize] = value; },
+            _ => { self.palette_table[palette_index(addr)] = value; },
+        }
+    }
+    // Within the first dots of the vblank line, where the vblank flag and NMI race with $2002 reads and NMI disables
+    fn vblank_just_started(&self) -> bool { self.scanline == self.region.vblank_line() && self.cycles < 3 }
     pub fn poll_nmi_interrupt(&mut self) -> Option<u8> { self.nmi_interrupt.take() }
     fn is_sprite_0_hit(&self, cycle: usize) -> bool {
         let y = self.oam_data[0] as usize;
         let x = self.oam_data[3] as usize;
-        (y == self.scanline as usize) && x <= cycle && self.get_mask(MaskFlags::ShowSprites)
+        // Nothing hits in the leftmost 8 pixels while either layer is clipped there; a sprite
+        // straddling the edge can still hit from pixel 8 on
+        let clipped: bool = !self.get_mask(MaskFlags::Leftmost8PixelBackground) || !self.get_mask(MaskFlags::Leftmost8PixelSprite);
+        let first_x: usize = if clipped { x.max(8) } else { x };
+        if first_x > x + 7 { return false; }
+        (y == self.scanline as usize) && first_x <= cycle && self.get_mask(MaskFlags::ShowSprites) && self.get_mask(MaskFlags::ShowBackground)
     }
 }
+// Palette RAM repeats every 32 bytes through $3F00-$3FFF, and the backdrop entries of the sprite
+// palettes ($3F10/$3F14/$3F18/$3F1C) are the ones of the background palettes
+pub(crate) fn palette_index(addr: u16) -> usize {
+    let index: usize = addr as usize & 0x1F;
+    if index & 0x13 == 0x10 { index & 0x0F } else { index }
+}
 impl PPU for NesPPU {
     fn write_to_ctrl(&mut self, value: u8) {
         let before_nmi: bool = self.get_flag(ControlFlags::GenerateNMI);
         self.ctrl = value;
+        // Turning NMIs off right as vblank starts still catches the one being raised
+        if before_nmi && !self.get_flag(ControlFlags::GenerateNMI) && self.vblank_just_started() { self.nmi_interrupt = None; }
+        self.loopy.write_ctrl(value);
         if !before_nmi && self.get_flag(ControlFlags::GenerateNMI) && self.get_status(StatusFlags::VBlankStarted) { self.nmi_interrupt = Some(1); }
     }
     fn write_to_mask(&mut self, value: u8) { self.mask = value; }
     fn read_status(&mut self) -> u8 {
-        let result: u8 = self.status;
+        // Only the top three bits are status; the rest is whatever the bus last held
+        let mut result: u8 = (self.status & 0xE0) | (self.open_bus & 0x1F);
+        // Reading just as vblank starts cancels that frame's NMI; one dot early, the flag reads as clear too
+        if self.vblank_just_started() {
+            self.nmi_interrupt = None;
+            if self.cycles == 0 { result &= !(StatusFlags::VBlankStarted as u8); }
+        }
         self.set_status(StatusFlags::VBlankStarted, false);
-        self.addr.reset_latch();
-        self.scroll.reset_latch();
+        self.loopy.reset_latch();
+        self.refresh_open_bus(result, 0xE0);
         result
     }
-    fn write_to_oam_addr(&mut self, value: u8) { self.oam_addr = value; }
+    fn write_to_oam_addr(&mut self, value: u8) {
+        if self.oam_quirks && self.is_rendering() { self.copy_oam_row(self.oam_addr, value); }
+        self.oam_addr = value;
+    }
     fn write_to_oam_data(&mut self, value: u8) {
         self.oam_data[self.oam_addr as usize] = value;
         self.oam_addr = self.oam_addr.wrapping_add(1);
     }
-    fn read_oam_data(&self) -> u8 { self.oam_data[self.oam_addr as usize] }
-    fn write_to_scroll(&mut self, value: u8) { self.scroll.write(value); }
-    fn write_to_ppu_addr(&mut self, value: u8) { self.addr.update(value); }
+    fn read_oam_data(&mut self) -> u8 {
+        let value: u8 = self.oam_data[self.oam_addr as usize];
+        self.refresh_open_bus(value, 0xFF);
+        value
+    }
+    fn write_to_scroll(&mut self, value: u8) { self.loopy.write_scroll(value); }
+    fn write_to_ppu_addr(&mut self, value: u8) { self.loopy.write_addr(value); }
     fn write_to_data(&mut self, value: u8) {
-        let addr: u16 = self.addr.get();
+        let addr: u16 = self.loopy.v & 0x3FFF;
         match addr {
-            0..=0x1fff => println!("attempt to write to chr rom space {:X}", addr), 
-            0x2000..=0x2fff => { self.vram[self.mirror_vram_addr(addr) as usize] = value; },
-
-            //0x3000..=0x3eff => panic!("addr {:X} shouldn't be used in reality", addr),
-            0x3000..=0x3eff => { self.vram[self.mirror_vram_addr(addr) as usize] = value; },
-
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = value;
-            },
-            0x3f00..=0x3fff => { self.palette_table[(addr - 0x3f00) as usize] = value; },
-            _ => panic!("unexpected access to mirrored space {:X}", addr),
+            0..=0x1fff => self.mapper.borrow_mut().write_chr(addr, value),
+            0x2000..=0x3eff => { self.vram[self.mirror_vram_addr(addr) as usize] = value; },
+            _ => { self.palette_table[palette_index(addr)] = value; },
         }
         self.increment_vram_addr();
     }
     fn read_data(&mut self) -> u8 {
-        let addr: u16 = self.addr.get();
+        let addr: u16 = self.loopy.v & 0x3FFF;
         self.increment_vram_addr();
         match addr {
             0..=0x1fff => {
                 let result: u8 = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.internal_data_buf = self.read_chr(addr);
+                self.refresh_open_bus(result, 0xFF);
                 result
             }
-            0x2000..=0x2fff => {
+            0x2000..=0x3eff => {
                 let result: u8 = self.internal_data_buf;
                 self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                self.refresh_open_bus(result, 0xFF);
                 result
             }
-
-            //0x3000..=0x3eff => panic!("Addr space 0x3000..=0x3eff is not supposed to be used, requested = {:X}", addr),
-            0x3000..=0x3eff => {
-                let result: u8 = self.internal_data_buf;
-                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+            // Palette reads skip the buffer, which picks up the nametable byte "under" the palette instead.
+            // Palette entries are 6 bits wide, so the top two come from the open bus.
+            _ => {
+                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr - 0x1000) as usize];
+                let result: u8 = (self.palette_table[palette_index(addr)] & 0x3F) | (self.open_bus & 0xC0);
+                self.refresh_open_bus(result, 0x3F);
                 result
             }
-
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let addr_mirror: u16 = addr - 0x10;
-                self.palette_table[(addr_mirror - 0x3f00) as usize]
-            }
-            0x3f00..=0x3fff => { self.palette_table[(addr - 0x3f00) as usize] } // ! need to check this
-            _ => panic!("Unexpected access to mirrored space: {:X}", addr)
         }
     }
     fn write_oam_dma(&mut self, data: &[u8; 256]) {
@@ -226,10 +366,10 @@ impl FlagArithmetic for NesPPU {
     fn master_slave_select(&self) -> u8 { if !self.get_flag(ControlFlags::SpriteSize) { 0 } else { 1 } }
 }
 impl MaskArithmetic for NesPPU {
-    fn get_mask(&self, flag: MaskFlags) -> bool { (self.ctrl & (flag as u8)) > 0 }
+    fn get_mask(&self, flag: MaskFlags) -> bool { (self.mask & (flag as u8)) > 0 }
     fn set_mask(&mut self, flag: MaskFlags, value: bool) {
-        if value { self.ctrl |= flag as u8; }
-        else { self.ctrl &= !(flag as u8); }
+        if value { self.mask |= flag as u8; }
+        else { self.mask &= !(flag as u8); }
     }
 }
 impl StatusArithmetic for NesPPU {
@@ -264,7 +404,7 @@ pub mod test {
         ppu.write_to_ppu_addr(0x05);
 
         ppu.read_data(); //load_into_buffer
-        assert_eq!(ppu.addr.get(), 0x2306);
+        assert_eq!(ppu.loopy.v, 0x2306);
         assert_eq!(ppu.read_data(), 0x66);
     }
 
@@ -359,6 +499,23 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x77); //read from B
     }
 
+    #[test]
+    fn test_vram_mirroring_follows_mapper() {
+        let rom: Rom = Rom { prg_rom: vec![0; 0x8000], chr_rom: vec![], mapper: 7, screen_mirroring: Mirroring::VERTICAL, trainer: None, battery: false, region: Region::NTSC };
+        let mut ppu = NesPPU::with_mapper(mapper::from_rom(rom).unwrap());
+
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66); // single screen: every nametable hits the lower page
+        assert_eq!(ppu.vram[0x0005], 0x66);
+
+        ppu.mapper.borrow_mut().write_prg(0x8000, 0b1_0000);
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x77); // ..until the mapper switches to the upper page
+        assert_eq!(ppu.vram[0x0405], 0x77);
+    }
+
     #[test]
     fn test_read_status_resets_latch() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -436,4 +593,256 @@ pub mod test {
         ppu.write_to_oam_addr(0x11);
         ppu.write_to_oam_addr(0x66);
     }
+
+    #[test]
+    fn test_sprite_zero_hit_skips_clipped_left_column() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.oam_data[0] = 0;
+        ppu.oam_data[3] = 0;
+        ppu.write_to_mask(MaskFlags::ShowSprites as u8 | MaskFlags::ShowBackground as u8);
+        assert!(!ppu.is_sprite_0_hit(340));
+        ppu.oam_data[3] = 4;
+        assert!(ppu.is_sprite_0_hit(340));
+        assert!(!ppu.is_sprite_0_hit(7));
+        ppu.write_to_mask(0b0001_1110);
+        assert!(ppu.is_sprite_0_hit(4));
+    }
+
+    #[test]
+    fn test_set_mask_leaves_ctrl_alone() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.set_mask(MaskFlags::ShowSprites, true);
+        assert_eq!((ppu.mask, ppu.ctrl), (MaskFlags::ShowSprites as u8, 0));
+        ppu.set_mask(MaskFlags::ShowSprites, false);
+        assert_eq!(ppu.mask, 0);
+    }
+
+    #[test]
+    fn test_scroll_and_addr_share_the_write_toggle() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.vram[0x0305] = 0x66;
+        // After one $2005 write, the next $2006 write is taken as the low byte
+        ppu.write_to_scroll(0x00);
+        ppu.write_to_ppu_addr(0x23);
+        assert_eq!(ppu.loopy.v, 0x0023);
+        ppu.read_status();
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    fn test_palette_ram_mirrors_every_32_bytes() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x3F);
+        ppu.write_to_ppu_addr(0xF0); // $3FF0 -> $3F10 -> $3F00
+        ppu.write_to_data(0x2A);
+        assert_eq!(ppu.palette_table[0x00], 0x2A);
+        ppu.palette_table[0x05] = 0x16;
+        ppu.vram[0x0725] = 0x77; // $2F25, under $3F25
+        ppu.write_to_ppu_addr(0x3F);
+        ppu.write_to_ppu_addr(0x25);
+        assert_eq!(ppu.read_data(), 0x16);
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.read_data(), 0x77);
+    }
+
+    #[test]
+    fn test_data_access_while_rendering_bumps_coarse_x_and_y() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_mask(0b0000_1000);
+        ppu.scanline = 10;
+        ppu.read_data();
+        assert_eq!(ppu.loopy.v, 0x2000 | 0x1000 | 1);
+    }
+
+    #[test]
+    fn test_status_read_races_vblank() {
+        // Stops the PPU `dots` into line 241 with NMIs on, then reads $2002
+        let read_at = |dots: u8| -> (u8, Option<u8>) {
+            let mut ppu = NesPPU::new_empty_rom();
+            ppu.write_to_ctrl(0x80);
+            (ppu.scanline, ppu.cycles) = (240, 340);
+            ppu.tick(1 + dots);
+            let status: u8 = ppu.read_status();
+            (status & 0x80, ppu.nmi_interrupt)
+        };
+        assert_eq!(read_at(0), (0x00, None));
+        assert_eq!(read_at(1), (0x80, None));
+        assert_eq!(read_at(3), (0x80, Some(1)));
+    }
+
+    #[test]
+    fn test_nmi_enable_during_vblank() {
+        let mut ppu = NesPPU::new_empty_rom();
+        (ppu.scanline, ppu.cycles) = (240, 340);
+        ppu.tick(10);
+        assert_eq!(ppu.poll_nmi_interrupt(), None);
+        // Every off-to-on edge while the flag is up raises another NMI
+        ppu.write_to_ctrl(0x80);
+        assert_eq!(ppu.poll_nmi_interrupt(), Some(1));
+        ppu.write_to_ctrl(0x00);
+        ppu.write_to_ctrl(0x80);
+        assert_eq!(ppu.poll_nmi_interrupt(), Some(1));
+        // Turning NMIs off on the dot vblank starts suppresses it
+        (ppu.scanline, ppu.cycles) = (240, 340);
+        ppu.tick(2);
+        ppu.write_to_ctrl(0x00);
+        assert_eq!(ppu.poll_nmi_interrupt(), None);
+        // The flag drops as the pre-render line starts
+        (ppu.scanline, ppu.cycles) = (260, 340);
+        ppu.tick(1);
+        assert!(!ppu.is_in_vblank());
+    }
+
+    #[test]
+    fn test_open_bus_bits_decay_unless_refreshed() {
+        let mut ppu = NesPPU::new_empty_rom();
+        let next_frame = |ppu: &mut NesPPU| { (ppu.scanline, ppu.cycles) = (261, 340); ppu.tick(1); };
+        ppu.write_open_bus(0xFF);
+        ppu.palette_table[0] = 0x2A;
+        ppu.write_to_ppu_addr(0x3F);
+        ppu.write_to_ppu_addr(0x00);
+        // Palette reads fill in their top two bits from the bus
+        assert_eq!(ppu.read_data(), 0xEA);
+        for _ in 0..10 { next_frame(&mut ppu); }
+        // A status read drives bits 5-7 only, so they outlast the rest
+        ppu.status = 0x80;
+        assert_eq!(ppu.read_status(), 0x8A);
+        for _ in 10..OPEN_BUS_DECAY_FRAMES - 1 { next_frame(&mut ppu); }
+        assert_eq!(ppu.read_open_bus(), 0x8A);
+        next_frame(&mut ppu);
+        assert_eq!(ppu.read_open_bus(), 0x80);
+        for _ in 0..10 { next_frame(&mut ppu); }
+        assert_eq!(ppu.read_open_bus(), 0x00);
+    }
+
+    #[test]
+    fn test_oam_addr_quirks() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.oam_quirks = true;
+        for (i, byte) in ppu.oam_data.iter_mut().enumerate() { *byte = i as u8; }
+        ppu.write_to_mask(0b0001_1000);
+        // Rendering starts with OAMADDR at 0x13: its row, 0x10-0x17, lands on the first sprites
+        (ppu.scanline, ppu.cycles) = (260, 340);
+        ppu.write_to_oam_addr(0x13);
+        ppu.tick(1);
+        assert_eq!(ppu.oam_data[0..8], [0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17]);
+        // ...and sprite fetches at the end of each line reset it
+        ppu.tick(200);
+        ppu.tick(141);
+        assert_eq!(ppu.oam_addr, 0);
+        // A $2003 write while rendering copies the old address's row over the new one's
+        ppu.write_to_oam_addr(0x21);
+        assert_eq!(ppu.oam_data[0x20..0x28], [0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17]);
+        assert_eq!(ppu.oam_addr, 0x21);
+    }
+
+    #[test]
+    fn test_version_1_state_loads() {
+        let mut w: StateWriter = StateWriter::new();
+        ([0x0Fu8; 32], [0x24u8; 2048]).save_state(&mut w);
+        (0x10u8, [0u8; 256]).save_state(&mut w);
+        // $2006 set to $2345, nametable 1, scroll (8, 16)
+        ((0x23u8, 0x45u8), true).save_state(&mut w);
+        (0x01u8, (0x18u8, 0x80u8)).save_state(&mut w);
+        ((8u8, 16u8), false).save_state(&mut w);
+        (100u16, 5usize).save_state(&mut w);
+        (0x66u8, None::<u8>).save_state(&mut w);
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.load_state(&mut StateReader::with_version(&w.data, 1)).unwrap();
+        assert_eq!((ppu.vram[0], ppu.oam_addr, ppu.ctrl, ppu.mask, ppu.status), (0x24, 0x10, 0x01, 0x18, 0x80));
+        assert_eq!((ppu.scanline, ppu.cycles, ppu.internal_data_buf), (100, 5, 0x66));
+        assert_eq!(ppu.loopy, LoopyRegisters { v: 0x2345, t: 0x0441, x: 0, w: false });
+    }
+
+    #[test]
+    fn test_dendy_holds_vblank_back_to_line_291() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.region = Region::Dendy;
+        ppu.write_to_ctrl(0x80);
+        (ppu.scanline, ppu.cycles) = (240, 340);
+        ppu.tick(1);
+        assert!(!ppu.is_in_vblank());
+        (ppu.scanline, ppu.cycles) = (290, 340);
+        ppu.tick(1);
+        assert_eq!((ppu.is_in_vblank(), ppu.poll_nmi_interrupt()), (true, Some(1)));
+        // ...and still ends the frame after 312 lines
+        (ppu.scanline, ppu.cycles) = (311, 340);
+        assert!(ppu.tick(1));
+    }
+}
+
+impl Savestate for NesPPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.palette_table.save_state(w);
+        self.vram.save_state(w);
+        self.oam_addr.save_state(w);
+        self.oam_data.save_state(w);
+        self.ctrl.save_state(w);
+        self.mask.save_state(w);
+        self.status.save_state(w);
+        self.scanline.save_state(w);
+        self.cycles.save_state(w);
+        self.loopy.save_state(w);
+        self.dot_pipeline.save_state(w);
+        self.internal_data_buf.save_state(w);
+        self.open_bus.save_state(w);
+        self.open_bus_decay.save_state(w);
+        self.nmi_interrupt.save_state(w);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        if r.version() < 2 { return self.load_version_1(r); }
+        self.palette_table.load_state(r)?;
+        self.vram.load_state(r)?;
+        self.oam_addr.load_state(r)?;
+        self.oam_data.load_state(r)?;
+        self.ctrl.load_state(r)?;
+        self.mask.load_state(r)?;
+        self.status.load_state(r)?;
+        self.scanline.load_state(r)?;
+        self.cycles.load_state(r)?;
+        self.loopy.load_state(r)?;
+        self.dot_pipeline.load_state(r)?;
+        self.internal_data_buf.load_state(r)?;
+        self.open_bus.load_state(r)?;
+        self.open_bus_decay.load_state(r)?;
+        self.nmi_interrupt.load_state(r)
+    }
+}
+
+impl NesPPU {
+    // Version 1 kept the $2006 address and the $2005 scroll in registers of their own, each with its
+    // own write toggle, and had no dot pipeline or open-bus latch: both start out empty
+    fn load_version_1(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let (mut addr, mut addr_high_next): ((u8, u8), bool) = ((0, 0), true);
+        let (mut scroll, mut scroll_second): ((u8, u8), bool) = ((0, 0), false);
+        self.palette_table.load_state(r)?;
+        self.vram.load_state(r)?;
+        self.oam_addr.load_state(r)?;
+        self.oam_data.load_state(r)?;
+        addr.load_state(r)?;
+        addr_high_next.load_state(r)?;
+        self.ctrl.load_state(r)?;
+        self.mask.load_state(r)?;
+        self.status.load_state(r)?;
+        scroll.load_state(r)?;
+        scroll_second.load_state(r)?;
+        self.scanline.load_state(r)?;
+        self.cycles.load_state(r)?;
+        self.internal_data_buf.load_state(r)?;
+        self.nmi_interrupt.load_state(r)?;
+        self.loopy = LoopyRegisters::new();
+        self.loopy.write_ctrl(self.ctrl);
+        self.loopy.set_scroll(scroll.0, scroll.1);
+        self.loopy.v = u16::from_be_bytes([addr.0, addr.1]);
+        self.loopy.w = scroll_second || !addr_high_next;
+        self.dot_pipeline = DotPipeline::default();
+        (self.open_bus, self.open_bus_decay) = (0, [0; 8]);
+        Ok(())
+    }
 }
@@ -0,0 +1,97 @@
+// The PPU's internal scroll/address registers, named after loopy's write-up on nesdev:
+// `v` is the VRAM address being rendered or accessed, `t` the one being set up for the next
+// frame or line, `x` the fine horizontal scroll and `w` the first/second write toggle that
+// $2005 and $2006 share. Both addresses are laid out as yyy NN YYYYY XXXXX (fine Y, nametable,
+// coarse Y, coarse X).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LoopyRegisters {
+    pub v: u16,
+    pub t: u16,
+    pub x: u8,
+    pub w: bool,
+}
+
+impl LoopyRegisters {
+    pub fn new() -> Self { LoopyRegisters::default() }
+    // $2000: the nametable select bits
+    pub fn write_ctrl(&mut self, value: u8) { self.t = (self.t & 0xF3FF) | ((value as u16 & 0b11) << 10); }
+    // $2002 read
+    pub fn reset_latch(&mut self) { self.w = false; }
+    // $2005: X scroll, then Y scroll
+    pub fn write_scroll(&mut self, value: u8) {
+        if !self.w {
+            self.t = (self.t & 0xFFE0) | (value as u16 >> 3);
+            self.x = value & 0b111;
+        } else {
+            self.t = (self.t & 0x8C1F) | ((value as u16 & 0b111) << 12) | ((value as u16 & 0xF8) << 2);
+        }
+        self.w = !self.w;
+    }
+    // Both $2005 writes at once, leaving the toggle alone
+    pub fn set_scroll(&mut self, x: u8, y: u8) {
+        let w: bool = self.w;
+        self.w = false;
+        self.write_scroll(x);
+        self.write_scroll(y);
+        self.w = w;
+    }
+    // $2006: high byte (bit 14 cleared), then low byte, which also copies t to v
+    pub fn write_addr(&mut self, value: u8) {
+        if !self.w {
+            self.t = (self.t & 0x80FF) | ((value as u16 & 0x3F) << 8);
+        } else {
+            self.t = (self.t & 0xFF00) | value as u16;
+            self.v = self.t;
+        }
+        self.w = !self.w;
+    }
+    // After each $2007 access
+    pub fn increment(&mut self, amount: u8) { self.v = self.v.wrapping_add(amount as u16) & 0x7FFF; }
+    pub fn coarse_x(&self) -> u16 { self.v & 0x1F }
+    pub fn coarse_y(&self) -> u16 { (self.v >> 5) & 0x1F }
+    pub fn fine_y(&self) -> u16 { (self.v >> 12) & 0b111 }
+    // Next tile to the right, into the horizontally neighbouring nametable after column 31
+    pub fn increment_x(&mut self) {
+        if self.coarse_x() == 31 { self.v = (self.v & !0x1F) ^ 0x0400; }
+        else { self.v += 1; }
+    }
+    // Next pixel row down; row 29 wraps into the vertically neighbouring nametable, while rows
+    // 30 and 31 (attribute data, reached through out-of-range scroll values) wrap in place
+    pub fn increment_y(&mut self) {
+        if self.fine_y() < 7 { self.v += 0x1000; return; }
+        self.v &= !0x7000;
+        let coarse_y: u16 = match self.coarse_y() {
+            29 => { self.v ^= 0x0800; 0 }
+            31 => 0,
+            y => y + 1,
+        };
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+    // t -> v for the horizontal bits, at the end of each line's visible part
+    pub fn copy_x(&mut self) { self.v = (self.v & !0x041F) | (self.t & 0x041F); }
+    // t -> v for the vertical bits, during the pre-render line
+    pub fn copy_y(&mut self) { self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0); }
+}
+
+savestate_fields!(LoopyRegisters { v, t, x, w });
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scroll_and_addr_writes_share_t() {
+        let mut loopy: LoopyRegisters = LoopyRegisters::new();
+        loopy.write_ctrl(0b10);
+        loopy.write_scroll(0x7D);
+        loopy.write_scroll(0x5E);
+        assert_eq!((loopy.t, loopy.x, loopy.w), (0b110_10_01011_01111, 0b101, false));
+        // The mid-frame $2006/$2005/$2005/$2006 split-scroll sequence
+        loopy.write_addr(0x04);
+        loopy.write_scroll(0x3E);
+        loopy.write_scroll(0x7D);
+        loopy.write_addr(0xEF);
+        assert_eq!(loopy.v, 0b110_01_00111_01111);
+        assert_eq!(loopy.x, 0b101);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::prelude::*;
 pub enum MaskFlags {
     Grayscale = (1 << 0),
     Leftmost8PixelBackground = (1 << 1),
@@ -1,5 +1,4 @@
 pub mod mask;
 pub mod control;
-pub mod addr;
 pub mod status;
-pub mod scroll;
\ No newline at end of file
+pub mod loopy;
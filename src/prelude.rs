@@ -0,0 +1,9 @@
+// alloc-backed replacements for the std prelude, so core modules build the same with and without `std`
+pub use alloc::boxed::Box;
+pub use alloc::collections::BTreeMap;
+pub use alloc::format;
+pub use alloc::rc::Rc;
+pub use alloc::string::{String, ToString};
+pub use alloc::vec;
+pub use alloc::vec::Vec;
+pub use core::cell::RefCell;
@@ -0,0 +1,113 @@
+use crate::prelude::*;
+use crate::callstack::CallStack;
+use crate::cpu::CPU;
+use crate::disasm::{self, Label};
+
+const BANK_SIZE: usize = 0x4000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RoutineStats {
+    pub calls: u64,
+    // Cycles spent in the routine's own instructions
+    pub self_cycles: u64,
+    // Including the subroutines and interrupt handlers it was running when they happened
+    pub total_cycles: u64,
+}
+
+// Counts CPU cycles per routine, keyed by JSR target (or handler address for interrupts; code outside
+// any call counts for the RESET handler), and per 16KB PRG ROM bank, by running the CPU itself.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    pub routines: BTreeMap<u16, RoutineStats>,
+    // None for code running from RAM
+    pub banks: BTreeMap<Option<usize>, u64>,
+    pub cycles: u64,
+    calls: CallStack,
+    reset: u16,
+    labels: Vec<Label>,
+}
+
+impl Profiler {
+    pub fn new(cpu: &CPU) -> Self {
+        let read = |addr: u16| cpu.bus.peek(addr);
+        let reset: u16 = u16::from_le_bytes([read(0xFFFC), read(0xFFFD)]);
+        Profiler { routines: BTreeMap::new(), banks: BTreeMap::new(), cycles: 0, calls: CallStack::new(), reset, labels: disasm::vector_labels(&read) }
+    }
+    // Like CPU::run_frame, counting every instruction
+    pub fn run_frame(&mut self, cpu: &mut CPU) {
+        let frame: u64 = cpu.bus.frames;
+        while cpu.bus.frames == frame { self.step(cpu); }
+    }
+    pub fn step(&mut self, cpu: &mut CPU) {
+        let cycles: usize = cpu.bus.cycles;
+        let (pc, stack_pointer): (u16, u8) = (cpu.program_counter, cpu.stack_pointer);
+        if cpu.service_interrupts() {
+            self.calls.interrupted(cpu, pc, stack_pointer);
+            self.routines.entry(cpu.program_counter).or_default().calls += 1;
+        }
+        // The whole step counts for the routine the instruction belongs to, interrupt entry included
+        let routine: u16 = self.calls.calls.last().map_or(self.reset, |call| call.target);
+        let (pc, stack_pointer, opcode): (u16, u8, u8) = (cpu.program_counter, cpu.stack_pointer, cpu.bus.peek(cpu.program_counter));
+        let bank: Option<usize> = cpu.bus.mapper().borrow().prg_rom_offset(pc).map(|offset| offset / BANK_SIZE);
+        cpu.execute();
+        let cycles: u64 = cpu.bus.cycles.saturating_sub(cycles) as u64;
+        self.cycles += cycles;
+        *self.banks.entry(bank).or_default() += cycles;
+        self.routines.entry(routine).or_default().self_cycles += cycles;
+        // Once per routine, however deep it recursed
+        let mut running: Vec<u16> = self.calls.calls.iter().map(|call| call.target).collect();
+        running.push(self.reset);
+        running.sort_unstable();
+        running.dedup();
+        for addr in running { self.routines.entry(addr).or_default().total_cycles += cycles; }
+        if self.calls.executed(cpu, pc, opcode, stack_pointer) { self.routines.entry(cpu.program_counter).or_default().calls += 1; }
+    }
+    // Routines by self cycles, hottest first, then the banks
+    pub fn report(&self) -> String {
+        let percent = |cycles: u64| if self.cycles == 0 { 0.0 } else { cycles as f64 * 100.0 / self.cycles as f64 };
+        let mut routines: Vec<(&u16, &RoutineStats)> = self.routines.iter().collect();
+        routines.sort_by(|a, b| b.1.self_cycles.cmp(&a.1.self_cycles).then(a.0.cmp(b.0)));
+        let mut lines: Vec<String> = vec![format!("{} cycles", self.cycles), format!("{:<12} {:>8} {:>12} {:>6} {:>12} {:>6}", "routine", "calls", "self", "%", "total", "%")];
+        for (addr, stats) in routines {
+            let name: String = match self.labels.iter().find(|(label, _)| label == addr) {
+                Some((_, label)) => format!("${:04X} {}", addr, label),
+                None => format!("${:04X}", addr),
+            };
+            lines.push(format!("{:<12} {:>8} {:>12} {:>5.1}% {:>12} {:>5.1}%", name, stats.calls, stats.self_cycles, percent(stats.self_cycles), stats.total_cycles, percent(stats.total_cycles)));
+        }
+        lines.push(format!("{:<12} {:>12} {:>6}", "bank", "cycles", "%"));
+        let mut banks: Vec<(&Option<usize>, &u64)> = self.banks.iter().collect();
+        banks.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (bank, cycles) in banks {
+            let name: String = bank.map_or(String::from("RAM"), |bank| format!("{}", bank));
+            lines.push(format!("{:<12} {:>12} {:>5.1}%", name, cycles, percent(*cycles)));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::{test, Rom};
+
+    #[test]
+    fn test_cycles_per_routine_and_bank() {
+        // RESET: JSR $8006; JMP $8000  $8006: NOP; RTS
+        let rom: Rom = test::program_rom(&[0x20, 0x06, 0x80, 0x4C, 0x00, 0x80, 0xEA, 0x60]);
+        let mut cpu: CPU<'static> = CPU::new(Bus::new(rom, |_, _, _| {}));
+        cpu.reset();
+        let mut profiler: Profiler = Profiler::new(&cpu);
+        for _ in 0..40 { profiler.step(&mut cpu); }
+        // Ten rounds of JSR (6) + JMP (3) in RESET, and NOP (2) + RTS (6) in $8006
+        let reset: RoutineStats = profiler.routines[&0x8000];
+        let routine: RoutineStats = profiler.routines[&0x8006];
+        assert_eq!((reset.self_cycles, reset.total_cycles, profiler.cycles), (90, 170, 170));
+        assert_eq!((routine.calls, routine.self_cycles, routine.total_cycles), (10, 80, 80));
+        assert_eq!(profiler.banks[&Some(0)], 170);
+        let report: String = profiler.report();
+        assert!(report.contains("$8000 RESET"));
+        assert!(report.find("$8000 RESET").unwrap() < report.find("$8006").unwrap());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::prelude::*;
+
+// How a byte must relate to its value in the previous snapshot, or to a fixed value, to stay a candidate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Greater,
+    Less,
+    // Exactly this much higher (or lower, when negative) than before, wrapping like the CPU does
+    ChangedBy(i16),
+    EqualTo(u8),
+    NotEqualTo(u8),
+    GreaterThan(u8),
+    LessThan(u8),
+}
+
+impl Comparison {
+    // `=`, `!=`, `>` or `<` against the previous snapshot, the same with a value to compare against
+    // it instead, or `+` / `-` with the amount it changed by
+    pub fn parse(op: &str, value: Option<u8>) -> Result<Comparison, String> {
+        match (op, value) {
+            ("=", None) => Ok(Comparison::Equal),
+            ("!=", None) => Ok(Comparison::NotEqual),
+            (">", None) => Ok(Comparison::Greater),
+            ("<", None) => Ok(Comparison::Less),
+            ("+", Some(n)) => Ok(Comparison::ChangedBy(n as i16)),
+            ("-", Some(n)) => Ok(Comparison::ChangedBy(-(n as i16))),
+            ("=", Some(n)) => Ok(Comparison::EqualTo(n)),
+            ("!=", Some(n)) => Ok(Comparison::NotEqualTo(n)),
+            (">", Some(n)) => Ok(Comparison::GreaterThan(n)),
+            ("<", Some(n)) => Ok(Comparison::LessThan(n)),
+            _ => Err(format!("Unknown comparison {:?}: expected =, !=, > or < with an optional value, or + / - with one", op)),
+        }
+    }
+    fn holds(self, value: u8, previous: u8) -> bool {
+        match self {
+            Comparison::Equal => value == previous,
+            Comparison::NotEqual => value != previous,
+            Comparison::Greater => value > previous,
+            Comparison::Less => value < previous,
+            Comparison::ChangedBy(delta) => value == previous.wrapping_add(delta as u8),
+            Comparison::EqualTo(n) => value == n,
+            Comparison::NotEqualTo(n) => value != n,
+            Comparison::GreaterThan(n) => value > n,
+            Comparison::LessThan(n) => value < n,
+        }
+    }
+}
+
+// Iterative search of the 2KB work RAM for the address of some game variable: start with every
+// address, then after each change in the game keep only those whose byte changed the same way
+// (lives went down by one, the timer is less than before...) until a handful is left.
+#[derive(Debug, Clone)]
+pub struct RamSearch {
+    candidates: Vec<u16>,
+    previous: [u8; 2048],
+}
+
+impl RamSearch {
+    pub fn new(ram: &[u8; 2048]) -> Self { RamSearch { candidates: (0..2048).collect(), previous: *ram } }
+    // Drops the candidates failing `comparison` against the last snapshot, then takes a new one
+    pub fn filter(&mut self, ram: &[u8; 2048], comparison: Comparison) -> usize {
+        let previous: [u8; 2048] = self.previous;
+        self.candidates.retain(|addr| comparison.holds(ram[*addr as usize], previous[*addr as usize]));
+        self.previous = *ram;
+        self.candidates.len()
+    }
+    pub fn candidates(&self) -> &[u16] { &self.candidates }
+    // Each candidate's address with its value in the last snapshot
+    pub fn results(&self) -> Vec<(u16, u8)> { self.candidates.iter().map(|addr| (*addr, self.previous[*addr as usize])).collect() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_narrowing_down_a_lives_counter() {
+        let mut ram: [u8; 2048] = [0; 2048];
+        ram[0x75] = 3;
+        ram[0x300] = 7;
+        let mut search: RamSearch = RamSearch::new(&ram);
+        ram[0x75] = 2;
+        ram[0x300] = 6;
+        ram[0x400] = 0xFF;
+        assert_eq!(search.filter(&ram, Comparison::parse("<", None).unwrap()), 2);
+        ram[0x300] = 5;
+        assert_eq!(search.filter(&ram, Comparison::Equal), 1);
+        assert_eq!(search.results(), vec![(0x75, 2)]);
+        ram[0x75] = 1;
+        assert_eq!(search.filter(&ram, Comparison::parse("-", Some(1)).unwrap()), 1);
+        assert_eq!(search.filter(&ram, Comparison::EqualTo(2)), 0);
+        assert!(Comparison::parse("+", None).is_err());
+    }
+}
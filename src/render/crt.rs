@@ -0,0 +1,96 @@
+use crate::prelude::*;
+use super::frame::{Frame, Image};
+
+// Post-processing that imitates a CRT: darkened gaps between scanlines, barrel curvature and a
+// vignette. Renders into a larger picture, since scanlines need more than one output row per NES line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CrtSettings {
+    // How much the last output row of each NES line is darkened, 0.0 (none) to 1.0 (black)
+    pub scanlines: f32,
+    // Barrel distortion strength; 0.0 keeps the picture flat
+    pub curvature: f32,
+    // Corner darkening, 0.0 (none) to 1.0
+    pub vignette: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrtPreset {
+    Off,
+    Scanlines,
+    Crt,
+}
+
+impl CrtPreset {
+    pub const ALL: [CrtPreset; 3] = [CrtPreset::Off, CrtPreset::Scanlines, CrtPreset::Crt];
+    pub fn parse(name: &str) -> Result<CrtPreset, String> {
+        CrtPreset::ALL.into_iter().find(|preset| preset.name() == name.trim().to_lowercase())
+            .ok_or_else(|| format!("Unknown CRT preset {:?}: expected off, scanlines or crt", name))
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            CrtPreset::Off => "off",
+            CrtPreset::Scanlines => "scanlines",
+            CrtPreset::Crt => "crt",
+        }
+    }
+    // The following preset, wrapping around, for a cycling hotkey
+    pub fn next(self) -> CrtPreset {
+        let index: usize = CrtPreset::ALL.iter().position(|preset| *preset == self).unwrap_or(0);
+        CrtPreset::ALL[(index + 1) % CrtPreset::ALL.len()]
+    }
+    pub fn settings(self) -> Option<CrtSettings> {
+        match self {
+            CrtPreset::Off => None,
+            CrtPreset::Scanlines => Some(CrtSettings { scanlines: 0.5, curvature: 0.0, vignette: 0.0 }),
+            CrtPreset::Crt => Some(CrtSettings { scanlines: 0.4, curvature: 0.08, vignette: 0.35 }),
+        }
+    }
+}
+
+// `source` (the Frame, or an upscaler's output) stretched to `width` x `height` with the CRT effects applied
+pub fn apply(source: &Image, width: usize, height: usize, settings: &CrtSettings) -> Image {
+    let mut image: Image = Image::new(width, height);
+    let rows_per_line: usize = height / Frame::HIGHT;
+    for y in 0..height {
+        // Normalized to -1.0..1.0 from the centre, sampled at pixel centres
+        let v: f32 = (y as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+        let scanline: f32 = if rows_per_line > 1 && y % rows_per_line == rows_per_line - 1 { 1.0 - settings.scanlines } else { 1.0 };
+        for x in 0..width {
+            let u: f32 = (x as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+            let (cu, cv) = (u * (1.0 + settings.curvature * v * v), v * (1.0 + settings.curvature * u * u));
+            if !(-1.0..1.0).contains(&cu) || !(-1.0..1.0).contains(&cv) { continue; }
+            let source_x: usize = (((cu + 1.0) / 2.0 * source.width as f32) as usize).min(source.width - 1);
+            let source_y: usize = (((cv + 1.0) / 2.0 * source.height as f32) as usize).min(source.height - 1);
+            let vignette: f32 = 1.0 - settings.vignette * (u * u + v * v) / 2.0;
+            let brightness: f32 = (scanline * vignette).clamp(0.0, 1.0);
+            image.set_pixel(x, y, source.pixel(source_x, source_y).map(|channel| (channel as f32 * brightness) as u8));
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scanlines_darken_every_third_row() {
+        let mut frame: Frame = Frame::new();
+        frame.data.fill(200);
+        let image: Image = apply(&Image::from_frame(&frame), 768, 720, &CrtPreset::Scanlines.settings().unwrap());
+        assert_eq!((image.width, image.height), (768, 720));
+        assert_eq!(image.pixel(10, 0), [200, 200, 200]);
+        assert_eq!(image.pixel(10, 2), [100, 100, 100]);
+        assert_eq!(CrtPreset::parse("CRT"), Ok(CrtPreset::Crt));
+        assert_eq!(CrtPreset::Crt.next(), CrtPreset::Off);
+    }
+
+    #[test]
+    fn test_curvature_blanks_the_corners() {
+        let mut frame: Frame = Frame::new();
+        frame.data.fill(200);
+        let image: Image = apply(&Image::from_frame(&frame), 512, 480, &CrtPreset::Crt.settings().unwrap());
+        assert_eq!(image.pixel(0, 0), [0, 0, 0]);
+        assert_ne!(image.pixel(256, 240), [0, 0, 0]);
+    }
+}
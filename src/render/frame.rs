@@ -1,8 +1,10 @@
+use crate::prelude::*;
+#[derive(Clone)]
 pub struct Frame { pub data: Vec<u8> }
 
 impl Frame {
-    const WIDTH: usize = 256;
-    const HIGHT: usize = 240;
+    pub const WIDTH: usize = 256;
+    pub const HIGHT: usize = 240;
     pub fn new() -> Self { Frame { data: vec![0; (Frame::WIDTH) * (Frame::HIGHT) * 3] } }
     pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
         let base: usize = y * 3 * Frame::WIDTH + x * 3;
@@ -12,4 +14,27 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
+    // Binary PPM (P6): trivially readable by image tools, no encoder needed
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm: Vec<u8> = format!("P6\n{} {}\n255\n", Frame::WIDTH, Frame::HIGHT).into_bytes();
+        ppm.extend(&self.data);
+        ppm
+    }
+}
+
+// An RGB picture of any size, produced from a Frame by post-processing (scaling, CRT effects)
+#[derive(Clone)]
+pub struct Image { pub width: usize, pub height: usize, pub data: Vec<u8> }
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Self { Image { width, height, data: vec![0; width * height * 3] } }
+    pub fn from_frame(frame: &Frame) -> Self { Image { width: Frame::WIDTH, height: Frame::HIGHT, data: frame.data.clone() } }
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let base: usize = (y * self.width + x) * 3;
+        [self.data[base], self.data[base + 1], self.data[base + 2]]
+    }
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: [u8; 3]) {
+        let base: usize = (y * self.width + x) * 3;
+        self.data[base..base + 3].copy_from_slice(&rgb);
+    }
 }
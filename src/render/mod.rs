@@ -1,8 +1,15 @@
+pub mod crt;
 pub mod frame;
+pub mod osd;
 pub mod palette;
+#[cfg(feature = "std")]
+pub mod png;
+pub mod scale;
 
-use crate::{ppu::{NesPPU, registers::control::FlagArithmetic}, cartridge::Mirroring};
+use crate::prelude::*;
+use crate::{ppu::{NesPPU, palette_index, registers::{control::FlagArithmetic, loopy::LoopyRegisters, mask::{MaskArithmetic, MaskFlags}}}, cartridge::Mirroring};
 use frame::Frame;
+use palette::Palette;
 
 
 fn bg_pallette(ppu: &NesPPU, attribute_table: &[u8], tile_column: usize, tile_row: usize) -> [u8; 4] {
@@ -36,136 +43,228 @@ fn sprite_palette(ppu: &NesPPU, pallete_idx: u8) -> [u8; 4] {
     ]
 }
 
-struct Rect {
-    x1: usize,
-    y1: usize,
-    x2: usize,
-    y2: usize,
-}
-impl Rect { fn new(x1: usize, y1: usize, x2: usize, y2: usize) -> Self { Rect { x1, y1, x2, y2 } } }
-
-fn render_name_table(ppu: &NesPPU, frame: &mut Frame, name_table: &[u8], 
-    view_port: Rect, shift_x: isize, shift_y: isize) {
-    let bank = ppu.bknd_pattern_addr();
-
-    let attribute_table = &name_table[0x3c0.. 0x400];
-
-    for i in 0..0x3c0 {
-        let tile_column = i % 32;
-        let tile_row = i / 32;
-        let tile_idx = name_table[i] as u16;
-        let tile = &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
-        let palette = bg_pallette(ppu, attribute_table, tile_column, tile_row);
-
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-
-            for x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[ppu.palette_table[0] as usize],
-                    1 => palette::SYSTEM_PALLETE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[palette[3] as usize],
-                    _ => panic!("can't be"),
-                };
-                let pixel_x: usize = tile_column * 8 + x;
-                let pixel_y: usize = tile_row * 8 + y;
-
-                if pixel_x >= view_port.x1 && pixel_x < view_port.x2 && pixel_y >= view_port.y1 && pixel_y < view_port.y2 {
-                    frame.set_pixel((shift_x + pixel_x as isize) as usize, (shift_y + pixel_y as isize) as usize, rgb);
-                }
-            }
-        }
-    }
+// Colour index of a palette RAM entry under the current PPUMASK: grayscale keeps only the
+// brightness column ($x0), and the emphasis bits (stored above the 6-bit index) select the tinted variant
+pub(crate) fn color(ppu: &NesPPU, index: u8) -> u16 {
+    let index: u8 = if ppu.is_grayscale() { index & 0x30 } else { index };
+    index as u16 | ((ppu.mask >> 5) as u16) << 6
 }
 
-pub fn render(ppu: &NesPPU, frame: &mut Frame) {
-    let scroll_x: usize = (ppu.scroll.scroll_x) as usize;
-    let scroll_y: usize = (ppu.scroll.scroll_y) as usize;
-    // println!("Scroll: ({}, {})", scroll_x, scroll_y);
+// The 1KB nametable the PPU sees at $2000 + 0x400 * `index`, after the cartridge's mirroring
+fn name_table(ppu: &NesPPU, index: usize) -> &[u8] {
+    let upper: bool = match ppu.mirroring() {
+        Mirroring::VERTICAL => index & 1 == 1,
+        Mirroring::HORIZONTAL => index & 2 == 2,
+        Mirroring::ONESCREENLOWER => false,
+        Mirroring::ONESCREENUPPER => true,
+        mirroring => panic!("Not supported mirroring type {:?}", mirroring),
+    };
+    if upper { &ppu.vram[0x400..0x800] } else { &ppu.vram[0..0x400] }
+}
 
-    let (main_nametable, second_nametable) = match (&ppu.mirroring, ppu.nametable_addr()) {
-        (Mirroring::VERTICAL, 0x2000) | (Mirroring::VERTICAL, 0x2800) | (Mirroring::HORIZONTAL, 0x2000) | (Mirroring::HORIZONTAL, 0x2400) => {
-            (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800])
-        }
-        (Mirroring::VERTICAL, 0x2400) | (Mirroring::VERTICAL, 0x2C00) | (Mirroring::HORIZONTAL, 0x2800) | (Mirroring::HORIZONTAL, 0x2C00) => {
-            ( &ppu.vram[0x400..0x800], &ppu.vram[0..0x400])
+// Draws screen line `y` into `line` as colour indices (see `color`), from the PPU state right now,
+// with the background starting at `scroll.v` and fine X. NesPPU::tick calls this as each visible
+// scanline ends, so scroll, PPUCTRL/PPUMASK, palette RAM and mapper bank changes made mid-frame
+// (split status bars, raster colour effects) land on the lines after them. Colours are looked up
+// here rather than when the frame is shown for the same reason.
+pub fn render_scanline(ppu: &NesPPU, y: usize, scroll: &LoopyRegisters, line: &mut [u16]) {
+    let scroll_x: usize = scroll.coarse_x() as usize * 8 + scroll.x as usize + (scroll.v as usize >> 10 & 1) * 256;
+    // Coarse Y 30 and 31 are out of range, and show the attribute bytes as tiles like the real PPU
+    let (tile_row, row) = (scroll.coarse_y() as usize, scroll.fine_y() as usize);
+    let vertical_table: usize = scroll.v as usize >> 11 & 1;
+    let bank: u16 = ppu.bknd_pattern_addr();
+    // Forced blank with v in palette RAM shows that entry, as in the dot pipeline (see ppu::dot)
+    let forced_blank_colour: bool = !ppu.rendering_enabled() && scroll.v & 0x3F00 == 0x3F00;
+    let backdrop: u16 = color(ppu, ppu.palette_table[if forced_blank_colour { palette_index(scroll.v) } else { 0 }]);
+    let show_background: bool = ppu.get_mask(MaskFlags::ShowBackground);
+    let show_left_background: bool = ppu.get_mask(MaskFlags::Leftmost8PixelBackground);
+    let mut bg_opaque: [bool; 256] = [false; 256];
+    for x in 0..256 {
+        // A layer switched off in PPUMASK (for this line only, when toggled mid-frame) leaves the backdrop
+        if !show_background {
+            line[x] = backdrop;
+            continue;
         }
-        (_,_) => {
-            panic!("Not supported mirroring type {:?}", ppu.mirroring);
+        let world_x: usize = (scroll_x + x) % 512;
+        let name_table: &[u8] = name_table(ppu, world_x / 256 + vertical_table * 2);
+        let tile_column: usize = world_x % 256 / 8;
+        let tile_idx: u16 = name_table[tile_row * 32 + tile_column] as u16;
+        let upper: u8 = ppu.read_chr(bank + tile_idx * 16 + row as u16);
+        let lower: u8 = ppu.read_chr(bank + tile_idx * 16 + row as u16 + 8);
+        let shift: usize = 7 - world_x % 8;
+        let value: u8 = (lower >> shift & 1) << 1 | (upper >> shift & 1);
+        // PPUMASK can hide either layer in the leftmost 8 pixels; the background shows the backdrop there
+        if value == 0 || (x < 8 && !show_left_background) {
+            line[x] = backdrop;
+            continue;
         }
-    };
-
-    render_name_table(ppu, frame, 
-        main_nametable, 
-        Rect::new(scroll_x, scroll_y, 256, 240 ),
-        -(scroll_x as isize), -(scroll_y as isize)
-    );
-    if scroll_x > 0 {
-        render_name_table(ppu, frame, 
-            second_nametable, 
-            Rect::new(0, 0, scroll_x, 240),
-            (256 - scroll_x) as isize, 0
-        );
-    } else if scroll_y > 0 {
-        render_name_table(ppu, frame, 
-            second_nametable, 
-            Rect::new(0, 0, 256, scroll_y),
-            0, (240 - scroll_y) as isize
-        );
+        let palette: [u8; 4] = bg_pallette(ppu, &name_table[0x3c0..0x400], tile_column, tile_row);
+        line[x] = color(ppu, palette[value as usize]);
+        bg_opaque[x] = true;
     }
 
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
+    // Sprites are drawn front (OAM 0) to back and the first opaque sprite pixel claims its spot. Only
+    // then is the priority bit checked, so a behind-background sprite also hides later sprites under
+    // opaque background: the hardware quirk games use to tuck sprites behind tiles.
+    if !ppu.get_mask(MaskFlags::ShowSprites) { return; }
+    let show_left_sprites: bool = ppu.get_mask(MaskFlags::Leftmost8PixelSprite);
+    let mut sprite_claimed: [bool; 256] = [false; 256];
+    for i in (0..ppu.oam_data.len()).step_by(4) {
+        let tile_y = ppu.oam_data[i] as usize;
+        if y < tile_y || y > tile_y + 7 { continue; }
         let tile_idx = ppu.oam_data[i + 1] as u16;
         let tile_x = ppu.oam_data[i + 3] as usize;
-        let tile_y = ppu.oam_data[i] as usize;
 
-        let flip_vertical: bool = if ppu.oam_data[i + 2] >> 7 & 1 == 1 { true } else { false };
-        let flip_horizontal: bool = if ppu.oam_data[i + 2] >> 6 & 1 == 1 { true } else { false };
-        let pallette_idx: u8 = ppu.oam_data[i + 2] & 0b11;
-        let sprite_palette: [u8; 4] = sprite_palette(ppu, pallette_idx);
+        let flip_vertical: bool = ppu.oam_data[i + 2] >> 7 & 1 == 1;
+        let flip_horizontal: bool = ppu.oam_data[i + 2] >> 6 & 1 == 1;
+        let behind_background: bool = ppu.oam_data[i + 2] >> 5 & 1 == 1;
+        let sprite_palette: [u8; 4] = sprite_palette(ppu, ppu.oam_data[i + 2] & 0b11);
         let bank: u16 = ppu.sprt_pattern_addr();
 
-        let tile: &[u8] =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
-
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-            'ololo: for x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => continue 'ololo, // skip coloring the pixel
-                    1 => palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[sprite_palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[sprite_palette[3] as usize],
-                    _ => panic!("can't be"),
-                };
-                match (flip_horizontal, flip_vertical) {
-                    (false, false) => {
-                        frame.set_pixel(tile_x + x , tile_y + y, rgb);
-                        // frame.set_pixel(tile_x + x, tile_y + y +250, rgb);
-                    },
-                    (true, false) => {
-                        frame.set_pixel(tile_x + 7 - x , tile_y + y , rgb);
-                        // frame.set_pixel(tile_x + 7 - x , tile_y + y + 250, rgb);
-                    }
-                    (false, true) => {
-                        frame.set_pixel(tile_x + x  , tile_y + 7 - y, rgb);
-                        // frame.set_pixel(tile_x + x, tile_y + 7 - y + 250, rgb);
-                    }
-                    (true, true) => {
-                        frame.set_pixel(tile_x + 7 - x , tile_y + 7 - y , rgb);
-                        // frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y+250, rgb);
-                    }
-                }
-            }
+        let row: u16 = if flip_vertical { 7 - (y - tile_y) } else { y - tile_y } as u16;
+        let upper: u8 = ppu.read_chr(bank + tile_idx * 16 + row);
+        let lower: u8 = ppu.read_chr(bank + tile_idx * 16 + row + 8);
+        for x in 0..=7 {
+            let value: u8 = (lower >> (7 - x) & 1) << 1 | (upper >> (7 - x) & 1);
+            if value == 0 { continue; }
+            let pixel_x: usize = if flip_horizontal { tile_x + 7 - x } else { tile_x + x };
+            if (pixel_x < 8 && !show_left_sprites) || pixel_x >= 256 || sprite_claimed[pixel_x] { continue; }
+            sprite_claimed[pixel_x] = true;
+            if behind_background && bg_opaque[pixel_x] { continue; }
+            line[pixel_x] = color(ppu, sprite_palette[value as usize]);
         }
     }
 }
+
+// Colours a picture of `render_scanline` indices into `frame`
+pub fn draw_picture(picture: &[u16], frame: &mut Frame, colors: &Palette) {
+    for (i, index) in picture.iter().enumerate() {
+        frame.set_pixel(i % Frame::WIDTH, i / Frame::WIDTH, colors.color((index & 0x3F) as u8, (index >> 6) as u8));
+    }
+}
+
+pub fn render(ppu: &NesPPU, frame: &mut Frame) { render_with_palette(ppu, frame, &Palette::default()) }
+
+// The whole picture from the PPU's current state, as if nothing changed during the frame (scrolled
+// by t, which the next frame starts from): for tools and tests. Emulation itself draws scanline by
+// scanline into NesPPU::picture as it runs.
+pub fn render_with_palette(ppu: &NesPPU, frame: &mut Frame, colors: &Palette) {
+    let mut picture: Vec<u16> = vec![0; Frame::WIDTH * Frame::HIGHT];
+    let mut scroll: LoopyRegisters = ppu.loopy;
+    scroll.v = scroll.t;
+    for (y, line) in picture.chunks_exact_mut(Frame::WIDTH).enumerate() {
+        render_scanline(ppu, y, &scroll, line);
+        scroll.increment_y();
+        scroll.copy_x();
+    }
+    draw_picture(&picture, frame, colors);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ppu::PPU;
+
+    #[test]
+    fn test_grayscale_mask_keeps_only_brightness() {
+        let mut ppu: NesPPU = NesPPU::new_empty_rom();
+        ppu.palette_table[0] = 0x16;
+        let mut frame: Frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.data[0..3], [0xFF, 0x22, 0x00]);
+        ppu.mask = MaskFlags::Grayscale as u8;
+        render(&ppu, &mut frame);
+        let gray = palette::SYSTEM_PALLETE[0x10];
+        assert_eq!(frame.data[0..3], [gray.0, gray.1, gray.2]);
+    }
+
+    #[test]
+    fn test_left_column_masking() {
+        // Tile 0 is solid colour 1: the whole background, and all 64 sprites stacked at (0, 0)
+        let mut chr: Vec<u8> = vec![0; 2048];
+        chr[0..8].fill(0xFF);
+        let mut ppu: NesPPU = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x01;
+        ppu.palette_table[0x11] = 0x16;
+        let mut frame: Frame = Frame::new();
+        let pixel = |frame: &Frame, x: usize| [frame.data[x * 3], frame.data[x * 3 + 1], frame.data[x * 3 + 2]];
+        let rgb = |index: usize| [palette::SYSTEM_PALLETE[index].0, palette::SYSTEM_PALLETE[index].1, palette::SYSTEM_PALLETE[index].2];
+        ppu.mask = 0b0001_1110;
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0), rgb(0x16));
+        ppu.mask = 0b0001_1010;
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0), rgb(0x01));
+        assert_eq!(pixel(&frame, 8), rgb(0x01));
+        ppu.mask = 0b0001_1000;
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0), rgb(0x0F));
+        assert_eq!(pixel(&frame, 8), rgb(0x01));
+    }
+
+    #[test]
+    fn test_behind_background_sprite_priority() {
+        // Tile 0 is solid colour 1 (the whole background), tile 1 is blank
+        let mut chr: Vec<u8> = vec![0; 2048];
+        chr[0..8].fill(0xFF);
+        let mut ppu: NesPPU = NesPPU::new(chr, Mirroring::HORIZONTAL);
+        ppu.palette_table[1] = 0x01;
+        ppu.palette_table[0x11] = 0x16;
+        ppu.mask = 0b0001_1110;
+        // Sprite 0 at (16, 16) behind the background, sprite 1 in front of it at the same spot
+        ppu.oam_data[0..8].copy_from_slice(&[16, 0, 0b0010_0000, 16, 16, 0, 0, 16]);
+        let mut frame: Frame = Frame::new();
+        let offset: usize = (16 * 256 + 16) * 3;
+        let rgb = |index: usize| [palette::SYSTEM_PALLETE[index].0, palette::SYSTEM_PALLETE[index].1, palette::SYSTEM_PALLETE[index].2];
+        render(&ppu, &mut frame);
+        // Sprite 0 wins the pixel and hides sprite 1 too, so the opaque background shows
+        assert_eq!(frame.data[offset..offset + 3], rgb(0x01));
+        ppu.vram[0..0x400].fill(1);
+        render(&ppu, &mut frame);
+        assert_eq!(frame.data[offset..offset + 3], rgb(0x16));
+    }
+
+    #[test]
+    fn test_scanlines_pick_up_mid_frame_changes() {
+        // Nametable 0 is solid colour 1, nametable 1 (vertical mirroring) is all blank tile 1
+        let mut chr: Vec<u8> = vec![0; 2048];
+        chr[0..8].fill(0xFF);
+        let mut ppu: NesPPU = NesPPU::new(chr, Mirroring::VERTICAL);
+        ppu.vram[0x400..0x800].fill(1);
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x01;
+        ppu.mask = 0b0000_1010;
+        let run_line = |ppu: &mut NesPPU| { ppu.tick(170); ppu.tick(171); };
+        for _ in 0..100 { run_line(&mut ppu); }
+        // Switching to nametable 1 mid-frame reaches v (and the picture) from the following line on
+        ppu.write_to_ctrl(0b01);
+        for _ in 100..240 { run_line(&mut ppu); }
+        assert_eq!(ppu.picture[100 * 256 + 50], 0x01);
+        assert_eq!(ppu.picture[101 * 256 + 50], 0x0F);
+    }
+
+    #[test]
+    fn test_rendering_toggled_mid_frame() {
+        // Tile rows 0-12 of nametable 0 are solid colour 1, the rest blank tile 1
+        let mut chr: Vec<u8> = vec![0; 2048];
+        chr[0..8].fill(0xFF);
+        let mut ppu: NesPPU = NesPPU::new(chr, Mirroring::VERTICAL);
+        ppu.vram[13 * 32..0x3C0].fill(1);
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x01;
+        ppu.write_to_mask(0b0000_1010);
+        let run_line = |ppu: &mut NesPPU| { ppu.tick(170); ppu.tick(171); };
+        for _ in 0..100 { run_line(&mut ppu); }
+        // Lines drawn with rendering off show the backdrop, and v stops moving down meanwhile
+        ppu.write_to_mask(0);
+        for _ in 100..150 { run_line(&mut ppu); }
+        ppu.write_to_mask(0b0000_1010);
+        for _ in 150..240 { run_line(&mut ppu); }
+        assert_eq!(ppu.picture[99 * 256 + 50], 0x01);
+        assert_eq!(ppu.picture[101 * 256 + 50], 0x0F);
+        // Picking up at line 100's tile row
+        assert_eq!(ppu.picture[153 * 256 + 50], 0x01);
+        assert_eq!(ppu.picture[154 * 256 + 50], 0x0F);
+    }
+}
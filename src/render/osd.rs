@@ -0,0 +1,128 @@
+use crate::prelude::*;
+use super::frame::Frame;
+
+// Frames a message stays up: two seconds at 60Hz
+pub const MESSAGE_FRAMES: u32 = 120;
+const MAX_MESSAGES: usize = 4;
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 3;
+
+struct Message {
+    text: String,
+    frames_left: u32,
+}
+
+// On-screen display: transient status lines ("Saved slot 3", "Paused") stacked in the bottom-left
+// corner, drawn over a copy of the emulated picture so the Frame itself stays untouched
+#[derive(Default)]
+pub struct Osd {
+    messages: Vec<Message>,
+}
+
+impl Osd {
+    pub fn new() -> Self { Osd::default() }
+    pub fn show(&mut self, text: &str) {
+        if self.messages.len() == MAX_MESSAGES { self.messages.remove(0); }
+        self.messages.push(Message { text: text.to_string(), frames_left: MESSAGE_FRAMES });
+    }
+    // Ages the messages by one presented frame, dropping the expired ones
+    pub fn tick(&mut self) {
+        for message in self.messages.iter_mut() { message.frames_left = message.frames_left.saturating_sub(1); }
+        self.messages.retain(|message| message.frames_left > 0);
+    }
+    pub fn is_empty(&self) -> bool { self.messages.is_empty() }
+    pub fn draw(&self, frame: &mut Frame) {
+        let top: usize = Frame::HIGHT - 8 - self.messages.len() * LINE_HEIGHT;
+        for (i, message) in self.messages.iter().enumerate() {
+            draw_text(frame, 8, top + i * LINE_HEIGHT, &message.text, (0xFF, 0xFF, 0xFF));
+        }
+    }
+}
+
+// 5x7 glyphs with a one pixel drop shadow, so text stays readable on any background.
+// Lowercase prints as uppercase; characters outside the font print as '?'.
+pub fn draw_text(frame: &mut Frame, x: usize, y: usize, text: &str, color: (u8, u8, u8)) {
+    for (i, c) in text.chars().enumerate() {
+        let left: usize = x + i * (GLYPH_WIDTH + 1);
+        if left + GLYPH_WIDTH >= Frame::WIDTH { break; }
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (0b10000 >> column) == 0 { continue; }
+                frame.set_pixel(left + column + 1, y + row + 1, (0, 0, 0));
+                frame.set_pixel(left + column, y + row, color);
+            }
+        }
+    }
+}
+
+// Rows top to bottom, bit 4 is the leftmost column
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        'A' => [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'B' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110],
+        'C' => [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110],
+        'D' => [0b11110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b11110],
+        'E' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111],
+        'F' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000],
+        'G' => [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111],
+        'H' => [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'I' => [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        'J' => [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100],
+        'K' => [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001],
+        'L' => [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111],
+        'M' => [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001],
+        'N' => [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001],
+        'O' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'P' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000],
+        'Q' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101],
+        'R' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001],
+        'S' => [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110],
+        'T' => [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100],
+        'U' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'V' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100],
+        'W' => [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010],
+        'X' => [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001],
+        'Y' => [0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100],
+        'Z' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111],
+        '0' => [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110],
+        '1' => [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        '2' => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111],
+        '3' => [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110],
+        '4' => [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010],
+        '5' => [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110],
+        '6' => [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110],
+        '7' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000],
+        '8' => [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110],
+        '9' => [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100],
+        ' ' => [0; GLYPH_HEIGHT],
+        '.' => [0, 0, 0, 0, 0, 0b01100, 0b01100],
+        ',' => [0, 0, 0, 0, 0b01100, 0b00100, 0b01000],
+        ':' => [0, 0b01100, 0b01100, 0, 0b01100, 0b01100, 0],
+        '!' => [0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0, 0b00100],
+        '%' => [0b11000, 0b11001, 0b00010, 0b00100, 0b01000, 0b10011, 0b00011],
+        '-' => [0, 0, 0, 0b11111, 0, 0, 0],
+        '+' => [0, 0b00100, 0b00100, 0b11111, 0b00100, 0b00100, 0],
+        '/' => [0, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0],
+        '(' => [0b00010, 0b00100, 0b01000, 0b01000, 0b01000, 0b00100, 0b00010],
+        ')' => [0b01000, 0b00100, 0b00010, 0b00010, 0b00010, 0b00100, 0b01000],
+        '\'' => [0b00100, 0b00100, 0b01000, 0, 0, 0, 0],
+        _ => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0, 0b00100],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_messages_expire_and_draw_over_frame() {
+        let mut osd: Osd = Osd::new();
+        osd.show("Saved slot 3");
+        let mut frame: Frame = Frame::new();
+        osd.draw(&mut frame);
+        assert!(frame.data.iter().any(|byte| *byte == 0xFF));
+        for _ in 0..MESSAGE_FRAMES { osd.tick(); }
+        assert!(osd.is_empty());
+    }
+}
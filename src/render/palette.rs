@@ -1,5 +1,6 @@
-#[rustfmt::skip]
+use crate::prelude::*;
 
+#[rustfmt::skip]
 pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
     (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E), 
     (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00), (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00), 
@@ -15,3 +16,64 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
     (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA), 
     (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
+
+// Emphasizing a colour channel through PPUMASK dims the other two by roughly this much
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+// RGB for each of the 64 NES colours under each of the 8 PPUMASK emphasis combinations
+#[derive(Clone)]
+pub struct Palette { colors: Vec<(u8, u8, u8)> }
+
+impl Default for Palette {
+    fn default() -> Self { Palette::from_base(&SYSTEM_PALLETE) }
+}
+
+impl Palette {
+    // A .pal file: 64 RGB triplets (emphasis is then approximated), or 512 with the emphasis variants
+    // stored one 64-colour block after another in PPUMASK bit order
+    pub fn from_pal(data: &[u8]) -> Result<Palette, String> {
+        let colors: Vec<(u8, u8, u8)> = data.chunks_exact(3).map(|rgb| (rgb[0], rgb[1], rgb[2])).collect();
+        match data.len() {
+            192 => Ok(Palette::from_base(&colors)),
+            1536 => Ok(Palette { colors }),
+            len => Err(format!("A .pal file has 192 or 1536 bytes (64 or 512 colours), got {}", len)),
+        }
+    }
+    fn from_base(base: &[(u8, u8, u8)]) -> Palette {
+        let mut colors: Vec<(u8, u8, u8)> = Vec::with_capacity(512);
+        for emphasis in 0..8u8 {
+            let dim = |channel: u8, own_bit: u8| -> u8 {
+                let others: u32 = (emphasis & !own_bit).count_ones();
+                let mut value: f32 = channel as f32;
+                for _ in 0..others { value *= EMPHASIS_ATTENUATION; }
+                value as u8
+            };
+            colors.extend(base.iter().map(|&(r, g, b)| (dim(r, 0b001), dim(g, 0b010), dim(b, 0b100))));
+        }
+        Palette { colors }
+    }
+    // `index` is a palette RAM entry, `emphasis` the top three PPUMASK bits (red, green, blue)
+    pub fn color(&self, index: u8, emphasis: u8) -> (u8, u8, u8) {
+        self.colors[(emphasis as usize & 0b111) * 64 + (index as usize & 0x3F)]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pal_files() {
+        let mut data: Vec<u8> = vec![0; 192];
+        data[3..6].copy_from_slice(&[100, 100, 100]);
+        let palette: Palette = Palette::from_pal(&data).unwrap();
+        assert_eq!(palette.color(1, 0), (100, 100, 100));
+        // Red emphasis keeps red and dims green and blue
+        assert_eq!(palette.color(1, 0b001), (100, 81, 81));
+        let mut data: Vec<u8> = vec![0; 1536];
+        data[64 * 3 * 7 + 3] = 42;
+        assert_eq!(Palette::from_pal(&data).unwrap().color(1 | 0x40, 0b111), (42, 0, 0));
+        assert!(Palette::from_pal(&[0; 100]).is_err());
+        assert_eq!(Palette::default().color(0x30, 0), SYSTEM_PALLETE[0x30]);
+    }
+}
@@ -0,0 +1,231 @@
+use std::io::{Read, Write};
+
+use crate::hash;
+use super::frame::Image;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+// 8 bit RGB, no interlacing, every row unfiltered: small enough for 256x240 screenshots and readable
+// by anything
+pub fn encode(image: &Image) -> Vec<u8> {
+    let mut header: Vec<u8> = Vec::new();
+    header.extend_from_slice(&(image.width as u32).to_be_bytes());
+    header.extend_from_slice(&(image.height as u32).to_be_bytes());
+    // Bit depth, colour type (RGB), compression, filter, interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    let mut png: Vec<u8> = SIGNATURE.to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"IDAT", &compress(image.data.chunks_exact(image.width * 3)));
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+// Longest run of identical frames merged into one, so its delay fits fcTL's 16 bits
+const MAX_RUN: u16 = 65;
+
+// An animated PNG (APNG) of `frames`, each `width` * `height` indices into `palette` (256 colours at
+// most), playing at `frame_rate` and looping. Kept small by showing runs of identical frames as one
+// and having each frame after the first only cover what changed since the one before it.
+pub fn encode_animation(width: usize, height: usize, palette: &[[u8; 3]], frames: &[&[u8]], frame_rate: f64) -> Vec<u8> {
+    // The first frame of each run, and how many frames the run lasts
+    let mut runs: Vec<(usize, u16)> = Vec::new();
+    for (i, frame) in frames.iter().enumerate() {
+        match runs.last_mut() {
+            Some((first, length)) if frames[*first] == *frame && *length < MAX_RUN => *length += 1,
+            _ => runs.push((i, 1)),
+        }
+    }
+    let mut header: Vec<u8> = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth, colour type (palette), compression, filter, interlace
+    header.extend_from_slice(&[8, 3, 0, 0, 0]);
+    let mut control: Vec<u8> = (runs.len() as u32).to_be_bytes().to_vec();
+    // Loops forever
+    control.extend_from_slice(&0u32.to_be_bytes());
+    let mut png: Vec<u8> = SIGNATURE.to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"acTL", &control);
+    chunk(&mut png, b"PLTE", &palette.iter().flatten().copied().collect::<Vec<u8>>());
+    // Delays are fractions of a second: frames over frames per second, in thousandths
+    let per_second: u16 = (frame_rate * 1000.0).round().min(u16::MAX as f64) as u16;
+    let mut sequence: u32 = 0;
+    for (n, (first, length)) in runs.iter().enumerate() {
+        let frame: &[u8] = frames[*first];
+        let (x, y, w, h): (usize, usize, usize, usize) = match n {
+            0 => (0, 0, width, height),
+            _ => changed(frames[runs[n - 1].0], frame, width, height),
+        };
+        let mut frame_control: Vec<u8> = sequence.to_be_bytes().to_vec();
+        for value in [w, h, x, y] { frame_control.extend_from_slice(&(value as u32).to_be_bytes()); }
+        frame_control.extend_from_slice(&(length * 1000).to_be_bytes());
+        frame_control.extend_from_slice(&per_second.to_be_bytes());
+        // Leave the frame in place for the next; replace the area it covers
+        frame_control.extend_from_slice(&[0, 0]);
+        chunk(&mut png, b"fcTL", &frame_control);
+        sequence += 1;
+        let data: Vec<u8> = compress((y..y + h).map(|row| &frame[row * width + x..row * width + x + w]));
+        if n == 0 {
+            chunk(&mut png, b"IDAT", &data);
+        } else {
+            let mut frame_data: Vec<u8> = sequence.to_be_bytes().to_vec();
+            frame_data.extend(data);
+            chunk(&mut png, b"fdAT", &frame_data);
+            sequence += 1;
+        }
+    }
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+// The rectangle (x, y, width, height) holding every pixel that differs between two frames; a
+// single pixel when none do
+fn changed(before: &[u8], after: &[u8], width: usize, height: usize) -> (usize, usize, usize, usize) {
+    let (mut left, mut top, mut right, mut bottom): (usize, usize, usize, usize) = (width, height, 0, 0);
+    for (i, _) in before.iter().zip(after).enumerate().filter(|(_, (a, b))| a != b) {
+        let (x, y): (usize, usize) = (i % width, i / width);
+        (left, top, right, bottom) = (left.min(x), top.min(y), right.max(x), bottom.max(y));
+    }
+    if left > right { return (0, 0, 1, 1); }
+    (left, top, right - left + 1, bottom - top + 1)
+}
+
+// Image data: each row unfiltered, then zlib compressed
+fn compress<'a>(rows: impl Iterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    for row in rows {
+        encoder.write_all(&[0]).expect("writing to a Vec can't fail");
+        encoder.write_all(row).expect("writing to a Vec can't fail");
+    }
+    encoder.finish().expect("writing to a Vec can't fail")
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start: usize = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc: u32 = hash::crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Reads back 8 bit RGB, RGBA (alpha dropped) and palette PNGs without interlacing, which covers what
+// image editors save reference screenshots as
+pub fn decode(data: &[u8]) -> Result<Image, String> {
+    if !data.starts_with(&SIGNATURE) { return Err(String::from("Not a PNG file")); }
+    let (mut header, mut palette, mut compressed): (Option<&[u8]>, &[u8], Vec<u8>) = (None, &[], Vec::new());
+    let mut pos: usize = SIGNATURE.len();
+    while pos + 8 <= data.len() {
+        let len: usize = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let kind: &[u8] = &data[pos + 4..pos + 8];
+        let body: &[u8] = data.get(pos + 8..pos + 8 + len).ok_or("Truncated PNG chunk")?;
+        match kind {
+            b"IHDR" => header = Some(body),
+            b"PLTE" => palette = body,
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {}
+        }
+        pos += len + 12;
+    }
+    let header: &[u8] = header.filter(|header| header.len() == 13).ok_or("PNG without a header")?;
+    let width: usize = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height: usize = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let channels: usize = match (header[8], header[9], header[12]) {
+        (8, 2, 0) => 3,
+        (8, 6, 0) => 4,
+        (8, 3, 0) => 1,
+        (depth, colour, interlace) => return Err(format!("Unsupported PNG: bit depth {}, colour type {}, interlace {}", depth, colour, interlace)),
+    };
+    let mut rows: Vec<u8> = Vec::new();
+    flate2::read::ZlibDecoder::new(compressed.as_slice()).read_to_end(&mut rows).map_err(|err| format!("Corrupt PNG data: {}", err))?;
+    let stride: usize = width * channels;
+    if rows.len() < (stride + 1) * height { return Err(String::from("Truncated PNG data")); }
+    let mut image: Image = Image::new(width, height);
+    let mut previous: Vec<u8> = vec![0; stride];
+    for y in 0..height {
+        let filter: u8 = rows[y * (stride + 1)];
+        let mut row: Vec<u8> = rows[y * (stride + 1) + 1..(y + 1) * (stride + 1)].to_vec();
+        unfilter(filter, &mut row, &previous, channels)?;
+        for x in 0..width {
+            let rgb: [u8; 3] = match channels {
+                1 => {
+                    let index: usize = row[x] as usize * 3;
+                    let entry: &[u8] = palette.get(index..index + 3).ok_or("PNG palette index out of range")?;
+                    [entry[0], entry[1], entry[2]]
+                }
+                _ => [row[x * channels], row[x * channels + 1], row[x * channels + 2]],
+            };
+            image.set_pixel(x, y, rgb);
+        }
+        previous = row;
+    }
+    Ok(image)
+}
+
+// Undoes the PNG filter of one row, given the row above it already unfiltered
+fn unfilter(filter: u8, row: &mut [u8], previous: &[u8], channels: usize) -> Result<(), String> {
+    for i in 0..row.len() {
+        let left: u8 = if i >= channels { row[i - channels] } else { 0 };
+        let up: u8 = previous[i];
+        let up_left: u8 = if i >= channels { previous[i - channels] } else { 0 };
+        let predicted: u8 = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((left as u16 + up as u16) / 2) as u8,
+            4 => paeth(left, up, up_left),
+            _ => return Err(format!("Unknown PNG filter {}", filter)),
+        };
+        row[i] = row[i].wrapping_add(predicted);
+    }
+    Ok(())
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p: i16 = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc): (i16, i16, i16) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_filters() {
+        let mut image: Image = Image::new(3, 2);
+        image.set_pixel(0, 0, [0xFF, 0, 0]);
+        image.set_pixel(2, 1, [1, 2, 3]);
+        let decoded: Image = decode(&encode(&image)).unwrap();
+        assert_eq!((decoded.width, decoded.height, decoded.data), (3, 2, image.data.clone()));
+        // Sub filtered: each byte stored as the difference to the one a pixel to its left
+        let mut row: Vec<u8> = vec![10, 20, 30, 5, 5, 5];
+        unfilter(1, &mut row, &[0; 6], 3).unwrap();
+        assert_eq!(row, vec![10, 20, 30, 15, 25, 35]);
+        let mut row: Vec<u8> = vec![1, 1, 1];
+        unfilter(4, &mut row, &[10, 20, 30], 1).unwrap();
+        assert_eq!(row, vec![11, 21, 31]);
+        assert!(decode(b"GIF89a").is_err());
+    }
+
+    #[test]
+    fn test_animation() {
+        let palette: [[u8; 3]; 2] = [[0, 0, 0], [0xFF, 0xFF, 0xFF]];
+        let (blank, dotted): (Vec<u8>, Vec<u8>) = (vec![0; 16], (0..16).map(|i| (i == 6) as u8).collect());
+        let apng: Vec<u8> = encode_animation(4, 4, &palette, &[&blank, &blank, &dotted], 60.0);
+        let chunk_at = |kind: &[u8; 4]| apng.windows(4).position(|window| window == kind).unwrap() + 4;
+        // Two frames, the blank one lasting two
+        assert_eq!(apng[chunk_at(b"acTL")..chunk_at(b"acTL") + 4], [0, 0, 0, 2]);
+        let first: usize = chunk_at(b"fcTL");
+        assert_eq!(apng[first + 20..first + 24], [0x07, 0xD0, 0xEA, 0x60]);
+        // The second covers just the changed pixel, at (2, 1)
+        let second: usize = first + apng[first..].windows(4).position(|window| window == b"fcTL").unwrap() + 4;
+        assert_eq!(apng[second..second + 20], [0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1]);
+        // Anything reading plain PNGs sees the first frame
+        let image: Image = decode(&apng).unwrap();
+        assert_eq!((image.width, image.height), (4, 4));
+        assert!(image.data.iter().all(|byte| *byte == 0));
+        assert_eq!(changed(&blank, &blank, 4, 4), (0, 0, 1, 1));
+    }
+}
@@ -0,0 +1,258 @@
+use crate::prelude::*;
+use super::frame::{Frame, Image};
+
+// CPU-side pixel-art upscalers. Scale2x/Scale3x (AdvMAME, the EPX family) round off diagonal
+// staircase edges while keeping flat areas and colours exactly as the PPU drew them; xBRZ
+// (Zenju's take on Hyllian's xBR) also blends the edges into smooth lines and curves.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Upscaler {
+    None,
+    Scale2x,
+    Scale3x,
+    Xbrz2x,
+    Xbrz3x,
+}
+
+impl Upscaler {
+    pub const ALL: [Upscaler; 5] = [Upscaler::None, Upscaler::Scale2x, Upscaler::Scale3x, Upscaler::Xbrz2x, Upscaler::Xbrz3x];
+    pub fn parse(name: &str) -> Result<Upscaler, String> {
+        Upscaler::ALL.into_iter().find(|upscaler| upscaler.name() == name.trim().to_lowercase())
+            .ok_or_else(|| format!("Unknown upscaler {:?}: expected none, scale2x, scale3x, xbrz2x or xbrz3x", name))
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Upscaler::None => "none",
+            Upscaler::Scale2x => "scale2x",
+            Upscaler::Scale3x => "scale3x",
+            Upscaler::Xbrz2x => "xbrz2x",
+            Upscaler::Xbrz3x => "xbrz3x",
+        }
+    }
+    pub fn factor(self) -> usize {
+        match self {
+            Upscaler::None => 1,
+            Upscaler::Scale2x | Upscaler::Xbrz2x => 2,
+            Upscaler::Scale3x | Upscaler::Xbrz3x => 3,
+        }
+    }
+    pub fn apply(self, frame: &Frame) -> Image {
+        let source: Image = Image::from_frame(frame);
+        match self {
+            Upscaler::None => source,
+            Upscaler::Scale2x => scale2x(&source),
+            Upscaler::Scale3x => scale3x(&source),
+            Upscaler::Xbrz2x => xbrz(&source, 2),
+            Upscaler::Xbrz3x => xbrz(&source, 3),
+        }
+    }
+}
+
+// The 3x3 neighbourhood around (x, y), row by row, clamped at the edges
+fn neighbours(image: &Image, x: usize, y: usize) -> [[u8; 3]; 9] {
+    let mut around: [[u8; 3]; 9] = [[0; 3]; 9];
+    for (i, pixel) in around.iter_mut().enumerate() {
+        let nx: usize = (x + i % 3).saturating_sub(1).min(image.width - 1);
+        let ny: usize = (y + i / 3).saturating_sub(1).min(image.height - 1);
+        *pixel = image.pixel(nx, ny);
+    }
+    around
+}
+
+fn scale2x(source: &Image) -> Image {
+    let mut image: Image = Image::new(source.width * 2, source.height * 2);
+    for y in 0..source.height {
+        for x in 0..source.width {
+            let [_, b, _, d, e, f, _, h, _] = neighbours(source, x, y);
+            let out: [[u8; 3]; 4] = if b != h && d != f {
+                [if d == b { d } else { e }, if b == f { f } else { e }, if d == h { d } else { e }, if h == f { f } else { e }]
+            } else {
+                [e; 4]
+            };
+            for (i, rgb) in out.into_iter().enumerate() { image.set_pixel(x * 2 + i % 2, y * 2 + i / 2, rgb); }
+        }
+    }
+    image
+}
+
+fn scale3x(source: &Image) -> Image {
+    let mut image: Image = Image::new(source.width * 3, source.height * 3);
+    for y in 0..source.height {
+        for x in 0..source.width {
+            let [a, b, c, d, e, f, g, h, i] = neighbours(source, x, y);
+            let out: [[u8; 3]; 9] = if b != h && d != f {
+                [
+                    if d == b { d } else { e },
+                    if (d == b && e != c) || (b == f && e != a) { b } else { e },
+                    if b == f { f } else { e },
+                    if (d == b && e != g) || (d == h && e != a) { d } else { e },
+                    e,
+                    if (b == f && e != i) || (h == f && e != c) { f } else { e },
+                    if d == h { d } else { e },
+                    if (d == h && e != i) || (h == f && e != g) { h } else { e },
+                    if h == f { f } else { e },
+                ]
+            } else {
+                [e; 9]
+            };
+            for (n, rgb) in out.into_iter().enumerate() { image.set_pixel(x * 3 + n % 3, y * 3 + n / 3, rgb); }
+        }
+    }
+    image
+}
+
+// xBRZ's tuning: colours closer than EQUAL_TOLERANCE count as equal, an edge is dominant when one
+// diagonal is DOMINANT_DIRECTION times more different than the other, and a line is shallow or steep
+// rather than diagonal when its two sides differ by STEEP_DIRECTION times
+const EQUAL_TOLERANCE: f64 = 30.0;
+const DOMINANT_DIRECTION: f64 = 3.6;
+const STEEP_DIRECTION: f64 = 2.2;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Blend { None, Normal, Dominant }
+
+// Perceptual distance between two colours, in YCbCr (BT.2020 weights)
+fn distance(first: [u8; 3], second: [u8; 3]) -> f64 {
+    if first == second { return 0.0; }
+    let [r, g, b]: [f64; 3] = [0, 1, 2].map(|i| first[i] as f64 - second[i] as f64);
+    let y: f64 = 0.2627 * r + 0.6780 * g + 0.0593 * b;
+    let cb: f64 = 0.5 / (1.0 - 0.0593) * (b - y);
+    let cr: f64 = 0.5 / (1.0 - 0.2627) * (r - y);
+    sqrt(y * y + cb * cb + cr * cr)
+}
+
+// Newton's method from a halved-exponent first guess (no libm in no_std builds)
+fn sqrt(x: f64) -> f64 {
+    if x <= 0.0 { return 0.0; }
+    let mut root: f64 = f64::from_bits((x.to_bits() >> 1) + (1023 << 51));
+    for _ in 0..4 { root = (root + x / root) / 2.0; }
+    root
+}
+
+// Which corners of each pixel to blend, clockwise from the top left, from the 4x4 kernel around
+// every 2x2 block: the corners away from the block's more alike diagonal get rounded off
+fn corner_blends(source: &Image) -> Vec<[Blend; 4]> {
+    let mut blends: Vec<[Blend; 4]> = vec![[Blend::None; 4]; source.width * source.height];
+    let pixel = |x: usize, y: usize, dx: isize, dy: isize| -> [u8; 3] {
+        let x: usize = (x as isize + dx).clamp(0, source.width as isize - 1) as usize;
+        let y: usize = (y as isize + dy).clamp(0, source.height as isize - 1) as usize;
+        source.pixel(x, y)
+    };
+    for y in 0..source.height - 1 {
+        for x in 0..source.width - 1 {
+            let [_, b, c, _, e, f, g, h, i, j, k, l, _, n, o, _]: [[u8; 3]; 16] =
+                core::array::from_fn(|n| pixel(x, y, n as isize % 4 - 1, n as isize / 4 - 1));
+            if (f == g && j == k) || (f == j && g == k) { continue; }
+            let jg: f64 = distance(i, f) + distance(f, c) + distance(n, k) + distance(k, h) + 4.0 * distance(j, g);
+            let fk: f64 = distance(e, j) + distance(j, o) + distance(b, g) + distance(g, l) + 4.0 * distance(f, k);
+            let width: usize = source.width;
+            if jg < fk {
+                let blend: Blend = if DOMINANT_DIRECTION * jg < fk { Blend::Dominant } else { Blend::Normal };
+                if f != g && f != j { blends[y * width + x][2] = blend; }
+                if k != j && k != g { blends[(y + 1) * width + x + 1][0] = blend; }
+            } else if fk < jg {
+                let blend: Blend = if DOMINANT_DIRECTION * fk < jg { Blend::Dominant } else { Blend::Normal };
+                if j != f && j != k { blends[(y + 1) * width + x][1] = blend; }
+                if g != f && g != k { blends[y * width + x + 1][3] = blend; }
+            }
+        }
+    }
+    blends
+}
+
+// Where (row, col) of a `size` x `size` block ends up once turned `turns` quarter turns clockwise back
+fn unrotate(size: usize, turns: usize, (mut row, mut col): (usize, usize)) -> (usize, usize) {
+    for _ in 0..turns { (row, col) = (size - 1 - col, row); }
+    (row, col)
+}
+
+fn xbrz(source: &Image, scale: usize) -> Image {
+    let blends: Vec<[Blend; 4]> = corner_blends(source);
+    let eq = |first: [u8; 3], second: [u8; 3]| distance(first, second) < EQUAL_TOLERANCE;
+    let mut image: Image = Image::new(source.width * scale, source.height * scale);
+    for y in 0..source.height {
+        for x in 0..source.width {
+            let around: [[u8; 3]; 9] = neighbours(source, x, y);
+            let mut out: Vec<[u8; 3]> = vec![around[4]; scale * scale];
+            // Each corner in turn, with the kernel and block rotated so it is the bottom right one
+            for turns in 0..4 {
+                let blend: [Blend; 4] = core::array::from_fn(|corner| blends[y * source.width + x][(corner + 4 - turns) % 4]);
+                if blend[2] == Blend::None { continue; }
+                let [_, b, c, d, e, f, g, h, i]: [[u8; 3]; 9] = core::array::from_fn(|n| {
+                    let (row, col) = unrotate(3, turns, (n / 3, n % 3));
+                    around[row * 3 + col]
+                });
+                let line: bool = blend[2] == Blend::Dominant || !(
+                    (blend[1] != Blend::None && !eq(e, g)) || (blend[3] != Blend::None && !eq(e, c))
+                    || (!eq(e, i) && eq(g, h) && eq(h, i) && eq(i, f) && eq(f, c))
+                );
+                let colour: [u8; 3] = if distance(e, f) <= distance(e, h) { f } else { h };
+                let mut mix = |(row, col): (usize, usize), amount: f64| {
+                    let (row, col) = unrotate(scale, turns, (row, col));
+                    let pixel: &mut [u8; 3] = &mut out[row * scale + col];
+                    *pixel = core::array::from_fn(|n| (pixel[n] as f64 + (colour[n] as f64 - pixel[n] as f64) * amount + 0.5) as u8);
+                };
+                let last: usize = scale - 1;
+                if !line {
+                    mix((last, last), if scale == 2 { 0.21 } else { 0.45 });
+                    continue;
+                }
+                let (fg, hc): (f64, f64) = (distance(f, g), distance(h, c));
+                let shallow: bool = STEEP_DIRECTION * fg <= hc && e != g && d != g;
+                let steep: bool = STEEP_DIRECTION * hc <= fg && e != c && b != c;
+                match (shallow, steep, scale) {
+                    (true, true, 2) => { mix((1, 0), 0.25); mix((0, 1), 0.25); mix((1, 1), 5.0 / 6.0); }
+                    (true, true, _) => { mix((2, 0), 0.25); mix((0, 2), 0.25); mix((2, 1), 0.75); mix((1, 2), 0.75); mix((2, 2), 1.0); }
+                    (true, false, 2) => { mix((1, 0), 0.25); mix((1, 1), 0.75); }
+                    (true, false, _) => { mix((2, 0), 0.25); mix((1, 2), 0.25); mix((2, 1), 0.75); mix((2, 2), 1.0); }
+                    (false, true, 2) => { mix((0, 1), 0.25); mix((1, 1), 0.75); }
+                    (false, true, _) => { mix((0, 2), 0.25); mix((2, 1), 0.25); mix((1, 2), 0.75); mix((2, 2), 1.0); }
+                    (false, false, 2) => mix((1, 1), 0.5),
+                    (false, false, _) => { mix((1, 2), 0.125); mix((2, 1), 0.125); mix((2, 2), 0.875); }
+                }
+            }
+            for (n, rgb) in out.into_iter().enumerate() { image.set_pixel(x * scale + n % scale, y * scale + n / scale, rgb); }
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scale2x_smooths_diagonals_and_keeps_flat_areas() {
+        // A white diagonal staircase on black: the inner corners of each step get filled in
+        let mut frame: Frame = Frame::new();
+        frame.set_pixel(1, 0, (0xFF, 0xFF, 0xFF));
+        frame.set_pixel(0, 1, (0xFF, 0xFF, 0xFF));
+        let image: Image = Upscaler::Scale2x.apply(&frame);
+        assert_eq!((image.width, image.height), (512, 480));
+        assert_eq!(image.pixel(1, 1), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(image.pixel(0, 0), [0, 0, 0]);
+        assert_eq!(image.pixel(100, 100), [0, 0, 0]);
+        let image: Image = Upscaler::Scale3x.apply(&frame);
+        assert_eq!((image.width, image.height), (768, 720));
+        assert_eq!(Upscaler::parse("Scale3x"), Ok(Upscaler::Scale3x));
+    }
+
+    #[test]
+    fn test_xbrz_blends_diagonal_edges() {
+        // A white staircase below the diagonal: the steps get blended in, flat areas stay exactly as drawn
+        let mut frame: Frame = Frame::new();
+        for y in 0..240 {
+            for x in 0..y { frame.set_pixel(x, y, (0xFF, 0xFF, 0xFF)); }
+        }
+        for upscaler in [Upscaler::Xbrz2x, Upscaler::Xbrz3x] {
+            let image: Image = upscaler.apply(&frame);
+            let scale: usize = upscaler.factor();
+            assert_eq!((image.width, image.height), (256 * scale, 240 * scale));
+            let blended: usize = image.data.chunks_exact(3).filter(|rgb| rgb[0] != 0 && rgb[0] != 0xFF).count();
+            assert!(blended > 0, "{}", upscaler.name());
+            assert_eq!(image.pixel(5 * scale, 100 * scale), [0xFF, 0xFF, 0xFF]);
+            assert_eq!(image.pixel(100 * scale, 5 * scale), [0, 0, 0]);
+        }
+        assert_eq!(Upscaler::parse("xBRZ2x"), Ok(Upscaler::Xbrz2x));
+        assert!((sqrt(2.25) - 1.5).abs() < 1e-12);
+    }
+}
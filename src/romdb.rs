@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use crate::cartridge::{Mirroring, Region, Rom};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomDbEntry {
+    pub crc32: u32,
+    pub title: String,
+    pub region: Option<Region>,
+    pub mapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub battery: Option<bool>,
+}
+
+// Known-good dumps keyed by CRC32, used to fix up bad iNES headers at load time. Nothing is built
+// in: the entries come from the user's own CSV, or from nes20db.xml for the whole No-Intro set.
+#[derive(Default)]
+pub struct RomDb { entries: HashMap<u32, RomDbEntry> }
+
+impl RomDb {
+    pub fn new() -> Self { RomDb::default() }
+    // A .xml file is read as the NES 2.0 database, anything else as our CSV
+    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let text: String = std::fs::read_to_string(path).map_err(|err| format!("Could not read {}: {}", path, err))?;
+        let loaded: Result<(), String> = if path.to_lowercase().ends_with(".xml") { self.load_nes20db(&text) } else { self.load_csv(&text) };
+        loaded.map_err(|err| format!("{}: {}", path, err))
+    }
+    // One game per line, `crc32,title,region,mapper,mirroring,battery`, e.g.
+    //   3337EC46,Super Mario Bros.,NTSC,0,VERTICAL,
+    // with the CRC32 taken over PRG+CHR (no header) like No-Intro, region NTSC, PAL or Dendy,
+    // mirroring HORIZONTAL, VERTICAL or FOURSCREEN and battery yes or no. Empty fields keep the
+    // header's value and # starts a comment. Later entries override earlier ones, so a CSV loaded
+    // after nes20db.xml can correct it.
+    pub fn load_csv(&mut self, text: &str) -> Result<(), String> {
+        for (n, line) in text.lines().enumerate() {
+            let line: &str = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let entry: RomDbEntry = parse_entry(line).map_err(|err| format!("line {}: {}", n + 1, err))?;
+            self.entries.insert(entry.crc32, entry);
+        }
+        Ok(())
+    }
+    // nes20db.xml, the NES 2.0 header database NewRisingSun keeps on the nesdev forums, which
+    // covers the whole No-Intro set. Each <game> names the dump in a comment, has the CRC32 of
+    // PRG+CHR in <rom>, and the header fields in <pcb> and <console>.
+    pub fn load_nes20db(&mut self, text: &str) -> Result<(), String> {
+        for (n, game) in text.split("<game>").skip(1).enumerate() {
+            let game: &str = game.split("</game>").next().unwrap_or(game);
+            // Disk images and the like have no <rom> to match
+            let Some(crc) = attribute(game, "rom", "crc32") else { continue; };
+            let crc32: u32 = u32::from_str_radix(crc, 16).map_err(|_| format!("game {}: invalid CRC32 '{}'", n + 1, crc))?;
+            let name: &str = game.split("<!--").nth(1).and_then(|comment| comment.split("-->").next()).unwrap_or("").trim();
+            let name: &str = name.rsplit(['\\', '/']).next().unwrap_or(name);
+            let title: String = String::from(name.strip_suffix(".nes").unwrap_or(name));
+            let region: Option<Region> = match attribute(game, "console", "region") {
+                Some("0") => Some(Region::NTSC),
+                Some("1") => Some(Region::PAL),
+                Some("3") => Some(Region::Dendy),
+                _ => None,
+            };
+            // Mappers past 255 are NES 2.0 only, which the iNES loader doesn't support anyway
+            let mapper: Option<u8> = attribute(game, "pcb", "mapper").and_then(|mapper| mapper.parse().ok());
+            let mirroring: Option<Mirroring> = match attribute(game, "pcb", "mirroring") {
+                Some("H") => Some(Mirroring::HORIZONTAL),
+                Some("V") => Some(Mirroring::VERTICAL),
+                Some("4") => Some(Mirroring::FOURSCREEN),
+                _ => None,
+            };
+            let battery: Option<bool> = attribute(game, "pcb", "battery").map(|battery| battery == "1");
+            self.entries.insert(crc32, RomDbEntry { crc32, title, region, mapper, mirroring, battery });
+        }
+        Ok(())
+    }
+    pub fn len(&self) -> usize { self.entries.len() }
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+    pub fn lookup(&self, rom: &Rom) -> Option<&RomDbEntry> { self.entries.get(&rom.crc32()) }
+    // Overwrites the header-derived fields with the database ones, returning the matched entry
+    pub fn apply(&self, rom: &mut Rom) -> Option<&RomDbEntry> {
+        let entry: &RomDbEntry = self.lookup(rom)?;
+        if let Some(region) = entry.region { rom.region = region; }
+        if let Some(mapper) = entry.mapper { rom.mapper = mapper; }
+        if let Some(mirroring) = &entry.mirroring { rom.screen_mirroring = mirroring.clone(); }
+        if let Some(battery) = entry.battery { rom.battery = battery; }
+        Some(entry)
+    }
+}
+
+fn optional<T>(field: Option<&str>, parse: impl Fn(&str) -> Result<T, String>) -> Result<Option<T>, String> {
+    match field.map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => parse(value).map(Some),
+    }
+}
+
+// The value of `name` in the first <`tag` ...> element of `xml`
+fn attribute<'a>(xml: &'a str, tag: &str, name: &str) -> Option<&'a str> {
+    let element: &str = xml.split(&format!("<{}", tag)).nth(1)?.split('>').next()?;
+    element.split(&format!(" {}=\"", name)).nth(1)?.split('"').next()
+}
+
+fn parse_entry(line: &str) -> Result<RomDbEntry, String> {
+    let mut fields = line.split(',');
+    let crc: &str = fields.next().unwrap_or("").trim();
+    let crc32: u32 = u32::from_str_radix(crc, 16).map_err(|_| format!("invalid CRC32 '{}'", crc))?;
+    let title: String = fields.next().unwrap_or("").trim().to_string();
+    let region: Option<Region> = optional(fields.next(), |v| match v.to_uppercase().as_str() {
+        "NTSC" => Ok(Region::NTSC),
+        "PAL" => Ok(Region::PAL),
+        "DENDY" => Ok(Region::Dendy),
+        _ => Err(format!("invalid region '{}'", v)),
+    })?;
+    let mapper: Option<u8> = optional(fields.next(), |v| v.parse::<u8>().map_err(|_| format!("invalid mapper '{}'", v)))?;
+    let mirroring: Option<Mirroring> = optional(fields.next(), |v| match v.to_uppercase().as_str() {
+        "HORIZONTAL" => Ok(Mirroring::HORIZONTAL),
+        "VERTICAL" => Ok(Mirroring::VERTICAL),
+        "FOURSCREEN" => Ok(Mirroring::FOURSCREEN),
+        _ => Err(format!("invalid mirroring '{}'", v)),
+    })?;
+    let battery: Option<bool> = optional(fields.next(), |v| match v {
+        "1" | "yes" | "true" => Ok(true),
+        "0" | "no" | "false" => Ok(false),
+        _ => Err(format!("invalid battery flag '{}'", v)),
+    })?;
+    Ok(RomDbEntry { crc32, title, region, mapper, mirroring, battery })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test;
+
+    #[test]
+    fn test_apply_overrides_header() {
+        let mut rom: Rom = test::test_rom();
+        let mut db: RomDb = RomDb::new();
+        db.load_csv(&format!("# comment\n{:08X},Test Game,PAL,1,HORIZONTAL,yes\n", rom.crc32())).unwrap();
+
+        let entry: RomDbEntry = db.apply(&mut rom).unwrap().clone();
+        assert_eq!(entry.title, "Test Game");
+        assert_eq!(rom.mapper, 1);
+        assert_eq!(rom.region, Region::PAL);
+        assert_eq!(rom.screen_mirroring, Mirroring::HORIZONTAL);
+        assert!(rom.battery);
+    }
+
+    #[test]
+    fn test_empty_fields_keep_header() {
+        let mut rom: Rom = test::test_rom();
+        let mut db: RomDb = RomDb::new();
+        db.load_csv(&format!("{:08x},Test Game,,,,", rom.crc32())).unwrap();
+        db.apply(&mut rom).unwrap();
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_nes20db_entries() {
+        let mut rom: Rom = test::test_rom();
+        let xml: String = format!(concat!(
+            "<?xml version=\"1.0\"?>\n<nes20db>\n",
+            "<game>\n\t<!-- Games\\Test Game (Europe).nes -->\n\t<prgrom size=\"32768\" crc32=\"00000000\"/>\n",
+            "\t<rom size=\"40960\" crc32=\"{:08X}\"/>\n\t<pcb mapper=\"1\" submapper=\"0\" mirroring=\"H\" battery=\"1\"/>\n",
+            "\t<console type=\"0\" region=\"1\"/>\n</game>\n",
+            "<game>\n\t<!-- Disk.fds -->\n\t<pcb mapper=\"20\"/>\n</game>\n</nes20db>\n",
+        ), rom.crc32());
+        let mut db: RomDb = RomDb::new();
+        db.load_nes20db(&xml).unwrap();
+        assert_eq!(db.len(), 1);
+        let entry: RomDbEntry = db.apply(&mut rom).unwrap().clone();
+        assert_eq!(entry.title, "Test Game (Europe)");
+        assert_eq!((rom.mapper, rom.region, rom.screen_mirroring, rom.battery), (1, Region::PAL, Mirroring::HORIZONTAL, true));
+    }
+
+    #[test]
+    fn test_malformed_line() {
+        let mut db: RomDb = RomDb::new();
+        assert_eq!(db.load_csv("\nZZZ,Bad"), Err("line 2: invalid CRC32 'ZZZ'".to_string()));
+    }
+}
@@ -0,0 +1,375 @@
+use crate::prelude::*;
+use crate::cpu::CPU;
+use ruzstd::decoding::FrameDecoder;
+use ruzstd::encoding::{CompressionLevel, compress_to_vec};
+
+// Savestates: every stateful component writes its fields, in declaration order, into a flat
+// little-endian byte stream. ROM contents are never saved; a state only loads back into the
+// same game (checked through the ROM's CRC32).
+//
+// Container layout, all little-endian:
+//   "GBNS" | version: u16 | compression: u8 | uncompressed length: u32 | payload
+// The (zstd-compressed) payload is a list of sections, one per component:
+//   tag: [u8; 4] | length: u32 | data
+// Unknown sections are skipped, so a newer emulator can add components without breaking
+// old states; a missing or malformed section rejects the whole state.
+//
+// Each change to a section's layout bumps the version; loading reads older layouts through
+// StateReader::version, filling in what they lack:
+//   1: the first layout
+//   2: PPU scroll and address as loopy v/t/x/w, the dot pipeline and the open-bus latch
+//   3: the bus's PAL dot remainder
+//   4: the frame counter's delayed $4017 write
+//   5: the length counter's clocked flag, and no more frame counter clock block
+//   6: the bus's overclock cycles left
+const MAGIC: [u8; 4] = *b"GBNS";
+pub const VERSION: u16 = 6;
+const HEADER_SIZE: usize = 11;
+const COMPRESSION_NONE: u8 = 0;
+const COMPRESSION_ZSTD: u8 = 1;
+// No real state is anywhere near this; a larger length means a corrupt header
+const MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Default)]
+pub struct StateWriter { pub data: Vec<u8> }
+
+impl StateWriter {
+    pub fn new() -> Self { StateWriter { data: Vec::new() } }
+    pub fn write(&mut self, bytes: &[u8]) { self.data.extend_from_slice(bytes); }
+}
+
+pub struct StateReader<'a> { data: &'a [u8], pos: usize, version: u16 }
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self { StateReader::with_version(data, VERSION) }
+    // For data written by an older layout
+    pub fn with_version(data: &'a [u8], version: u16) -> Self { StateReader { data, pos: 0, version } }
+    pub fn version(&self) -> u16 { self.version }
+    pub fn read(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes: &[u8] = self.data.get(self.pos..self.pos + len).ok_or("Savestate is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut array: [u8; N] = [0; N];
+        array.copy_from_slice(self.read(N)?);
+        Ok(array)
+    }
+    pub fn remaining(&self) -> usize { self.data.len() - self.pos }
+}
+
+pub trait Savestate {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String>;
+}
+
+// Implements Savestate by saving the listed fields in order
+macro_rules! savestate_fields {
+    ($type:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::savestate::Savestate for $type {
+            fn save_state(&self, w: &mut $crate::savestate::StateWriter) {
+                $($crate::savestate::Savestate::save_state(&self.$field, w);)*
+            }
+            fn load_state(&mut self, r: &mut $crate::savestate::StateReader) -> Result<(), $crate::prelude::String> {
+                $($crate::savestate::Savestate::load_state(&mut self.$field, r)?;)*
+                Ok(())
+            }
+        }
+    };
+}
+
+macro_rules! savestate_numbers {
+    ($($type:ty),*) => {$(
+        impl Savestate for $type {
+            fn save_state(&self, w: &mut StateWriter) { w.write(&self.to_le_bytes()); }
+            fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+                *self = <$type>::from_le_bytes(r.read_array()?);
+                Ok(())
+            }
+        }
+    )*};
+}
+savestate_numbers!(u8, i8, u16, u32, u64, i64, f32, f64);
+
+impl Savestate for bool {
+    fn save_state(&self, w: &mut StateWriter) { (*self as u8).save_state(w); }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        *self = r.read(1)?[0] != 0;
+        Ok(())
+    }
+}
+
+impl Savestate for usize {
+    fn save_state(&self, w: &mut StateWriter) { (*self as u64).save_state(w); }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        *self = u64::from_le_bytes(r.read_array()?) as usize;
+        Ok(())
+    }
+}
+
+impl<T: Savestate + Default> Savestate for Option<T> {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.is_some().save_state(w);
+        if let Some(value) = self { value.save_state(w); }
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let mut present: bool = false;
+        present.load_state(r)?;
+        *self = if present {
+            let mut value: T = T::default();
+            value.load_state(r)?;
+            Some(value)
+        } else { None };
+        Ok(())
+    }
+}
+
+impl<T: Savestate, const N: usize> Savestate for [T; N] {
+    fn save_state(&self, w: &mut StateWriter) { for item in self.iter() { item.save_state(w); } }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        for item in self.iter_mut() { item.load_state(r)?; }
+        Ok(())
+    }
+}
+
+impl<A: Savestate, B: Savestate> Savestate for (A, B) {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.0.save_state(w);
+        self.1.save_state(w);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.0.load_state(r)?;
+        self.1.load_state(r)
+    }
+}
+
+// RAM whose size depends on the cartridge (e.g. CHR RAM): the length must match on load
+impl Savestate for Vec<u8> {
+    fn save_state(&self, w: &mut StateWriter) {
+        (self.len() as u32).save_state(w);
+        w.write(self);
+    }
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let mut len: u32 = 0;
+        len.load_state(r)?;
+        if len as usize != self.len() { return Err(String::from("Savestate was made with a different ROM")); }
+        self.copy_from_slice(r.read(len as usize)?);
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct SectionWriter { data: Vec<u8> }
+
+impl SectionWriter {
+    pub fn new() -> Self { SectionWriter { data: Vec::new() } }
+    pub fn section(&mut self, tag: &[u8; 4], state: &dyn Savestate) {
+        let mut w: StateWriter = StateWriter::new();
+        state.save_state(&mut w);
+        self.data.extend_from_slice(tag);
+        self.data.extend_from_slice(&(w.data.len() as u32).to_le_bytes());
+        self.data.extend_from_slice(&w.data);
+    }
+}
+
+pub struct Sections<'a> { sections: Vec<([u8; 4], &'a [u8])>, version: u16 }
+
+impl<'a> Sections<'a> {
+    pub fn parse(payload: &'a [u8], version: u16) -> Result<Self, String> {
+        let mut r: StateReader = StateReader::new(payload);
+        let mut sections: Vec<([u8; 4], &'a [u8])> = Vec::new();
+        while r.remaining() > 0 {
+            let tag: [u8; 4] = r.read_array()?;
+            let len: u32 = u32::from_le_bytes(r.read_array()?);
+            sections.push((tag, r.read(len as usize)?));
+        }
+        Ok(Sections { sections, version })
+    }
+    pub fn has(&self, tag: &[u8; 4]) -> bool { self.sections.iter().any(|(t, _)| t == tag) }
+    pub fn load(&self, tag: &[u8; 4], state: &mut dyn Savestate) -> Result<(), String> {
+        let name = String::from_utf8_lossy(tag);
+        let data: &[u8] = self.sections.iter().find(|(t, _)| t == tag).map(|(_, data)| *data)
+            .ok_or(format!("Savestate is missing the {} section", name.trim_end()))?;
+        let mut r: StateReader = StateReader::with_version(data, self.version);
+        state.load_state(&mut r).map_err(|err| format!("Savestate section {} is corrupt: {}", name.trim_end(), err))?;
+        if r.remaining() != 0 { return Err(format!("Savestate section {} has the wrong size", name.trim_end())); }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StateInfo {
+    pub frame: u64,
+    pub timestamp: u64, // seconds since the Unix epoch, supplied by the frontend
+}
+savestate_fields!(StateInfo { frame, timestamp });
+
+pub fn save(cpu: &CPU, timestamp: u64) -> Vec<u8> {
+    let mut sections: SectionWriter = SectionWriter::new();
+    sections.section(b"INFO", &StateInfo { frame: cpu.bus.frames, timestamp });
+    sections.section(b"ROM ", &cpu.bus.rom_crc32);
+    sections.section(b"CPU ", cpu);
+    cpu.bus.save_sections(&mut sections);
+    let payload: Vec<u8> = compress_to_vec(&sections.data[..], CompressionLevel::Fastest);
+
+    let mut data: Vec<u8> = Vec::with_capacity(HEADER_SIZE + payload.len());
+    data.extend_from_slice(&MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.push(COMPRESSION_ZSTD);
+    data.extend_from_slice(&(sections.data.len() as u32).to_le_bytes());
+    data.extend_from_slice(&payload);
+    data
+}
+
+// Checks the container header and returns its version and the uncompressed section list
+fn payload(data: &[u8]) -> Result<(u16, Vec<u8>), String> {
+    let mut r: StateReader = StateReader::new(data);
+    if r.read(MAGIC.len()).ok() != Some(&MAGIC[..]) { return Err(String::from("Not a savestate")); }
+    let version: u16 = u16::from_le_bytes(r.read_array()?);
+    if version > VERSION { return Err(format!("Savestate version {} was made by a newer emulator (this one reads up to {})", version, VERSION)); }
+    let compression: u8 = r.read(1)?[0];
+    let len: usize = u32::from_le_bytes(r.read_array()?) as usize;
+    if len > MAX_PAYLOAD_SIZE { return Err(String::from("Savestate header is corrupt")); }
+    let body: &[u8] = r.read(r.remaining())?;
+    match compression {
+        COMPRESSION_NONE => Ok((version, body.to_vec())),
+        COMPRESSION_ZSTD => {
+            let mut payload: Vec<u8> = Vec::with_capacity(len);
+            FrameDecoder::new().decode_all_to_vec(body, &mut payload).map_err(|err| format!("Savestate is corrupt: {:?}", err))?;
+            if payload.len() != len { return Err(String::from("Savestate is corrupt: wrong uncompressed size")); }
+            Ok((version, payload))
+        }
+        n => Err(format!("Savestate uses unknown compression {}", n)),
+    }
+}
+
+pub fn info(data: &[u8]) -> Result<StateInfo, String> {
+    let (version, payload): (u16, Vec<u8>) = payload(data)?;
+    let mut info: StateInfo = StateInfo { frame: 0, timestamp: 0 };
+    Sections::parse(&payload, version)?.load(b"INFO", &mut info)?;
+    Ok(info)
+}
+
+fn load_sections(cpu: &mut CPU, sections: &Sections) -> Result<(), String> {
+    sections.load(b"CPU ", cpu)?;
+    cpu.bus.load_sections(sections)
+}
+
+// A state that fails to load leaves the machine exactly as it was
+pub fn load(cpu: &mut CPU, data: &[u8]) -> Result<StateInfo, String> {
+    let (version, payload): (u16, Vec<u8>) = payload(data)?;
+    let sections: Sections = Sections::parse(&payload, version)?;
+    let mut info: StateInfo = StateInfo { frame: 0, timestamp: 0 };
+    sections.load(b"INFO", &mut info)?;
+    let mut rom_crc32: u32 = 0;
+    sections.load(b"ROM ", &mut rom_crc32)?;
+    if rom_crc32 != cpu.bus.rom_crc32 {
+        return Err(format!("Savestate belongs to a different ROM (CRC32 {:08X}, loaded {:08X})", rom_crc32, cpu.bus.rom_crc32));
+    }
+
+    let backup: Vec<u8> = payload_of(cpu);
+    if let Err(err) = load_sections(cpu, &sections) {
+        load_sections(cpu, &Sections::parse(&backup, VERSION)?).expect("restoring a fresh savestate");
+        return Err(err);
+    }
+    Ok(info)
+}
+
+fn payload_of(cpu: &CPU) -> Vec<u8> {
+    let mut sections: SectionWriter = SectionWriter::new();
+    sections.section(b"CPU ", cpu);
+    cpu.bus.save_sections(&mut sections);
+    sections.data
+}
+
+// The machine's sections with no container, compression or ROM check, for states taken and
+// restored within a session many times a second (run-ahead); `save` is for states kept on disk
+pub fn snapshot(cpu: &CPU) -> Vec<u8> { payload_of(cpu) }
+pub fn restore(cpu: &mut CPU, snapshot: &[u8]) -> Result<(), String> { load_sections(cpu, &Sections::parse(snapshot, VERSION)?) }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+    use crate::cpu::Mem;
+
+    fn spinning_cpu() -> CPU<'static> {
+        let mut cpu = CPU::new(Bus::new(test::spinning_rom(), |_, _, _| {}));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_save_and_load_roundtrip() {
+        let mut cpu = spinning_cpu();
+        cpu.run_frame();
+        cpu.mem_write(0x10, 0x42);
+        cpu.register_a = 7;
+        let state: Vec<u8> = save(&cpu, 1234);
+        cpu.run_frame();
+        cpu.mem_write(0x10, 0);
+        cpu.register_a = 0;
+        let info: StateInfo = load(&mut cpu, &state).unwrap();
+        assert_eq!(info, StateInfo { frame: 1, timestamp: 1234 });
+        assert_eq!(cpu.mem_read(0x10), 0x42);
+        assert_eq!(cpu.register_a, 7);
+        assert_eq!(cpu.bus.frames, 1);
+        assert_eq!(save(&cpu, 1234), state);
+    }
+
+    #[test]
+    fn test_snapshot_restores_the_machine() {
+        let mut cpu = spinning_cpu();
+        cpu.mem_write(0x10, 0x42);
+        let state: Vec<u8> = snapshot(&cpu);
+        cpu.run_frame();
+        cpu.mem_write(0x10, 0);
+        restore(&mut cpu, &state).unwrap();
+        assert_eq!((cpu.mem_read(0x10), cpu.bus.frames), (0x42, 0));
+        assert_eq!(snapshot(&cpu), state);
+    }
+
+    #[test]
+    fn test_bad_state_is_rejected_and_machine_untouched() {
+        let mut cpu = spinning_cpu();
+        cpu.mem_write(0x10, 0x42);
+        let mut state: Vec<u8> = save(&cpu, 0);
+        state.truncate(state.len() - 1);
+        cpu.mem_write(0x10, 0x43);
+        assert!(load(&mut cpu, &state).is_err());
+        assert_eq!(cpu.mem_read(0x10), 0x43);
+        assert!(load(&mut cpu, b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_newer_version_and_other_rom_are_rejected() {
+        let mut cpu = spinning_cpu();
+        let mut state: Vec<u8> = save(&cpu, 0);
+        state[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(load(&mut cpu, &state).unwrap_err().contains("newer emulator"));
+
+        let mut rom = test::test_rom();
+        rom.prg_rom[0] = 0xEA;
+        let mut other = CPU::new(Bus::new(rom, |_, _, _| {}));
+        assert!(load(&mut other, &save(&cpu, 0)).unwrap_err().contains("different ROM"));
+    }
+
+    #[test]
+    fn test_unknown_sections_are_skipped() {
+        let mut cpu = spinning_cpu();
+        cpu.register_x = 9;
+        let mut sections = SectionWriter::new();
+        sections.section(b"NEW!", &0xDEADu32);
+        let (_, mut data): (u16, Vec<u8>) = payload(&save(&cpu, 0)).unwrap();
+        data.extend(&sections.data);
+        let mut state: Vec<u8> = MAGIC.to_vec();
+        state.extend_from_slice(&VERSION.to_le_bytes());
+        state.push(COMPRESSION_NONE);
+        state.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        state.extend(&data);
+        cpu.register_x = 0;
+        load(&mut cpu, &state).unwrap();
+        assert_eq!(cpu.register_x, 9);
+    }
+}
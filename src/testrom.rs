@@ -0,0 +1,169 @@
+use crate::prelude::*;
+use crate::headless::Headless;
+use crate::render::frame::Image;
+
+const STATUS: u16 = 0x6000;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEXT: u16 = 0x6004;
+const TEXT_END: u16 = 0x7FFF;
+const RUNNING: u8 = 0x80;
+const NEEDS_RESET: u8 = 0x81;
+// blargg asks for the reset button to be held at least 100ms after requesting it
+const RESET_DELAY_FRAMES: u64 = 6;
+
+// What a blargg test ROM reported at $6000 (0 for a pass, else the number of the failed test) and the
+// text it printed at $6004
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlarggResult {
+    pub code: u8,
+    pub text: String,
+    pub frames: u64,
+}
+
+impl BlarggResult {
+    pub fn passed(&self) -> bool { self.code == 0 }
+}
+
+// Runs a ROM that follows blargg's $6000 protocol until it reports a result: it writes $DE $B0 $61
+// to $6001-$6003 once $6000 holds a status, $80 while running, $81 when it wants a reset and the
+// result code when done. Err when it hasn't finished within `max_frames`.
+pub fn run_blargg(nes: &mut Headless, max_frames: u64) -> Result<BlarggResult, String> {
+    let mut reset_at: Option<u64> = None;
+    while nes.frame_count() < max_frames {
+        nes.run_frame();
+        let frame: u64 = nes.frame_count();
+        if reset_at.is_some_and(|at| frame >= at) {
+            reset_at = None;
+            nes.cpu.reset();
+            continue;
+        }
+        let signature: [u8; 3] = [nes.cpu.bus.peek(STATUS + 1), nes.cpu.bus.peek(STATUS + 2), nes.cpu.bus.peek(STATUS + 3)];
+        if signature != SIGNATURE { continue; }
+        match nes.cpu.bus.peek(STATUS) {
+            RUNNING => {}
+            NEEDS_RESET => { reset_at.get_or_insert(frame + RESET_DELAY_FRAMES); }
+            code => return Ok(BlarggResult { code, text: text(nes), frames: frame }),
+        }
+    }
+    let text: String = text(nes);
+    Err(format!("No result after {} frames{}", max_frames, if text.is_empty() { String::new() } else { format!(", printed so far:\n{}", text) }))
+}
+
+// nestest.nes's automated mode: started at $C000 instead of its RESET vector, it runs through every
+// official then unofficial opcode and ends at the RTS at $C66E (line 8991 of nestest.log), with the
+// number of the first failed official test in $02 and unofficial test in $03. Err on a failure, or
+// if it doesn't get there within `max_instructions`.
+pub const NESTEST_START: u16 = 0xC000;
+const NESTEST_END: u16 = 0xC66E;
+
+pub fn run_nestest(nes: &mut Headless, max_instructions: usize) -> Result<(), String> {
+    nes.cpu.program_counter = NESTEST_START;
+    for _ in 0..max_instructions {
+        if nes.cpu.program_counter == NESTEST_END {
+            let (official, unofficial): (u8, u8) = (nes.cpu.bus.peek(0x02), nes.cpu.bus.peek(0x03));
+            if official == 0 && unofficial == 0 { return Ok(()); }
+            return Err(format!("nestest failed: $02 = ${:02X} (official opcodes), $03 = ${:02X} (unofficial opcodes); nestest.txt lists what the codes mean", official, unofficial));
+        }
+        nes.cpu.step();
+    }
+    Err(format!("nestest didn't reach ${:04X} within {} instructions, PC is ${:04X}", NESTEST_END, max_instructions, nes.cpu.program_counter))
+}
+
+// How far a rendered picture may stray from its reference: each channel may be off by up to
+// `channel`, and up to `pixels` pixels may be off by more than that
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    pub channel: u8,
+    pub pixels: usize,
+}
+
+impl Tolerance {
+    pub const EXACT: Tolerance = Tolerance { channel: 0, pixels: 0 };
+}
+
+// For golden-frame tests: Err describing how many pixels differ and the first one that does
+pub fn compare_images(actual: &Image, expected: &Image, tolerance: Tolerance) -> Result<(), String> {
+    if (actual.width, actual.height) != (expected.width, expected.height) {
+        return Err(format!("Expected a {}x{} picture, got {}x{}", expected.width, expected.height, actual.width, actual.height));
+    }
+    let differs = |(a, b): (&[u8], &[u8])| a.iter().zip(b).any(|(a, b)| a.abs_diff(*b) > tolerance.channel);
+    let pixels = || actual.data.chunks_exact(3).zip(expected.data.chunks_exact(3));
+    let count: usize = pixels().filter(|pair| differs(*pair)).count();
+    if count <= tolerance.pixels { return Ok(()); }
+    let first: usize = pixels().position(differs).unwrap_or(0);
+    let (x, y): (usize, usize) = (first % actual.width, first / actual.width);
+    let hex = |rgb: [u8; 3]| format!("#{:02X}{:02X}{:02X}", rgb[0], rgb[1], rgb[2]);
+    Err(format!("{} pixels differ (tolerance {}), the first at ({}, {}): expected {}, got {}", count, tolerance.pixels, x, y, hex(expected.pixel(x, y)), hex(actual.pixel(x, y))))
+}
+
+// The NUL-terminated text at $6004, once the signature is there
+fn text(nes: &Headless) -> String {
+    let bytes: Vec<u8> = (TEXT..=TEXT_END).map(|addr| nes.cpu.bus.peek(addr)).take_while(|byte| *byte != 0).collect();
+    String::from_utf8_lossy(&bytes).trim_end().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::{test, Rom};
+
+    // Asks for a reset on its first run, then reports `code` with the text "ok"
+    fn reporting_rom(code: u8) -> Rom {
+        let mut program: Vec<u8> = Vec::new();
+        let store = |program: &mut Vec<u8>, value: u8, addr: u16| program.extend_from_slice(&[0xA9, value, 0x8D, addr as u8, (addr >> 8) as u8]);
+        let jmp_to_self = |program: &mut Vec<u8>| {
+            let addr: u16 = 0x8000 + program.len() as u16;
+            program.extend_from_slice(&[0x4C, addr as u8, (addr >> 8) as u8]);
+        };
+        store(&mut program, RUNNING, STATUS);
+        for (i, byte) in SIGNATURE.iter().enumerate() { store(&mut program, *byte, STATUS + 1 + i as u16); }
+        for (i, byte) in b"ok\0".iter().enumerate() { store(&mut program, *byte, TEXT + i as u16); }
+        // LDA $6010; BNE past the reset request, which is two stores and a JMP
+        program.extend_from_slice(&[0xAD, 0x10, 0x60, 0xD0, 13]);
+        store(&mut program, NEEDS_RESET, STATUS);
+        store(&mut program, 1, 0x6010);
+        jmp_to_self(&mut program);
+        store(&mut program, code, STATUS);
+        jmp_to_self(&mut program);
+        test::program_rom(&program)
+    }
+
+    #[test]
+    fn test_reads_the_result_after_a_requested_reset() {
+        let mut nes: Headless = Headless::new(reporting_rom(0)).unwrap();
+        let result: BlarggResult = run_blargg(&mut nes, 60).unwrap();
+        assert!(result.passed());
+        assert_eq!(result.text, "ok");
+        assert!(result.frames > RESET_DELAY_FRAMES);
+        let mut nes: Headless = Headless::new(reporting_rom(3)).unwrap();
+        assert_eq!(run_blargg(&mut nes, 60).unwrap().code, 3);
+        // One that never writes the signature
+        let mut nes: Headless = Headless::new(test::spinning_rom()).unwrap();
+        assert!(run_blargg(&mut nes, 5).is_err());
+    }
+
+    #[test]
+    fn test_compare_images_with_tolerance() {
+        let expected: Image = Image::new(4, 2);
+        let mut actual: Image = Image::new(4, 2);
+        actual.set_pixel(1, 0, [2, 0, 0]);
+        actual.set_pixel(3, 1, [0, 0, 9]);
+        assert_eq!(compare_images(&actual, &expected, Tolerance { channel: 9, pixels: 0 }), Ok(()));
+        assert_eq!(compare_images(&actual, &expected, Tolerance { channel: 2, pixels: 1 }), Ok(()));
+        assert_eq!(compare_images(&actual, &expected, Tolerance::EXACT).unwrap_err(), "2 pixels differ (tolerance 0), the first at (1, 0): expected #000000, got #020000");
+        assert!(compare_images(&Image::new(2, 4), &expected, Tolerance { channel: 255, pixels: 8 }).is_err());
+    }
+
+    #[test]
+    fn test_nestest_result_bytes() {
+        // At $C000: LDA #code; STA $03; JMP $C66E
+        let nestest = |code: u8| {
+            let mut rom: Rom = test::test_rom();
+            rom.prg_rom[0x4000..0x4007].copy_from_slice(&[0xA9, code, 0x85, 0x03, 0x4C, 0x6E, 0xC6]);
+            Headless::new(rom).unwrap()
+        };
+        assert_eq!(run_nestest(&mut nestest(0), 10), Ok(()));
+        assert!(run_nestest(&mut nestest(0x4F), 10).unwrap_err().contains("$03 = $4F"));
+        assert!(run_nestest(&mut nestest(0), 2).unwrap_err().contains("didn't reach"));
+    }
+}
@@ -1,14 +1,38 @@
+use crate::prelude::*;
 use crate::cpu::AddressingMode;
-use crate::cpu::Mem;
 use crate::cpu::CPU;
 use crate::opcodes;
-use std::collections::HashMap;
 
-pub fn trace(cpu: &mut CPU) -> String {
-    let ref opscodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+// Tracing goes through Bus::peek, so it never clears vblank, pops the controller shift registers
+// or otherwise changes what the traced instruction will see
+fn peek_u16(cpu: &CPU, pos: u16) -> u16 {
+    u16::from_le_bytes([cpu.bus.peek(pos), cpu.bus.peek(pos.wrapping_add(1))])
+}
+
+// CPU::get_absolute_address, but through Bus::peek
+fn peek_address(cpu: &CPU, mode: &AddressingMode, addr: u16) -> u16 {
+    match mode {
+        AddressingMode::ZeroPage => cpu.bus.peek(addr) as u16,
+        AddressingMode::Absolute => peek_u16(cpu, addr),
+        AddressingMode::ZeroPage_X => cpu.bus.peek(addr).wrapping_add(cpu.register_x) as u16,
+        AddressingMode::ZeroPage_Y => cpu.bus.peek(addr).wrapping_add(cpu.register_y) as u16,
+        AddressingMode::Absolute_X => peek_u16(cpu, addr).wrapping_add(cpu.register_x as u16),
+        AddressingMode::Absolute_Y => peek_u16(cpu, addr).wrapping_add(cpu.register_y as u16),
+        AddressingMode::Indirect_X => {
+            let ptr: u8 = cpu.bus.peek(addr).wrapping_add(cpu.register_x);
+            u16::from_le_bytes([cpu.bus.peek(ptr as u16), cpu.bus.peek(ptr.wrapping_add(1) as u16)])
+        }
+        AddressingMode::Indirect_Y => {
+            let base: u8 = cpu.bus.peek(addr);
+            u16::from_le_bytes([cpu.bus.peek(base as u16), cpu.bus.peek(base.wrapping_add(1) as u16)]).wrapping_add(cpu.register_y as u16)
+        }
+        _ => 0,
+    }
+}
 
-    let code = cpu.mem_read(cpu.program_counter);
-    let ops = opscodes.get(&code).unwrap();
+pub fn trace(cpu: &CPU) -> String {
+    let code = cpu.bus.peek(cpu.program_counter);
+    let ops = opcodes::lookup(code).unwrap();
 
     let begin = cpu.program_counter;
     let mut hex_dump = vec![];
@@ -17,8 +41,8 @@ pub fn trace(cpu: &mut CPU) -> String {
     let (mem_addr, stored_value) = match ops.mode {
         AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
         _ => {
-            let addr = cpu.get_absolute_address(&ops.mode, begin + 1);
-            (addr, cpu.mem_read(addr))
+            let addr = peek_address(cpu, &ops.mode, begin + 1);
+            (addr, cpu.bus.peek(addr))
         }
     };
 
@@ -28,7 +52,7 @@ pub fn trace(cpu: &mut CPU) -> String {
             _ => String::from(""),
         },
         2 => {
-            let address: u8 = cpu.mem_read(begin + 1);
+            let address: u8 = cpu.bus.peek(begin + 1);
             // let value = cpu.mem_read(address));
             hex_dump.push(address);
 
@@ -71,23 +95,23 @@ pub fn trace(cpu: &mut CPU) -> String {
             }
         }
         3 => {
-            let address_lo = cpu.mem_read(begin + 1);
-            let address_hi = cpu.mem_read(begin + 2);
+            let address_lo = cpu.bus.peek(begin + 1);
+            let address_hi = cpu.bus.peek(begin + 2);
             hex_dump.push(address_lo);
             hex_dump.push(address_hi);
 
-            let address = cpu.mem_read_u16(begin + 1);
+            let address = peek_u16(cpu, begin + 1);
 
             match ops.mode {
                 AddressingMode::NoneAddressing => {
                     if ops.code == 0x6c {
                         //jmp indirect
                         let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
+                            let lo = cpu.bus.peek(address);
+                            let hi = cpu.bus.peek(address & 0xFF00);
                             (hi as u16) << 8 | (lo as u16)
                         } else {
-                            cpu.mem_read_u16(address)
+                            peek_u16(cpu, address)
                         };
 
                         // let jmp_addr = cpu.mem_read_u16(address);
@@ -129,3 +153,79 @@ pub fn trace(cpu: &mut CPU) -> String {
     )
     .to_ascii_uppercase()
 }
+
+// Where a run first disagreed with a reference log, with the matching lines leading up to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    // 1-based line number in the reference
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+    pub before: Vec<String>,
+}
+
+// The disassembly and registers of a log line: everything up to SP, so the PPU and CYC columns of
+// nestest.log-style logs don't have to match
+fn registers_part(line: &str) -> &str {
+    match line.find("SP:") {
+        Some(i) => line.get(..i + 5).unwrap_or(line),
+        None => line.trim_end(),
+    }
+}
+
+// Steps the CPU once per line of `reference` (e.g. nestest.log), comparing its trace before each
+// instruction. Blank lines are skipped but still counted, so a divergence's line is the one an editor shows.
+// Returns how many lines matched, or the first divergence with up to `context` lines before it.
+pub fn compare(cpu: &mut CPU, reference: &str, context: usize) -> Result<usize, Divergence> {
+    let mut before: Vec<String> = Vec::new();
+    let mut matched: usize = 0;
+    for (i, line) in reference.lines().enumerate() {
+        if line.trim().is_empty() { continue; }
+        let code: u8 = cpu.bus.peek(cpu.program_counter);
+        let actual: String = match opcodes::lookup(code) {
+            Some(_) => trace(cpu),
+            None => format!("{:04X}  {:02X}  unknown opcode", cpu.program_counter, code),
+        };
+        if registers_part(&actual) != registers_part(line) {
+            return Err(Divergence { line: i + 1, expected: String::from(line), actual, before });
+        }
+        before.push(actual);
+        if before.len() > context { before.remove(0); }
+        matched += 1;
+        cpu.step();
+    }
+    Ok(matched)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test;
+
+    // `INX; JMP $8000`
+    fn looping_cpu() -> CPU<'static> {
+        let mut cpu: CPU<'static> = CPU::new(Bus::new(test::program_rom(&[0xE8, 0x4C, 0x00, 0x80]), |_, _, _| {}));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_compare_stops_at_first_divergence() {
+        let reference: &str = "\
+8000  E8        INX                             A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+8001  4C 00 80  JMP $8000                       A:00 X:01 Y:00 P:24 SP:FD PPU:  0, 27 CYC:9
+8000  E8        INX                             A:00 X:01 Y:00 P:24 SP:FD PPU:  0, 36 CYC:12
+8001  4C 00 80  JMP $8000                       A:00 X:03 Y:00 P:24 SP:FD PPU:  0, 42 CYC:14
+";
+        assert_eq!(compare(&mut looping_cpu(), &reference.lines().take(3).collect::<Vec<&str>>().join("\n"), 1), Ok(3));
+        let divergence: Divergence = compare(&mut looping_cpu(), reference, 2).unwrap_err();
+        assert_eq!(divergence.line, 4);
+        assert!(divergence.actual.ends_with("X:02 Y:00 P:24 SP:FD"));
+        assert_eq!(divergence.before.len(), 2);
+        assert!(divergence.before[1].starts_with("8000  E8        INX"));
+        // A blank line still counts towards the divergence's line number
+        let spaced: String = reference.replacen("\n", "\n\n", 1);
+        assert_eq!(compare(&mut looping_cpu(), &spaced, 2).unwrap_err().line, 5);
+    }
+}
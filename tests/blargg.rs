@@ -0,0 +1,62 @@
+// blargg's test ROM suites, run headless through testrom::run_blargg. The ROMs aren't part of the
+// repository: unpack the suites under tests/roms/ (or the directory in GBNES_TEST_ROMS), keeping
+// their directory names, e.g. tests/roms/instr_test-v5/rom_singles/01-basics.nes, and run them with
+// `cargo test --test blargg -- --ignored`. A suite that isn't there fails; every .nes file under
+// one that is has to pass.
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use gbnes_core::{Headless, Rom};
+use gbnes_core::testrom::{self, BlarggResult};
+
+use common::roms_dir;
+
+// Two minutes of emulated time; the slowest single ROMs finish in well under one
+const MAX_FRAMES: u64 = 2 * 60 * 60;
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else { return; };
+    for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if path.is_dir() { find_roms(&path, roms); }
+        else if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("nes")) { roms.push(path); }
+    }
+}
+
+fn run_suite(suite: &str) {
+    let dir: PathBuf = roms_dir().join(suite);
+    let mut roms: Vec<PathBuf> = Vec::new();
+    find_roms(&dir, &mut roms);
+    assert!(!roms.is_empty(), "No {} ROMs in {}", suite, dir.display());
+    roms.sort();
+    let mut failures: Vec<String> = Vec::new();
+    for path in &roms {
+        let name: String = path.strip_prefix(&dir).unwrap_or(path).display().to_string();
+        let result: Result<BlarggResult, String> = std::fs::read(path).map_err(|err| err.to_string())
+            .and_then(|data| Rom::new(&data))
+            .and_then(Headless::new)
+            .and_then(|mut nes| testrom::run_blargg(&mut nes, MAX_FRAMES));
+        match result {
+            Ok(result) if result.passed() => {}
+            Ok(result) => failures.push(format!("{}: failed with code {}\n{}", name, result.code, result.text)),
+            Err(err) => failures.push(format!("{}: {}", name, err)),
+        }
+    }
+    assert!(failures.is_empty(), "{} of {} {} ROMs failed:\n\n{}", failures.len(), roms.len(), suite, failures.join("\n\n"));
+}
+
+#[test]
+#[ignore = "needs blargg's instr_test-v5 under tests/roms/"]
+fn cpu_instr_test() { run_suite("instr_test-v5"); }
+
+#[test]
+#[ignore = "needs blargg's ppu_vbl_nmi under tests/roms/"]
+fn ppu_vbl_nmi() { run_suite("ppu_vbl_nmi"); }
+
+#[test]
+#[ignore = "needs blargg's apu_test under tests/roms/"]
+fn apu_test() { run_suite("apu_test"); }
+
+#[test]
+#[ignore = "needs blargg's sprite_hit_tests_2005.10.05 under tests/roms/"]
+fn sprite_hit() { run_suite("sprite_hit_tests_2005.10.05"); }
@@ -0,0 +1,7 @@
+// Shared by the test ROM integration tests
+use std::path::{Path, PathBuf};
+
+// Where the test ROMs are unpacked: tests/roms/, or the directory in GBNES_TEST_ROMS
+pub fn roms_dir() -> PathBuf {
+    std::env::var_os("GBNES_TEST_ROMS").map(PathBuf::from).unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms"))
+}
@@ -0,0 +1,89 @@
+// Golden-frame regression tests: small programs built on cartridge::test::program_rom run headless
+// for a few frames, and the picture has to match the reference PNG in tests/golden/. Outdated
+// references are rewritten from the current output with GBNES_UPDATE_GOLDEN=1; a mismatching
+// picture is saved as <reference>.actual.png for inspection.
+use std::path::{Path, PathBuf};
+
+use gbnes_core::Headless;
+use gbnes_core::cartridge::test;
+use gbnes_core::render::frame::Image;
+use gbnes_core::render::png;
+use gbnes_core::testrom::{self, Tolerance};
+
+struct Golden {
+    reference: &'static str,
+    program: Vec<u8>,
+    frames: u64,
+    tolerance: Tolerance,
+}
+
+// Waits for vblank, fills OAM with `sprites` and the palette RAM with `palette`, points the PPU back
+// at $0000 and writes `mask` to PPUMASK, then spins
+fn program(palette: &[u8], sprites: &[[u8; 4]], mask: u8) -> Vec<u8> {
+    // BIT $2002; BPL -5
+    let mut code: Vec<u8> = vec![0x2C, 0x02, 0x20, 0x10, 0xFB];
+    // LDA #value; STA addr
+    let mut store = |value: u8, addr: u16| code.extend([0xA9, value, 0x8D, addr as u8, (addr >> 8) as u8]);
+    store(0x00, 0x2003);
+    for byte in sprites.iter().flatten() { store(*byte, 0x2004); }
+    store(0x3F, 0x2006);
+    store(0x00, 0x2006);
+    for colour in palette { store(*colour, 0x2007); }
+    for addr in [0x2006, 0x2006, 0x2005, 0x2005] { store(0x00, addr); }
+    store(mask, 0x2001);
+    // JMP to itself
+    let spin: u16 = 0x8000 + code.len() as u16;
+    code.extend([0x4C, spin as u8, (spin >> 8) as u8]);
+    code
+}
+
+fn goldens() -> Vec<Golden> {
+    // test_rom's CHR is all $02 bytes: every tile is a column of colour 1 in every row
+    let palette: [u8; 32] = [
+        0x21, 0x16, 0x2A, 0x12, 0x21, 0x16, 0x2A, 0x12, 0x21, 0x16, 0x2A, 0x12, 0x21, 0x16, 0x2A, 0x12,
+        0x21, 0x30, 0x27, 0x14, 0x21, 0x30, 0x27, 0x14, 0x21, 0x30, 0x27, 0x14, 0x21, 0x30, 0x27, 0x14,
+    ];
+    vec![
+        // Rendering off: the backdrop colour only
+        Golden { reference: "backdrop.png", program: program(&palette[..1], &[], 0x00), frames: 3, tolerance: Tolerance::EXACT },
+        // The background, clipped out of the leftmost 8 pixels
+        Golden { reference: "background.png", program: program(&palette, &[], 0x08), frames: 3, tolerance: Tolerance::EXACT },
+        // Background and two sprites, one of them flipped and behind the background
+        Golden {
+            reference: "sprites.png",
+            program: program(&palette, &[[40, 0, 0x00, 64], [100, 0, 0x60, 128]], 0x1E),
+            frames: 3,
+            tolerance: Tolerance::EXACT,
+        },
+    ]
+}
+
+fn check(golden: &Golden, dir: &Path, update: bool) -> Result<(), String> {
+    let mut nes: Headless = Headless::new(test::program_rom(&golden.program))?;
+    nes.run_frames(golden.frames);
+    let actual: Image = Image::from_frame(&nes.frame);
+    let reference_path: PathBuf = dir.join(golden.reference);
+    if update {
+        std::fs::write(&reference_path, png::encode(&actual)).map_err(|err| format!("Could not write {}: {}", reference_path.display(), err))?;
+        eprintln!("Wrote {}", reference_path.display());
+        return Ok(());
+    }
+    let reference: Vec<u8> = std::fs::read(&reference_path)
+        .map_err(|err| format!("Could not read {} ({}), run with GBNES_UPDATE_GOLDEN=1 to create it", reference_path.display(), err))?;
+    let result: Result<(), String> = png::decode(&reference).and_then(|expected| testrom::compare_images(&actual, &expected, golden.tolerance));
+    if result.is_err() {
+        let actual_path: PathBuf = reference_path.with_extension("actual.png");
+        std::fs::write(&actual_path, png::encode(&actual)).map_err(|err| format!("Could not write {}: {}", actual_path.display(), err))?;
+    }
+    result
+}
+
+#[test]
+fn golden_frames() {
+    let dir: PathBuf = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden");
+    let update: bool = std::env::var_os("GBNES_UPDATE_GOLDEN").is_some_and(|value| value == "1");
+    let failures: Vec<String> = goldens().iter()
+        .filter_map(|golden| check(golden, &dir, update).err().map(|err| format!("{} after {} frames: {}", golden.reference, golden.frames, err)))
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
@@ -0,0 +1,21 @@
+// nestest.nes in its automated mode, see testrom::run_nestest. Like the blargg suites the ROM isn't
+// part of the repository: put it at tests/roms/nestest.nes (or in the directory in GBNES_TEST_ROMS)
+// and run `cargo test --test nestest -- --ignored`.
+mod common;
+
+use std::path::PathBuf;
+
+use gbnes_core::{Headless, Rom};
+use gbnes_core::testrom;
+
+// nestest.log has 8991 lines
+const MAX_INSTRUCTIONS: usize = 10_000;
+
+#[test]
+#[ignore = "needs nestest.nes under tests/roms/"]
+fn nestest() {
+    let path: PathBuf = common::roms_dir().join("nestest.nes");
+    let data: Vec<u8> = std::fs::read(&path).unwrap_or_else(|err| panic!("Could not read {}: {}", path.display(), err));
+    let mut nes: Headless = Rom::new(&data).and_then(Headless::new).unwrap();
+    testrom::run_nestest(&mut nes, MAX_INSTRUCTIONS).unwrap();
+}
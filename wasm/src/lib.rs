@@ -0,0 +1,42 @@
+use wasm_bindgen::prelude::*;
+
+use gbnes_core::{Headless, Rom};
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
+
+// Browser-facing handle: JS calls `run_frame` `frame_rate` times a second, then blits
+// `frame_ptr` (RGBA, WIDTH x HEIGHT) to a canvas and queues `take_audio` on WebAudio.
+#[wasm_bindgen]
+pub struct WasmNes {
+    nes: Headless,
+    rgba: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl WasmNes {
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom_bytes: &[u8]) -> Result<WasmNes, JsValue> {
+        let rom: Rom = Rom::new(&rom_bytes.to_vec()).map_err(|err| JsValue::from_str(&err))?;
+        let nes: Headless = Headless::new(rom).map_err(|err| JsValue::from_str(&err))?;
+        Ok(WasmNes { nes, rgba: vec![0xFF; WIDTH * HEIGHT * 4] })
+    }
+    pub fn width() -> usize { WIDTH }
+    pub fn height() -> usize { HEIGHT }
+    pub fn sample_rate() -> f32 { 44_100.0 }
+    // Frames per second of the ROM's region, which the page paces run_frame at
+    pub fn frame_rate(&mut self) -> f64 { self.nes.cpu.bus.ppu().region.frame_rate() }
+    pub fn run_frame(&mut self) {
+        self.nes.run_frame();
+        for (rgba, rgb) in self.rgba.chunks_exact_mut(4).zip(self.nes.frame.data.chunks_exact(3)) {
+            rgba[..3].copy_from_slice(rgb);
+        }
+    }
+    // Pointer into wasm memory; wrap it in a Uint8ClampedArray of WIDTH * HEIGHT * 4 bytes
+    pub fn frame_ptr(&self) -> *const u8 { self.rgba.as_ptr() }
+    // Samples generated by the last frame (interleaved stereo f32 at sample_rate)
+    pub fn take_audio(&mut self) -> Vec<f32> { self.nes.audio().to_vec() }
+    // Buttons as one bit each: A, B, Select, Start, Up, Down, Left, Right (bit 0 to 7)
+    pub fn set_buttons(&mut self, status: u8) { self.nes.set_buttons(status); }
+    pub fn reset(&mut self) { self.nes.cpu.reset(); }
+}
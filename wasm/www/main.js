@@ -0,0 +1,78 @@
+// Build the package first: wasm-pack build wasm --target web --out-dir www/pkg (which names it after the crate, gbnesmulator-wasm)
+import init, { WasmNes } from "./pkg/gbnesmulator_wasm.js";
+
+// Bit order matches JoypadButton: A, B, Select, Start, Up, Down, Left, Right
+const KEY_MAP = {
+    KeyS: 0, KeyA: 1, Space: 2, Enter: 3,
+    ArrowUp: 4, ArrowDown: 5, ArrowLeft: 6, ArrowRight: 7,
+};
+
+const wasm = await init();
+const canvas = document.getElementById("screen");
+const ctx = canvas.getContext("2d");
+let nes = null;
+let buttons = 0;
+let audio = null;
+let audioTime = 0;
+// The ROM's frames per second, and how far emulation is behind the clock, in seconds
+let frameRate = 60;
+let lastTime = null;
+let behind = 0;
+// Audio queued further ahead than this is dropped rather than let latency build up
+const MAX_AUDIO_LEAD = 0.1;
+
+function setKey(event, pressed) {
+    const bit = KEY_MAP[event.code];
+    if (bit === undefined) { return; }
+    buttons = pressed ? buttons | (1 << bit) : buttons & ~(1 << bit);
+    event.preventDefault();
+}
+document.addEventListener("keydown", (event) => setKey(event, true));
+document.addEventListener("keyup", (event) => setKey(event, false));
+
+function queueAudio(samples) {
+    if (samples.length === 0) { return; }
+    // Keep a small lead over the audio clock; resync if we fell behind, skip a buffer if too far ahead
+    audioTime = Math.max(audioTime, audio.currentTime + 0.03);
+    if (audioTime - audio.currentTime > MAX_AUDIO_LEAD) { return; }
+    // Interleaved left, right
+    const buffer = audio.createBuffer(2, samples.length / 2, WasmNes.sample_rate());
+    for (let channel = 0; channel < 2; channel++) {
+        buffer.copyToChannel(samples.filter((_, i) => i % 2 === channel).map((s) => s * 0.2), channel);
+    }
+    const source = audio.createBufferSource();
+    source.buffer = buffer;
+    source.connect(audio.destination);
+    source.start(audioTime);
+    audioTime += buffer.duration;
+}
+
+// Runs the frames the time since the last animation frame holds at the ROM's own rate, so games
+// keep their speed whatever the display's refresh rate
+function frame(now) {
+    // A quarter second at most, so a tab coming back from the background doesn't race to catch up
+    if (lastTime !== null) { behind += Math.min((now - lastTime) / 1000, 0.25); }
+    lastTime = now;
+    if (behind < 1 / frameRate) {
+        requestAnimationFrame(frame);
+        return;
+    }
+    while (behind >= 1 / frameRate) {
+        behind -= 1 / frameRate;
+        nes.set_buttons(buttons);
+        nes.run_frame();
+        queueAudio(nes.take_audio());
+    }
+    const pixels = new Uint8ClampedArray(wasm.memory.buffer, nes.frame_ptr(), WasmNes.width() * WasmNes.height() * 4);
+    ctx.putImageData(new ImageData(pixels, WasmNes.width(), WasmNes.height()), 0, 0);
+    requestAnimationFrame(frame);
+}
+
+document.getElementById("rom").addEventListener("change", async (event) => {
+    const bytes = new Uint8Array(await event.target.files[0].arrayBuffer());
+    const start = nes === null;
+    nes = new WasmNes(bytes);
+    frameRate = nes.frame_rate();
+    audio = audio || new AudioContext({ sampleRate: WasmNes.sample_rate() });
+    if (start) { requestAnimationFrame(frame); }
+});